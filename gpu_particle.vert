@@ -0,0 +1,22 @@
+#version 430 core
+struct Particle {
+	vec4 position;
+	vec4 velocity;
+};
+
+layout(std430, binding=0) readonly buffer ParticleBuffer {
+	Particle Particles[];
+};
+
+out vec4 fragColor;
+
+uniform mat4 ViewProjMtx=mat4(1);
+
+void main() {
+	Particle particle=Particles[gl_VertexID];
+	gl_Position=ViewProjMtx * vec4(particle.position.xyz,1);
+
+	// Hot and bright when young, fading out towards the end of the lifetime
+	float life=clamp(particle.position.w/max(particle.velocity.w,1e-3),0.0,1.0);
+	fragColor=vec4(mix(vec3(1.0,0.8,0.3),vec3(0.8,0.2,0.1),life),1.0-life);
+}
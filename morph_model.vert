@@ -0,0 +1,38 @@
+#version 430 core
+layout(location=0) in vec3 Position;
+layout(location=1) in vec3 Normal;
+layout(location=2) in uvec2 MorphRange;
+
+out vec3 fragPosition;
+out vec3 fragNormal;
+
+uniform mat4 ModelMtx=mat4(1);
+uniform mat4 ModelViewProjMtx=mat4(1);
+
+// Two texels per delta: position delta with the target index in w, then the
+// normal delta. MorphRange is this vertex's first delta and count.
+uniform samplerBuffer MorphDeltas;
+uniform samplerBuffer MorphWeights;
+
+void main() {
+	vec3 position=Position;
+	vec3 normal=Normal;
+	for(uint i=0u; i<MorphRange.y; i++) {
+		int texel=int(MorphRange.x + i) * 2;
+		vec4 positionDelta=texelFetch(MorphDeltas, texel);
+		vec3 normalDelta=texelFetch(MorphDeltas, texel + 1).xyz;
+		float weight=texelFetch(MorphWeights, int(positionDelta.w)).r;
+		position+=weight * positionDelta.xyz;
+		normal+=weight * normalDelta;
+	}
+	if(length(normal) > 0.0) {
+		normal=normalize(normal);
+	} else {
+		normal=Normal;
+	}
+
+	gl_Position=ModelViewProjMtx * vec4(position,1);
+
+	fragPosition=vec3(ModelMtx * vec4(position,1));
+	fragNormal=vec3(ModelMtx * vec4(normal,0));
+}
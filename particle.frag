@@ -0,0 +1,16 @@
+#version 430 core
+in vec3 fragNormal;
+in vec4 fragColor;
+
+uniform vec3 AmbientColor=vec3(0.4);
+uniform vec3 LightDirection=normalize(vec3(1,5,2));
+uniform vec3 LightColor=vec3(0.6);
+
+out vec4 finalColor;
+
+void main() {
+	vec3 irradiance=AmbientColor + LightColor * max(0,dot(LightDirection,normalize(fragNormal)));
+
+	// Gamma correction, alpha fades particles out over their life
+	finalColor=vec4(sqrt(irradiance * fragColor.rgb),fragColor.a);
+}
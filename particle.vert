@@ -0,0 +1,19 @@
+#version 430 core
+layout(location=0) in vec3 Position;
+layout(location=1) in vec3 Normal;
+layout(location=4) in vec3 InstancePosition;
+layout(location=5) in float InstanceSize;
+layout(location=6) in vec4 InstanceColor;
+
+out vec3 fragNormal;
+out vec4 fragColor;
+
+uniform mat4 ModelMtx=mat4(1);
+uniform mat4 ModelViewProjMtx=mat4(1);
+
+void main() {
+	gl_Position=ModelViewProjMtx * vec4(InstancePosition + Position * InstanceSize,1);
+
+	fragNormal=vec3(ModelMtx * vec4(Normal,0));
+	fragColor=InstanceColor;
+}
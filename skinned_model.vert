@@ -0,0 +1,60 @@
+#version 430 core
+layout(location=0) in vec3 Position;
+layout(location=1) in vec3 Normal;
+layout(location=2) in uvec4 Joints;
+layout(location=3) in vec4 Weights;
+
+out vec3 fragPosition;
+out vec3 fragNormal;
+
+uniform mat4 ModelMtx=mat4(1);
+uniform mat4 ModelViewProjMtx=mat4(1);
+uniform bool DualQuaternionSkinning=false;
+
+// Joint world matrix times inverse binding, one per joint
+layout(std430, binding=0) readonly buffer SkinningPalette {
+	mat4 SkinningMtx[];
+};
+
+// The same transforms as dual quaternions, real part then dual part
+layout(std430, binding=1) readonly buffer DualQuaternionPalette {
+	vec4 SkinningDq[];
+};
+
+vec3 rotate(vec4 q, vec3 v) {
+	return v + 2.0 * cross(q.xyz, cross(q.xyz, v) + q.w * v);
+}
+
+void main() {
+	vec4 skinnedPosition;
+	vec3 skinnedNormal;
+	if(DualQuaternionSkinning) {
+		vec4 pivot=SkinningDq[2 * Joints.x];
+		vec4 real=vec4(0);
+		vec4 dual=vec4(0);
+		for(int i=0; i<4; i++) {
+			vec4 r=SkinningDq[2 * Joints[i]];
+			float w=dot(r, pivot) < 0 ? -Weights[i] : Weights[i];
+			real+=w * r;
+			dual+=w * SkinningDq[2 * Joints[i] + 1];
+		}
+		float len=length(real);
+		real/=len;
+		dual/=len;
+		vec3 translation=2.0 * (real.w * dual.xyz - dual.w * real.xyz + cross(real.xyz, dual.xyz));
+		skinnedPosition=vec4(rotate(real, Position) + translation, 1);
+		skinnedNormal=rotate(real, Normal);
+	} else {
+		mat4 skinMtx=Weights.x * SkinningMtx[Joints.x]
+			+ Weights.y * SkinningMtx[Joints.y]
+			+ Weights.z * SkinningMtx[Joints.z]
+			+ Weights.w * SkinningMtx[Joints.w];
+		skinnedPosition=skinMtx * vec4(Position,1);
+		skinnedNormal=normalize(vec3(skinMtx * vec4(Normal,0)));
+	}
+
+	gl_Position=ModelViewProjMtx * skinnedPosition;
+
+	fragPosition=vec3(ModelMtx * skinnedPosition);
+	fragNormal=vec3(ModelMtx * vec4(skinnedNormal,0));
+}
@@ -0,0 +1,644 @@
+use std::rc::Rc;
+use crate::pose::*;
+use crate::skeleton::*;
+use crate::tokenizer::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TangentRule {
+    Flat,
+    Linear,
+    Smooth,
+    Fixed(f32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Extrapolation {
+    Constant,
+    Linear,
+    Cycle,
+    CycleOffset,
+    Bounce,
+}
+
+impl TangentRule {
+    fn parse(tokenizer: &mut Tokenizer) -> Result<TangentRule, ParseError> {
+        let line = tokenizer.get_line();
+        let token = tokenizer.next_token()?;
+        match token.as_str() {
+            "flat" => Ok(TangentRule::Flat),
+            "linear" => Ok(TangentRule::Linear),
+            "smooth" => Ok(TangentRule::Smooth),
+            _ => token.parse::<f32>()
+                .map(TangentRule::Fixed)
+                .map_err(|_| ParseError::new(tokenizer.get_file(), line, &format!("unknown tangent rule '{}'", token))),
+        }
+    }
+}
+
+impl Extrapolation {
+    fn parse(tokenizer: &mut Tokenizer) -> Result<Extrapolation, ParseError> {
+        let line = tokenizer.get_line();
+        let token = tokenizer.next_token()?;
+        match token.as_str() {
+            "constant" => Ok(Extrapolation::Constant),
+            "linear" => Ok(Extrapolation::Linear),
+            "cycle" => Ok(Extrapolation::Cycle),
+            "cycle_offset" => Ok(Extrapolation::CycleOffset),
+            "bounce" => Ok(Extrapolation::Bounce),
+            _ => Err(ParseError::new(tokenizer.get_file(), line, &format!("unknown extrapolation mode '{}'", token))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Keyframe {
+    time: f32,
+    value: f32,
+    rule_in: TangentRule,
+    rule_out: TangentRule,
+    tangent_in: f32,
+    tangent_out: f32,
+    // Cubic coefficients of the span starting at this key, in terms of u in [0, 1]
+    a: f32,
+    b: f32,
+    c: f32,
+    d: f32,
+}
+
+impl Keyframe {
+    pub fn new(time: f32, value: f32, rule_in: TangentRule, rule_out: TangentRule) -> Keyframe {
+        Keyframe {
+            time,
+            value,
+            rule_in,
+            rule_out,
+            tangent_in: 0.0,
+            tangent_out: 0.0,
+            a: 0.0,
+            b: 0.0,
+            c: 0.0,
+            d: value,
+        }
+    }
+
+    pub fn get_time(&self) -> f32 {
+        self.time
+    }
+
+    pub fn get_value(&self) -> f32 {
+        self.value
+    }
+
+    pub fn get_rule_in(&self) -> TangentRule {
+        self.rule_in
+    }
+
+    pub fn get_rule_out(&self) -> TangentRule {
+        self.rule_out
+    }
+
+    pub fn get_tangent_in(&self) -> f32 {
+        self.tangent_in
+    }
+
+    pub fn get_tangent_out(&self) -> f32 {
+        self.tangent_out
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Channel {
+    keys: Vec<Keyframe>,
+    extrapolation_in: Extrapolation,
+    extrapolation_out: Extrapolation,
+}
+
+impl Channel {
+    // Keys sharing a time would give zero length spans, the one given last wins
+    pub fn new(mut keys: Vec<Keyframe>, extrapolation_in: Extrapolation, extrapolation_out: Extrapolation) -> Channel {
+        keys.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(std::cmp::Ordering::Equal));
+        keys.dedup_by(|later, earlier| {
+            if later.time != earlier.time {
+                return false;
+            }
+            *earlier = *later;
+            true
+        });
+        let mut channel = Channel {
+            keys,
+            extrapolation_in,
+            extrapolation_out,
+        };
+        channel.precompute();
+        channel
+    }
+
+    pub fn parse(tokenizer: &mut Tokenizer) -> Result<Channel, ParseError> {
+        tokenizer.expect("{")?;
+        let mut extrapolation_in = Extrapolation::Constant;
+        let mut extrapolation_out = Extrapolation::Constant;
+        let mut keys = Vec::new();
+        loop {
+            let line = tokenizer.get_line();
+            let token = tokenizer.next_token()?;
+            match token.as_str() {
+                "extrapolate" => {
+                    extrapolation_in = Extrapolation::parse(tokenizer)?;
+                    extrapolation_out = Extrapolation::parse(tokenizer)?;
+                },
+                "keys" => {
+                    let count = tokenizer.get_usize()?;
+                    tokenizer.expect("{")?;
+                    keys.clear();
+                    for _ in 0..count {
+                        let line = tokenizer.get_line();
+                        let time = tokenizer.get_float()?;
+                        let value = tokenizer.get_float()?;
+                        let rule_in = TangentRule::parse(tokenizer)?;
+                        let rule_out = TangentRule::parse(tokenizer)?;
+                        if keys.last().is_some_and(|last: &Keyframe| last.time >= time) {
+                            return Err(ParseError::new(tokenizer.get_file(), line, "keyframe times must be increasing"));
+                        }
+                        keys.push(Keyframe::new(time, value, rule_in, rule_out));
+                    }
+                    tokenizer.expect("}")?;
+                },
+                "}" => break,
+                _ => return Err(ParseError::new(tokenizer.get_file(), line, &format!("unknown channel attribute '{}'", token))),
+            }
+        }
+        Ok(Channel::new(keys, extrapolation_in, extrapolation_out))
+    }
+
+    pub fn get_keys(&self) -> &[Keyframe] {
+        &self.keys
+    }
+
+    pub fn get_extrapolation_in(&self) -> Extrapolation {
+        self.extrapolation_in
+    }
+
+    pub fn get_extrapolation_out(&self) -> Extrapolation {
+        self.extrapolation_out
+    }
+
+    pub fn get_start_time(&self) -> f32 {
+        self.keys.first().map_or(0.0, |key| key.time)
+    }
+
+    pub fn get_end_time(&self) -> f32 {
+        self.keys.last().map_or(0.0, |key| key.time)
+    }
+
+    fn precompute(&mut self) {
+        let count = self.keys.len();
+        if count == 0 {
+            return;
+        }
+        if count == 1 {
+            let key = &mut self.keys[0];
+            key.tangent_in = if let TangentRule::Fixed(slope) = key.rule_in { slope } else { 0.0 };
+            key.tangent_out = if let TangentRule::Fixed(slope) = key.rule_out { slope } else { 0.0 };
+            return;
+        }
+
+        for i in 0..count {
+            let prev = if i > 0 { Some(self.keys[i - 1]) } else { None };
+            let next = self.keys.get(i + 1).copied();
+            let key = self.keys[i];
+            let slope = |from: &Keyframe, to: &Keyframe| (to.value - from.value) / (to.time - from.time);
+            let linear_in = prev.map(|p| slope(&p, &key)).unwrap_or_else(|| slope(&key, next.as_ref().unwrap()));
+            let linear_out = next.map(|n| slope(&key, &n)).unwrap_or(linear_in);
+            let smooth = match (prev, next) {
+                (Some(p), Some(n)) => slope(&p, &n),
+                (None, Some(_)) => linear_out,
+                _ => linear_in,
+            };
+            let compute = |rule: TangentRule, linear: f32| match rule {
+                TangentRule::Flat => 0.0,
+                TangentRule::Linear => linear,
+                TangentRule::Smooth => smooth,
+                TangentRule::Fixed(tangent) => tangent,
+            };
+            self.keys[i].tangent_in = compute(key.rule_in, linear_in);
+            self.keys[i].tangent_out = compute(key.rule_out, linear_out);
+        }
+
+        for i in 0..count - 1 {
+            let next = self.keys[i + 1];
+            let key = &mut self.keys[i];
+            let span = next.time - key.time;
+            let p0 = key.value;
+            let p1 = next.value;
+            let v0 = key.tangent_out * span;
+            let v1 = next.tangent_in * span;
+            key.a = 2.0 * p0 - 2.0 * p1 + v0 + v1;
+            key.b = -3.0 * p0 + 3.0 * p1 - 2.0 * v0 - v1;
+            key.c = v0;
+            key.d = p0;
+        }
+    }
+
+    pub fn evaluate(&self, time: f32) -> f32 {
+        let (first, last) = match (self.keys.first(), self.keys.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return 0.0,
+        };
+        if time < first.time {
+            self.extrapolate(time, self.extrapolation_in, first, last, first.tangent_in)
+        } else if time > last.time {
+            self.extrapolate(time, self.extrapolation_out, first, last, last.tangent_out)
+        } else {
+            self.interpolate(time)
+        }
+    }
+
+    fn extrapolate(&self, time: f32, mode: Extrapolation, first: &Keyframe, last: &Keyframe, slope: f32) -> f32 {
+        let length = last.time - first.time;
+        let edge = if time < first.time { first } else { last };
+        if length <= 0.0 {
+            return match mode {
+                Extrapolation::Linear => edge.value + slope * (time - edge.time),
+                _ => edge.value,
+            };
+        }
+        let cycles = ((time - first.time) / length).floor();
+        let local = time - cycles * length;
+        match mode {
+            Extrapolation::Constant => edge.value,
+            Extrapolation::Linear => edge.value + slope * (time - edge.time),
+            Extrapolation::Cycle => self.interpolate(local),
+            Extrapolation::CycleOffset => self.interpolate(local) + cycles * (last.value - first.value),
+            Extrapolation::Bounce => {
+                if (cycles as i64).rem_euclid(2) == 0 {
+                    self.interpolate(local)
+                } else {
+                    self.interpolate(first.time + last.time - local)
+                }
+            },
+        }
+    }
+
+    // Time must lie within the key range
+    fn interpolate(&self, time: f32) -> f32 {
+        let index = match self.keys.binary_search_by(|key| key.time.partial_cmp(&time).unwrap_or(std::cmp::Ordering::Less)) {
+            Ok(index) => return self.keys[index].value,
+            Err(index) => index.max(1) - 1,
+        };
+        let key = &self.keys[index];
+        let next = match self.keys.get(index + 1) {
+            Some(next) => next,
+            None => return key.value,
+        };
+        let u = (time - key.time) / (next.time - key.time);
+        ((key.a * u + key.b) * u + key.c) * u + key.d
+    }
+}
+
+// Channels 0-2 are the root translation, followed by x, y and z rotation
+// channels for every joint in skeleton order.
+#[derive(Clone, Debug)]
+pub struct Animation {
+    start_time: f32,
+    end_time: f32,
+    channels: Vec<Channel>,
+    // Other joints the clip translates, with x, y and z channels for their offsets
+    translations: Vec<(usize, [Channel; 3])>,
+}
+
+impl Animation {
+    pub fn new(start_time: f32, end_time: f32, channels: Vec<Channel>) -> Animation {
+        Animation {
+            start_time,
+            end_time,
+            channels,
+            translations: Vec::new(),
+        }
+    }
+
+    pub fn from_file(filename: &str) -> Result<Animation, ParseError> {
+        let mut tokenizer = Tokenizer::from_file(filename)?;
+        Self::parse(&mut tokenizer)
+    }
+
+    pub fn parse(tokenizer: &mut Tokenizer) -> Result<Animation, ParseError> {
+        tokenizer.expect("animation")?;
+        tokenizer.expect("{")?;
+        let mut start_time = 0.0;
+        let mut end_time = 0.0;
+        let mut num_channels = None;
+        let mut channels = Vec::new();
+        loop {
+            let line = tokenizer.get_line();
+            let token = tokenizer.next_token()?;
+            match token.as_str() {
+                "range" => {
+                    start_time = tokenizer.get_float()?;
+                    end_time = tokenizer.get_float()?;
+                },
+                "numchannels" => num_channels = Some(tokenizer.get_usize()?),
+                "channel" => channels.push(Channel::parse(tokenizer)?),
+                "}" => break,
+                _ => return Err(ParseError::new(tokenizer.get_file(), line, &format!("unknown animation attribute '{}'", token))),
+            }
+        }
+        if let Some(num_channels) = num_channels {
+            if num_channels != channels.len() {
+                return Err(tokenizer.error(&format!("numchannels is {} but {} channels were found", num_channels, channels.len())));
+            }
+        }
+        Ok(Animation::new(start_time, end_time, channels))
+    }
+
+    // Bakes poses sampled every `frame_time` from `start_time` into linear
+    // keys, with each Euler angle unwrapped against the last key so
+    // interpolation doesn't turn the long way round
+    pub fn from_poses(start_time: f32, frame_time: f32, poses: &[Pose], skeleton: &Skeleton,
+                      extrapolation_in: Extrapolation, extrapolation_out: Extrapolation) -> Animation {
+        let end_time = start_time + poses.len().saturating_sub(1) as f32 * frame_time;
+        if skeleton.get_num_joints() == 0 {
+            return Animation::new(start_time, end_time, Vec::new());
+        }
+        let pi = glm::pi::<f32>();
+        let mut values = vec![Vec::with_capacity(poses.len()); 3 + 3 * skeleton.get_num_joints()];
+        for pose in poses {
+            let root = pose.get_transform(0).get_translation();
+            for axis in 0..3 {
+                values[axis].push(root[axis]);
+            }
+            for (joint, transform) in pose.get_transforms().iter().enumerate().take(skeleton.get_num_joints()) {
+                let angles = quat_to_euler(&transform.get_rotation(), skeleton.get_joint(joint).get_rotation_order());
+                for axis in 0..3 {
+                    let channel = &mut values[3 + joint * 3 + axis];
+                    let value = match channel.last() {
+                        Some(&last) => last + (angles[axis] - last + pi).rem_euclid(2.0 * pi) - pi,
+                        None => angles[axis],
+                    };
+                    channel.push(value);
+                }
+            }
+        }
+        let bake = |values: Vec<f32>| {
+            let keys = values.into_iter().enumerate()
+                .map(|(frame, value)| Keyframe::new(start_time + frame as f32 * frame_time, value, TangentRule::Linear, TangentRule::Linear))
+                .collect();
+            Channel::new(keys, extrapolation_in, extrapolation_out)
+        };
+        let mut animation = Animation::new(start_time, end_time, values.into_iter().map(&bake).collect());
+        // Other joints only get translation channels if they move off their offsets
+        for joint in 1..skeleton.get_num_joints() {
+            let offset = skeleton.get_joint(joint).get_offset();
+            let translations: Vec<glm::Vec3> = poses.iter()
+                .filter(|pose| joint < pose.get_num_joints())
+                .map(|pose| pose.get_transform(joint).get_translation())
+                .collect();
+            if translations.len() == poses.len() && translations.iter().any(|translation| glm::length(&(translation - offset)) > 1e-6) {
+                let axis = |axis: usize| bake(translations.iter().map(|translation| translation[axis]).collect());
+                animation.add_joint_translation(joint, [axis(0), axis(1), axis(2)]);
+            }
+        }
+        animation
+    }
+
+    pub fn get_start_time(&self) -> f32 {
+        self.start_time
+    }
+
+    pub fn get_end_time(&self) -> f32 {
+        self.end_time
+    }
+
+    pub fn get_channels(&self) -> &[Channel] {
+        &self.channels
+    }
+
+    pub fn get_channel(&self, index: usize) -> Option<&Channel> {
+        self.channels.get(index)
+    }
+
+    pub fn evaluate_channel(&self, index: usize, time: f32) -> Option<f32> {
+        self.channels.get(index).map(|channel| channel.evaluate(time))
+    }
+
+    pub fn get_num_joints(&self) -> usize {
+        self.channels.len().saturating_sub(3) / 3
+    }
+
+    pub fn get_joint_translations(&self) -> &[(usize, [Channel; 3])] {
+        &self.translations
+    }
+
+    // Animates the offset of a joint other than the root, replacing any
+    // channels it already had
+    pub fn add_joint_translation(&mut self, joint: usize, channels: [Channel; 3]) {
+        self.translations.retain(|(existing, _)| *existing != joint);
+        self.translations.push((joint, channels));
+    }
+
+    fn evaluate_translation(channels: &[Channel; 3], time: f32) -> glm::Vec3 {
+        glm::vec3(channels[0].evaluate(time), channels[1].evaluate(time), channels[2].evaluate(time))
+    }
+
+    pub fn apply(&self, time: f32, skeleton: &mut Skeleton) {
+        if skeleton.get_num_joints() == 0 {
+            return;
+        }
+        if self.channels.len() >= 3 {
+            let translation = glm::vec3(
+                self.channels[0].evaluate(time),
+                self.channels[1].evaluate(time),
+                self.channels[2].evaluate(time),
+            );
+            skeleton.get_joint_mut(0).set_offset(translation);
+        }
+        let num_joints = self.get_num_joints().min(skeleton.get_num_joints());
+        for joint in 0..num_joints {
+            for axis in 0..3 {
+                let value = self.channels[3 + joint * 3 + axis].evaluate(time);
+                skeleton.get_joint_mut(joint).get_dof_mut(axis).set_value(value);
+            }
+        }
+        for (joint, channels) in &self.translations {
+            if *joint < skeleton.get_num_joints() {
+                skeleton.get_joint_mut(*joint).set_offset(Self::evaluate_translation(channels, time));
+            }
+        }
+    }
+
+    // Same as apply, but into a pose. Joints without channels keep the
+    // skeleton's current local transform.
+    pub fn sample(&self, time: f32, skeleton: &Skeleton) -> Pose {
+        let mut pose = Pose::from_skeleton(skeleton);
+        if pose.get_num_joints() == 0 {
+            return pose;
+        }
+        if self.channels.len() >= 3 {
+            let translation = glm::vec3(
+                self.channels[0].evaluate(time),
+                self.channels[1].evaluate(time),
+                self.channels[2].evaluate(time),
+            );
+            pose.get_transform_mut(0).set_translation(translation);
+        }
+        let num_joints = self.get_num_joints().min(skeleton.get_num_joints());
+        for joint in 0..num_joints {
+            let mut angles = glm::vec3(0.0, 0.0, 0.0);
+            for axis in 0..3 {
+                let dof = skeleton.get_joint(joint).get_dof(axis);
+                let value = self.channels[3 + joint * 3 + axis].evaluate(time);
+                angles[axis] = glm::clamp_scalar(value, dof.get_min(), dof.get_max());
+            }
+            let rotation = euler_to_quat(angles, skeleton.get_joint(joint).get_rotation_order());
+            pose.get_transform_mut(joint).set_rotation(rotation);
+        }
+        for (joint, channels) in &self.translations {
+            if *joint < pose.get_num_joints() {
+                pose.get_transform_mut(*joint).set_translation(Self::evaluate_translation(channels, time));
+            }
+        }
+        pose
+    }
+}
+
+#[derive(Clone)]
+pub struct AnimationPlayer {
+    animation: Rc<Animation>,
+    time: f32,
+    speed: f32,
+}
+
+impl AnimationPlayer {
+    // Clips are shared, so several players can run the same animation
+    pub fn new(animation: Rc<Animation>) -> AnimationPlayer {
+        let time = animation.get_start_time();
+        AnimationPlayer {
+            animation,
+            time,
+            speed: 1.0,
+        }
+    }
+
+    pub fn get_animation(&self) -> &Rc<Animation> {
+        &self.animation
+    }
+
+    pub fn get_time(&self) -> f32 {
+        self.time
+    }
+
+    pub fn set_time(&mut self, time: f32) {
+        self.time = time;
+    }
+
+    pub fn get_speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed;
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        self.time += dt * self.speed;
+    }
+
+    pub fn pose(&self, skeleton: &mut Skeleton) {
+        self.animation.apply(self.time, skeleton);
+    }
+
+    pub fn sample(&self, skeleton: &Skeleton) -> Pose {
+        self.animation.sample(self.time, skeleton)
+    }
+
+    pub fn reset(&mut self) {
+        self.time = self.animation.get_start_time();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // Also used by the blending tests to fill channels that don't matter
+    pub fn constant(value: f32) -> Channel {
+        Channel::new(
+            vec![Keyframe::new(0.0, value, TangentRule::Flat, TangentRule::Flat)],
+            Extrapolation::Constant,
+            Extrapolation::Constant,
+        )
+    }
+
+    fn parse_channel(text: &str) -> Result<Channel, ParseError> {
+        Channel::parse(&mut Tokenizer::from_contents("test.anim", text))
+    }
+
+    fn ramp(extrapolation: Extrapolation) -> Channel {
+        Channel::new(vec![
+            Keyframe::new(0.0, 0.0, TangentRule::Linear, TangentRule::Linear),
+            Keyframe::new(1.0, 1.0, TangentRule::Linear, TangentRule::Linear),
+        ], extrapolation, extrapolation)
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-5, "{} vs {}", actual, expected);
+    }
+
+    #[test]
+    fn hermite_tangents_follow_their_rules() {
+        let channel = parse_channel("{
+            extrapolate constant linear
+            keys 3 {
+                0 0 flat flat
+                1 2 smooth 1.5
+                3 0 linear linear
+            }
+        }").unwrap();
+        assert_eq!(channel.get_extrapolation_in(), Extrapolation::Constant);
+        assert_eq!(channel.get_extrapolation_out(), Extrapolation::Linear);
+        let keys = channel.get_keys();
+        assert_eq!(keys[1].get_rule_out(), TangentRule::Fixed(1.5));
+        assert_close(keys[0].get_tangent_out(), 0.0);
+        // Smooth takes the slope between the neighbours, linear the one towards the previous key
+        assert_close(keys[1].get_tangent_in(), 0.0);
+        assert_close(keys[1].get_tangent_out(), 1.5);
+        assert_close(keys[2].get_tangent_in(), -1.0);
+
+        assert_close(channel.evaluate(1.0), 2.0);
+        // Halfway through the second span: 0.5 * 2 + 0.125 * 1.5 * 2 + 0.125 * 1 * 2
+        assert_close(channel.evaluate(2.0), 1.625);
+        assert_close(channel.evaluate(4.0), -1.0);
+    }
+
+    #[test]
+    fn malformed_channels_are_rejected() {
+        let error = parse_channel("{ extrapolate constant sideways keys 0 { } }").err().unwrap();
+        assert_eq!(error.get_message(), "unknown extrapolation mode 'sideways'");
+        let error = parse_channel("{\n keys 2 {\n 1 0 flat flat\n 0 1 flat flat\n }\n }").err().unwrap();
+        assert_eq!((error.get_line(), error.get_message()), (4, "keyframe times must be increasing"));
+    }
+
+    #[test]
+    fn extrapolation_modes() {
+        let channel = ramp(Extrapolation::Constant);
+        assert_close(channel.evaluate(-1.0), 0.0);
+        assert_close(channel.evaluate(2.0), 1.0);
+
+        let channel = ramp(Extrapolation::Linear);
+        assert_close(channel.evaluate(-0.5), -0.5);
+        assert_close(channel.evaluate(2.0), 2.0);
+
+        let channel = ramp(Extrapolation::Cycle);
+        assert_close(channel.evaluate(1.25), 0.25);
+        assert_close(channel.evaluate(-0.75), 0.25);
+
+        let channel = ramp(Extrapolation::CycleOffset);
+        assert_close(channel.evaluate(1.25), 1.25);
+        assert_close(channel.evaluate(2.5), 2.5);
+        assert_close(channel.evaluate(-0.75), -0.75);
+
+        let channel = ramp(Extrapolation::Bounce);
+        assert_close(channel.evaluate(1.25), 0.75);
+        assert_close(channel.evaluate(2.25), 0.25);
+        assert_close(channel.evaluate(-0.25), 0.25);
+    }
+}
@@ -0,0 +1,303 @@
+use std::rc::Rc;
+use crate::animation::*;
+use crate::blender::*;
+use crate::pose::*;
+use crate::skeleton::*;
+
+#[derive(Clone)]
+pub struct BlendSample {
+    position: glm::Vec2,
+    animation: Rc<Animation>,
+}
+
+impl BlendSample {
+    pub fn get_position(&self) -> glm::Vec2 {
+        self.position
+    }
+
+    pub fn get_animation(&self) -> &Rc<Animation> {
+        &self.animation
+    }
+
+    fn get_duration(&self) -> f32 {
+        self.animation.get_end_time() - self.animation.get_start_time()
+    }
+}
+
+// Clips placed at points of a 1D (speed) or 2D (speed and direction) space.
+// 1D spaces only use the x coordinate.
+#[derive(Clone)]
+pub struct BlendSpace {
+    dimensions: usize,
+    samples: Vec<BlendSample>,
+}
+
+impl BlendSpace {
+    pub fn new_1d() -> BlendSpace {
+        BlendSpace {
+            dimensions: 1,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn new_2d() -> BlendSpace {
+        BlendSpace {
+            dimensions: 2,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn get_dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    pub fn get_samples(&self) -> &[BlendSample] {
+        &self.samples
+    }
+
+    pub fn add_sample(&mut self, position: glm::Vec2, animation: Rc<Animation>) -> usize {
+        let position = if self.dimensions == 1 { glm::vec2(position.x, 0.0) } else { position };
+        self.samples.push(BlendSample {
+            position,
+            animation,
+        });
+        self.samples.len() - 1
+    }
+
+    // One weight per sample, summing to one
+    pub fn get_weights(&self, input: glm::Vec2) -> Vec<f32> {
+        match self.samples.len() {
+            0 => Vec::new(),
+            1 => vec![1.0],
+            _ if self.dimensions == 1 => self.get_weights_1d(input.x),
+            _ => self.get_weights_2d(input),
+        }
+    }
+
+    // Linear between the two closest samples, clamped to the ends
+    fn get_weights_1d(&self, x: f32) -> Vec<f32> {
+        let mut order: Vec<usize> = (0..self.samples.len()).collect();
+        order.sort_by(|&a, &b| self.samples[a].position.x.partial_cmp(&self.samples[b].position.x)
+            .unwrap_or(std::cmp::Ordering::Equal));
+        let mut weights = vec![0.0; self.samples.len()];
+        let first = order[0];
+        let last = order[order.len() - 1];
+        if x <= self.samples[first].position.x {
+            weights[first] = 1.0;
+        } else if x >= self.samples[last].position.x {
+            weights[last] = 1.0;
+        } else {
+            for pair in order.windows(2) {
+                let (a, b) = (self.samples[pair[0]].position.x, self.samples[pair[1]].position.x);
+                if x >= a && x <= b {
+                    let t = if b > a { (x - a) / (b - a) } else { 0.0 };
+                    weights[pair[0]] = 1.0 - t;
+                    weights[pair[1]] = t;
+                    break;
+                }
+            }
+        }
+        weights
+    }
+
+    // Gradient band interpolation (Johansen 2009): each sample's influence
+    // falls off linearly towards every other sample, then everything is
+    // normalized. Works for any layout, no triangulation needed.
+    fn get_weights_2d(&self, input: glm::Vec2) -> Vec<f32> {
+        let mut weights: Vec<f32> = self.samples.iter().enumerate().map(|(i, sample)| {
+            let to_input = input - sample.position;
+            let mut weight: f32 = 1.0;
+            for (j, other) in self.samples.iter().enumerate() {
+                if i == j {
+                    continue;
+                }
+                let to_other = other.position - sample.position;
+                let length_squared = glm::dot(&to_other, &to_other);
+                if length_squared <= 1e-12 {
+                    continue;
+                }
+                weight = weight.min(1.0 - glm::dot(&to_input, &to_other) / length_squared);
+            }
+            weight.max(0.0)
+        }).collect();
+        let total: f32 = weights.iter().sum();
+        if total > 0.0 {
+            for weight in &mut weights {
+                *weight /= total;
+            }
+        } else {
+            weights = vec![1.0 / self.samples.len() as f32; self.samples.len()];
+        }
+        weights
+    }
+
+    // Length of one cycle at this input, the weighted average of the clip durations
+    pub fn get_duration(&self, input: glm::Vec2) -> f32 {
+        self.get_weights(input).iter().zip(self.samples.iter())
+            .map(|(weight, sample)| weight * sample.get_duration())
+            .sum()
+    }
+
+    // All clips are sampled at the same phase (0 to 1 over their own length)
+    // so feet stay in sync between clips of different lengths.
+    pub fn evaluate(&self, input: glm::Vec2, phase: f32, skeleton: &Skeleton) -> Pose {
+        let weights = self.get_weights(input);
+        let poses: Vec<(Pose, f32)> = self.samples.iter().zip(weights)
+            .filter(|&(_, weight)| weight > 0.0)
+            .map(|(sample, weight)| {
+                let time = sample.animation.get_start_time() + phase * sample.get_duration();
+                (sample.animation.sample(time, skeleton), weight)
+            })
+            .collect();
+        if poses.is_empty() {
+            return Pose::from_skeleton(skeleton);
+        }
+        let weighted: Vec<(&Pose, f32)> = poses.iter().map(|(pose, weight)| (pose, *weight)).collect();
+        Pose::blend(&weighted)
+    }
+}
+
+pub struct BlendSpacePlayer {
+    space: Rc<BlendSpace>,
+    input: glm::Vec2,
+    phase: f32,
+    speed: f32,
+}
+
+impl BlendSpacePlayer {
+    pub fn new(space: Rc<BlendSpace>) -> BlendSpacePlayer {
+        BlendSpacePlayer {
+            space,
+            input: glm::vec2(0.0, 0.0),
+            phase: 0.0,
+            speed: 1.0,
+        }
+    }
+
+    pub fn get_space(&self) -> &Rc<BlendSpace> {
+        &self.space
+    }
+
+    pub fn get_input(&self) -> glm::Vec2 {
+        self.input
+    }
+
+    pub fn set_input(&mut self, input: glm::Vec2) {
+        self.input = input;
+    }
+
+    // Completed cycles, the fractional part is where every clip is sampled
+    pub fn get_phase(&self) -> f32 {
+        self.phase
+    }
+
+    pub fn set_phase(&mut self, phase: f32) {
+        self.phase = phase;
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed;
+    }
+}
+
+impl PoseSource for BlendSpacePlayer {
+    fn update(&mut self, dt: f32) {
+        let duration = self.space.get_duration(self.input);
+        if duration > 0.0 {
+            self.phase += dt * self.speed / duration;
+        }
+    }
+
+    fn evaluate(&self, skeleton: &Skeleton) -> Pose {
+        self.space.evaluate(self.input, self.phase - self.phase.floor(), skeleton)
+    }
+
+    fn reset(&mut self) {
+        self.phase = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animation::tests::constant;
+
+    // Root slides along x from 0 to 1 over `duration`
+    fn slide(duration: f32) -> Rc<Animation> {
+        let x = Channel::new(vec![
+            Keyframe::new(0.0, 0.0, TangentRule::Linear, TangentRule::Linear),
+            Keyframe::new(duration, 1.0, TangentRule::Linear, TangentRule::Linear),
+        ], Extrapolation::Constant, Extrapolation::Constant);
+        let mut channels = vec![x];
+        channels.extend((0..5).map(|_| constant(0.0)));
+        Rc::new(Animation::new(0.0, duration, channels))
+    }
+
+    fn assert_weights(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-5, "{:?} vs {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn weights_1d() {
+        let mut space = BlendSpace::new_1d();
+        // Added out of order, the weights still follow the positions
+        space.add_sample(glm::vec2(3.0, 0.0), slide(1.0));
+        space.add_sample(glm::vec2(0.0, 0.0), slide(1.0));
+        space.add_sample(glm::vec2(1.0, 0.0), slide(1.0));
+        assert_weights(&space.get_weights(glm::vec2(0.0, 0.0)), &[0.0, 1.0, 0.0]);
+        assert_weights(&space.get_weights(glm::vec2(1.0, 0.0)), &[0.0, 0.0, 1.0]);
+        assert_weights(&space.get_weights(glm::vec2(3.0, 0.0)), &[1.0, 0.0, 0.0]);
+        assert_weights(&space.get_weights(glm::vec2(0.25, 0.0)), &[0.0, 0.75, 0.25]);
+        assert_weights(&space.get_weights(glm::vec2(2.5, 0.0)), &[0.75, 0.0, 0.25]);
+        // Clamped past the ends
+        assert_weights(&space.get_weights(glm::vec2(-1.0, 0.0)), &[0.0, 1.0, 0.0]);
+        assert_weights(&space.get_weights(glm::vec2(5.0, 0.0)), &[1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn weights_2d() {
+        let mut space = BlendSpace::new_2d();
+        let positions = [
+            glm::vec2(0.0, 0.0),
+            glm::vec2(1.0, 0.0),
+            glm::vec2(-1.0, 0.0),
+            glm::vec2(0.0, 2.0),
+            glm::vec2(0.5, -1.0),
+            glm::vec2(2.0, 2.0),
+        ];
+        for &position in &positions {
+            space.add_sample(position, slide(1.0));
+        }
+        for (index, &position) in positions.iter().enumerate() {
+            let mut expected = vec![0.0; positions.len()];
+            expected[index] = 1.0;
+            assert_weights(&space.get_weights(position), &expected);
+        }
+        for &input in &[glm::vec2(0.3, 0.4), glm::vec2(-0.7, 1.5), glm::vec2(1.5, -0.5), glm::vec2(5.0, 5.0)] {
+            let weights = space.get_weights(input);
+            assert!(weights.iter().all(|&weight| weight >= 0.0), "{:?}", weights);
+            assert!((weights.iter().sum::<f32>() - 1.0).abs() < 1e-5, "{:?}", weights);
+        }
+    }
+
+    #[test]
+    fn clips_of_different_lengths_stay_in_phase() {
+        let mut space = BlendSpace::new_1d();
+        space.add_sample(glm::vec2(0.0, 0.0), slide(1.0));
+        space.add_sample(glm::vec2(1.0, 0.0), slide(3.0));
+        let mut skeleton = Skeleton::new();
+        skeleton.add_joint(Joint::new("root", None));
+
+        let mut player = BlendSpacePlayer::new(Rc::new(space));
+        player.set_input(glm::vec2(0.5, 0.0));
+        assert!((player.get_space().get_duration(player.get_input()) - 2.0).abs() < 1e-5);
+        // Half a cycle of the averaged length is halfway through both clips
+        player.update(1.0);
+        assert!((player.get_phase() - 0.5).abs() < 1e-5);
+        let x = player.evaluate(&skeleton).get_transform(0).get_translation().x;
+        assert!((x - 0.5).abs() < 1e-5, "{}", x);
+    }
+}
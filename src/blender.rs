@@ -0,0 +1,257 @@
+use crate::animation::*;
+use crate::pose::*;
+use crate::skeleton::*;
+
+// Anything that advances with time and produces a pose: clips, blends, graph states
+pub trait PoseSource {
+    fn update(&mut self, dt: f32);
+    fn evaluate(&self, skeleton: &Skeleton) -> Pose;
+    fn reset(&mut self);
+}
+
+impl PoseSource for AnimationPlayer {
+    fn update(&mut self, dt: f32) {
+        AnimationPlayer::update(self, dt);
+    }
+
+    fn evaluate(&self, skeleton: &Skeleton) -> Pose {
+        self.sample(skeleton)
+    }
+
+    fn reset(&mut self) {
+        AnimationPlayer::reset(self);
+    }
+}
+
+// Plays several clips at once and mixes them with per-clip weights
+pub struct ClipBlender {
+    clips: Vec<(AnimationPlayer, f32)>,
+}
+
+impl ClipBlender {
+    pub fn new() -> ClipBlender {
+        ClipBlender {
+            clips: Vec::new(),
+        }
+    }
+
+    pub fn add_clip(&mut self, player: AnimationPlayer, weight: f32) -> usize {
+        self.clips.push((player, weight));
+        self.clips.len() - 1
+    }
+
+    pub fn get_num_clips(&self) -> usize {
+        self.clips.len()
+    }
+
+    pub fn get_player(&self, index: usize) -> &AnimationPlayer {
+        &self.clips[index].0
+    }
+
+    pub fn get_player_mut(&mut self, index: usize) -> &mut AnimationPlayer {
+        &mut self.clips[index].0
+    }
+
+    pub fn get_weight(&self, index: usize) -> f32 {
+        self.clips[index].1
+    }
+
+    pub fn set_weight(&mut self, index: usize, weight: f32) {
+        self.clips[index].1 = weight.max(0.0);
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        for (player, _) in &mut self.clips {
+            player.update(dt);
+        }
+    }
+
+    // Weights don't need to sum to one, they are normalized by the blend
+    pub fn evaluate(&self, skeleton: &Skeleton) -> Pose {
+        let poses: Vec<(Pose, f32)> = self.clips.iter()
+            .filter(|&&(_, weight)| weight > 0.0)
+            .map(|(player, weight)| (player.sample(skeleton), *weight))
+            .collect();
+        if poses.is_empty() {
+            return Pose::from_skeleton(skeleton);
+        }
+        let weighted: Vec<(&Pose, f32)> = poses.iter().map(|(pose, weight)| (pose, *weight)).collect();
+        Pose::blend(&weighted)
+    }
+
+    pub fn pose(&self, skeleton: &mut Skeleton) {
+        self.evaluate(skeleton).apply(skeleton);
+    }
+
+    pub fn reset(&mut self) {
+        for (player, _) in &mut self.clips {
+            player.reset();
+        }
+    }
+}
+
+impl PoseSource for ClipBlender {
+    fn update(&mut self, dt: f32) {
+        ClipBlender::update(self, dt);
+    }
+
+    fn evaluate(&self, skeleton: &Skeleton) -> Pose {
+        ClipBlender::evaluate(self, skeleton)
+    }
+
+    fn reset(&mut self) {
+        ClipBlender::reset(self);
+    }
+}
+
+// Fades from whatever was playing to a new source. Starting a fade while one is
+// still running keeps the old fade going underneath, so nothing pops.
+pub struct CrossFade<S: PoseSource> {
+    current: S,
+    previous: Option<Box<CrossFade<S>>>,
+    duration: f32,
+    elapsed: f32,
+}
+
+impl<S: PoseSource> CrossFade<S> {
+    pub fn new(player: S) -> CrossFade<S> {
+        CrossFade {
+            current: player,
+            previous: None,
+            duration: 0.0,
+            elapsed: 0.0,
+        }
+    }
+
+    pub fn fade_to(&mut self, player: S, duration: f32) {
+        let previous = std::mem::replace(self, CrossFade::new(player));
+        if duration > 0.0 {
+            self.previous = Some(Box::new(previous));
+            self.duration = duration;
+        }
+    }
+
+    pub fn get_current(&self) -> &S {
+        &self.current
+    }
+
+    pub fn get_current_mut(&mut self) -> &mut S {
+        &mut self.current
+    }
+
+    // The current source and every one still fading out underneath it
+    pub fn for_each_mut<F: FnMut(&mut S)>(&mut self, mut f: F) {
+        let mut fade = Some(self);
+        while let Some(next) = fade {
+            f(&mut next.current);
+            fade = next.previous.as_deref_mut();
+        }
+    }
+
+    pub fn is_fading(&self) -> bool {
+        self.previous.is_some()
+    }
+
+    // Weight of the current clip, eased so the blend starts and ends without a velocity jump
+    pub fn get_weight(&self) -> f32 {
+        if self.previous.is_none() {
+            return 1.0;
+        }
+        let t = glm::clamp_scalar(self.elapsed / self.duration, 0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        self.current.update(dt);
+        if let Some(previous) = self.previous.as_mut() {
+            previous.update(dt);
+            self.elapsed += dt;
+            if self.elapsed >= self.duration {
+                self.previous = None;
+            }
+        }
+    }
+
+    pub fn evaluate(&self, skeleton: &Skeleton) -> Pose {
+        let current = self.current.evaluate(skeleton);
+        match self.previous.as_ref() {
+            Some(previous) => Pose::lerp(&previous.evaluate(skeleton), &current, self.get_weight()),
+            None => current,
+        }
+    }
+
+    pub fn pose(&self, skeleton: &mut Skeleton) {
+        self.evaluate(skeleton).apply(skeleton);
+    }
+
+    pub fn reset(&mut self) {
+        self.current.reset();
+        self.previous = None;
+        self.elapsed = 0.0;
+    }
+}
+
+impl<S: PoseSource> PoseSource for CrossFade<S> {
+    fn update(&mut self, dt: f32) {
+        CrossFade::update(self, dt);
+    }
+
+    fn evaluate(&self, skeleton: &Skeleton) -> Pose {
+        CrossFade::evaluate(self, skeleton)
+    }
+
+    fn reset(&mut self) {
+        CrossFade::reset(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use crate::animation::tests::constant;
+
+    fn skeleton() -> Skeleton {
+        let mut skeleton = Skeleton::new();
+        skeleton.add_joint(Joint::new("root", None));
+        skeleton
+    }
+
+    // A still clip of the root at `x`, turned `angle` about the x axis
+    fn still(x: f32, angle: f32) -> AnimationPlayer {
+        let values = [x, 0.0, 0.0, angle, 0.0, 0.0];
+        let channels = values.iter().map(|&value| constant(value)).collect();
+        AnimationPlayer::new(Rc::new(Animation::new(0.0, 1.0, channels)))
+    }
+
+    #[test]
+    fn clip_weights_are_normalized() {
+        let skeleton = skeleton();
+        let mut heavy = ClipBlender::new();
+        heavy.add_clip(still(0.0, 0.0), 2.0);
+        heavy.add_clip(still(4.0, 0.8), 6.0);
+        let mut unit = ClipBlender::new();
+        unit.add_clip(still(0.0, 0.0), 0.25);
+        unit.add_clip(still(4.0, 0.8), 0.75);
+
+        let a = *heavy.evaluate(&skeleton).get_transform(0);
+        let b = *unit.evaluate(&skeleton).get_transform(0);
+        assert!((a.get_translation().x - 3.0).abs() < 1e-5);
+        assert!(glm::length(&(a.get_translation() - b.get_translation())) < 1e-5);
+        assert!(glm::quat_dot(&a.get_rotation(), &b.get_rotation()).abs() > 1.0 - 1e-6);
+        assert!((glm::quat_length(&a.get_rotation()) - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn clip_rotations_blend_the_short_way() {
+        // 170 and -170 degrees are 20 degrees apart through 180, but their
+        // quaternions point into opposite hemispheres
+        let skeleton = skeleton();
+        let mut blender = ClipBlender::new();
+        blender.add_clip(still(0.0, 170.0f32.to_radians()), 1.0);
+        blender.add_clip(still(0.0, -170.0f32.to_radians()), 1.0);
+        let rotation = blender.evaluate(&skeleton).get_transform(0).get_rotation();
+        let half_turn = glm::quat_angle_axis(glm::pi::<f32>(), &glm::vec3(1.0, 0.0, 0.0));
+        assert!(glm::quat_dot(&rotation, &half_turn).abs() > 1.0 - 1e-5, "{:?}", rotation);
+    }
+}
@@ -14,8 +14,26 @@ impl BufferType for BufferTypeElementArray {
     const BUFFER_TYPE: GLuint = gl::ELEMENT_ARRAY_BUFFER;
 }
 
+pub struct BufferTypeUniform;
+impl BufferType for BufferTypeUniform {
+    const BUFFER_TYPE: GLuint = gl::UNIFORM_BUFFER;
+}
+
+pub struct BufferTypeShaderStorage;
+impl BufferType for BufferTypeShaderStorage {
+    const BUFFER_TYPE: GLuint = gl::SHADER_STORAGE_BUFFER;
+}
+
+pub struct BufferTypeTexture;
+impl BufferType for BufferTypeTexture {
+    const BUFFER_TYPE: GLuint = gl::TEXTURE_BUFFER;
+}
+
 pub type ArrayBuffer = Buffer<BufferTypeArray>;
 pub type ElementArrayBuffer = Buffer<BufferTypeElementArray>;
+pub type UniformBuffer = Buffer<BufferTypeUniform>;
+pub type ShaderStorageBuffer = Buffer<BufferTypeShaderStorage>;
+pub type TextureBuffer = Buffer<BufferTypeTexture>;
 
 pub struct Buffer<B> where B: BufferType {
     vbo: GLuint,
@@ -46,13 +64,57 @@ impl<B> Buffer<B> where B: BufferType {
         }
     }
 
+    // Binds to an indexed binding point, the `binding` of a uniform or buffer block
+    pub fn bind_base(&self, index: GLuint) {
+        unsafe {
+            gl::BindBufferBase(B::BUFFER_TYPE, index, self.vbo);
+        }
+    }
+
     pub fn static_draw_data<T>(&self, data: &[T]) {
+        self.buffer_data(data, gl::STATIC_DRAW);
+    }
+
+    pub fn stream_draw_data<T>(&self, data: &[T]) {
+        self.buffer_data(data, gl::STREAM_DRAW);
+    }
+
+    pub fn dynamic_draw_data<T>(&self, data: &[T]) {
+        self.buffer_data(data, gl::DYNAMIC_DRAW);
+    }
+
+    // Overwrites elements starting at `offset` without reallocating, the
+    // store must already be large enough
+    pub fn sub_data<T>(&self, offset: usize, data: &[T]) {
+        unsafe {
+            gl::BufferSubData(
+                B::BUFFER_TYPE,
+                (offset * ::std::mem::size_of::<T>()) as GLintptr,
+                ::std::mem::size_of_val(data) as GLsizeiptr,
+                data.as_ptr() as *const GLvoid,
+            );
+        }
+    }
+
+    // Copies the start of the store back into `data`, stalling until the GPU is done with it
+    pub fn read_data<T>(&self, data: &mut [T]) {
+        unsafe {
+            gl::GetBufferSubData(
+                B::BUFFER_TYPE,
+                0,
+                ::std::mem::size_of_val(data) as GLsizeiptr,
+                data.as_mut_ptr() as *mut GLvoid,
+            );
+        }
+    }
+
+    pub fn buffer_data<T>(&self, data: &[T], usage: GLenum) {
         unsafe {
             gl::BufferData(
                 B::BUFFER_TYPE,
-                (data.len() * ::std::mem::size_of::<T>()) as GLsizeiptr, // size of data in bytes
+                ::std::mem::size_of_val(data) as GLsizeiptr, // size of data in bytes
                 data.as_ptr() as *const GLvoid, // pointer to data
-                gl::STATIC_DRAW,
+                usage,
             );
         }
     }
@@ -61,7 +123,7 @@ impl<B> Buffer<B> where B: BufferType {
 impl<B> Drop for Buffer<B> where B: BufferType {
     fn drop(&mut self) {
         unsafe {
-            gl::DeleteBuffers(1, &mut self.vbo);
+            gl::DeleteBuffers(1, &self.vbo);
         }
     }
 }
@@ -98,7 +160,57 @@ impl VertexArray {
 impl Drop for VertexArray {
     fn drop(&mut self) {
         unsafe {
-            gl::DeleteVertexArrays(1, &mut self.vao);
+            gl::DeleteVertexArrays(1, &self.vao);
+        }
+    }
+}
+
+// Texture over a buffer, read in shaders with texelFetch on a samplerBuffer.
+// `format` is the sized texel format, like gl::RGBA32F.
+pub struct BufferTexture {
+    texture: GLuint,
+    buffer: TextureBuffer,
+    format: GLenum,
+}
+
+impl BufferTexture {
+    pub fn new(format: GLenum) -> BufferTexture {
+        let mut texture: GLuint = 0;
+        unsafe {
+            gl::GenTextures(1, &mut texture);
+        }
+        BufferTexture {
+            texture,
+            buffer: TextureBuffer::new(),
+            format,
+        }
+    }
+
+    // Reallocates the buffer and reattaches it to the texture
+    pub fn set_data<T>(&self, data: &[T]) {
+        self.buffer.bind();
+        self.buffer.stream_draw_data(data);
+        self.buffer.unbind();
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_BUFFER, self.texture);
+            gl::TexBuffer(gl::TEXTURE_BUFFER, self.format, self.buffer.vbo);
+            gl::BindTexture(gl::TEXTURE_BUFFER, 0);
+        }
+    }
+
+    pub fn bind(&self, unit: GLuint) {
+        unsafe {
+            gl::ActiveTexture(gl::TEXTURE0 + unit);
+            gl::BindTexture(gl::TEXTURE_BUFFER, self.texture);
+            gl::ActiveTexture(gl::TEXTURE0);
+        }
+    }
+}
+
+impl Drop for BufferTexture {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteTextures(1, &self.texture);
         }
     }
 }
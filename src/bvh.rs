@@ -0,0 +1,364 @@
+use crate::animation::*;
+use crate::skeleton::*;
+use crate::tokenizer::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpAxis {
+    Y,
+    Z,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BvhOptions {
+    pub scale: f32,
+    pub up_axis: UpAxis,
+}
+
+impl BvhOptions {
+    pub fn new() -> BvhOptions {
+        BvhOptions {
+            scale: 1.0,
+            up_axis: UpAxis::Y,
+        }
+    }
+
+    // Maps a source axis to the Y-up axis it ends up on, and the sign flip
+    // that comes with it. Z-up data is rotated -90 degrees about x.
+    fn convert_axis(&self, axis: usize) -> (usize, f32) {
+        match (self.up_axis, axis) {
+            (UpAxis::Z, 1) => (2, -1.0),
+            (UpAxis::Z, 2) => (1, 1.0),
+            _ => (axis, 1.0),
+        }
+    }
+
+    fn convert_position(&self, position: glm::Vec3) -> glm::Vec3 {
+        let mut converted = glm::vec3(0.0, 0.0, 0.0);
+        for axis in 0..3 {
+            let (target, sign) = self.convert_axis(axis);
+            converted[target] = position[axis] * sign * self.scale;
+        }
+        converted
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum BvhChannel {
+    Position(usize),
+    Rotation(usize),
+}
+
+struct BvhJoint {
+    index: usize,
+    channels: Vec<BvhChannel>,
+}
+
+pub struct Bvh {
+    skeleton: Skeleton,
+    animation: Animation,
+    frame_time: f32,
+    num_frames: usize,
+}
+
+impl Bvh {
+    pub fn from_file(filename: &str, options: BvhOptions) -> Result<Bvh, ParseError> {
+        let mut tokenizer = Tokenizer::from_file(filename)?;
+        Self::parse(&mut tokenizer, options)
+    }
+
+    pub fn parse(tokenizer: &mut Tokenizer, options: BvhOptions) -> Result<Bvh, ParseError> {
+        let mut skeleton = Skeleton::new();
+        let mut joints = Vec::new();
+        let mut end_sites = Vec::new();
+        tokenizer.expect("HIERARCHY")?;
+        tokenizer.expect("ROOT")?;
+        Self::parse_joint(tokenizer, &options, &mut skeleton, &mut joints, &mut end_sites, None)?;
+        Self::fit_boxes(&mut skeleton, options.scale);
+
+        tokenizer.expect("MOTION")?;
+        tokenizer.expect("Frames:")?;
+        let num_frames = tokenizer.get_usize()?;
+        tokenizer.expect("Frame")?;
+        tokenizer.expect("Time:")?;
+        let frame_time = tokenizer.get_float()?;
+        if frame_time <= 0.0 {
+            return Err(tokenizer.error("frame time must be positive"));
+        }
+
+        // Same channel layout as .anim files: root translation, then x/y/z rotation
+        // per joint. Other joints with position channels get translation channels of their own.
+        let num_channels = 3 + skeleton.get_num_joints() * 3;
+        let mut samples: Vec<Vec<f32>> = vec![Vec::with_capacity(num_frames); num_channels];
+        let mut positions: Vec<Vec<glm::Vec3>> = vec![Vec::new(); joints.len()];
+        let pi = glm::pi::<f32>();
+        for frame in 0..num_frames {
+            let mut values = vec![0.0; num_channels];
+            for (entry, joint) in joints.iter().enumerate() {
+                let mut position = glm::vec3(0.0, 0.0, 0.0);
+                let mut has_position = false;
+                for channel in &joint.channels {
+                    let value = tokenizer.get_float()
+                        .map_err(|e| ParseError::new(tokenizer.get_file(), e.get_line(), &format!("frame {}: {}", frame, e.get_message())))?;
+                    match *channel {
+                        BvhChannel::Position(axis) => {
+                            position[axis] = value;
+                            has_position = true;
+                        },
+                        BvhChannel::Rotation(axis) => {
+                            let (target, sign) = options.convert_axis(axis);
+                            values[3 + joint.index * 3 + target] = (value * sign).to_radians();
+                        },
+                    }
+                }
+                // Position channels move the joint away from its OFFSET
+                let translation = skeleton.get_joint(joint.index).get_offset() + options.convert_position(position);
+                if joint.index == 0 {
+                    for axis in 0..3 {
+                        values[axis] = translation[axis];
+                    }
+                } else if has_position {
+                    positions[entry].push(translation);
+                }
+            }
+            for axis in 0..3 {
+                samples[axis].push(values[axis]);
+            }
+            // Angles that wrap at +-180 degrees are unwrapped against the last
+            // frame, so interpolation doesn't turn the long way round
+            for (channel, value) in samples.iter_mut().zip(values).skip(3) {
+                let value = match channel.last() {
+                    Some(&last) => last + (value - last + pi).rem_euclid(2.0 * pi) - pi,
+                    None => value,
+                };
+                channel.push(value);
+            }
+        }
+        let bake = |values: Vec<f32>| {
+            let keys = values.into_iter().enumerate()
+                .map(|(frame, value)| Keyframe::new(frame as f32 * frame_time, value, TangentRule::Linear, TangentRule::Linear))
+                .collect();
+            Channel::new(keys, Extrapolation::Cycle, Extrapolation::Cycle)
+        };
+        let end_time = num_frames.saturating_sub(1) as f32 * frame_time;
+        let mut animation = Animation::new(0.0, end_time, samples.into_iter().map(&bake).collect());
+        for (joint, positions) in joints.iter().zip(positions) {
+            if !positions.is_empty() {
+                let axis = |axis: usize| bake(positions.iter().map(|position| position[axis]).collect());
+                animation.add_joint_translation(joint.index, [axis(0), axis(1), axis(2)]);
+            }
+        }
+        animation.apply(0.0, &mut skeleton);
+        skeleton.set_rest();
+        skeleton.update();
+
+        Ok(Bvh {
+            skeleton,
+            animation,
+            frame_time,
+            num_frames,
+        })
+    }
+
+    // First free name of `base`, `base2`, `base3`...
+    fn unique_name(skeleton: &Skeleton, base: &str) -> String {
+        let mut name = base.to_string();
+        let mut count = 1;
+        while skeleton.find_joint(&name).is_some() {
+            count += 1;
+            name = format!("{}{}", base, count);
+        }
+        name
+    }
+
+    // End sites are named after their joint, so a joint declared later may
+    // want the same name. The end site makes way for it.
+    fn parse_joint(tokenizer: &mut Tokenizer, options: &BvhOptions, skeleton: &mut Skeleton,
+                   joints: &mut Vec<BvhJoint>, end_sites: &mut Vec<usize>, parent: Option<usize>) -> Result<(), ParseError> {
+        let name = tokenizer.next_token()?;
+        match skeleton.find_joint(&name) {
+            Some(existing) if end_sites.contains(&existing) => {
+                let renamed = Self::unique_name(skeleton, &name);
+                skeleton.get_joint_mut(existing).set_name(&renamed);
+            },
+            Some(_) => return Err(tokenizer.error(&format!("duplicate joint name '{}'", name))),
+            None => {},
+        }
+        let index = skeleton.add_joint(Joint::new(&name, parent));
+        tokenizer.expect("{")?;
+
+        // Motion data follows the order in which joints appear in the file
+        let entry = joints.len();
+        joints.push(BvhJoint {
+            index,
+            channels: Vec::new(),
+        });
+        loop {
+            let line = tokenizer.get_line();
+            let token = tokenizer.next_token()?;
+            match token.as_str() {
+                "OFFSET" => {
+                    let offset = options.convert_position(tokenizer.get_vec3()?);
+                    skeleton.get_joint_mut(index).set_offset(offset);
+                },
+                "CHANNELS" => {
+                    let count = tokenizer.get_usize()?;
+                    let mut channels = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        let line = tokenizer.get_line();
+                        let channel = match tokenizer.next_token()?.as_str() {
+                            "Xposition" => BvhChannel::Position(0),
+                            "Yposition" => BvhChannel::Position(1),
+                            "Zposition" => BvhChannel::Position(2),
+                            "Xrotation" => BvhChannel::Rotation(0),
+                            "Yrotation" => BvhChannel::Rotation(1),
+                            "Zrotation" => BvhChannel::Rotation(2),
+                            other => return Err(ParseError::new(tokenizer.get_file(), line, &format!("unknown channel '{}'", other))),
+                        };
+                        channels.push(channel);
+                    }
+                    let mut order: Vec<usize> = channels.iter().filter_map(|channel| match *channel {
+                        BvhChannel::Rotation(axis) => Some(options.convert_axis(axis).0),
+                        _ => None,
+                    }).collect();
+                    for axis in (0..3).rev() {
+                        if !order.contains(&axis) {
+                            order.push(axis);
+                        }
+                    }
+                    if order.len() != 3 {
+                        return Err(ParseError::new(tokenizer.get_file(), line, "repeated rotation channel"));
+                    }
+                    skeleton.get_joint_mut(index).set_rotation_order([order[0], order[1], order[2]]);
+                    joints[entry].channels = channels;
+                },
+                "JOINT" => Self::parse_joint(tokenizer, options, skeleton, joints, end_sites, Some(index))?,
+                "End" => {
+                    tokenizer.expect("Site")?;
+                    tokenizer.expect("{")?;
+                    tokenizer.expect("OFFSET")?;
+                    let offset = options.convert_position(tokenizer.get_vec3()?);
+                    tokenizer.expect("}")?;
+                    let mut end = Joint::new(&Self::unique_name(skeleton, &format!("{}_end", name)), Some(index));
+                    end.set_offset(offset);
+                    end_sites.push(skeleton.add_joint(end));
+                },
+                "}" => break,
+                _ => return Err(ParseError::new(tokenizer.get_file(), line, &format!("unknown joint attribute '{}'", token))),
+            }
+        }
+        Ok(())
+    }
+
+    // Stretches each joint's box towards its children so the rig reads as bones
+    fn fit_boxes(skeleton: &mut Skeleton, scale: f32) {
+        for index in 0..skeleton.get_num_joints() {
+            let mut box_min = glm::vec3(0.0, 0.0, 0.0);
+            let mut box_max = glm::vec3(0.0, 0.0, 0.0);
+            for &child in skeleton.get_joint(index).get_children() {
+                let offset = skeleton.get_joint(child).get_offset();
+                box_min = glm::min2(&box_min, &offset);
+                box_max = glm::max2(&box_max, &offset);
+            }
+            let thickness = glm::vec3(2.0, 2.0, 2.0) * scale.abs();
+            skeleton.get_joint_mut(index).set_box(box_min - thickness, box_max + thickness);
+        }
+    }
+
+    pub fn get_skeleton(&self) -> &Skeleton {
+        &self.skeleton
+    }
+
+    pub fn get_animation(&self) -> &Animation {
+        &self.animation
+    }
+
+    pub fn get_frame_time(&self) -> f32 {
+        self.frame_time
+    }
+
+    pub fn get_num_frames(&self) -> usize {
+        self.num_frames
+    }
+
+    pub fn into_parts(self) -> (Skeleton, Animation) {
+        (self.skeleton, self.animation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Z-up, in centimetres. Spine_end is a real joint, named like Spine's end site.
+    const CLIP: &str = "HIERARCHY
+ROOT Hips
+{
+    OFFSET 0 0 0
+    CHANNELS 6 Xposition Yposition Zposition Zrotation Xrotation Yrotation
+    JOINT Spine
+    {
+        OFFSET 0 10 0
+        CHANNELS 3 Zrotation Xrotation Yrotation
+        End Site
+        {
+            OFFSET 0 0 5
+        }
+    }
+    JOINT Spine_end
+    {
+        OFFSET 0 0 10
+        CHANNELS 3 Zrotation Xrotation Yrotation
+        End Site
+        {
+            OFFSET 1 0 0
+        }
+    }
+}
+MOTION
+Frames: 2
+Frame Time: 0.5
+0 0 0 0 0 0 0 0 0 0 0 0
+10 0 0 0 0 0 90 0 0 0 0 0
+";
+
+    fn load(options: BvhOptions) -> Bvh {
+        Bvh::parse(&mut Tokenizer::from_contents("test.bvh", CLIP), options).unwrap()
+    }
+
+    fn assert_close(actual: glm::Vec3, expected: glm::Vec3) {
+        assert!(glm::length(&(actual - expected)) < 1e-5, "{} vs {}", actual, expected);
+    }
+
+    #[test]
+    fn end_sites_make_way_for_joints() {
+        let bvh = load(BvhOptions::new());
+        let skeleton = bvh.get_skeleton();
+        let spine_end = skeleton.find_joint("Spine_end").unwrap();
+        assert_eq!(skeleton.get_joint(spine_end).get_parent(), skeleton.find_joint("Hips"));
+        let mut names: Vec<&str> = (0..skeleton.get_num_joints()).map(|joint| skeleton.get_joint(joint).get_name()).collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), skeleton.get_num_joints());
+    }
+
+    #[test]
+    fn z_up_clips_are_converted_and_scaled() {
+        let mut options = BvhOptions::new();
+        options.up_axis = UpAxis::Z;
+        options.scale = 0.01;
+        let bvh = load(options);
+        let skeleton = bvh.get_skeleton();
+        let spine = skeleton.find_joint("Spine").unwrap();
+        let spine_end = skeleton.find_joint("Spine_end").unwrap();
+        // Source z is up, source y points away from the viewer
+        assert_close(skeleton.get_joint(spine).get_offset(), glm::vec3(0.0, 0.0, -0.1));
+        assert_close(skeleton.get_joint(spine_end).get_offset(), glm::vec3(0.0, 0.1, 0.0));
+
+        let animation = bvh.get_animation();
+        assert!((animation.get_end_time() - 0.5).abs() < 1e-6);
+        assert!((animation.get_channel(0).unwrap().evaluate(0.5) - 0.1).abs() < 1e-6);
+        // A turn about the source z axis is a turn about y
+        let yaw = animation.get_channel(3 + spine * 3 + 1).unwrap().evaluate(0.5);
+        assert!((yaw - 90.0f32.to_radians()).abs() < 1e-5, "{}", yaw);
+    }
+}
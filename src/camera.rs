@@ -55,6 +55,21 @@ impl Camera {
         self.view_proj_mat
     }
 
+    // Window coordinates (origin top left) of a world point, with its depth
+    // in normalized device coordinates as z
+    pub fn project(&self, point: &glm::Vec3, width: f32, height: f32) -> glm::Vec3 {
+        let clip = self.view_proj_mat * glm::vec4(point.x, point.y, point.z, 1.0);
+        let ndc = glm::vec3(clip.x, clip.y, clip.z) / clip.w;
+        glm::vec3((ndc.x + 1.0) * 0.5 * width, (1.0 - ndc.y) * 0.5 * height, ndc.z)
+    }
+
+    // Inverse of project, the world point under a window position at a given depth
+    pub fn unproject(&self, x: f32, y: f32, depth: f32, width: f32, height: f32) -> glm::Vec3 {
+        let ndc = glm::vec4(2.0 * x / width - 1.0, 1.0 - 2.0 * y / height, depth, 1.0);
+        let world = glm::inverse(&self.view_proj_mat) * ndc;
+        glm::vec3(world.x, world.y, world.z) / world.w
+    }
+
     pub fn update(&mut self) {
         let mut world = glm::Mat4::identity();
         world[(2, 3)] = self.distance;
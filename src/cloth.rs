@@ -0,0 +1,380 @@
+use gl::types::*;
+use crate::particles::*;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClothOptions {
+    pub width: f32,
+    pub height: f32,
+    // Particles along each side
+    pub resolution_x: usize,
+    pub resolution_y: usize,
+    pub mass: f32,
+    pub structural_stiffness: f32,
+    pub shear_stiffness: f32,
+    pub bend_stiffness: f32,
+    // Damping along each spring, against the relative velocity of its ends
+    pub spring_damping: f32,
+    pub drag: f32,
+    pub lift: f32,
+    pub air_density: f32,
+    pub gravity: glm::Vec3,
+    pub friction: f32,
+    // Largest integration step, frames are split into substeps of this size
+    pub max_step: f32,
+}
+
+impl ClothOptions {
+    pub fn new() -> ClothOptions {
+        ClothOptions {
+            width: 2.0,
+            height: 2.0,
+            resolution_x: 20,
+            resolution_y: 20,
+            mass: 1.0,
+            structural_stiffness: 500.0,
+            shear_stiffness: 200.0,
+            bend_stiffness: 50.0,
+            spring_damping: 0.2,
+            drag: 1.0,
+            lift: 0.5,
+            air_density: 1.2,
+            gravity: glm::vec3(0.0, -9.8, 0.0),
+            friction: 0.5,
+            max_step: 0.001,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Spring {
+    a: usize,
+    b: usize,
+    rest_length: f32,
+    stiffness: f32,
+}
+
+// Particle grid hanging in the xy plane from `position`, its top left corner.
+// Particles are stored row by row from the top.
+pub struct Cloth {
+    options: ClothOptions,
+    particles: Particles,
+    forces: Vec<glm::Vec3>,
+    particle_mass: f32,
+    springs: Vec<Spring>,
+    mesh: ParticleMesh,
+    wind: glm::Vec3,
+    ground: Option<f32>,
+    spheres: Vec<CollisionSphere>,
+    initial: Vec<glm::Vec3>,
+}
+
+impl Cloth {
+    pub fn new(position: glm::Vec3, options: ClothOptions) -> Cloth {
+        let columns = options.resolution_x.max(2);
+        let rows = options.resolution_y.max(2);
+        let dx = options.width / (columns - 1) as f32;
+        let dy = options.height / (rows - 1) as f32;
+        let index = |x: usize, y: usize| y * columns + x;
+
+        let mut positions = Vec::with_capacity(columns * rows);
+        for y in 0..rows {
+            for x in 0..columns {
+                positions.push(position + glm::vec3(x as f32 * dx, -(y as f32) * dy, 0.0));
+            }
+        }
+
+        let mut springs = Vec::new();
+        let mut add_spring = |a: usize, b: usize, stiffness: f32| {
+            springs.push(Spring {
+                a,
+                b,
+                rest_length: glm::length(&(positions[b] - positions[a])),
+                stiffness,
+            });
+        };
+        for y in 0..rows {
+            for x in 0..columns {
+                if x + 1 < columns {
+                    add_spring(index(x, y), index(x + 1, y), options.structural_stiffness);
+                }
+                if y + 1 < rows {
+                    add_spring(index(x, y), index(x, y + 1), options.structural_stiffness);
+                }
+                if x + 1 < columns && y + 1 < rows {
+                    add_spring(index(x, y), index(x + 1, y + 1), options.shear_stiffness);
+                    add_spring(index(x + 1, y), index(x, y + 1), options.shear_stiffness);
+                }
+                if x + 2 < columns {
+                    add_spring(index(x, y), index(x + 2, y), options.bend_stiffness);
+                }
+                if y + 2 < rows {
+                    add_spring(index(x, y), index(x, y + 2), options.bend_stiffness);
+                }
+            }
+        }
+
+        let mut indices = Vec::new();
+        for y in 0..rows - 1 {
+            for x in 0..columns - 1 {
+                let (a, b, c, d) = (index(x, y), index(x, y + 1), index(x + 1, y + 1), index(x + 1, y));
+                indices.extend_from_slice(&[a as u32, b as u32, c as u32, a as u32, c as u32, d as u32]);
+            }
+        }
+
+        let count = positions.len();
+        let particle_mass = options.mass / count as f32;
+        let mut particles = Particles::new();
+        for &position in &positions {
+            particles.add(position, particle_mass);
+        }
+        particles.set_inverse_mass(index(0, 0), 0.0);
+        particles.set_inverse_mass(index(columns - 1, 0), 0.0);
+        Cloth {
+            options,
+            particles,
+            forces: vec![glm::vec3(0.0, 0.0, 0.0); count],
+            particle_mass,
+            springs,
+            mesh: ParticleMesh::new(indices),
+            wind: glm::vec3(0.0, 0.0, 0.0),
+            ground: None,
+            spheres: Vec::new(),
+            initial: positions,
+        }
+    }
+
+    pub fn get_options(&self) -> &ClothOptions {
+        &self.options
+    }
+
+    pub fn get_particles(&self) -> &Particles {
+        &self.particles
+    }
+
+    pub fn get_wind(&self) -> glm::Vec3 {
+        self.wind
+    }
+
+    pub fn set_wind(&mut self, wind: glm::Vec3) {
+        self.wind = wind;
+    }
+
+    pub fn get_ground(&self) -> Option<f32> {
+        self.ground
+    }
+
+    // Height of a horizontal ground plane, None for no ground
+    pub fn set_ground(&mut self, ground: Option<f32>) {
+        self.ground = ground;
+    }
+
+    pub fn get_spheres(&self) -> &[CollisionSphere] {
+        &self.spheres
+    }
+
+    pub fn add_sphere(&mut self, center: glm::Vec3, radius: f32) -> usize {
+        self.spheres.push(CollisionSphere {
+            center,
+            radius,
+        });
+        self.spheres.len() - 1
+    }
+
+    pub fn get_sphere_mut(&mut self, sphere: usize) -> &mut CollisionSphere {
+        &mut self.spheres[sphere]
+    }
+
+    // Pinned particles ignore forces and only move through move_pinned
+    pub fn set_pinned(&mut self, particle: usize, pinned: bool) {
+        self.particles.set_inverse_mass(particle, if pinned { 0.0 } else { 1.0 / self.particle_mass });
+    }
+
+    pub fn move_pinned(&mut self, delta: glm::Vec3) {
+        self.particles.move_pinned(delta);
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        // Don't try to catch up after a long stall
+        let dt = dt.min(1.0 / 30.0);
+        let steps = (dt / self.options.max_step).ceil().max(1.0) as usize;
+        let step = dt / steps as f32;
+        for _ in 0..steps {
+            self.compute_forces();
+            self.integrate(step);
+            self.collide();
+        }
+    }
+
+    fn compute_forces(&mut self) {
+        let gravity = self.options.gravity * self.particle_mass;
+        for force in &mut self.forces {
+            *force = gravity;
+        }
+        let positions = self.particles.get_positions();
+        let velocities = self.particles.get_velocities();
+
+        for spring in &self.springs {
+            let delta = positions[spring.b] - positions[spring.a];
+            let length = glm::length(&delta);
+            if length <= 1e-6 {
+                continue;
+            }
+            let direction = delta / length;
+            let closing = glm::dot(&(velocities[spring.b] - velocities[spring.a]), &direction);
+            let force = direction * (spring.stiffness * (length - spring.rest_length) + self.options.spring_damping * closing);
+            self.forces[spring.a] += force;
+            self.forces[spring.b] -= force;
+        }
+
+        // Drag against and lift across the relative air flow, per triangle,
+        // scaled by the area facing the flow
+        for triangle in self.mesh.get_indices().chunks(3) {
+            let (a, b, c) = (triangle[0] as usize, triangle[1] as usize, triangle[2] as usize);
+            let velocity = (velocities[a] + velocities[b] + velocities[c]) / 3.0 - self.wind;
+            let speed = glm::length(&velocity);
+            let cross = (positions[b] - positions[a]).cross(&(positions[c] - positions[a]));
+            let cross_length = glm::length(&cross);
+            if speed <= 1e-6 || cross_length <= 1e-12 {
+                continue;
+            }
+            let flow = velocity / speed;
+            let mut normal = cross / cross_length;
+            if glm::dot(&normal, &flow) < 0.0 {
+                normal = -normal;
+            }
+            let area = 0.5 * cross_length * glm::dot(&normal, &flow);
+            let pressure = 0.5 * self.options.air_density * speed * speed * area;
+            let mut force = -flow * (pressure * self.options.drag);
+            let lift_direction = normal.cross(&flow).cross(&flow);
+            let lift_length = glm::length(&lift_direction);
+            if lift_length > 1e-6 {
+                force += lift_direction / lift_length * (pressure * self.options.lift);
+            }
+            for &particle in &[a, b, c] {
+                self.forces[particle] += force / 3.0;
+            }
+        }
+    }
+
+    // Semi-implicit Euler
+    fn integrate(&mut self, dt: f32) {
+        for particle in 0..self.particles.get_num_particles() {
+            let inverse_mass = self.particles.get_inverse_mass(particle);
+            if inverse_mass == 0.0 {
+                continue;
+            }
+            let velocity = self.particles.get_velocities()[particle] + self.forces[particle] * (dt * inverse_mass);
+            self.particles.get_velocities_mut()[particle] = velocity;
+            self.particles.get_positions_mut()[particle] += velocity * dt;
+        }
+    }
+
+    // Pushes particles out of the ground and spheres, removing the velocity
+    // into the surface and some of the sliding velocity
+    fn collide(&mut self) {
+        let friction = glm::clamp_scalar(self.options.friction, 0.0, 1.0);
+        for particle in 0..self.particles.get_num_particles() {
+            if self.particles.is_pinned(particle) {
+                continue;
+            }
+            let mut position = self.particles.get_positions()[particle];
+            let mut contacts = Vec::new();
+            if let Some(ground) = self.ground {
+                if position.y < ground {
+                    position.y = ground;
+                    contacts.push(glm::vec3(0.0, 1.0, 0.0));
+                }
+            }
+            for sphere in &self.spheres {
+                let offset = position - sphere.center;
+                let distance = glm::length(&offset);
+                if distance < sphere.radius && distance > 1e-6 {
+                    let normal = offset / distance;
+                    position = sphere.center + normal * sphere.radius;
+                    contacts.push(normal);
+                }
+            }
+            self.particles.get_positions_mut()[particle] = position;
+            let mut velocity = self.particles.get_velocities()[particle];
+            for normal in contacts {
+                let normal_speed = glm::dot(&velocity, &normal);
+                if normal_speed < 0.0 {
+                    velocity = (velocity - normal * normal_speed) * (1.0 - friction);
+                }
+            }
+            self.particles.get_velocities_mut()[particle] = velocity;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.particles.set_positions(&self.initial);
+    }
+
+    pub fn make_model(&mut self) {
+        self.mesh.make_model(self.particles.get_positions());
+    }
+
+    pub fn draw(&mut self, view_proj_mat: glm::Mat4, shader: GLuint) {
+        self.mesh.draw(self.particles.get_positions(), view_proj_mat, shader);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_options() -> ClothOptions {
+        let mut options = ClothOptions::new();
+        options.resolution_x = 8;
+        options.resolution_y = 8;
+        options
+    }
+
+    fn run(cloth: &mut Cloth, seconds: f32) {
+        for _ in 0..(seconds * 60.0) as usize {
+            cloth.update(1.0 / 60.0);
+        }
+    }
+
+    #[test]
+    fn pinned_particles_stay_put() {
+        let mut cloth = Cloth::new(glm::vec3(0.0, 2.0, 0.0), small_options());
+        cloth.set_pinned(7, false);
+        cloth.set_pinned(3, true);
+        cloth.set_wind(glm::vec3(0.0, 0.0, 3.0));
+        let start = cloth.get_particles().get_positions().to_vec();
+        run(&mut cloth, 1.0);
+        let positions = cloth.get_particles().get_positions();
+        assert_eq!(positions[0], start[0]);
+        assert_eq!(positions[3], start[3]);
+        // The unpinned corner swings away
+        assert!(glm::length(&(positions[7] - start[7])) > 0.5, "{:?}", positions[7]);
+
+        cloth.move_pinned(glm::vec3(0.0, 1.0, 0.0));
+        let positions = cloth.get_particles().get_positions();
+        assert_eq!(positions[3], start[3] + glm::vec3(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn falling_cloth_settles_on_the_obstacles() {
+        let center = glm::vec3(0.0, 0.4, 0.0);
+        let radius = 0.4;
+        let mut cloth = Cloth::new(glm::vec3(-1.0, 2.0, 0.0), small_options());
+        cloth.set_pinned(0, false);
+        cloth.set_pinned(7, false);
+        cloth.set_ground(Some(0.0));
+        cloth.add_sphere(center, radius);
+        let mut lowest = f32::MAX;
+        for _ in 0..4 {
+            run(&mut cloth, 1.0);
+            for position in cloth.get_particles().get_positions() {
+                assert!(position.y >= -1e-5, "{:?} went through the ground", position);
+                assert!(glm::length(&(position - center)) >= radius - 1e-4, "{:?} went into the sphere", position);
+                lowest = lowest.min(position.y);
+            }
+        }
+        assert!(lowest < 1e-3, "never reached the ground");
+        let fastest = cloth.get_particles().get_velocities().iter().map(glm::length).fold(0.0, f32::max);
+        assert!(fastest < 0.1, "still moving at {}", fastest);
+    }
+}
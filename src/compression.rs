@@ -0,0 +1,504 @@
+use std::error::Error;
+use std::fmt;
+use crate::animation::*;
+use crate::ik::*;
+use crate::pose::*;
+use crate::skeleton::*;
+
+#[derive(Clone, Copy, Debug)]
+pub struct CompressionOptions {
+    // Largest joint position error key removal may introduce, in world units.
+    // Half of it goes to the root translation and half to the rotations.
+    pub tolerance: f32,
+    // Rate the clip is sampled at before keys are removed. Between frames the
+    // clip is followed in straight lines, so fast motion needs a higher rate
+    // to stay within the tolerance.
+    pub frame_rate: f32,
+}
+
+impl CompressionOptions {
+    pub fn new() -> CompressionOptions {
+        CompressionOptions {
+            tolerance: 0.01,
+            frame_rate: 30.0,
+        }
+    }
+
+    pub fn get_translation_tolerance(&self) -> f32 {
+        0.5 * self.tolerance
+    }
+
+    // Angle each joint's rotation may be off by. A joint moves everything below
+    // it by up to its angle error times its reach, and the errors of all the
+    // joints down a chain add up, so the rotation half of the budget is shared
+    // out over the longest chain.
+    pub fn get_rotation_tolerances(&self, skeleton: &Skeleton) -> Vec<f32> {
+        let num_joints = skeleton.get_num_joints();
+        let mut reach = vec![0.0f32; num_joints];
+        for index in (0..num_joints).rev() {
+            if let Some(parent) = skeleton.get_joint(index).get_parent() {
+                let length = glm::length(&skeleton.get_joint(index).get_offset());
+                reach[parent] = reach[parent].max(length + reach[index]);
+            }
+        }
+        let mut depth = vec![0usize; num_joints];
+        for index in 0..num_joints {
+            let above = skeleton.get_joint(index).get_parent().map_or(0, |parent| depth[parent]);
+            depth[index] = above + if reach[index] > 0.0 { 1 } else { 0 };
+        }
+        let chain = depth.iter().cloned().max().unwrap_or(0).max(1) as f32;
+        let budget = 0.5 * self.tolerance / chain;
+        // Joints without anything below them still keep their orientation roughly
+        reach.iter().map(|&reach| if reach > 0.0 { (budget / reach).min(0.1) } else { 0.1 }).collect()
+    }
+}
+
+const ROTATION_SCALE: f32 = 32767.0;
+const TRANSLATION_SCALE: f32 = 65535.0;
+
+// Smallest three: the largest component is dropped, made positive by flipping
+// the quaternion and recovered from the unit length. The other three lie
+// within +-1/sqrt(2) and get 15 bits each, the dropped index rides in the top
+// bits of the first two.
+pub fn quantize_rotation(rotation: &glm::Quat) -> [u16; 3] {
+    let mut coords = glm::quat_normalize(rotation).coords;
+    let largest = (0..4)
+        .max_by(|&a, &b| coords[a].abs().partial_cmp(&coords[b].abs()).unwrap_or(std::cmp::Ordering::Equal))
+        .unwrap_or(3);
+    if coords[largest] < 0.0 {
+        coords = -coords;
+    }
+    let range = std::f32::consts::FRAC_1_SQRT_2;
+    let mut packed = [0u16; 3];
+    for (slot, index) in (0..4).filter(|&index| index != largest).enumerate() {
+        let unit = glm::clamp_scalar((coords[index] / range + 1.0) * 0.5, 0.0, 1.0);
+        packed[slot] = (unit * ROTATION_SCALE).round() as u16;
+    }
+    packed[0] |= ((largest & 1) as u16) << 15;
+    packed[1] |= ((largest >> 1) as u16) << 15;
+    packed
+}
+
+pub fn dequantize_rotation(packed: &[u16; 3]) -> glm::Quat {
+    let largest = ((packed[0] >> 15) | ((packed[1] >> 15) << 1)) as usize;
+    let range = std::f32::consts::FRAC_1_SQRT_2;
+    let mut coords = glm::vec4(0.0, 0.0, 0.0, 0.0);
+    let mut sum = 0.0;
+    for (slot, index) in (0..4).filter(|&index| index != largest).enumerate() {
+        let value = ((packed[slot] & 0x7fff) as f32 / ROTATION_SCALE * 2.0 - 1.0) * range;
+        coords[index] = value;
+        sum += value * value;
+    }
+    coords[largest] = (1.0 - sum).max(0.0).sqrt();
+    glm::quat(coords.x, coords.y, coords.z, coords.w)
+}
+
+// Normalized lerp through the shorter way round
+fn nlerp(a: &glm::Quat, b: &glm::Quat, t: f32) -> glm::Quat {
+    let b = if glm::dot(&a.coords, &b.coords) < 0.0 { -b.coords } else { b.coords };
+    let coords = a.coords * (1.0 - t) + b * t;
+    glm::quat_normalize(&glm::quat(coords.x, coords.y, coords.z, coords.w))
+}
+
+fn rotation_error(a: &glm::Quat, b: &glm::Quat) -> f32 {
+    2.0 * glm::clamp_scalar(glm::dot(&a.coords, &b.coords).abs(), 0.0, 1.0).acos()
+}
+
+// Greedy curve fit: each key reaches as many frames ahead as it can while
+// the clip stays within the tolerance of interpolating the two ends.
+// `fits(from, to)` does that check for one span.
+fn reduce_keys(num_frames: usize, fits: impl Fn(usize, usize) -> bool) -> Vec<u16> {
+    let mut keys = vec![0];
+    let mut start = 0;
+    while start + 1 < num_frames {
+        let mut end = start + 1;
+        while end + 1 < num_frames && end + 1 - start <= u16::MAX as usize && fits(start, end + 1) {
+            end += 1;
+        }
+        keys.push(end as u16);
+        start = end;
+    }
+    keys
+}
+
+// Tracks are fit to samples on every frame and halfway between, so the clip
+// doesn't stray between frames either. Frame `frame` is sample 2 * frame.
+fn span_fits(from: usize, to: usize, error: impl Fn(usize, f32) -> f32, tolerance: f32) -> bool {
+    let (first, last) = (2 * from, 2 * to);
+    (first..=last).all(|sample| error(sample, (sample - first) as f32 / (last - first) as f32) <= tolerance)
+}
+
+// Span of `frames` holding `frame` and how far along it is
+fn find_span(frames: &[u16], frame: f32) -> (usize, usize, f32) {
+    let last = frames.len() - 1;
+    let next = frames.iter().position(|&key| key as f32 > frame).unwrap_or(last + 1);
+    if next == 0 {
+        return (0, 0, 0.0);
+    } else if next > last {
+        return (last, last, 0.0);
+    }
+    let (from, to) = (frames[next - 1] as f32, frames[next] as f32);
+    (next - 1, next, (frame - from) / (to - from))
+}
+
+#[derive(Clone, Debug)]
+struct RotationTrack {
+    frames: Vec<u16>,
+    rotations: Vec<[u16; 3]>,
+}
+
+impl RotationTrack {
+    fn new(samples: &[glm::Quat], tolerance: f32) -> RotationTrack {
+        let quantized: Vec<[u16; 3]> = samples.iter().map(quantize_rotation).collect();
+        let decoded: Vec<glm::Quat> = quantized.iter().map(dequantize_rotation).collect();
+        let frames = reduce_keys(samples.len().div_ceil(2), |from, to| span_fits(from, to, |sample, t| {
+            rotation_error(&nlerp(&decoded[2 * from], &decoded[2 * to], t), &samples[sample])
+        }, tolerance));
+        let rotations = frames.iter().map(|&frame| quantized[2 * frame as usize]).collect();
+        RotationTrack {
+            frames,
+            rotations,
+        }
+    }
+
+    fn sample(&self, frame: f32) -> glm::Quat {
+        let (from, to, t) = find_span(&self.frames, frame);
+        nlerp(&dequantize_rotation(&self.rotations[from]), &dequantize_rotation(&self.rotations[to]), t)
+    }
+
+    fn get_size(&self) -> usize {
+        2 + self.frames.len() * (2 + 6)
+    }
+}
+
+// Fixed point over the range the track actually covers, 16 bits per axis
+#[derive(Clone, Debug)]
+struct TranslationTrack {
+    frames: Vec<u16>,
+    min: glm::Vec3,
+    extent: glm::Vec3,
+    values: Vec<[u16; 3]>,
+}
+
+impl TranslationTrack {
+    fn new(samples: &[glm::Vec3], tolerance: f32) -> TranslationTrack {
+        let min = samples.iter().fold(glm::vec3(f32::MAX, f32::MAX, f32::MAX), |min, sample| glm::min2(&min, sample));
+        let max = samples.iter().fold(glm::vec3(f32::MIN, f32::MIN, f32::MIN), |max, sample| glm::max2(&max, sample));
+        let extent = max - min;
+        let mut track = TranslationTrack {
+            frames: Vec::new(),
+            min,
+            extent,
+            values: Vec::new(),
+        };
+        let quantized: Vec<[u16; 3]> = samples.iter().map(|sample| track.quantize(sample)).collect();
+        let decoded: Vec<glm::Vec3> = quantized.iter().map(|value| track.dequantize(value)).collect();
+        track.frames = reduce_keys(samples.len().div_ceil(2), |from, to| span_fits(from, to, |sample, t| {
+            glm::length(&(glm::lerp(&decoded[2 * from], &decoded[2 * to], t) - samples[sample]))
+        }, tolerance));
+        track.values = track.frames.iter().map(|&frame| quantized[2 * frame as usize]).collect();
+        track
+    }
+
+    fn quantize(&self, value: &glm::Vec3) -> [u16; 3] {
+        let mut packed = [0u16; 3];
+        for (axis, packed) in packed.iter_mut().enumerate() {
+            if self.extent[axis] > 0.0 {
+                let unit = glm::clamp_scalar((value[axis] - self.min[axis]) / self.extent[axis], 0.0, 1.0);
+                *packed = (unit * TRANSLATION_SCALE).round() as u16;
+            }
+        }
+        packed
+    }
+
+    fn dequantize(&self, packed: &[u16; 3]) -> glm::Vec3 {
+        let unit = glm::vec3(packed[0] as f32, packed[1] as f32, packed[2] as f32) / TRANSLATION_SCALE;
+        self.min + self.extent.component_mul(&unit)
+    }
+
+    fn sample(&self, frame: f32) -> glm::Vec3 {
+        let (from, to, t) = find_span(&self.frames, frame);
+        glm::lerp(&self.dequantize(&self.values[from]), &self.dequantize(&self.values[to]), t)
+    }
+
+    fn get_size(&self) -> usize {
+        2 + 24 + self.frames.len() * (2 + 6)
+    }
+}
+
+// Key frame numbers are 16 bit, so a clip has to fit in that many frames at
+// the frame rate it is compressed at
+#[derive(Clone, Debug)]
+pub struct CompressionError {
+    num_frames: usize,
+    frame_rate: f32,
+}
+
+impl fmt::Display for CompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "clip needs {} frames at {} frames per second but at most {} fit", self.num_frames, self.frame_rate, u16::MAX as usize + 1)
+    }
+}
+
+impl Error for CompressionError {}
+
+// A clip sampled at a fixed rate with the keys that interpolation can stand
+// in for removed, rotations kept as smallest three quaternions and the root
+// translation in range reduced fixed point
+#[derive(Clone, Debug)]
+pub struct CompressedAnimation {
+    start_time: f32,
+    frame_time: f32,
+    num_frames: usize,
+    extrapolation_in: Extrapolation,
+    extrapolation_out: Extrapolation,
+    translation: TranslationTrack,
+    rotations: Vec<RotationTrack>,
+}
+
+impl CompressedAnimation {
+    pub fn new(animation: &Animation, skeleton: &Skeleton, options: CompressionOptions) -> Result<CompressedAnimation, CompressionError> {
+        let start = animation.get_start_time();
+        let end = animation.get_end_time().max(start);
+        let num_frames = ((end - start) * options.frame_rate).ceil() as usize + 1;
+        if num_frames > u16::MAX as usize + 1 {
+            return Err(CompressionError {
+                num_frames,
+                frame_rate: options.frame_rate,
+            });
+        }
+        let frame_time = if num_frames > 1 { (end - start) / (num_frames - 1) as f32 } else { 0.0 };
+        let poses: Vec<Pose> = (0..2 * num_frames - 1).map(|sample| animation.sample(start + sample as f32 * 0.5 * frame_time, skeleton)).collect();
+
+        let translations: Vec<glm::Vec3> = poses.iter()
+            .map(|pose| pose.get_transforms().first().map_or(glm::vec3(0.0, 0.0, 0.0), |root| root.get_translation()))
+            .collect();
+        let tolerances = options.get_rotation_tolerances(skeleton);
+        let rotations = tolerances.iter().enumerate().map(|(joint, &tolerance)| {
+            let samples: Vec<glm::Quat> = poses.iter().map(|pose| pose.get_transform(joint).get_rotation()).collect();
+            RotationTrack::new(&samples, tolerance)
+        }).collect();
+        let (extrapolation_in, extrapolation_out) = animation.get_channel(0)
+            .map_or((Extrapolation::Constant, Extrapolation::Constant), |channel| (channel.get_extrapolation_in(), channel.get_extrapolation_out()));
+        Ok(CompressedAnimation {
+            start_time: start,
+            frame_time,
+            num_frames,
+            extrapolation_in,
+            extrapolation_out,
+            translation: TranslationTrack::new(&translations, options.get_translation_tolerance()),
+            rotations,
+        })
+    }
+
+    pub fn get_start_time(&self) -> f32 {
+        self.start_time
+    }
+
+    pub fn get_end_time(&self) -> f32 {
+        self.start_time + (self.num_frames - 1) as f32 * self.frame_time
+    }
+
+    pub fn get_num_frames(&self) -> usize {
+        self.num_frames
+    }
+
+    // Keys left across the root translation and every rotation
+    pub fn get_num_keys(&self) -> usize {
+        self.translation.frames.len() + self.rotations.iter().map(|track| track.frames.len()).sum::<usize>()
+    }
+
+    // Bytes the compressed form takes: times and counts, the tracks with a
+    // 16 bit frame number per key, and the translation range
+    pub fn get_size(&self) -> usize {
+        4 + 4 + 2 + 2 + self.translation.get_size() + self.rotations.iter().map(RotationTrack::get_size).sum::<usize>()
+    }
+
+    // Frame position within the clip for `time`, extrapolated like the source
+    // clip's root channel except that linear holds the ends, and the number of
+    // laps for cycle_offset
+    fn local_frame(&self, time: f32) -> (f32, f32) {
+        let end = self.get_end_time();
+        let length = end - self.start_time;
+        let mode = if time < self.start_time { self.extrapolation_in } else { self.extrapolation_out };
+        if length <= 0.0 || (time >= self.start_time && time <= end) {
+            return ((time - self.start_time) / self.frame_time.max(f32::EPSILON), 0.0);
+        }
+        let cycles = ((time - self.start_time) / length).floor();
+        let local = time - cycles * length - self.start_time;
+        let local = match mode {
+            Extrapolation::Cycle | Extrapolation::CycleOffset => local,
+            Extrapolation::Bounce if (cycles as i64).rem_euclid(2) == 1 => length - local,
+            Extrapolation::Bounce => local,
+            Extrapolation::Constant | Extrapolation::Linear => glm::clamp_scalar(time - self.start_time, 0.0, length),
+        };
+        let laps = if mode == Extrapolation::CycleOffset { cycles } else { 0.0 };
+        (local / self.frame_time, laps)
+    }
+
+    // Same contract as Animation::sample
+    pub fn sample(&self, time: f32, skeleton: &Skeleton) -> Pose {
+        let mut pose = Pose::from_skeleton(skeleton);
+        if pose.get_num_joints() == 0 {
+            return pose;
+        }
+        let (frame, laps) = self.local_frame(time);
+        let mut translation = self.translation.sample(frame);
+        if laps != 0.0 {
+            let first = self.translation.sample(0.0);
+            let last = self.translation.sample((self.num_frames - 1) as f32);
+            translation += (last - first) * laps;
+        }
+        pose.get_transform_mut(0).set_translation(translation);
+        for (joint, track) in self.rotations.iter().enumerate().take(pose.get_num_joints()) {
+            pose.get_transform_mut(joint).set_rotation(track.sample(frame));
+        }
+        pose
+    }
+
+    // Back into a clip the players can run, keyed every frame
+    pub fn decompress(&self, skeleton: &Skeleton) -> Animation {
+        let poses: Vec<Pose> = (0..self.num_frames).map(|frame| self.sample(self.start_time + frame as f32 * self.frame_time, skeleton)).collect();
+        Animation::from_poses(self.start_time, self.frame_time, &poses, skeleton, self.extrapolation_in, self.extrapolation_out)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CompressionReport {
+    // The clip sampled at the same rate with a float per channel per frame
+    pub raw_bytes: usize,
+    pub compressed_bytes: usize,
+    pub raw_keys: usize,
+    pub kept_keys: usize,
+    // Largest distance between a joint of the original and compressed clips
+    pub max_error: f32,
+    pub worst_joint: usize,
+    pub worst_time: f32,
+}
+
+impl CompressionReport {
+    // Compares the clips on every frame and halfway between frames
+    pub fn new(original: &Animation, compressed: &CompressedAnimation, skeleton: &Skeleton) -> CompressionReport {
+        let num_channels = 3 + 3 * skeleton.get_num_joints();
+        let mut report = CompressionReport {
+            raw_bytes: 4 * num_channels * compressed.num_frames,
+            compressed_bytes: compressed.get_size(),
+            raw_keys: (1 + skeleton.get_num_joints()) * compressed.num_frames,
+            kept_keys: compressed.get_num_keys(),
+            max_error: 0.0,
+            worst_joint: 0,
+            worst_time: compressed.start_time,
+        };
+        for step in 0..(2 * compressed.num_frames - 1) {
+            let time = compressed.start_time + step as f32 * 0.5 * compressed.frame_time;
+            let expected = original.sample(time, skeleton).world_mats(skeleton);
+            let actual = compressed.sample(time, skeleton).world_mats(skeleton);
+            for (joint, (expected, actual)) in expected.iter().zip(&actual).enumerate() {
+                let error = glm::length(&(world_position(expected) - world_position(actual)));
+                if error > report.max_error {
+                    report.max_error = error;
+                    report.worst_joint = joint;
+                    report.worst_time = time;
+                }
+            }
+        }
+        report
+    }
+
+    pub fn get_saved_bytes(&self) -> usize {
+        self.raw_bytes.saturating_sub(self.compressed_bytes)
+    }
+
+    pub fn get_ratio(&self) -> f32 {
+        self.raw_bytes as f32 / self.compressed_bytes.max(1) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smallest_three_round_trips() {
+        let small = [0.3, -0.2, 0.1];
+        for largest in 0..4 {
+            for &sign in &[1.0f32, -1.0] {
+                let mut coords = glm::vec4(0.0, 0.0, 0.0, 0.0);
+                for (slot, index) in (0..4).filter(|&index| index != largest).enumerate() {
+                    coords[index] = small[slot];
+                }
+                coords[largest] = sign * (1.0 - small.iter().map(|value| value * value).sum::<f32>()).sqrt();
+                let rotation = glm::quat(coords.x, coords.y, coords.z, coords.w);
+                let decoded = dequantize_rotation(&quantize_rotation(&rotation));
+                // Back the same rotation, made to have a positive largest component
+                assert!(decoded.coords[largest] > 0.0, "{} {}: {:?}", largest, sign, decoded);
+                assert!(glm::length(&(decoded.coords - rotation.coords * sign)) < 1e-4, "{} {}: {:?} != {:?}", largest, sign, decoded, rotation);
+                assert!(rotation_error(&decoded, &rotation) < 1e-3);
+            }
+        }
+
+        // Two components tied for largest are still in range
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        for &rotation in &[glm::quat(half, 0.0, 0.0, half), glm::quat(0.0, -half, half, 0.0), glm::quat(0.5, -0.5, 0.5, -0.5)] {
+            let decoded = dequantize_rotation(&quantize_rotation(&rotation));
+            assert!(rotation_error(&decoded, &rotation) < 1e-3, "{:?} != {:?}", decoded, rotation);
+        }
+    }
+
+    fn smooth(values: &[f32]) -> Channel {
+        let keys = values.iter().enumerate()
+            .map(|(index, &value)| Keyframe::new(index as f32, value, TangentRule::Smooth, TangentRule::Smooth))
+            .collect();
+        Channel::new(keys, Extrapolation::Constant, Extrapolation::Constant)
+    }
+
+    // Three bones swinging about while the root walks and bobs, slow enough for
+    // straight lines between frames to follow closely
+    fn swing() -> (Animation, Skeleton) {
+        let mut skeleton = Skeleton::new();
+        let mut parent = None;
+        for (index, name) in ["root", "upper", "lower", "end"].iter().enumerate() {
+            let mut joint = Joint::new(name, parent);
+            if index > 0 {
+                joint.set_offset(glm::vec3(0.0, 0.8, 0.0));
+            }
+            parent = Some(skeleton.add_joint(joint));
+        }
+        skeleton.update();
+        let mut channels = vec![
+            smooth(&[0.0, 1.0, 2.0, 3.0, 4.0]),
+            smooth(&[1.0, 1.1, 1.0, 1.1, 1.0]),
+            smooth(&[0.0, 0.0, 0.2, 0.1, 0.0]),
+        ];
+        for joint in 0..4 {
+            let phase = joint as f32;
+            channels.push(smooth(&[0.0, 0.8, -0.5, 0.3, phase * 0.1]));
+            channels.push(smooth(&[phase * 0.2, 0.0, 0.4, -0.2, 0.0]));
+            channels.push(smooth(&[-0.3, 0.6 - phase * 0.1, 0.0, -0.7, 0.2]));
+        }
+        (Animation::new(0.0, 4.0, channels), skeleton)
+    }
+
+    #[test]
+    fn error_stays_within_the_tolerance() {
+        let (clip, skeleton) = swing();
+        for &tolerance in &[0.05, 0.02, 0.01, 0.005] {
+            let mut options = CompressionOptions::new();
+            options.tolerance = tolerance;
+            let compressed = CompressedAnimation::new(&clip, &skeleton, options).unwrap();
+            let report = CompressionReport::new(&clip, &compressed, &skeleton);
+            assert!(report.max_error <= tolerance, "{} off at '{}' {}s with tolerance {}",
+                report.max_error, skeleton.get_joint(report.worst_joint).get_name(), report.worst_time, tolerance);
+            assert!(report.kept_keys < report.raw_keys, "nothing removed at tolerance {}", tolerance);
+        }
+    }
+
+    #[test]
+    fn clips_too_long_for_the_frame_numbers_are_refused() {
+        let (clip, skeleton) = swing();
+        let mut options = CompressionOptions::new();
+        options.frame_rate = 65536.0 / 4.0;
+        let error = CompressedAnimation::new(&clip, &skeleton, options).unwrap_err();
+        assert_eq!(error.to_string(), "clip needs 65537 frames at 16384 frames per second but at most 65536 fit");
+    }
+}
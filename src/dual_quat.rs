@@ -0,0 +1,96 @@
+// Unit dual quaternion for rigid transforms: real is the rotation, dual is
+// half the translation times the rotation.
+#[derive(Clone, Copy, Debug)]
+pub struct DualQuat {
+    real: glm::Quat,
+    dual: glm::Quat,
+}
+
+impl DualQuat {
+    pub fn new(real: glm::Quat, dual: glm::Quat) -> DualQuat {
+        DualQuat {
+            real,
+            dual,
+        }
+    }
+
+    pub fn identity() -> DualQuat {
+        Self::new(glm::quat(0.0, 0.0, 0.0, 1.0), glm::quat(0.0, 0.0, 0.0, 0.0))
+    }
+
+    pub fn from_rotation_translation(rotation: glm::Quat, translation: glm::Vec3) -> DualQuat {
+        let real = glm::quat_normalize(&rotation);
+        let dual = glm::quat(translation.x, translation.y, translation.z, 0.0) * real * 0.5;
+        Self::new(real, dual)
+    }
+
+    // Any scale in the matrix is dropped, dual quaternions only represent rotation and translation
+    pub fn from_mat4(mat: &glm::Mat4) -> DualQuat {
+        let mut rotation = glm::mat4_to_mat3(mat);
+        for axis in 0..3 {
+            let column = rotation.column(axis).normalize();
+            rotation.set_column(axis, &column);
+        }
+        let translation = glm::vec3(mat[(0, 3)], mat[(1, 3)], mat[(2, 3)]);
+        Self::from_rotation_translation(glm::mat3_to_quat(&rotation), translation)
+    }
+
+    pub fn get_real(&self) -> glm::Quat {
+        self.real
+    }
+
+    pub fn get_dual(&self) -> glm::Quat {
+        self.dual
+    }
+
+    pub fn get_rotation(&self) -> glm::Quat {
+        self.real
+    }
+
+    pub fn get_translation(&self) -> glm::Vec3 {
+        let t = self.dual * glm::quat_conjugate(&self.real) * 2.0;
+        glm::vec3(t.coords.x, t.coords.y, t.coords.z)
+    }
+
+    pub fn normalize(&self) -> DualQuat {
+        let length = glm::quat_length(&self.real);
+        if length <= 0.0 {
+            return Self::identity();
+        }
+        let real = self.real / length;
+        let dual = self.dual / length;
+        // Remove the part of dual parallel to real so the result stays a rigid transform
+        let dual = dual - real * glm::quat_dot(&real, &dual);
+        Self::new(real, dual)
+    }
+
+    // Weighted sum followed by normalization (DLB). Each quaternion is flipped into
+    // the hemisphere of the first one, since q and -q are the same rotation but
+    // would cancel out in the sum.
+    pub fn blend(influences: &[(DualQuat, f32)]) -> DualQuat {
+        let pivot = match influences.first() {
+            Some(&(pivot, _)) => pivot.real,
+            None => return Self::identity(),
+        };
+        let mut real = glm::quat(0.0, 0.0, 0.0, 0.0);
+        let mut dual = glm::quat(0.0, 0.0, 0.0, 0.0);
+        for &(dq, weight) in influences {
+            let weight = if glm::quat_dot(&dq.real, &pivot) < 0.0 { -weight } else { weight };
+            real += dq.real * weight;
+            dual += dq.dual * weight;
+        }
+        Self::new(real, dual).normalize()
+    }
+
+    pub fn transform_point(&self, point: &glm::Vec3) -> glm::Vec3 {
+        glm::quat_rotate_vec3(&self.real, point) + self.get_translation()
+    }
+
+    pub fn transform_vector(&self, vector: &glm::Vec3) -> glm::Vec3 {
+        glm::quat_rotate_vec3(&self.real, vector)
+    }
+
+    pub fn get_matrix(&self) -> glm::Mat4 {
+        glm::translation(&self.get_translation()) * glm::quat_to_mat4(&self.real)
+    }
+}
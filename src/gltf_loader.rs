@@ -0,0 +1,800 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use base64::Engine;
+use gl::types::*;
+use gltf::animation::util::ReadOutputs;
+use gltf::animation::Interpolation as GltfInterpolation;
+use crate::model::*;
+
+#[derive(Debug)]
+pub enum GltfError {
+    Io(String, std::io::Error),
+    Gltf(String, gltf::Error),
+    Data(String, String),
+}
+
+impl fmt::Display for GltfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GltfError::Io(file, err) => write!(f, "{}: {}", file, err),
+            GltfError::Gltf(file, err) => write!(f, "{}: {}", file, err),
+            GltfError::Data(file, message) => write!(f, "{}: {}", file, message),
+        }
+    }
+}
+
+impl Error for GltfError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    CubicSpline,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TargetPath {
+    Translation,
+    Rotation,
+    Scale,
+    Weights,
+}
+
+// Keyframes of a flat list of floats. Cubic spline samplers store an in-tangent,
+// value and out-tangent per key, each `width` floats long.
+pub struct Sampler {
+    times: Vec<f32>,
+    values: Vec<f32>,
+    width: usize,
+    interpolation: Interpolation,
+}
+
+impl Sampler {
+    pub fn new(times: Vec<f32>, values: Vec<f32>, width: usize, interpolation: Interpolation) -> Sampler {
+        Sampler {
+            times,
+            values,
+            width,
+            interpolation,
+        }
+    }
+
+    pub fn get_start_time(&self) -> f32 {
+        self.times.first().copied().unwrap_or(0.0)
+    }
+
+    pub fn get_end_time(&self) -> f32 {
+        self.times.last().copied().unwrap_or(0.0)
+    }
+
+    fn value(&self, key: usize) -> &[f32] {
+        let start = match self.interpolation {
+            Interpolation::CubicSpline => (key * 3 + 1) * self.width,
+            _ => key * self.width,
+        };
+        &self.values[start..start + self.width]
+    }
+
+    fn in_tangent(&self, key: usize) -> &[f32] {
+        let start = key * 3 * self.width;
+        &self.values[start..start + self.width]
+    }
+
+    fn out_tangent(&self, key: usize) -> &[f32] {
+        let start = (key * 3 + 2) * self.width;
+        &self.values[start..start + self.width]
+    }
+
+    // Rotation samplers are blended as quaternions, everything else per component
+    pub fn evaluate(&self, time: f32, rotation: bool) -> Vec<f32> {
+        let count = self.times.len();
+        if count == 0 {
+            return vec![0.0; self.width];
+        }
+        if count == 1 || time <= self.times[0] {
+            return self.value(0).to_vec();
+        }
+        if time >= self.times[count - 1] {
+            return self.value(count - 1).to_vec();
+        }
+        let next = self.times.partition_point(|&key_time| key_time <= time);
+        let key = next - 1;
+        let span = self.times[next] - self.times[key];
+        let u = if span > 0.0 { (time - self.times[key]) / span } else { 0.0 };
+
+        match self.interpolation {
+            Interpolation::Step => self.value(key).to_vec(),
+            Interpolation::Linear => {
+                let from = self.value(key);
+                let to = self.value(next);
+                if rotation {
+                    let q = slerp(&to_quat(from), &to_quat(to), u);
+                    vec![q.coords.x, q.coords.y, q.coords.z, q.coords.w]
+                } else {
+                    from.iter().zip(to.iter()).map(|(a, b)| a + (b - a) * u).collect()
+                }
+            },
+            Interpolation::CubicSpline => {
+                let u2 = u * u;
+                let u3 = u2 * u;
+                let h00 = 2.0 * u3 - 3.0 * u2 + 1.0;
+                let h10 = u3 - 2.0 * u2 + u;
+                let h01 = -2.0 * u3 + 3.0 * u2;
+                let h11 = u3 - u2;
+                let p0 = self.value(key);
+                let m0 = self.out_tangent(key);
+                let p1 = self.value(next);
+                let m1 = self.in_tangent(next);
+                let mut result: Vec<f32> = (0..self.width)
+                    .map(|i| h00 * p0[i] + h10 * span * m0[i] + h01 * p1[i] + h11 * span * m1[i])
+                    .collect();
+                if rotation {
+                    let q = glm::quat_normalize(&to_quat(&result));
+                    result = vec![q.coords.x, q.coords.y, q.coords.z, q.coords.w];
+                }
+                result
+            },
+        }
+    }
+}
+
+fn to_quat(values: &[f32]) -> glm::Quat {
+    glm::quat(values[0], values[1], values[2], values[3])
+}
+
+// Spherical lerp along the shorter arc, as the glTF spec asks for linear
+// rotations. Nearly equal keys fall back to a normalized lerp.
+fn slerp(from: &glm::Quat, to: &glm::Quat, u: f32) -> glm::Quat {
+    let dot = glm::quat_dot(from, to);
+    let (to, dot) = if dot < 0.0 { (-to, -dot) } else { (*to, dot) };
+    if dot > 0.9995 {
+        return glm::quat_normalize(&(from * (1.0 - u) + to * u));
+    }
+    let angle = dot.min(1.0).acos();
+    let sin = angle.sin();
+    glm::quat_normalize(&(from * (((1.0 - u) * angle).sin() / sin) + to * ((u * angle).sin() / sin)))
+}
+
+pub struct AnimationChannel {
+    node: usize,
+    path: TargetPath,
+    sampler: Sampler,
+}
+
+impl AnimationChannel {
+    pub fn get_node(&self) -> usize {
+        self.node
+    }
+
+    pub fn get_path(&self) -> TargetPath {
+        self.path
+    }
+
+    pub fn get_sampler(&self) -> &Sampler {
+        &self.sampler
+    }
+}
+
+pub struct GltfAnimation {
+    name: String,
+    channels: Vec<AnimationChannel>,
+    duration: f32,
+}
+
+impl GltfAnimation {
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_channels(&self) -> &[AnimationChannel] {
+        &self.channels
+    }
+
+    pub fn get_duration(&self) -> f32 {
+        self.duration
+    }
+}
+
+pub struct MorphTarget {
+    positions: Vec<glm::Vec3>,
+    normals: Vec<glm::Vec3>,
+}
+
+impl MorphTarget {
+    pub fn get_positions(&self) -> &[glm::Vec3] {
+        &self.positions
+    }
+
+    pub fn get_normals(&self) -> &[glm::Vec3] {
+        &self.normals
+    }
+}
+
+pub struct Primitive {
+    vertices: Vec<SkinVertex>,
+    indices: Vec<u32>,
+    targets: Vec<MorphTarget>,
+}
+
+impl Primitive {
+    pub fn get_vertices(&self) -> &[SkinVertex] {
+        &self.vertices
+    }
+
+    pub fn get_indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    pub fn get_targets(&self) -> &[MorphTarget] {
+        &self.targets
+    }
+
+    fn is_dynamic(&self, skinned: bool) -> bool {
+        skinned || !self.targets.is_empty()
+    }
+
+    fn bind_vertices(&self) -> Vec<ModelVertex> {
+        self.vertices.iter().map(|v| ModelVertex::new(v.get_position(), v.get_normal())).collect()
+    }
+
+    // Applies morph weights in bind space, then skinning if joint matrices are
+    // given. Skinned vertices without any joint weight follow `node_mat`.
+    fn deform(&self, weights: &[f32], skinning_mats: Option<&[glm::Mat4]>, node_mat: &glm::Mat4, deformed: &mut [ModelVertex]) {
+        for (index, (vertex, deformed)) in self.vertices.iter().zip(deformed.iter_mut()).enumerate() {
+            let mut position = vertex.get_position();
+            let mut normal = vertex.get_normal();
+            for (target, &weight) in self.targets.iter().zip(weights.iter()) {
+                if weight == 0.0 {
+                    continue;
+                }
+                if let Some(delta) = target.positions.get(index) {
+                    position += delta * weight;
+                }
+                if let Some(delta) = target.normals.get(index) {
+                    normal += delta * weight;
+                }
+            }
+            if let Some(mats) = skinning_mats {
+                let mut skinned_position = glm::vec3(0.0, 0.0, 0.0);
+                let mut skinned_normal = glm::vec3(0.0, 0.0, 0.0);
+                let mut total = 0.0;
+                for (joint, weight) in vertex.influences() {
+                    let mat = match mats.get(joint) {
+                        Some(mat) => mat,
+                        None => continue,
+                    };
+                    skinned_position += glm::vec4_to_vec3(&(mat * glm::vec4(position.x, position.y, position.z, 1.0))) * weight;
+                    skinned_normal += glm::vec4_to_vec3(&(mat * glm::vec4(normal.x, normal.y, normal.z, 0.0))) * weight;
+                    total += weight;
+                }
+                // Exporters don't always write weights that sum to one
+                if total > 0.0 {
+                    position = skinned_position / total;
+                    normal = skinned_normal / total;
+                } else {
+                    position = glm::vec4_to_vec3(&(node_mat * glm::vec4(position.x, position.y, position.z, 1.0)));
+                    normal = glm::vec4_to_vec3(&(node_mat * glm::vec4(normal.x, normal.y, normal.z, 0.0)));
+                }
+            }
+            let length = glm::length(&normal);
+            *deformed = ModelVertex::new(position, if length > 0.0 { normal / length } else { vertex.get_normal() });
+        }
+    }
+}
+
+// A node's copy of a mesh, deformed with that node's skin and weights
+pub struct MeshInstance {
+    node: usize,
+    mesh: usize,
+    // Vertices of each primitive, skinned ones already in world space
+    deformed: Vec<Vec<ModelVertex>>,
+    models: Vec<Model>,
+}
+
+impl MeshInstance {
+    pub fn get_node(&self) -> usize {
+        self.node
+    }
+
+    pub fn get_mesh(&self) -> usize {
+        self.mesh
+    }
+
+    pub fn get_deformed(&self, primitive: usize) -> &[ModelVertex] {
+        &self.deformed[primitive]
+    }
+}
+
+pub struct GltfMesh {
+    name: String,
+    primitives: Vec<Primitive>,
+    weights: Vec<f32>,
+}
+
+impl GltfMesh {
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_primitives(&self) -> &[Primitive] {
+        &self.primitives
+    }
+}
+
+pub struct GltfSkin {
+    joints: Vec<usize>,
+    inverse_bind_mats: Vec<glm::Mat4>,
+}
+
+impl GltfSkin {
+    pub fn get_joints(&self) -> &[usize] {
+        &self.joints
+    }
+
+    pub fn get_inverse_bind_mats(&self) -> &[glm::Mat4] {
+        &self.inverse_bind_mats
+    }
+}
+
+pub struct GltfNode {
+    name: String,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    mesh: Option<usize>,
+    skin: Option<usize>,
+    translation: glm::Vec3,
+    rotation: glm::Quat,
+    scale: glm::Vec3,
+    weights: Vec<f32>,
+    rest_translation: glm::Vec3,
+    rest_rotation: glm::Quat,
+    rest_scale: glm::Vec3,
+    rest_weights: Vec<f32>,
+    world_mat: glm::Mat4,
+}
+
+impl GltfNode {
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_parent(&self) -> Option<usize> {
+        self.parent
+    }
+
+    pub fn get_children(&self) -> &[usize] {
+        &self.children
+    }
+
+    pub fn get_mesh(&self) -> Option<usize> {
+        self.mesh
+    }
+
+    pub fn get_skin(&self) -> Option<usize> {
+        self.skin
+    }
+
+    pub fn get_translation(&self) -> glm::Vec3 {
+        self.translation
+    }
+
+    pub fn get_rotation(&self) -> glm::Quat {
+        self.rotation
+    }
+
+    pub fn get_scale(&self) -> glm::Vec3 {
+        self.scale
+    }
+
+    pub fn get_weights(&self) -> &[f32] {
+        &self.weights
+    }
+
+    pub fn get_local_mat(&self) -> glm::Mat4 {
+        glm::translation(&self.translation) * glm::quat_to_mat4(&self.rotation) * glm::scaling(&self.scale)
+    }
+
+    pub fn get_world_mat(&self) -> glm::Mat4 {
+        self.world_mat
+    }
+}
+
+pub struct GltfScene {
+    nodes: Vec<GltfNode>,
+    // Nodes of the scene, parents before children, for propagating world matrices
+    order: Vec<usize>,
+    meshes: Vec<GltfMesh>,
+    instances: Vec<MeshInstance>,
+    skins: Vec<GltfSkin>,
+    animations: Vec<GltfAnimation>,
+    active_animation: Option<usize>,
+    time: f32,
+    // Problems that didn't stop the scene from loading
+    warnings: Vec<GltfError>,
+}
+
+impl GltfScene {
+    pub fn from_file(filename: &str) -> Result<GltfScene, GltfError> {
+        let bytes = fs::read(filename).map_err(|e| GltfError::Io(filename.to_string(), e))?;
+        let gltf = gltf::Gltf::from_slice(&bytes).map_err(|e| GltfError::Gltf(filename.to_string(), e))?;
+        let base = Path::new(filename).parent().unwrap_or_else(|| Path::new(""));
+        let data_error = |message: String| GltfError::Data(filename.to_string(), message);
+
+        let mut buffers: Vec<Vec<u8>> = Vec::new();
+        for buffer in gltf.document.buffers() {
+            let mut data = match buffer.source() {
+                gltf::buffer::Source::Bin => gltf.blob.clone()
+                    .ok_or_else(|| data_error("buffer refers to a missing GLB binary chunk".to_string()))?,
+                gltf::buffer::Source::Uri(uri) => {
+                    if uri.starts_with("data:") {
+                        let encoded = uri.split(";base64,").nth(1)
+                            .ok_or_else(|| data_error("only base64 data URIs are supported".to_string()))?;
+                        base64::engine::general_purpose::STANDARD.decode(encoded)
+                            .map_err(|e| data_error(format!("invalid base64 buffer: {}", e)))?
+                    } else {
+                        let path = base.join(uri);
+                        fs::read(&path).map_err(|e| GltfError::Io(path.display().to_string(), e))?
+                    }
+                },
+            };
+            if data.len() < buffer.length() {
+                return Err(data_error(format!("buffer {} is {} bytes, expected {}", buffer.index(), data.len(), buffer.length())));
+            }
+            data.truncate(buffer.length());
+            buffers.push(data);
+        }
+        let get_buffer = |buffer: gltf::Buffer| buffers.get(buffer.index()).map(|data| data.as_slice());
+
+        let mut warnings = Vec::new();
+        let mut meshes = Vec::new();
+        for mesh in gltf.document.meshes() {
+            let mut primitives = Vec::new();
+            for primitive in mesh.primitives() {
+                if primitive.mode() != gltf::mesh::Mode::Triangles {
+                    warnings.push(data_error(format!("skipping non-triangle primitive in mesh {}", mesh.index())));
+                    continue;
+                }
+                let reader = primitive.reader(get_buffer);
+                let positions: Vec<glm::Vec3> = reader.read_positions()
+                    .ok_or_else(|| data_error(format!("mesh {} has a primitive without positions", mesh.index())))?
+                    .map(|p| glm::vec3(p[0], p[1], p[2]))
+                    .collect();
+                let indices: Vec<u32> = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().collect(),
+                    None => (0..positions.len() as u32).collect(),
+                };
+                if let Some(&index) = indices.iter().find(|&&index| index as usize >= positions.len()) {
+                    return Err(data_error(format!("mesh {} references vertex {} of {}", mesh.index(), index, positions.len())));
+                }
+                let normals: Vec<glm::Vec3> = match reader.read_normals() {
+                    Some(normals) => normals.map(|n| glm::vec3(n[0], n[1], n[2])).collect(),
+                    None => smooth_normals(&positions, &indices),
+                };
+                let joints: Vec<[u16; 4]> = reader.read_joints(0).map(|j| j.into_u16().collect()).unwrap_or_default();
+                let weights: Vec<[f32; 4]> = reader.read_weights(0).map(|w| w.into_f32().collect()).unwrap_or_default();
+                let vertices: Vec<SkinVertex> = positions.iter().zip(normals.iter()).enumerate().map(|(i, (&position, &normal))| {
+                    let influences: Vec<(usize, f32)> = match (joints.get(i), weights.get(i)) {
+                        (Some(j), Some(w)) => (0..4).map(|k| (j[k] as usize, w[k])).collect(),
+                        _ => Vec::new(),
+                    };
+                    SkinVertex::new(position, normal, &influences)
+                }).collect();
+                let targets = reader.read_morph_targets().map(|(positions, normals, _)| MorphTarget {
+                    positions: positions.map(|p| p.map(|d| glm::vec3(d[0], d[1], d[2])).collect()).unwrap_or_default(),
+                    normals: normals.map(|n| n.map(|d| glm::vec3(d[0], d[1], d[2])).collect()).unwrap_or_default(),
+                }).collect();
+                primitives.push(Primitive {
+                    vertices,
+                    indices,
+                    targets,
+                });
+            }
+            meshes.push(GltfMesh {
+                name: mesh.name().unwrap_or("").to_string(),
+                primitives,
+                weights: mesh.weights().map(|w| w.to_vec()).unwrap_or_default(),
+            });
+        }
+
+        let mut skins = Vec::new();
+        for skin in gltf.document.skins() {
+            let joints: Vec<usize> = skin.joints().map(|joint| joint.index()).collect();
+            let inverse_bind_mats = match skin.reader(get_buffer).read_inverse_bind_matrices() {
+                Some(mats) => mats.map(|m| glm::Mat4::from_column_slice(&[
+                    m[0][0], m[0][1], m[0][2], m[0][3],
+                    m[1][0], m[1][1], m[1][2], m[1][3],
+                    m[2][0], m[2][1], m[2][2], m[2][3],
+                    m[3][0], m[3][1], m[3][2], m[3][3],
+                ])).collect(),
+                None => vec![glm::Mat4::identity(); joints.len()],
+            };
+            if inverse_bind_mats.len() < joints.len() {
+                return Err(data_error(format!("skin {} has fewer inverse bind matrices than joints", skin.index())));
+            }
+            skins.push(GltfSkin {
+                joints,
+                inverse_bind_mats,
+            });
+        }
+
+        let mut nodes: Vec<GltfNode> = gltf.document.nodes().map(|node| {
+            let (translation, rotation, scale) = node.transform().decomposed();
+            let translation = glm::vec3(translation[0], translation[1], translation[2]);
+            let rotation = glm::quat(rotation[0], rotation[1], rotation[2], rotation[3]);
+            let scale = glm::vec3(scale[0], scale[1], scale[2]);
+            let weights = node.weights().map(|w| w.to_vec())
+                .or_else(|| node.mesh().and_then(|mesh| mesh.weights().map(|w| w.to_vec())))
+                .unwrap_or_default();
+            GltfNode {
+                name: node.name().unwrap_or("").to_string(),
+                parent: None,
+                children: node.children().map(|child| child.index()).collect(),
+                mesh: node.mesh().map(|mesh| mesh.index()),
+                skin: node.skin().map(|skin| skin.index()),
+                translation,
+                rotation,
+                scale,
+                weights: weights.clone(),
+                rest_translation: translation,
+                rest_rotation: rotation,
+                rest_scale: scale,
+                rest_weights: weights,
+                world_mat: glm::Mat4::identity(),
+            }
+        }).collect();
+        for index in 0..nodes.len() {
+            for child in nodes[index].children.clone() {
+                nodes[child].parent = Some(index);
+            }
+        }
+
+        let roots: Vec<usize> = match gltf.document.default_scene().or_else(|| gltf.document.scenes().next()) {
+            Some(scene) => scene.nodes().map(|node| node.index()).collect(),
+            None => (0..nodes.len()).filter(|&index| nodes[index].parent.is_none()).collect(),
+        };
+        let mut order = Vec::with_capacity(nodes.len());
+        let mut stack: Vec<usize> = roots.into_iter().rev().collect();
+        while let Some(index) = stack.pop() {
+            order.push(index);
+            stack.extend(nodes[index].children.iter().rev());
+        }
+
+        let instances = order.iter().filter_map(|&node| {
+            let mesh = nodes[node].mesh?;
+            Some(MeshInstance {
+                node,
+                mesh,
+                deformed: meshes[mesh].primitives.iter().map(Primitive::bind_vertices).collect(),
+                models: Vec::new(),
+            })
+        }).collect();
+
+        let mut animations = Vec::new();
+        for animation in gltf.document.animations() {
+            let mut channels = Vec::new();
+            for channel in animation.channels() {
+                let reader = channel.reader(get_buffer);
+                let times: Vec<f32> = match reader.read_inputs() {
+                    Some(inputs) => inputs.collect(),
+                    None => continue,
+                };
+                let (path, width, values): (TargetPath, usize, Vec<f32>) = match reader.read_outputs() {
+                    Some(ReadOutputs::Translations(t)) => (TargetPath::Translation, 3, t.flatten().collect()),
+                    Some(ReadOutputs::Rotations(r)) => (TargetPath::Rotation, 4, r.into_f32().flatten().collect()),
+                    Some(ReadOutputs::Scales(s)) => (TargetPath::Scale, 3, s.flatten().collect()),
+                    Some(ReadOutputs::MorphTargetWeights(w)) => {
+                        let values: Vec<f32> = w.into_f32().collect();
+                        let keys = times.len().max(1);
+                        let per_key = if channel.sampler().interpolation() == GltfInterpolation::CubicSpline { 3 } else { 1 };
+                        (TargetPath::Weights, values.len() / (keys * per_key), values)
+                    },
+                    None => continue,
+                };
+                let interpolation = match channel.sampler().interpolation() {
+                    GltfInterpolation::Step => Interpolation::Step,
+                    GltfInterpolation::Linear => Interpolation::Linear,
+                    GltfInterpolation::CubicSpline => Interpolation::CubicSpline,
+                };
+                let per_key = if interpolation == Interpolation::CubicSpline { 3 } else { 1 };
+                if width == 0 || values.len() < times.len() * per_key * width {
+                    return Err(data_error(format!("animation {} has a sampler with too few output values", animation.index())));
+                }
+                channels.push(AnimationChannel {
+                    node: channel.target().node().index(),
+                    path,
+                    sampler: Sampler::new(times, values, width, interpolation),
+                });
+            }
+            let duration = channels.iter().map(|channel| channel.sampler.get_end_time()).fold(0.0, f32::max);
+            animations.push(GltfAnimation {
+                name: animation.name().unwrap_or("").to_string(),
+                channels,
+                duration,
+            });
+        }
+
+        let active_animation = if animations.is_empty() { None } else { Some(0) };
+        let mut scene = GltfScene {
+            nodes,
+            order,
+            meshes,
+            instances,
+            skins,
+            animations,
+            active_animation,
+            time: 0.0,
+            warnings,
+        };
+        scene.pose(0.0);
+        Ok(scene)
+    }
+
+    pub fn make_models(&mut self) {
+        for instance in &mut self.instances {
+            let skinned = self.nodes[instance.node].skin.is_some();
+            let primitives = &self.meshes[instance.mesh].primitives;
+            instance.models = primitives.iter().zip(instance.deformed.iter()).map(|(primitive, deformed)| {
+                let mut model = Model::new();
+                if primitive.is_dynamic(skinned) {
+                    model.set_stream_buffers(deformed, &primitive.indices);
+                } else {
+                    model.set_buffers(deformed, &primitive.indices);
+                }
+                model
+            }).collect();
+        }
+    }
+
+    pub fn get_nodes(&self) -> &[GltfNode] {
+        &self.nodes
+    }
+
+    pub fn get_meshes(&self) -> &[GltfMesh] {
+        &self.meshes
+    }
+
+    pub fn get_instances(&self) -> &[MeshInstance] {
+        &self.instances
+    }
+
+    pub fn get_skins(&self) -> &[GltfSkin] {
+        &self.skins
+    }
+
+    pub fn get_animations(&self) -> &[GltfAnimation] {
+        &self.animations
+    }
+
+    pub fn get_warnings(&self) -> &[GltfError] {
+        &self.warnings
+    }
+
+    pub fn find_node(&self, name: &str) -> Option<usize> {
+        self.nodes.iter().position(|node| node.name == name)
+    }
+
+    pub fn get_active_animation(&self) -> Option<usize> {
+        self.active_animation
+    }
+
+    pub fn set_active_animation(&mut self, animation: Option<usize>) {
+        self.active_animation = animation.filter(|&index| index < self.animations.len());
+        self.time = 0.0;
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        self.time += dt;
+        let duration = self.active_animation.map_or(0.0, |index| self.animations[index].duration);
+        if duration > 0.0 {
+            self.time %= duration;
+        }
+        self.pose(self.time);
+    }
+
+    pub fn reset(&mut self) {
+        self.time = 0.0;
+        self.pose(0.0);
+    }
+
+    fn pose(&mut self, time: f32) {
+        for node in &mut self.nodes {
+            node.translation = node.rest_translation;
+            node.rotation = node.rest_rotation;
+            node.scale = node.rest_scale;
+            node.weights.clone_from(&node.rest_weights);
+        }
+        if let Some(index) = self.active_animation {
+            for channel in &self.animations[index].channels {
+                let node = &mut self.nodes[channel.node];
+                let value = channel.sampler.evaluate(time, channel.path == TargetPath::Rotation);
+                match channel.path {
+                    TargetPath::Translation => node.translation = glm::vec3(value[0], value[1], value[2]),
+                    TargetPath::Rotation => node.rotation = to_quat(&value),
+                    TargetPath::Scale => node.scale = glm::vec3(value[0], value[1], value[2]),
+                    TargetPath::Weights => node.weights = value,
+                }
+            }
+        }
+
+        for &index in &self.order {
+            let local = self.nodes[index].get_local_mat();
+            self.nodes[index].world_mat = match self.nodes[index].parent {
+                Some(parent) => self.nodes[parent].world_mat * local,
+                None => local,
+            };
+        }
+
+        let nodes = &self.nodes;
+        let skins = &self.skins;
+        for instance in &mut self.instances {
+            let node = &nodes[instance.node];
+            let mesh = &self.meshes[instance.mesh];
+            let skinning_mats: Option<Vec<glm::Mat4>> = node.skin.map(|skin| {
+                let skin = &skins[skin];
+                skin.joints.iter().zip(skin.inverse_bind_mats.iter())
+                    .map(|(&joint, inverse_bind)| nodes[joint].world_mat * inverse_bind)
+                    .collect()
+            });
+            let weights = if node.weights.is_empty() { &mesh.weights } else { &node.weights };
+            for (index, primitive) in mesh.primitives.iter().enumerate() {
+                if !primitive.is_dynamic(skinning_mats.is_some()) {
+                    continue;
+                }
+                primitive.deform(weights, skinning_mats.as_deref(), &node.world_mat, &mut instance.deformed[index]);
+                if let Some(model) = instance.models.get_mut(index) {
+                    model.update_vertices(&instance.deformed[index]);
+                }
+            }
+        }
+    }
+
+    pub fn draw(&self, view_proj_mat: glm::Mat4, shader: GLuint) {
+        for instance in &self.instances {
+            let node = &self.nodes[instance.node];
+            // Skinned vertices are already in world space
+            let model_mat = if node.skin.is_some() { glm::Mat4::identity() } else { node.world_mat };
+            for model in &instance.models {
+                model.draw(model_mat, view_proj_mat, shader);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear_rotations_turn_at_a_constant_rate() {
+        // 160 degrees about y between two keys, where nlerp would visibly speed up mid-way
+        let end = glm::quat_angle_axis(160.0f32.to_radians(), &glm::vec3(0.0, 1.0, 0.0));
+        let values = vec![0.0, 0.0, 0.0, 1.0, end.coords.x, end.coords.y, end.coords.z, end.coords.w];
+        let sampler = Sampler::new(vec![0.0, 1.0], values, 4, Interpolation::Linear);
+        for &time in &[0.1, 0.25, 0.5, 0.9] {
+            let q = to_quat(&sampler.evaluate(time, true));
+            let angle = 2.0 * q.coords.w.abs().min(1.0).acos();
+            assert!((angle - 160.0f32.to_radians() * time).abs() < 1e-4, "{} at {}", angle.to_degrees(), time);
+        }
+    }
+
+    #[test]
+    fn unweighted_vertices_follow_the_node() {
+        let influences = [(0, 1.0)];
+        let primitive = Primitive {
+            vertices: vec![
+                SkinVertex::new(glm::vec3(1.0, 0.0, 0.0), glm::vec3(0.0, 1.0, 0.0), &influences),
+                SkinVertex::new(glm::vec3(0.0, 1.0, 0.0), glm::vec3(0.0, 1.0, 0.0), &[(0, 0.0)]),
+            ],
+            indices: Vec::new(),
+            targets: Vec::new(),
+        };
+        let joint_mat = glm::translation(&glm::vec3(0.0, 0.0, 5.0));
+        let node_mat = glm::translation(&glm::vec3(2.0, 0.0, 0.0));
+        let mut deformed = primitive.bind_vertices();
+        primitive.deform(&[], Some(&[joint_mat]), &node_mat, &mut deformed);
+        assert_eq!(deformed[0].get_position(), glm::vec3(1.0, 0.0, 5.0));
+        assert_eq!(deformed[1].get_position(), glm::vec3(2.0, 1.0, 0.0));
+    }
+}
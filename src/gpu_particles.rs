@@ -0,0 +1,326 @@
+use gl::types::*;
+use crate::buffer::*;
+use crate::shader_program::*;
+
+// Matches the std430 layout of Particle in particles.comp, the age rides in
+// position.w and the lifetime in velocity.w
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GpuParticle {
+    pub position: [f32; 4],
+    pub velocity: [f32; 4],
+}
+
+impl GpuParticle {
+    pub fn get_position(&self) -> glm::Vec3 {
+        glm::vec3(self.position[0], self.position[1], self.position[2])
+    }
+
+    pub fn get_velocity(&self) -> glm::Vec3 {
+        glm::vec3(self.velocity[0], self.velocity[1], self.velocity[2])
+    }
+
+    pub fn get_age(&self) -> f32 {
+        self.position[3]
+    }
+
+    pub fn get_lifetime(&self) -> f32 {
+        self.velocity[3]
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GpuParticleOptions {
+    pub count: usize,
+    pub emitter: glm::Vec3,
+    // Upward launch speed range and the horizontal speed range around it
+    pub speed: (f32, f32),
+    pub spread: f32,
+    pub lifetime: (f32, f32),
+    pub gravity: glm::Vec3,
+    pub drag: f32,
+    pub ground: f32,
+    pub restitution: f32,
+}
+
+impl GpuParticleOptions {
+    pub fn new(count: usize) -> GpuParticleOptions {
+        GpuParticleOptions {
+            count,
+            emitter: glm::vec3(0.0, 0.0, 0.0),
+            speed: (4.0, 7.0),
+            spread: 1.5,
+            lifetime: (1.5, 3.0),
+            gravity: glm::vec3(0.0, -9.8, 0.0),
+            drag: 0.1,
+            ground: 0.0,
+            restitution: 0.5,
+        }
+    }
+}
+
+const GROUP_SIZE: usize = 256;
+
+// Integer hash shared with particles.comp, so both sides draw the same
+// random numbers for a particle on a given frame
+fn hash(x: u32) -> u32 {
+    let mut x = x;
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    x
+}
+
+// Uniform in [0, 1), exact in f32 so no rounding differs between CPU and GPU
+fn random(index: u32, frame: u32, channel: u32) -> f32 {
+    (hash(index.wrapping_mul(4).wrapping_add(channel) ^ hash(frame)) >> 8) as f32 / 16_777_216.0
+}
+
+// Particles start at the emitter with their ages spread over a lifetime so
+// they don't all respawn on the same frame
+pub fn initial_particles(options: &GpuParticleOptions) -> Vec<GpuParticle> {
+    let emitter = options.emitter;
+    (0..options.count).map(|index| {
+        let lifetime = options.lifetime.0 + (options.lifetime.1 - options.lifetime.0) * random(index as u32, u32::MAX, 3);
+        GpuParticle {
+            position: [emitter.x, emitter.y, emitter.z, lifetime * (index as f32 / options.count as f32)],
+            velocity: [0.0, 0.0, 0.0, lifetime],
+        }
+    }).collect()
+}
+
+// CPU version of particles.comp, operation for operation. Steps particles
+// where there's no compute shader and is what the GPU results are checked against
+pub fn step_reference(particles: &mut [GpuParticle], options: &GpuParticleOptions, dt: f32, frame: u32) {
+    for (index, particle) in particles.iter_mut().enumerate() {
+        let index = index as u32;
+        let mut position = particle.get_position();
+        let mut velocity = particle.get_velocity();
+        let mut age = particle.get_age() + dt;
+        let mut lifetime = particle.get_lifetime();
+
+        if age >= lifetime {
+            position = options.emitter;
+            velocity = glm::vec3(
+                options.spread * (random(index, frame, 0) * 2.0 - 1.0),
+                options.speed.0 + (options.speed.1 - options.speed.0) * random(index, frame, 1),
+                options.spread * (random(index, frame, 2) * 2.0 - 1.0),
+            );
+            lifetime = options.lifetime.0 + (options.lifetime.1 - options.lifetime.0) * random(index, frame, 3);
+            age = 0.0;
+        } else {
+            velocity += (options.gravity - velocity * options.drag) * dt;
+            position += velocity * dt;
+            if position.y < options.ground {
+                position.y = options.ground;
+                if velocity.y < 0.0 {
+                    velocity.y = -velocity.y * options.restitution;
+                }
+            }
+        }
+        particle.position = [position.x, position.y, position.z, age];
+        particle.velocity = [velocity.x, velocity.y, velocity.z, lifetime];
+    }
+}
+
+// Particles living in a shader storage buffer, stepped by particles.comp and
+// drawn as points straight from the buffer by gpu_particle.vert
+pub struct GpuParticleSystem {
+    options: GpuParticleOptions,
+    buffer: ShaderStorageBuffer,
+    vao: VertexArray,
+    frame: u32,
+}
+
+impl GpuParticleSystem {
+    pub fn new(options: GpuParticleOptions) -> GpuParticleSystem {
+        let system = GpuParticleSystem {
+            options,
+            buffer: ShaderStorageBuffer::new(),
+            vao: VertexArray::new(),
+            frame: 0,
+        };
+        system.upload(&initial_particles(&options));
+        system
+    }
+
+    pub fn get_options(&self) -> &GpuParticleOptions {
+        &self.options
+    }
+
+    pub fn get_frame(&self) -> u32 {
+        self.frame
+    }
+
+    pub fn upload(&self, particles: &[GpuParticle]) {
+        self.buffer.bind();
+        self.buffer.dynamic_draw_data(particles);
+        self.buffer.unbind();
+    }
+
+    pub fn update(&mut self, program: &ShaderProgram, dt: f32) {
+        let options = &self.options;
+        let program_id = program.id();
+        let location = |name: &[u8]| unsafe { gl::GetUniformLocation(program_id, name.as_ptr() as _) };
+        unsafe {
+            gl::ProgramUniform1ui(program_id, location(b"Count\0"), options.count as GLuint);
+            gl::ProgramUniform1ui(program_id, location(b"Frame\0"), self.frame);
+            gl::ProgramUniform1f(program_id, location(b"DeltaTime\0"), dt);
+            gl::ProgramUniform3fv(program_id, location(b"Emitter\0"), 1, options.emitter.as_slice().as_ptr());
+            gl::ProgramUniform2f(program_id, location(b"Speed\0"), options.speed.0, options.speed.1);
+            gl::ProgramUniform1f(program_id, location(b"Spread\0"), options.spread);
+            gl::ProgramUniform2f(program_id, location(b"Lifetime\0"), options.lifetime.0, options.lifetime.1);
+            gl::ProgramUniform3fv(program_id, location(b"Gravity\0"), 1, options.gravity.as_slice().as_ptr());
+            gl::ProgramUniform1f(program_id, location(b"Drag\0"), options.drag);
+            gl::ProgramUniform1f(program_id, location(b"Ground\0"), options.ground);
+            gl::ProgramUniform1f(program_id, location(b"Restitution\0"), options.restitution);
+        }
+        self.buffer.bind_base(0);
+        let groups = options.count.div_ceil(GROUP_SIZE) as GLuint;
+        program.dispatch(groups, 1, 1);
+        // Drawing and read back both go through the storage buffer
+        memory_barrier(gl::SHADER_STORAGE_BARRIER_BIT | gl::BUFFER_UPDATE_BARRIER_BIT);
+        self.frame = self.frame.wrapping_add(1);
+    }
+
+    // Copies the particles back from the GPU, which waits for the simulation
+    pub fn read_back(&self) -> Vec<GpuParticle> {
+        let mut particles = vec![GpuParticle { position: [0.0; 4], velocity: [0.0; 4] }; self.options.count];
+        self.buffer.bind();
+        self.buffer.read_data(&mut particles);
+        self.buffer.unbind();
+        particles
+    }
+
+    pub fn reset(&mut self) {
+        self.upload(&initial_particles(&self.options));
+        self.frame = 0;
+    }
+
+    // The vertex shader fetches its particle by gl_VertexID, so the vertex
+    // array has no attributes
+    pub fn draw(&self, view_proj_mat: glm::Mat4, shader: GLuint) {
+        self.buffer.bind_base(0);
+        unsafe {
+            gl::UseProgram(shader);
+            gl::UniformMatrix4fv(gl::GetUniformLocation(shader, b"ViewProjMtx\0".as_ptr() as _), 1, gl::FALSE, view_proj_mat.as_slice().as_ptr() as _);
+            gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+            gl::DepthMask(gl::FALSE);
+        }
+        self.vao.bind();
+        unsafe {
+            gl::DrawArrays(gl::POINTS, 0, self.options.count as GLsizei);
+        }
+        self.vao.unbind();
+        unsafe {
+            gl::DepthMask(gl::TRUE);
+            gl::BlendFunc(gl::ONE, gl::ZERO);
+            gl::UseProgram(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glutin::GlContext;
+    use super::*;
+
+    fn particle(position: glm::Vec3, velocity: glm::Vec3, age: f32, lifetime: f32) -> GpuParticle {
+        GpuParticle {
+            position: [position.x, position.y, position.z, age],
+            velocity: [velocity.x, velocity.y, velocity.z, lifetime],
+        }
+    }
+
+    fn assert_close(a: glm::Vec3, b: glm::Vec3) {
+        assert!(glm::length(&(a - b)) < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn reference_step_integrates_and_bounces() {
+        let options = GpuParticleOptions::new(2);
+        let mut particles = vec![
+            particle(glm::vec3(0.0, 1.0, 0.0), glm::vec3(1.0, 2.0, 0.0), 0.0, 10.0),
+            particle(glm::vec3(0.0, 0.01, 0.0), glm::vec3(0.0, -5.0, 0.0), 0.0, 10.0),
+        ];
+        step_reference(&mut particles, &options, 0.1, 0);
+        // v += (g - v drag) dt, then p += v dt
+        assert_close(particles[0].get_velocity(), glm::vec3(0.99, 1.0, 0.0));
+        assert_close(particles[0].get_position(), glm::vec3(0.099, 1.1, 0.0));
+        assert!((particles[0].get_age() - 0.1).abs() < 1e-6);
+        // Through the ground, back onto it going up at half the speed
+        let falling = -5.0 + (-9.8 + 0.5) * 0.1;
+        assert_close(particles[1].get_position(), glm::vec3(0.0, 0.0, 0.0));
+        assert_close(particles[1].get_velocity(), glm::vec3(0.0, -falling * 0.5, 0.0));
+    }
+
+    #[test]
+    fn reference_respawns_within_the_options() {
+        let options = GpuParticleOptions::new(64);
+        let mut particles = initial_particles(&options);
+        for particle in &mut particles {
+            particle.position[3] = particle.get_lifetime();
+        }
+        let mut again = particles.clone();
+        step_reference(&mut particles, &options, 0.01, 7);
+        step_reference(&mut again, &options, 0.01, 7);
+        assert_eq!(particles, again, "respawns depend only on index and frame");
+        for particle in &particles {
+            assert_eq!(particle.get_age(), 0.0);
+            assert_eq!(particle.get_position(), options.emitter);
+            let velocity = particle.get_velocity();
+            assert!(velocity.x.abs() <= options.spread && velocity.z.abs() <= options.spread);
+            assert!(velocity.y >= options.speed.0 && velocity.y <= options.speed.1);
+            assert!(particle.get_lifetime() >= options.lifetime.0 && particle.get_lifetime() <= options.lifetime.1);
+        }
+        assert!(particles.windows(2).any(|pair| pair[0].get_velocity() != pair[1].get_velocity()));
+    }
+
+    // Steps the same particles with particles.comp and step_reference. Needs
+    // an OpenGL 4.3 context and a display, Mesa's llvmpipe will do on a
+    // machine without a GPU:
+    // xvfb-run -a env LIBGL_ALWAYS_SOFTWARE=1 GALLIUM_DRIVER=llvmpipe cargo test -- --ignored gpu_matches_reference
+    #[test]
+    #[ignore]
+    fn gpu_matches_reference() {
+        let events_loop = glutin::EventsLoop::new();
+        let window = glutin::WindowBuilder::new()
+            .with_title("animbox")
+            .with_visibility(false);
+        let context = glutin::ContextBuilder::new()
+            .with_gl_profile(glutin::GlProfile::Core)
+            .with_gl(glutin::GlRequest::Specific(glutin::Api::OpenGl, (4, 3)));
+        let gl_window = glutin::GlWindow::new(window, context, &events_loop).expect("no OpenGL 4.3 context");
+        unsafe {
+            gl_window.make_current().unwrap();
+            gl::load_with(|symbol| gl_window.get_proc_address(symbol) as *const _);
+        }
+
+        let compute_program = ShaderProgram::from_file("particles", ProgramType::Compute);
+        let options = GpuParticleOptions::new(4096);
+        let mut system = GpuParticleSystem::new(options);
+        let mut reference = initial_particles(&options);
+        let dt = 1.0 / 60.0;
+        for frame in 0..120 {
+            system.update(&compute_program, dt);
+            step_reference(&mut reference, &options, dt, frame);
+        }
+        let gpu = system.read_back();
+        let mut position_error: f32 = 0.0;
+        let mut velocity_error: f32 = 0.0;
+        let mut respawn_mismatches = 0;
+        for (a, b) in gpu.iter().zip(reference.iter()) {
+            if (a.get_age() - b.get_age()).abs() > dt * 0.5 {
+                respawn_mismatches += 1;
+                continue;
+            }
+            position_error = position_error.max(glm::length(&(a.get_position() - b.get_position())));
+            velocity_error = velocity_error.max(glm::length(&(a.get_velocity() - b.get_velocity())));
+        }
+        assert_eq!(respawn_mismatches, 0);
+        assert!(position_error < 1e-3, "position error {}", position_error);
+        assert!(velocity_error < 1e-3, "velocity error {}", velocity_error);
+    }
+}
@@ -0,0 +1,373 @@
+use crate::pose::*;
+use crate::skeleton::*;
+
+pub fn world_position(world_mat: &glm::Mat4) -> glm::Vec3 {
+    glm::vec3(world_mat[(0, 3)], world_mat[(1, 3)], world_mat[(2, 3)])
+}
+
+// Rotation part of the skeleton transform, the frame the root joint sits in
+pub fn transform_rotation(skeleton: &Skeleton) -> glm::Quat {
+    let transform = skeleton.get_transform();
+    let column = |axis: usize| {
+        let column = glm::vec3(transform[(0, axis)], transform[(1, axis)], transform[(2, axis)]);
+        let length = glm::length(&column);
+        if length > 1e-6 { column / length } else { column }
+    };
+    glm::quat_normalize(&glm::mat3_to_quat(&glm::Mat3::from_columns(&[column(0), column(1), column(2)])))
+}
+
+// Rotation from the world down to `joint`, ignoring scale
+pub fn world_rotation(pose: &Pose, skeleton: &Skeleton, joint: usize) -> glm::Quat {
+    let mut rotation = pose.get_transform(joint).get_rotation();
+    let mut parent = skeleton.get_joint(joint).get_parent();
+    while let Some(index) = parent {
+        rotation = pose.get_transform(index).get_rotation() * rotation;
+        parent = skeleton.get_joint(index).get_parent();
+    }
+    transform_rotation(skeleton) * rotation
+}
+
+// Rotates `joint` and everything below it by `delta`, given in world space
+pub fn rotate_world(pose: &mut Pose, skeleton: &Skeleton, joint: usize, delta: &glm::Quat) {
+    let parent_rotation = match skeleton.get_joint(joint).get_parent() {
+        Some(parent) => world_rotation(pose, skeleton, parent),
+        None => transform_rotation(skeleton),
+    };
+    let transform = pose.get_transform_mut(joint);
+    let local = glm::quat_inverse(&parent_rotation) * delta * parent_rotation * transform.get_rotation();
+    transform.set_rotation(glm::quat_normalize(&local));
+}
+
+// Shortest rotation taking direction `from` onto `to`
+pub fn rotation_between(from: &glm::Vec3, to: &glm::Vec3) -> glm::Quat {
+    let from_length = glm::length(from);
+    let to_length = glm::length(to);
+    if from_length <= 1e-6 || to_length <= 1e-6 {
+        return glm::quat(0.0, 0.0, 0.0, 1.0);
+    }
+    let from = from / from_length;
+    let to = to / to_length;
+    let cos = glm::clamp_scalar(glm::dot(&from, &to), -1.0, 1.0);
+    let mut axis = from.cross(&to);
+    if glm::length(&axis) <= 1e-6 {
+        if cos > 0.0 {
+            return glm::quat(0.0, 0.0, 0.0, 1.0);
+        }
+        // Opposite directions, any perpendicular axis does
+        axis = if from.x.abs() < 0.9 { from.cross(&glm::vec3(1.0, 0.0, 0.0)) } else { from.cross(&glm::vec3(0.0, 1.0, 0.0)) };
+    }
+    glm::quat_angle_axis(cos.acos(), &glm::normalize(&axis))
+}
+
+// Splits `rotation` into a swing that moves `axis` and a twist around it,
+// with rotation = swing * twist
+pub fn swing_twist(rotation: &glm::Quat, axis: &glm::Vec3) -> (glm::Quat, glm::Quat) {
+    let vector = glm::vec3(rotation.coords.x, rotation.coords.y, rotation.coords.z);
+    let projected = axis * glm::dot(&vector, axis);
+    let twist = glm::quat(projected.x, projected.y, projected.z, rotation.coords.w);
+    let length = glm::quat_length(&twist);
+    // A half turn swing leaves no twist to extract
+    let twist = if length > 1e-6 { twist / length } else { glm::quat(0.0, 0.0, 0.0, 1.0) };
+    (rotation * glm::quat_conjugate(&twist), twist)
+}
+
+// Signed angle of a twist quaternion around `axis`, in [-pi, pi]
+pub fn twist_angle(twist: &glm::Quat, axis: &glm::Vec3) -> f32 {
+    let vector = glm::vec3(twist.coords.x, twist.coords.y, twist.coords.z);
+    let angle = 2.0 * glm::dot(&vector, axis).atan2(twist.coords.w);
+    if angle > glm::pi::<f32>() {
+        angle - 2.0 * glm::pi::<f32>()
+    } else if angle < -glm::pi::<f32>() {
+        angle + 2.0 * glm::pi::<f32>()
+    } else {
+        angle
+    }
+}
+
+// What an iterative solver did: iterations run and the final distance between
+// effectors and targets
+#[derive(Clone, Copy, Debug)]
+pub struct IkResult {
+    iterations: usize,
+    residual: f32,
+    converged: bool,
+}
+
+impl IkResult {
+    pub fn new(iterations: usize, residual: f32, converged: bool) -> IkResult {
+        IkResult {
+            iterations,
+            residual,
+            converged,
+        }
+    }
+
+    pub fn get_iterations(&self) -> usize {
+        self.iterations
+    }
+
+    pub fn get_residual(&self) -> f32 {
+        self.residual
+    }
+
+    pub fn is_converged(&self) -> bool {
+        self.converged
+    }
+}
+
+// Closed-form solver for a root-middle-end chain like shoulder-elbow-wrist or
+// hip-knee-ankle. The middle joint bends towards the pole when one is set,
+// otherwise it keeps bending the way it already does.
+#[derive(Clone, Debug)]
+pub struct TwoBoneIk {
+    root: usize,
+    middle: usize,
+    end: usize,
+    pole: Option<glm::Vec3>,
+    softness: f32,
+    weight: f32,
+}
+
+impl TwoBoneIk {
+    pub fn new(root: usize, middle: usize, end: usize) -> TwoBoneIk {
+        TwoBoneIk {
+            root,
+            middle,
+            end,
+            pole: None,
+            softness: 0.0,
+            weight: 1.0,
+        }
+    }
+
+    // Chain made of `end`, its parent and its grandparent
+    pub fn from_end(skeleton: &Skeleton, end: usize) -> Option<TwoBoneIk> {
+        let middle = skeleton.get_joint(end).get_parent()?;
+        let root = skeleton.get_joint(middle).get_parent()?;
+        Some(Self::new(root, middle, end))
+    }
+
+    pub fn get_joints(&self) -> [usize; 3] {
+        [self.root, self.middle, self.end]
+    }
+
+    pub fn get_pole(&self) -> Option<glm::Vec3> {
+        self.pole
+    }
+
+    // World space point the middle joint should point towards
+    pub fn set_pole(&mut self, pole: Option<glm::Vec3>) {
+        self.pole = pole;
+    }
+
+    pub fn get_softness(&self) -> f32 {
+        self.softness
+    }
+
+    // Fraction of the chain length over which reaching slows down before full
+    // extension, so targets moving out of reach don't snap the limb straight
+    pub fn set_softness(&mut self, softness: f32) {
+        self.softness = glm::clamp_scalar(softness, 0.0, 1.0);
+    }
+
+    pub fn get_weight(&self) -> f32 {
+        self.weight
+    }
+
+    pub fn set_weight(&mut self, weight: f32) {
+        self.weight = glm::clamp_scalar(weight, 0.0, 1.0);
+    }
+
+    // Distance the end joint is kept from the root for a target at `distance`
+    fn soft_distance(&self, distance: f32, chain_length: f32) -> f32 {
+        let soft = self.softness * chain_length;
+        let hard = chain_length - soft;
+        if soft <= 0.0 || distance <= hard {
+            return distance.min(chain_length);
+        }
+        hard + soft * (1.0 - (-(distance - hard) / soft).exp())
+    }
+
+    // Writes new rotations for the root and middle joints into `pose`. Returns
+    // how far the end joint ended up from the target.
+    pub fn solve(&self, skeleton: &Skeleton, pose: &mut Pose, target: glm::Vec3) -> f32 {
+        let original = pose.clone();
+        let world_mats = pose.world_mats(skeleton);
+        let a = world_position(&world_mats[self.root]);
+        let b = world_position(&world_mats[self.middle]);
+        let c = world_position(&world_mats[self.end]);
+        let upper = glm::length(&(b - a));
+        let lower = glm::length(&(c - b));
+        let chain_length = upper + lower;
+        if upper <= 1e-6 || lower <= 1e-6 {
+            return glm::length(&(c - target));
+        }
+
+        let to_target = target - a;
+        let eps = 1e-4 * chain_length;
+        let distance = self.soft_distance(glm::length(&to_target), chain_length);
+        let distance = glm::clamp_scalar(distance, (upper - lower).abs() + eps, chain_length - eps);
+
+        // Law of cosines for the interior angles at the root and the middle joint
+        let angle = |x: &glm::Vec3, y: &glm::Vec3| glm::clamp_scalar(glm::dot(&glm::normalize(x), &glm::normalize(y)), -1.0, 1.0).acos();
+        let root_angle = angle(&(c - a), &(b - a));
+        let middle_angle = angle(&(a - b), &(c - b));
+        let cos_root = (lower * lower - upper * upper - distance * distance) / (-2.0 * upper * distance);
+        let cos_middle = (distance * distance - upper * upper - lower * lower) / (-2.0 * upper * lower);
+        let desired_root = glm::clamp_scalar(cos_root, -1.0, 1.0).acos();
+        let desired_middle = glm::clamp_scalar(cos_middle, -1.0, 1.0).acos();
+
+        // Bend in the current plane of the limb. A straight limb has no plane,
+        // so it bends towards the pole or failing that around any axis.
+        let mut axis = (c - a).cross(&(b - a));
+        if glm::length(&axis) <= 1e-6 * upper * lower {
+            let fallbacks = [self.pole.map(|pole| pole - a), Some(glm::vec3(0.0, 0.0, 1.0)), Some(glm::vec3(1.0, 0.0, 0.0))];
+            for bend in fallbacks.iter().flatten() {
+                axis = (c - a).cross(bend);
+                if glm::length(&axis) > 1e-6 {
+                    break;
+                }
+            }
+        }
+        let axis = glm::normalize(&axis);
+        rotate_world(pose, skeleton, self.middle, &glm::quat_angle_axis(desired_middle - middle_angle, &axis));
+        rotate_world(pose, skeleton, self.root, &glm::quat_angle_axis(desired_root - root_angle, &axis));
+
+        // Swing the whole limb onto the target direction
+        let world_mats = pose.world_mats(skeleton);
+        let c = world_position(&world_mats[self.end]);
+        rotate_world(pose, skeleton, self.root, &rotation_between(&(c - a), &to_target));
+
+        // Twist around the root-target line so the middle joint faces the pole
+        if let Some(pole) = self.pole {
+            let world_mats = pose.world_mats(skeleton);
+            let b = world_position(&world_mats[self.middle]);
+            let direction = glm::normalize(&to_target);
+            let project = |v: glm::Vec3| v - direction * glm::dot(&v, &direction);
+            let current = project(b - a);
+            let wanted = project(pole - a);
+            if glm::length(&current) > 1e-6 && glm::length(&wanted) > 1e-6 {
+                rotate_world(pose, skeleton, self.root, &rotation_between(&current, &wanted));
+            }
+        }
+
+        if self.weight < 1.0 {
+            for &joint in &[self.root, self.middle] {
+                let blended = JointTransform::lerp(original.get_transform(joint), pose.get_transform(joint), self.weight);
+                pose.get_transform_mut(joint).set_rotation(blended.get_rotation());
+            }
+        }
+        let world_mats = pose.world_mats(skeleton);
+        glm::length(&(world_position(&world_mats[self.end]) - target))
+    }
+
+    // Solves from the skeleton's current pose and writes the result back into it
+    pub fn solve_skeleton(&self, skeleton: &mut Skeleton, target: glm::Vec3) -> f32 {
+        let mut pose = Pose::from_skeleton(skeleton);
+        let residual = self.solve(skeleton, &mut pose, target);
+        pose.apply(skeleton);
+        skeleton.update();
+        residual
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ik_chain::*;
+
+    // Straight arm of three unit bones along x, the skeleton turned a quarter
+    // around y and moved off the origin
+    fn yawed_arm() -> Skeleton {
+        let mut skeleton = Skeleton::new();
+        let mut parent = None;
+        for (index, name) in ["shoulder", "elbow", "wrist", "hand"].iter().enumerate() {
+            let mut joint = Joint::new(name, parent);
+            if index > 0 {
+                joint.set_offset(glm::vec3(1.0, 0.0, 0.0));
+            }
+            parent = Some(skeleton.add_joint(joint));
+        }
+        let transform = glm::translation(&glm::vec3(2.0, 1.0, -3.0)) * glm::rotation(glm::half_pi(), &glm::vec3(0.0, 1.0, 0.0));
+        skeleton.set_transform(transform);
+        skeleton.update();
+        skeleton
+    }
+
+    #[test]
+    fn rotate_world_follows_skeleton_transform() {
+        let skeleton = yawed_arm();
+        let mut pose = Pose::from_skeleton(&skeleton);
+        let before = world_rotation(&pose, &skeleton, 1);
+        let delta = glm::quat_angle_axis(0.5, &glm::normalize(&glm::vec3(1.0, 2.0, 0.5)));
+        rotate_world(&mut pose, &skeleton, 0, &delta);
+        let after = world_rotation(&pose, &skeleton, 1);
+        assert!(glm::quat_dot(&after, &(delta * before)).abs() > 1.0 - 1e-6);
+
+        // World rotations agree with the world matrices
+        let world_mats = pose.world_mats(&skeleton);
+        let direction = world_position(&world_mats[2]) - world_position(&world_mats[1]);
+        let expected = glm::quat_rotate_vec3(&after, &glm::vec3(1.0, 0.0, 0.0));
+        assert!(glm::length(&(direction - expected)) < 1e-5);
+    }
+
+    #[test]
+    fn solvers_reach_with_yawed_skeleton() {
+        let skeleton = yawed_arm();
+        let root = world_position(&skeleton.get_world_mat(0));
+        let target = root + glm::vec3(0.5, 1.2, 0.8);
+
+        let mut pose = Pose::from_skeleton(&skeleton);
+        let residual = TwoBoneIk::new(0, 1, 2).solve(&skeleton, &mut pose, target);
+        assert!(residual < 1e-3, "two bone residual {}", residual);
+
+        for &solver in &[IkSolver::Ccd, IkSolver::Fabrik] {
+            let mut chain = IkChain::new(&skeleton, 0, 3).unwrap();
+            chain.set_solver(solver);
+            chain.set_max_iterations(100);
+            chain.add_target(3, root + glm::vec3(1.0, 1.5, 1.0));
+            let mut pose = Pose::from_skeleton(&skeleton);
+            let result = chain.solve(&skeleton, &mut pose);
+            assert!(result.is_converged(), "{:?} residual {}", solver, result.get_residual());
+        }
+    }
+
+    #[test]
+    fn two_bone_bends_towards_pole() {
+        let skeleton = yawed_arm();
+        let root = world_position(&skeleton.get_world_mat(0));
+        let target = root + glm::vec3(0.0, 0.0, -1.5);
+        for &side in &[1.0, -1.0] {
+            let mut limb = TwoBoneIk::new(0, 1, 2);
+            let pole = root + glm::vec3(0.0, side, -0.75);
+            limb.set_pole(Some(pole));
+            let mut pose = Pose::from_skeleton(&skeleton);
+            assert!(limb.solve(&skeleton, &mut pose, target) < 1e-3);
+            let elbow = world_position(&pose.world_mats(&skeleton)[1]);
+            assert!((elbow.y - root.y) * side > 0.5, "elbow {:?} for pole {:?}", elbow, pole);
+        }
+    }
+
+    #[test]
+    fn two_bone_softness_eases_into_full_reach() {
+        let skeleton = yawed_arm();
+        let root = world_position(&skeleton.get_world_mat(0));
+        let reach = |softness: f32, distance: f32| {
+            let mut limb = TwoBoneIk::new(0, 1, 2);
+            limb.set_softness(softness);
+            let mut pose = Pose::from_skeleton(&skeleton);
+            limb.solve(&skeleton, &mut pose, root + glm::vec3(0.0, 0.0, -distance));
+            glm::length(&(world_position(&pose.world_mats(&skeleton)[2]) - root))
+        };
+        // Hard limbs track the target until they lock straight
+        assert!((reach(0.0, 1.9) - 1.9).abs() < 1e-3);
+        // Soft limbs fall behind near full length and never quite straighten
+        let mut last = 0.0;
+        for &distance in &[1.7, 1.9, 2.0, 2.5] {
+            let soft = reach(0.2, distance);
+            assert!(soft < distance.min(2.0) - 1e-3 && soft > last, "{} at {}", soft, distance);
+            last = soft;
+        }
+        assert!((reach(0.2, 1.5) - 1.5).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,408 @@
+use crate::ik::*;
+use crate::pose::*;
+use crate::skeleton::*;
+
+// Limits on a joint's local rotation, measured from its orientation when the
+// chain was made. Axes are in the joint's rest frame and angles are in radians.
+#[derive(Clone, Copy, Debug)]
+pub enum JointLimit {
+    // Only rotation around `axis`, like a knee or elbow
+    Hinge { axis: glm::Vec3, min: f32, max: f32 },
+    // `axis` may swing at most `angle` away from where it points at rest
+    Cone { axis: glm::Vec3, angle: f32 },
+    // Rotation around `axis`, leaving any swing alone
+    Twist { axis: glm::Vec3, min: f32, max: f32 },
+}
+
+impl JointLimit {
+    pub fn apply(&self, rotation: &glm::Quat) -> glm::Quat {
+        match *self {
+            JointLimit::Hinge { axis, min, max } => {
+                let axis = glm::normalize(&axis);
+                let (_, twist) = swing_twist(rotation, &axis);
+                glm::quat_angle_axis(glm::clamp_scalar(twist_angle(&twist, &axis), min, max), &axis)
+            },
+            JointLimit::Cone { axis, angle } => {
+                let axis = glm::normalize(&axis);
+                let (swing, twist) = swing_twist(rotation, &axis);
+                let swung = glm::quat_rotate_vec3(&swing, &axis);
+                let current = glm::clamp_scalar(glm::dot(&axis, &swung), -1.0, 1.0).acos();
+                if current <= angle {
+                    return *rotation;
+                }
+                let swing_axis = axis.cross(&swung);
+                let swing = if glm::length(&swing_axis) > 1e-6 {
+                    glm::quat_angle_axis(angle, &glm::normalize(&swing_axis))
+                } else {
+                    glm::quat(0.0, 0.0, 0.0, 1.0)
+                };
+                glm::quat_normalize(&(swing * twist))
+            },
+            JointLimit::Twist { axis, min, max } => {
+                let axis = glm::normalize(&axis);
+                let (swing, twist) = swing_twist(rotation, &axis);
+                let twist = glm::quat_angle_axis(glm::clamp_scalar(twist_angle(&twist, &axis), min, max), &axis);
+                glm::quat_normalize(&(swing * twist))
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IkSolver {
+    Ccd,
+    Fabrik,
+}
+
+// World space position a joint of the chain should reach. Weights below one
+// only pull the joint part of the way.
+#[derive(Clone, Copy, Debug)]
+pub struct IkTarget {
+    joint: usize,
+    position: glm::Vec3,
+    weight: f32,
+}
+
+impl IkTarget {
+    pub fn get_joint(&self) -> usize {
+        self.joint
+    }
+
+    pub fn get_position(&self) -> glm::Vec3 {
+        self.position
+    }
+
+    pub fn get_weight(&self) -> f32 {
+        self.weight
+    }
+}
+
+// Any length parent-child chain for spines, tails and tentacles. Every joint
+// of the chain can carry a target, the tip usually does.
+#[derive(Clone, Debug)]
+pub struct IkChain {
+    joints: Vec<usize>,
+    // Local rotation of each joint when the chain was made, what limits are measured from
+    rest: Vec<glm::Quat>,
+    limits: Vec<Vec<JointLimit>>,
+    targets: Vec<IkTarget>,
+    solver: IkSolver,
+    max_iterations: usize,
+    tolerance: f32,
+}
+
+impl IkChain {
+    // Joints from `root` down to `tip`, None if `root` isn't above `tip`
+    pub fn new(skeleton: &Skeleton, root: usize, tip: usize) -> Option<IkChain> {
+        let mut joints = vec![tip];
+        let mut joint = tip;
+        while joint != root {
+            joint = skeleton.get_joint(joint).get_parent()?;
+            joints.push(joint);
+        }
+        if joints.len() < 2 {
+            return None;
+        }
+        joints.reverse();
+        Some(IkChain {
+            rest: joints.iter().map(|&joint| skeleton.get_joint(joint).get_rotation()).collect(),
+            limits: vec![Vec::new(); joints.len()],
+            joints,
+            targets: Vec::new(),
+            solver: IkSolver::Fabrik,
+            max_iterations: 20,
+            tolerance: 1e-3,
+        })
+    }
+
+    pub fn get_joints(&self) -> &[usize] {
+        &self.joints
+    }
+
+    pub fn get_solver(&self) -> IkSolver {
+        self.solver
+    }
+
+    pub fn set_solver(&mut self, solver: IkSolver) {
+        self.solver = solver;
+    }
+
+    pub fn get_max_iterations(&self) -> usize {
+        self.max_iterations
+    }
+
+    pub fn set_max_iterations(&mut self, max_iterations: usize) {
+        self.max_iterations = max_iterations.max(1);
+    }
+
+    pub fn get_tolerance(&self) -> f32 {
+        self.tolerance
+    }
+
+    // Solving stops once every target is closer than this
+    pub fn set_tolerance(&mut self, tolerance: f32) {
+        self.tolerance = tolerance.max(0.0);
+    }
+
+    fn find(&self, joint: usize) -> Option<usize> {
+        self.joints.iter().position(|&chain_joint| chain_joint == joint)
+    }
+
+    pub fn get_limits(&self, joint: usize) -> &[JointLimit] {
+        match self.find(joint) {
+            Some(index) => &self.limits[index],
+            None => &[],
+        }
+    }
+
+    // Limits of a joint are applied in the order they were added. Returns
+    // false if the joint isn't part of the chain.
+    pub fn add_limit(&mut self, joint: usize, limit: JointLimit) -> bool {
+        match self.find(joint) {
+            Some(index) => {
+                self.limits[index].push(limit);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn get_targets(&self) -> &[IkTarget] {
+        &self.targets
+    }
+
+    // The chain root can't move, so it can't take a target
+    pub fn add_target(&mut self, joint: usize, position: glm::Vec3) -> Option<usize> {
+        if self.find(joint)? == 0 {
+            return None;
+        }
+        self.targets.push(IkTarget {
+            joint,
+            position,
+            weight: 1.0,
+        });
+        Some(self.targets.len() - 1)
+    }
+
+    pub fn set_target_position(&mut self, target: usize, position: glm::Vec3) {
+        self.targets[target].position = position;
+    }
+
+    pub fn set_target_weight(&mut self, target: usize, weight: f32) {
+        self.targets[target].weight = glm::clamp_scalar(weight, 0.0, 1.0);
+    }
+
+    fn apply_limits(&self, pose: &mut Pose, index: usize) {
+        if self.limits[index].is_empty() {
+            return;
+        }
+        let rest = self.rest[index];
+        let transform = pose.get_transform_mut(self.joints[index]);
+        let relative = glm::quat_inverse(&rest) * transform.get_rotation();
+        let relative = self.limits[index].iter().fold(relative, |rotation, limit| limit.apply(&rotation));
+        transform.set_rotation(glm::quat_normalize(&(rest * relative)));
+    }
+
+    // Largest distance between a weighted target and its joint
+    fn residual(&self, skeleton: &Skeleton, pose: &Pose) -> f32 {
+        let world_mats = pose.world_mats(skeleton);
+        self.targets.iter()
+            .filter(|target| target.weight > 0.0)
+            .map(|target| glm::length(&(world_position(&world_mats[target.joint]) - target.position)))
+            .fold(0.0, f32::max)
+    }
+
+    // Rotates the chain joints in `pose` towards the targets
+    pub fn solve(&self, skeleton: &Skeleton, pose: &mut Pose) -> IkResult {
+        let mut residual = self.residual(skeleton, pose);
+        if self.targets.is_empty() {
+            return IkResult::new(0, residual, true);
+        }
+        let mut iterations = 0;
+        while iterations < self.max_iterations && residual > self.tolerance {
+            match self.solver {
+                IkSolver::Ccd => self.iterate_ccd(skeleton, pose),
+                IkSolver::Fabrik => self.iterate_fabrik(skeleton, pose),
+            }
+            iterations += 1;
+            residual = self.residual(skeleton, pose);
+        }
+        IkResult::new(iterations, residual, residual <= self.tolerance)
+    }
+
+    // Solves from the skeleton's current pose and writes the result back into it
+    pub fn solve_skeleton(&self, skeleton: &mut Skeleton) -> IkResult {
+        let mut pose = Pose::from_skeleton(skeleton);
+        let result = self.solve(skeleton, &mut pose);
+        pose.apply(skeleton);
+        skeleton.update();
+        result
+    }
+
+    // Cyclic coordinate descent: from the tip towards the root, turn each
+    // joint so the targets below it line up, averaging when there are several
+    fn iterate_ccd(&self, skeleton: &Skeleton, pose: &mut Pose) {
+        for index in (0..self.joints.len() - 1).rev() {
+            let world_mats = pose.world_mats(skeleton);
+            let pivot = world_position(&world_mats[self.joints[index]]);
+            let mut rotation = glm::quat(0.0, 0.0, 0.0, 0.0);
+            let mut total = 0.0;
+            for target in &self.targets {
+                if self.find(target.joint).is_none_or(|target_index| target_index <= index) {
+                    continue;
+                }
+                let effector = world_position(&world_mats[target.joint]);
+                rotation += rotation_between(&(effector - pivot), &(target.position - pivot)) * target.weight;
+                total += target.weight;
+            }
+            if total <= 0.0 {
+                continue;
+            }
+            // Partial weights leave some of the identity in
+            if total < 1.0 {
+                rotation += glm::quat(0.0, 0.0, 0.0, 1.0) * (1.0 - total);
+            }
+            let length = glm::quat_length(&rotation);
+            if length <= 1e-6 {
+                continue;
+            }
+            rotate_world(pose, skeleton, self.joints[index], &(rotation / length));
+            self.apply_limits(pose, index);
+        }
+    }
+
+    // Forward and backward reaching: move the joint positions onto the targets
+    // and back onto the root keeping bone lengths, then turn the positions
+    // into rotations so limits and joints past the last target follow along
+    fn iterate_fabrik(&self, skeleton: &Skeleton, pose: &mut Pose) {
+        let world_mats = pose.world_mats(skeleton);
+        let mut positions: Vec<glm::Vec3> = self.joints.iter().map(|&joint| world_position(&world_mats[joint])).collect();
+        let lengths: Vec<f32> = positions.windows(2).map(|pair| glm::length(&(pair[1] - pair[0]))).collect();
+        let root = positions[0];
+        let mut pulls: Vec<Option<(glm::Vec3, f32)>> = vec![None; self.joints.len()];
+        for target in &self.targets {
+            if let Some(index) = self.find(target.joint) {
+                pulls[index] = Some((target.position, target.weight));
+            }
+        }
+        let last = match pulls.iter().rposition(|pull| pull.is_some()) {
+            Some(last) => last,
+            None => return,
+        };
+
+        let reach = |from: glm::Vec3, towards: glm::Vec3, length: f32| {
+            let direction = towards - from;
+            if glm::length(&direction) <= 1e-6 { towards } else { from + glm::normalize(&direction) * length }
+        };
+        for index in (1..=last).rev() {
+            if index < last {
+                positions[index] = reach(positions[index + 1], positions[index], lengths[index]);
+            }
+            if let Some((position, weight)) = pulls[index] {
+                positions[index] = glm::lerp(&positions[index], &position, weight);
+            }
+        }
+        positions[0] = root;
+        for index in 0..last {
+            positions[index + 1] = reach(positions[index], positions[index + 1], lengths[index]);
+        }
+
+        for index in 0..last {
+            let world_mats = pose.world_mats(skeleton);
+            let from = world_position(&world_mats[self.joints[index]]);
+            let to = world_position(&world_mats[self.joints[index + 1]]);
+            let wanted = positions[index + 1] - from;
+            rotate_world(pose, skeleton, self.joints[index], &rotation_between(&(to - from), &wanted));
+            self.apply_limits(pose, index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Three unit bones along x with the middle joint bent 30 degrees around z at rest
+    fn bent_arm() -> Skeleton {
+        let mut skeleton = Skeleton::new();
+        let mut parent = None;
+        for (index, name) in ["shoulder", "elbow", "wrist", "hand"].iter().enumerate() {
+            let mut joint = Joint::new(name, parent);
+            if index > 0 {
+                joint.set_offset(glm::vec3(1.0, 0.0, 0.0));
+            }
+            parent = Some(skeleton.add_joint(joint));
+        }
+        skeleton.get_joint_mut(1).set_pose(glm::vec3(0.0, 0.0, 30.0f32.to_radians()));
+        skeleton.update();
+        skeleton
+    }
+
+    // Angle of `joint` around z away from its rest rotation
+    fn bend_from_rest(skeleton: &Skeleton, pose: &Pose, joint: usize) -> f32 {
+        let relative = glm::quat_inverse(&skeleton.get_joint(joint).get_rotation()) * pose.get_transform(joint).get_rotation();
+        let axis = glm::vec3(0.0, 0.0, 1.0);
+        twist_angle(&swing_twist(&relative, &axis).1, &axis)
+    }
+
+    #[test]
+    fn limits_are_measured_from_rest() {
+        let skeleton = bent_arm();
+        let limit = 10.0f32.to_radians();
+        for &solver in &[IkSolver::Ccd, IkSolver::Fabrik] {
+            let mut chain = IkChain::new(&skeleton, 0, 3).unwrap();
+            chain.set_solver(solver);
+            chain.add_limit(1, JointLimit::Hinge { axis: glm::vec3(0.0, 0.0, 1.0), min: -limit, max: limit });
+            // Folding the arm up hard stops the elbow at its limit past the rest bend
+            chain.add_target(3, glm::vec3(0.2, 1.5, 0.0));
+            let mut pose = Pose::from_skeleton(&skeleton);
+            chain.solve(&skeleton, &mut pose);
+            let bend = bend_from_rest(&skeleton, &pose, 1);
+            assert!(bend.abs() <= limit + 1e-3, "{:?} bent the elbow {} degrees", solver, bend.to_degrees());
+            let swing = glm::quat_rotate_vec3(&(glm::quat_inverse(&skeleton.get_joint(1).get_rotation()) * pose.get_transform(1).get_rotation()), &glm::vec3(0.0, 0.0, 1.0));
+            assert!(glm::length(&(swing - glm::vec3(0.0, 0.0, 1.0))) < 1e-3, "{:?} left the hinge plane", solver);
+        }
+    }
+
+    fn joint_position(skeleton: &Skeleton, pose: &Pose, joint: usize) -> glm::Vec3 {
+        world_position(&pose.world_mats(skeleton)[joint])
+    }
+
+    #[test]
+    fn two_targets_pull_one_chain() {
+        let skeleton = bent_arm();
+        // Both reachable at once with the elbow at (1, 0, 0) or (0, 1, 0)
+        let wrist = glm::vec3(1.0, 1.0, 0.0);
+        let hand = glm::vec3(1.0, 2.0, 0.0);
+        for &solver in &[IkSolver::Ccd, IkSolver::Fabrik] {
+            let mut chain = IkChain::new(&skeleton, 0, 3).unwrap();
+            chain.set_solver(solver);
+            chain.set_max_iterations(200);
+            chain.set_tolerance(1e-3);
+            chain.add_target(2, wrist);
+            chain.add_target(3, hand);
+            let mut pose = Pose::from_skeleton(&skeleton);
+            let result = chain.solve(&skeleton, &mut pose);
+            assert!(result.is_converged(), "{:?} stopped {} away", solver, result.get_residual());
+            assert!(glm::length(&(joint_position(&skeleton, &pose, 2) - wrist)) <= 1e-3, "{:?}", solver);
+            assert!(glm::length(&(joint_position(&skeleton, &pose, 3) - hand)) <= 1e-3, "{:?}", solver);
+
+            // A target with no weight has no say, the result is the same as without it
+            let mut hand_only = IkChain::new(&skeleton, 0, 3).unwrap();
+            hand_only.set_solver(solver);
+            hand_only.add_target(3, hand);
+            let mut expected = Pose::from_skeleton(&skeleton);
+            hand_only.solve(&skeleton, &mut expected);
+            let ignored = chain.add_target(1, glm::vec3(-5.0, 0.0, 0.0)).unwrap();
+            chain.set_target_weight(ignored, 0.0);
+            chain.set_target_weight(0, 0.0);
+            chain.set_max_iterations(hand_only.get_max_iterations());
+            let mut pose = Pose::from_skeleton(&skeleton);
+            chain.solve(&skeleton, &mut pose);
+            for joint in 0..4 {
+                let distance = glm::length(&(joint_position(&skeleton, &pose, joint) - joint_position(&skeleton, &expected, joint)));
+                assert!(distance < 1e-5, "{:?} moved joint {} by {}", solver, joint, distance);
+            }
+        }
+    }
+}
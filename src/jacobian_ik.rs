@@ -0,0 +1,413 @@
+use crate::ik::*;
+use crate::skeleton::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JacobianMethod {
+    Transpose,
+    PseudoInverse,
+    DampedLeastSquares,
+}
+
+// World space position a joint should reach. Weights scale its rows of the
+// Jacobian, so heavier effectors win when not all can be reached.
+#[derive(Clone, Copy, Debug)]
+pub struct Effector {
+    joint: usize,
+    target: glm::Vec3,
+    weight: f32,
+}
+
+impl Effector {
+    pub fn get_joint(&self) -> usize {
+        self.joint
+    }
+
+    pub fn get_target(&self) -> glm::Vec3 {
+        self.target
+    }
+
+    pub fn get_weight(&self) -> f32 {
+        self.weight
+    }
+}
+
+// Secondary goal pulling one DOF towards a preferred angle. It only acts in
+// the null space of the effectors so it never pulls them off their targets.
+#[derive(Clone, Copy, Debug)]
+struct RestGoal {
+    dof: usize,
+    value: f32,
+    gain: f32,
+}
+
+// Solves over the Euler angle DOFs of any set of joints, straight on a
+// Skeleton so it runs without models or a GL context
+#[derive(Clone, Debug)]
+pub struct JacobianIk {
+    method: JacobianMethod,
+    dofs: Vec<(usize, usize)>,
+    effectors: Vec<Effector>,
+    rest_goals: Vec<RestGoal>,
+    damping: f32,
+    max_iterations: usize,
+    tolerance: f32,
+    max_step: f32,
+}
+
+impl JacobianIk {
+    pub fn new(method: JacobianMethod) -> JacobianIk {
+        JacobianIk {
+            method,
+            dofs: Vec::new(),
+            effectors: Vec::new(),
+            rest_goals: Vec::new(),
+            damping: 0.1,
+            max_iterations: 100,
+            tolerance: 1e-3,
+            max_step: f32::INFINITY,
+        }
+    }
+
+    pub fn get_method(&self) -> JacobianMethod {
+        self.method
+    }
+
+    pub fn set_method(&mut self, method: JacobianMethod) {
+        self.method = method;
+    }
+
+    pub fn get_damping(&self) -> f32 {
+        self.damping
+    }
+
+    // Lambda of damped least squares, higher is steadier near singularities
+    // but slower to converge
+    pub fn set_damping(&mut self, damping: f32) {
+        self.damping = damping.max(0.0);
+    }
+
+    pub fn get_max_iterations(&self) -> usize {
+        self.max_iterations
+    }
+
+    pub fn set_max_iterations(&mut self, max_iterations: usize) {
+        self.max_iterations = max_iterations.max(1);
+    }
+
+    pub fn get_tolerance(&self) -> f32 {
+        self.tolerance
+    }
+
+    pub fn set_tolerance(&mut self, tolerance: f32) {
+        self.tolerance = tolerance.max(0.0);
+    }
+
+    pub fn get_max_step(&self) -> f32 {
+        self.max_step
+    }
+
+    // Longest error vector per effector used in one iteration. Keeps far
+    // away targets inside the range where the linearization holds.
+    pub fn set_max_step(&mut self, max_step: f32) {
+        self.max_step = max_step.max(0.0);
+    }
+
+    // (joint, axis) pairs, one column of the Jacobian each
+    pub fn get_dofs(&self) -> &[(usize, usize)] {
+        &self.dofs
+    }
+
+    // Locked DOFs (min >= max) are left out. Returns whether the DOF was added.
+    pub fn add_dof(&mut self, skeleton: &Skeleton, joint: usize, axis: usize) -> bool {
+        let dof = skeleton.get_joint(joint).get_dof(axis);
+        if dof.get_min() >= dof.get_max() || self.find_dof(joint, axis).is_some() {
+            return false;
+        }
+        self.dofs.push((joint, axis));
+        true
+    }
+
+    pub fn add_joint(&mut self, skeleton: &Skeleton, joint: usize) {
+        for axis in 0..3 {
+            self.add_dof(skeleton, joint, axis);
+        }
+    }
+
+    // Every joint from `root` down to the parent of `tip`. Returns false if
+    // `root` isn't above `tip`.
+    pub fn add_chain(&mut self, skeleton: &Skeleton, root: usize, tip: usize) -> bool {
+        let mut joints = Vec::new();
+        let mut joint = tip;
+        while joint != root {
+            joint = match skeleton.get_joint(joint).get_parent() {
+                Some(parent) => parent,
+                None => return false,
+            };
+            joints.push(joint);
+        }
+        for &joint in joints.iter().rev() {
+            self.add_joint(skeleton, joint);
+        }
+        true
+    }
+
+    fn find_dof(&self, joint: usize, axis: usize) -> Option<usize> {
+        self.dofs.iter().position(|&dof| dof == (joint, axis))
+    }
+
+    pub fn get_effectors(&self) -> &[Effector] {
+        &self.effectors
+    }
+
+    pub fn add_effector(&mut self, joint: usize, target: glm::Vec3) -> usize {
+        self.effectors.push(Effector {
+            joint,
+            target,
+            weight: 1.0,
+        });
+        self.effectors.len() - 1
+    }
+
+    pub fn set_effector_target(&mut self, effector: usize, target: glm::Vec3) {
+        self.effectors[effector].target = target;
+    }
+
+    pub fn set_effector_weight(&mut self, effector: usize, weight: f32) {
+        self.effectors[effector].weight = weight.max(0.0);
+    }
+
+    // Pulls a DOF towards `value` with strength `gain` per iteration. Returns
+    // false if the DOF isn't being solved for.
+    pub fn add_rest_goal(&mut self, joint: usize, axis: usize, value: f32, gain: f32) -> bool {
+        match self.find_dof(joint, axis) {
+            Some(dof) => {
+                self.rest_goals.push(RestGoal {
+                    dof,
+                    value,
+                    gain,
+                });
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Largest distance between a weighted effector and its target
+    fn residual(&self, skeleton: &Skeleton) -> f32 {
+        self.effectors.iter()
+            .filter(|effector| effector.weight > 0.0)
+            .map(|effector| glm::length(&(world_position(&skeleton.get_world_mat(effector.joint)) - effector.target)))
+            .fold(0.0, f32::max)
+    }
+
+    // World axis a DOF rotates around, taken from the frame just before its
+    // rotation is applied in the joint's rotation order
+    fn world_axis(skeleton: &Skeleton, joint: usize, axis: usize) -> glm::Vec3 {
+        let joint = skeleton.get_joint(joint);
+        let mut frame = match joint.get_parent() {
+            Some(parent) => skeleton.get_world_mat(parent),
+            None => skeleton.get_transform(),
+        } * glm::translation(&joint.get_offset());
+        for &order_axis in &joint.get_rotation_order() {
+            if order_axis == axis {
+                break;
+            }
+            let mut unit = glm::vec3(0.0, 0.0, 0.0);
+            unit[order_axis] = 1.0;
+            frame = glm::rotate(&frame, joint.get_dof(order_axis).get_value(), &unit);
+        }
+        glm::normalize(&glm::vec3(frame[(0, axis)], frame[(1, axis)], frame[(2, axis)]))
+    }
+
+    fn is_below(skeleton: &Skeleton, joint: usize, ancestor: usize) -> bool {
+        let mut current = Some(joint);
+        while let Some(index) = current {
+            if index == ancestor {
+                return true;
+            }
+            current = skeleton.get_joint(index).get_parent();
+        }
+        false
+    }
+
+    // Weighted 3 rows per effector by one column per DOF, and the matching error
+    fn jacobian(&self, skeleton: &Skeleton) -> (na::DMatrix<f32>, na::DVector<f32>) {
+        let rows = 3 * self.effectors.len();
+        let mut jacobian = na::DMatrix::zeros(rows, self.dofs.len());
+        let mut error = na::DVector::zeros(rows);
+        for (row, effector) in self.effectors.iter().enumerate() {
+            let scale = effector.weight.sqrt();
+            let position = world_position(&skeleton.get_world_mat(effector.joint));
+            let mut delta = effector.target - position;
+            let length = glm::length(&delta);
+            if length > self.max_step {
+                delta *= self.max_step / length;
+            }
+            for axis in 0..3 {
+                error[3 * row + axis] = delta[axis] * scale;
+            }
+            for (column, &(joint, axis)) in self.dofs.iter().enumerate() {
+                if !Self::is_below(skeleton, effector.joint, joint) {
+                    continue;
+                }
+                let pivot = world_position(&skeleton.get_world_mat(joint));
+                let derivative = Self::world_axis(skeleton, joint, axis).cross(&(position - pivot));
+                for component in 0..3 {
+                    jacobian[(3 * row + component, column)] = derivative[component] * scale;
+                }
+            }
+        }
+        (jacobian, error)
+    }
+
+    fn step(&self, skeleton: &Skeleton) -> na::DVector<f32> {
+        let (jacobian, error) = self.jacobian(skeleton);
+        let transpose = jacobian.transpose();
+        let mut delta = match self.method {
+            // Step length that best reduces the error along J^T e
+            JacobianMethod::Transpose => {
+                let gradient = &transpose * &error;
+                let change = &jacobian * &gradient;
+                let length = change.dot(&change);
+                let alpha = if length > 1e-12 { error.dot(&change) / length } else { 0.0 };
+                gradient * alpha
+            },
+            JacobianMethod::PseudoInverse => jacobian.clone().pseudo_inverse(1e-6) * &error,
+            // J^T (J J^T + lambda^2 I)^-1 e
+            JacobianMethod::DampedLeastSquares => {
+                let rows = jacobian.nrows();
+                let damped = &jacobian * &transpose + na::DMatrix::identity(rows, rows) * (self.damping * self.damping);
+                match damped.lu().solve(&error) {
+                    Some(solved) => &transpose * solved,
+                    None => na::DVector::zeros(self.dofs.len()),
+                }
+            },
+        };
+
+        // Project the rest goals through (I - J+ J) so effectors don't feel them
+        if !self.rest_goals.is_empty() {
+            let mut goal = na::DVector::zeros(self.dofs.len());
+            for rest in &self.rest_goals {
+                let (joint, axis) = self.dofs[rest.dof];
+                goal[rest.dof] += rest.gain * (rest.value - skeleton.get_joint(joint).get_dof(axis).get_value());
+            }
+            let columns = self.dofs.len();
+            let projector = na::DMatrix::identity(columns, columns) - jacobian.clone().pseudo_inverse(1e-6) * &jacobian;
+            delta += projector * goal;
+        }
+        delta
+    }
+
+    // Iterates on the skeleton's DOFs until every effector is within
+    // tolerance or the iteration budget runs out
+    pub fn solve(&self, skeleton: &mut Skeleton) -> IkResult {
+        skeleton.update();
+        let mut residual = self.residual(skeleton);
+        let mut iterations = 0;
+        if self.dofs.is_empty() || self.effectors.is_empty() {
+            return IkResult::new(iterations, residual, residual <= self.tolerance);
+        }
+        while iterations < self.max_iterations && residual > self.tolerance {
+            let delta = self.step(skeleton);
+            for (column, &(joint, axis)) in self.dofs.iter().enumerate() {
+                let dof = skeleton.get_joint_mut(joint).get_dof_mut(axis);
+                let value = dof.get_value() + delta[column];
+                dof.set_value(value);
+            }
+            skeleton.update();
+            iterations += 1;
+            residual = self.residual(skeleton);
+        }
+        IkResult::new(iterations, residual, residual <= self.tolerance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Three unit bones along x ending in a hand, bending only around z
+    fn planar_chain(transform: glm::Mat4) -> (Skeleton, JacobianIk) {
+        let mut skeleton = Skeleton::new();
+        let mut parent = None;
+        for (index, name) in ["shoulder", "elbow", "wrist", "hand"].iter().enumerate() {
+            let mut joint = Joint::new(name, parent);
+            if index > 0 {
+                joint.set_offset(glm::vec3(1.0, 0.0, 0.0));
+            }
+            parent = Some(skeleton.add_joint(joint));
+        }
+        skeleton.set_transform(transform);
+        skeleton.update();
+        let mut ik = JacobianIk::new(JacobianMethod::DampedLeastSquares);
+        for joint in 0..3 {
+            ik.add_dof(&skeleton, joint, 2);
+        }
+        ik.set_max_iterations(1000);
+        (skeleton, ik)
+    }
+
+    #[test]
+    fn every_method_converges() {
+        for &method in &[JacobianMethod::Transpose, JacobianMethod::PseudoInverse, JacobianMethod::DampedLeastSquares] {
+            let (mut skeleton, mut ik) = planar_chain(glm::Mat4::identity());
+            ik.set_method(method);
+            ik.add_effector(3, glm::vec3(1.5, 1.5, 0.0));
+            let result = ik.solve(&mut skeleton);
+            assert!(result.is_converged(), "{:?} residual {}", method, result.get_residual());
+            assert!(result.get_residual() <= ik.get_tolerance());
+            let hand = world_position(&skeleton.get_world_mat(3));
+            assert!(glm::length(&(hand - glm::vec3(1.5, 1.5, 0.0))) <= ik.get_tolerance(), "{:?} {:?}", method, hand);
+        }
+    }
+
+    #[test]
+    fn jacobian_matches_finite_differences() {
+        let transform = glm::translation(&glm::vec3(2.0, 1.0, -3.0)) * glm::rotation(glm::half_pi(), &glm::vec3(0.0, 1.0, 0.0));
+        let (mut skeleton, _) = planar_chain(transform);
+        let mut ik = JacobianIk::new(JacobianMethod::DampedLeastSquares);
+        for joint in 0..3 {
+            ik.add_joint(&skeleton, joint);
+            skeleton.get_joint_mut(joint).set_pose(glm::vec3(0.3, -0.4, 0.5));
+        }
+        ik.add_effector(3, glm::vec3(0.0, 0.0, 0.0));
+        skeleton.update();
+        let (jacobian, _) = ik.jacobian(&skeleton);
+        let hand = world_position(&skeleton.get_world_mat(3));
+        let epsilon = 1e-3;
+        for (column, &(joint, axis)) in ik.get_dofs().iter().enumerate() {
+            let value = skeleton.get_joint(joint).get_dof(axis).get_value();
+            skeleton.get_joint_mut(joint).get_dof_mut(axis).set_value(value + epsilon);
+            skeleton.update();
+            let derivative = (world_position(&skeleton.get_world_mat(3)) - hand) / epsilon;
+            skeleton.get_joint_mut(joint).get_dof_mut(axis).set_value(value);
+            skeleton.update();
+            for component in 0..3 {
+                let error = (jacobian[(component, column)] - derivative[component]).abs();
+                assert!(error < 1e-2, "joint {} axis {}: {} vs {}", joint, axis, jacobian[(component, column)], derivative[component]);
+            }
+        }
+    }
+
+    #[test]
+    fn rest_goal_stays_out_of_the_effectors_way() {
+        let (mut skeleton, mut ik) = planar_chain(glm::Mat4::identity());
+        ik.set_method(JacobianMethod::PseudoInverse);
+        let target = glm::vec3(2.0, 1.0, 0.0);
+        ik.add_effector(3, target);
+        assert!(ik.solve(&mut skeleton).is_converged());
+        let elbow = skeleton.get_joint(1).get_dof(2).get_value();
+
+        // Three angles for a point in the plane leave one free; the goal
+        // uses it to bend the elbow the other way without moving the hand
+        let goal = elbow - 1.0;
+        assert!(ik.add_rest_goal(1, 2, goal, 0.1));
+        ik.set_tolerance(0.0);
+        ik.set_max_iterations(50);
+        let result = ik.solve(&mut skeleton);
+        assert!(result.get_residual() < 1e-3, "residual {}", result.get_residual());
+        let moved = skeleton.get_joint(1).get_dof(2).get_value();
+        assert!((moved - goal).abs() < 0.5 * (elbow - goal).abs(), "elbow {} -> {}, goal {}", elbow, moved, goal);
+        assert!(!ik.add_rest_goal(1, 0, 0.0, 0.1));
+    }
+}
@@ -0,0 +1,408 @@
+use crate::animation::*;
+use crate::blender::*;
+use crate::pose::*;
+use crate::skeleton::*;
+
+// How much a layer affects each joint, zero for joints it leaves alone
+#[derive(Clone, Debug)]
+pub struct JointMask {
+    weights: Vec<f32>,
+}
+
+impl JointMask {
+    pub fn new(num_joints: usize, weight: f32) -> JointMask {
+        JointMask {
+            weights: vec![glm::clamp_scalar(weight, 0.0, 1.0); num_joints],
+        }
+    }
+
+    // `root` and everything below it, like the spine for an upper body mask
+    pub fn from_subtree(skeleton: &Skeleton, root: usize, weight: f32) -> JointMask {
+        let mut mask = Self::new(skeleton.get_num_joints(), 0.0);
+        mask.set_subtree(skeleton, root, weight);
+        mask
+    }
+
+    pub fn get_weights(&self) -> &[f32] {
+        &self.weights
+    }
+
+    // Joints past the end of the mask are left alone
+    pub fn get_weight(&self, joint: usize) -> f32 {
+        self.weights.get(joint).cloned().unwrap_or(0.0)
+    }
+
+    pub fn set_weight(&mut self, joint: usize, weight: f32) {
+        if let Some(entry) = self.weights.get_mut(joint) {
+            *entry = glm::clamp_scalar(weight, 0.0, 1.0);
+        }
+    }
+
+    // Joints are in depth-first order, so the subtree follows `root` directly
+    pub fn set_subtree(&mut self, skeleton: &Skeleton, root: usize, weight: f32) {
+        let mut inside = vec![false; skeleton.get_num_joints()];
+        for index in root..skeleton.get_num_joints() {
+            inside[index] = index == root || skeleton.get_joint(index).get_parent().is_some_and(|parent| inside[parent]);
+            if inside[index] {
+                self.set_weight(index, weight);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum LayerMode {
+    // Blends towards the layer's pose
+    Override,
+    // Adds the layer's difference from the reference pose on top
+    Additive { reference: Pose },
+}
+
+// A clip, blend or graph played over the layers beneath it
+pub struct AnimationLayer {
+    source: Box<dyn PoseSource>,
+    mode: LayerMode,
+    mask: Option<JointMask>,
+    weight: f32,
+    // Weight being faded towards and the change per second
+    target_weight: f32,
+    fade_rate: f32,
+}
+
+impl AnimationLayer {
+    pub fn new(source: Box<dyn PoseSource>, mode: LayerMode) -> AnimationLayer {
+        AnimationLayer {
+            source,
+            mode,
+            mask: None,
+            weight: 1.0,
+            target_weight: 1.0,
+            fade_rate: 0.0,
+        }
+    }
+
+    pub fn get_source(&self) -> &dyn PoseSource {
+        self.source.as_ref()
+    }
+
+    pub fn get_source_mut(&mut self) -> &mut dyn PoseSource {
+        self.source.as_mut()
+    }
+
+    pub fn get_mode(&self) -> &LayerMode {
+        &self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: LayerMode) {
+        self.mode = mode;
+    }
+
+    pub fn get_mask(&self) -> Option<&JointMask> {
+        self.mask.as_ref()
+    }
+
+    // Without a mask the layer covers the whole skeleton
+    pub fn set_mask(&mut self, mask: Option<JointMask>) {
+        self.mask = mask;
+    }
+
+    pub fn get_weight(&self) -> f32 {
+        self.weight
+    }
+
+    // Stops any fade in progress
+    pub fn set_weight(&mut self, weight: f32) {
+        self.weight = glm::clamp_scalar(weight, 0.0, 1.0);
+        self.target_weight = self.weight;
+        self.fade_rate = 0.0;
+    }
+
+    pub fn fade_to(&mut self, weight: f32, duration: f32) {
+        let weight = glm::clamp_scalar(weight, 0.0, 1.0);
+        if duration <= 0.0 {
+            self.set_weight(weight);
+            return;
+        }
+        self.target_weight = weight;
+        self.fade_rate = (weight - self.weight).abs() / duration;
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        self.source.update(dt);
+        let step = self.fade_rate * dt;
+        if (self.target_weight - self.weight).abs() <= step {
+            self.weight = self.target_weight;
+            self.fade_rate = 0.0;
+        } else {
+            self.weight += step.copysign(self.target_weight - self.weight);
+        }
+    }
+
+    fn get_joint_weight(&self, joint: usize) -> f32 {
+        self.weight * self.mask.as_ref().map_or(1.0, |mask| mask.get_weight(joint))
+    }
+
+    // Layers this layer's pose onto `pose`
+    pub fn apply(&self, pose: &mut Pose, skeleton: &Skeleton) {
+        if self.weight <= 0.0 {
+            return;
+        }
+        let layer = self.source.evaluate(skeleton);
+        let num_joints = pose.get_num_joints().min(layer.get_num_joints());
+        for joint in 0..num_joints {
+            let weight = self.get_joint_weight(joint);
+            if weight <= 0.0 {
+                continue;
+            }
+            let base = *pose.get_transform(joint);
+            let blended = match &self.mode {
+                LayerMode::Override => JointTransform::lerp(&base, layer.get_transform(joint), weight),
+                LayerMode::Additive { reference } if joint < reference.get_num_joints() => {
+                    let delta = additive_delta(reference.get_transform(joint), layer.get_transform(joint));
+                    add_delta(&base, &JointTransform::lerp(&JointTransform::identity(), &delta, weight))
+                },
+                LayerMode::Additive { .. } => base,
+            };
+            pose.set_transform(joint, blended);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.source.reset();
+    }
+}
+
+// Difference taking `reference` to `transform`, rotation applied after the
+// reference's and scale as a factor
+pub fn additive_delta(reference: &JointTransform, transform: &JointTransform) -> JointTransform {
+    let reference_scale = reference.get_scale();
+    let scale = transform.get_scale();
+    let ratio = |axis: usize| if reference_scale[axis].abs() > 1e-6 { scale[axis] / reference_scale[axis] } else { 1.0 };
+    JointTransform::new(
+        transform.get_translation() - reference.get_translation(),
+        glm::quat_normalize(&(glm::quat_inverse(&reference.get_rotation()) * transform.get_rotation())),
+        glm::vec3(ratio(0), ratio(1), ratio(2)),
+    )
+}
+
+pub fn add_delta(base: &JointTransform, delta: &JointTransform) -> JointTransform {
+    JointTransform::new(
+        base.get_translation() + delta.get_translation(),
+        glm::quat_normalize(&(base.get_rotation() * delta.get_rotation())),
+        base.get_scale().component_mul(&delta.get_scale()),
+    )
+}
+
+// Usual reference for an additive clip, its first frame
+pub fn reference_pose(animation: &Animation, skeleton: &Skeleton) -> Pose {
+    animation.sample(animation.get_start_time(), skeleton)
+}
+
+// A base source with layers on top, applied in the order they were added
+pub struct LayerStack<S: PoseSource> {
+    base: S,
+    layers: Vec<AnimationLayer>,
+}
+
+impl<S: PoseSource> LayerStack<S> {
+    pub fn new(base: S) -> LayerStack<S> {
+        LayerStack {
+            base,
+            layers: Vec::new(),
+        }
+    }
+
+    pub fn get_base(&self) -> &S {
+        &self.base
+    }
+
+    pub fn get_base_mut(&mut self) -> &mut S {
+        &mut self.base
+    }
+
+    pub fn add_layer(&mut self, layer: AnimationLayer) -> usize {
+        self.layers.push(layer);
+        self.layers.len() - 1
+    }
+
+    pub fn get_num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn get_layer(&self, index: usize) -> &AnimationLayer {
+        &self.layers[index]
+    }
+
+    pub fn get_layer_mut(&mut self, index: usize) -> &mut AnimationLayer {
+        &mut self.layers[index]
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        self.base.update(dt);
+        for layer in &mut self.layers {
+            layer.update(dt);
+        }
+    }
+
+    pub fn evaluate(&self, skeleton: &Skeleton) -> Pose {
+        let mut pose = self.base.evaluate(skeleton);
+        for layer in &self.layers {
+            layer.apply(&mut pose, skeleton);
+        }
+        pose
+    }
+
+    pub fn pose(&self, skeleton: &mut Skeleton) {
+        self.evaluate(skeleton).apply(skeleton);
+    }
+
+    pub fn reset(&mut self) {
+        self.base.reset();
+        for layer in &mut self.layers {
+            layer.reset();
+        }
+    }
+}
+
+impl<S: PoseSource> PoseSource for LayerStack<S> {
+    fn update(&mut self, dt: f32) {
+        LayerStack::update(self, dt);
+    }
+
+    fn evaluate(&self, skeleton: &Skeleton) -> Pose {
+        LayerStack::evaluate(self, skeleton)
+    }
+
+    fn reset(&mut self) {
+        LayerStack::reset(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use super::*;
+    use crate::animation::tests::constant;
+
+    // Root with a spine and head above it and a leg below
+    fn skeleton() -> Skeleton {
+        let mut skeleton = Skeleton::new();
+        let root = skeleton.add_joint(Joint::new("root", None));
+        let spine = skeleton.add_joint(Joint::new("spine", Some(root)));
+        skeleton.add_joint(Joint::new("head", Some(spine)));
+        skeleton.add_joint(Joint::new("leg", Some(root)));
+        for (joint, offset) in [(1, 0.5), (2, 0.5), (3, -1.0)] {
+            skeleton.get_joint_mut(joint).set_offset(glm::vec3(0.0, offset, 0.0));
+        }
+        skeleton.update();
+        skeleton
+    }
+
+    // Holds the root at `translation` and every joint at `angle` around its own axis
+    fn hold(translation: glm::Vec3, angles: [f32; 4]) -> AnimationPlayer {
+        let mut channels = vec![constant(translation.x), constant(translation.y), constant(translation.z)];
+        for (joint, &angle) in angles.iter().enumerate() {
+            for axis in 0..3 {
+                channels.push(constant(if axis == joint % 3 { angle } else { 0.0 }));
+            }
+        }
+        AnimationPlayer::new(Rc::new(Animation::new(0.0, 1.0, channels)))
+    }
+
+    fn same(a: &JointTransform, b: &JointTransform) -> bool {
+        glm::length(&(a.get_translation() - b.get_translation())) < 1e-5
+            && glm::quat_dot(&a.get_rotation(), &b.get_rotation()).abs() > 1.0 - 1e-6
+            && glm::length(&(a.get_scale() - b.get_scale())) < 1e-5
+    }
+
+    fn assert_same(a: &Pose, b: &Pose, joints: &[usize]) {
+        for &joint in joints {
+            assert!(same(a.get_transform(joint), b.get_transform(joint)), "joint {}: {:?} != {:?}", joint, a.get_transform(joint), b.get_transform(joint));
+        }
+    }
+
+    #[test]
+    fn deltas_add_back_up() {
+        let reference = JointTransform::new(glm::vec3(1.0, 2.0, 3.0), glm::quat_angle_axis(0.4, &glm::vec3(0.0, 1.0, 0.0)), glm::vec3(2.0, 1.0, 1.0));
+        let transform = JointTransform::new(glm::vec3(0.0, 1.0, 0.5), glm::quat_angle_axis(-0.7, &glm::vec3(1.0, 0.0, 0.0)), glm::vec3(1.0, 3.0, 1.0));
+        let delta = additive_delta(&reference, &transform);
+        assert!(same(&add_delta(&reference, &delta), &transform));
+        assert!(same(&additive_delta(&reference, &reference), &JointTransform::identity()));
+        assert!(same(&add_delta(&transform, &JointTransform::identity()), &transform));
+    }
+
+    #[test]
+    fn additive_layers_scale_with_their_weight() {
+        let skeleton = skeleton();
+        let idle = || hold(glm::vec3(0.0, 1.0, 0.0), [0.0, 0.1, 0.0, 0.2]);
+        let nod = hold(glm::vec3(0.0, 1.2, 0.0), [0.3, 0.4, -0.5, 0.6]);
+        let expected_nod = nod.sample(&skeleton);
+        let reference = idle().sample(&skeleton);
+
+        let mut stack = LayerStack::new(idle());
+        let layer = stack.add_layer(AnimationLayer::new(Box::new(nod), LayerMode::Additive { reference: reference.clone() }));
+        // With the base at the reference the full layer comes out as the layer clip
+        assert_same(&stack.evaluate(&skeleton), &expected_nod, &[0, 1, 2, 3]);
+
+        stack.get_layer_mut(layer).set_weight(0.0);
+        assert_same(&stack.evaluate(&skeleton), &reference, &[0, 1, 2, 3]);
+
+        // A base away from the reference gets the same difference added on
+        let mut stack = LayerStack::new(hold(glm::vec3(0.0, 0.5, 0.0), [0.0; 4]));
+        stack.add_layer(AnimationLayer::new(Box::new(hold(glm::vec3(0.0, 1.5, 0.0), [0.0; 4])), LayerMode::Additive { reference }));
+        let pose = stack.evaluate(&skeleton);
+        assert!(glm::length(&(pose.get_transform(0).get_translation() - glm::vec3(0.0, 1.0, 0.0))) < 1e-5);
+    }
+
+    #[test]
+    fn masked_overrides_leave_other_joints_alone() {
+        let skeleton = skeleton();
+        let base = || hold(glm::vec3(0.0, 1.0, 0.0), [0.1, 0.2, 0.3, 0.4]);
+        let wave = || hold(glm::vec3(0.0, 3.0, 0.0), [-0.5, -0.6, 0.7, -0.8]);
+        let (base_pose, wave_pose) = (base().sample(&skeleton), wave().sample(&skeleton));
+
+        let mut mask = JointMask::new(skeleton.get_num_joints(), 0.0);
+        mask.set_subtree(&skeleton, 1, 1.0);
+        assert_eq!(mask.get_weights(), &[0.0, 1.0, 1.0, 0.0]);
+        let mut layer = AnimationLayer::new(Box::new(wave()), LayerMode::Override);
+        layer.set_mask(Some(mask));
+        let mut stack = LayerStack::new(base());
+        let layer = stack.add_layer(layer);
+        let pose = stack.evaluate(&skeleton);
+        assert_same(&pose, &base_pose, &[0, 3]);
+        assert_same(&pose, &wave_pose, &[1, 2]);
+
+        // Half way in on the leg, the upper body stays fully covered
+        let mut mask = JointMask::from_subtree(&skeleton, 1, 1.0);
+        mask.set_subtree(&skeleton, 3, 0.5);
+        stack.get_layer_mut(layer).set_mask(Some(mask));
+        let pose = stack.evaluate(&skeleton);
+        assert_same(&pose, &base_pose, &[0]);
+        assert_same(&pose, &wave_pose, &[1, 2]);
+        let halfway = JointTransform::lerp(base_pose.get_transform(3), wave_pose.get_transform(3), 0.5);
+        assert!(same(pose.get_transform(3), &halfway));
+    }
+
+    #[test]
+    fn fades_reach_their_weight_and_stop() {
+        let mut layer = AnimationLayer::new(Box::new(hold(glm::vec3(0.0, 0.0, 0.0), [0.0; 4])), LayerMode::Override);
+        layer.set_weight(0.0);
+        layer.fade_to(1.0, 0.5);
+        layer.update(0.25);
+        assert!((layer.get_weight() - 0.5).abs() < 1e-5, "{}", layer.get_weight());
+        layer.update(0.5);
+        assert_eq!(layer.get_weight(), 1.0);
+        layer.update(0.5);
+        assert_eq!(layer.get_weight(), 1.0);
+
+        layer.fade_to(0.25, 1.5);
+        layer.update(1.0);
+        assert!((layer.get_weight() - 0.5).abs() < 1e-5, "{}", layer.get_weight());
+        // A new weight stops the fade
+        layer.set_weight(0.8);
+        layer.update(1.0);
+        assert_eq!(layer.get_weight(), 0.8);
+        layer.fade_to(0.0, 0.0);
+        assert_eq!(layer.get_weight(), 0.0);
+    }
+}
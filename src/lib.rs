@@ -0,0 +1,35 @@
+// Everything is built with new(), Default impls would only duplicate it
+#![allow(clippy::new_without_default)]
+
+pub mod shader_program;
+pub mod model;
+pub mod camera;
+pub mod spinning_cube;
+pub mod buffer;
+pub mod tokenizer;
+pub mod skeleton;
+pub mod skinned_model;
+pub mod dual_quat;
+pub mod animation;
+pub mod pose;
+pub mod blender;
+pub mod blend_space;
+pub mod state_machine;
+pub mod ik;
+pub mod ik_chain;
+pub mod jacobian_ik;
+pub mod particles;
+pub mod cloth;
+pub mod xpbd;
+pub mod particle_system;
+pub mod gpu_particles;
+pub mod rigid_body;
+pub mod morph_model;
+pub mod root_motion;
+pub mod retarget;
+pub mod compression;
+pub mod layers;
+pub mod bvh;
+pub mod gltf_loader;
+pub mod obj;
+pub mod primitives;
@@ -1,19 +1,384 @@
+use std::rc::Rc;
 use std::time::Instant;
 
 use glutin::GlContext;
 use glutin::Event;
 use glutin::dpi::*;
 
-mod shader_program;
-use crate::shader_program::*;
-
-mod model;
-mod camera;
-mod spinning_cube;
-mod buffer;
+use animbox::shader_program::*;
+use animbox::{model, camera, spinning_cube, skeleton, skinned_model, animation, pose, blender,
+    state_machine, ik, ik_chain, jacobian_ik, cloth, xpbd, particle_system, gpu_particles,
+    rigid_body, morph_model, root_motion, retarget, compression, layers, bvh, gltf_loader, obj,
+    primitives};
 
 fn main() {
-    run();
+    if std::env::args().any(|arg| arg == "--ik-report") {
+        ik_report();
+    } else if std::env::args().any(|arg| arg.starts_with("--rigid-report=")) {
+        rigid_report();
+    } else if std::env::args().any(|arg| arg == "--compress-report" || arg.starts_with("--compress-report=")) {
+        compress_report();
+    } else {
+        run();
+    }
+}
+
+// Headless comparison of the Jacobian IK methods:
+// animbox --ik-report <skeleton> --effector=<joint>:<x>,<y>,<z>[:<weight>] [--damping=<d>] [--iterations=<n>]
+//     [--tolerance=<distance>] [--max-step=<distance>] [--rest=<joint>:<x|y|z>:<degrees>[:<gain>]]
+// Rest goals pull a DOF towards an angle without moving the effectors.
+fn ik_report() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut skeleton: Option<skeleton::Skeleton> = None;
+    let mut effectors: Vec<(String, glm::Vec3, f32)> = Vec::new();
+    let mut rest_goals: Vec<(String, usize, f32, f32)> = Vec::new();
+    let mut damping = 0.1;
+    let mut iterations = 100;
+    let mut tolerance = 1e-3;
+    let mut max_step = f32::INFINITY;
+    for arg in &args {
+        if let Some(spec) = arg.strip_prefix("--effector=") {
+            let parts: Vec<&str> = spec.split(':').collect();
+            let position: Vec<f32> = parts.get(1).map_or(Vec::new(), |position| {
+                position.split(',').filter_map(|value| value.parse().ok()).collect()
+            });
+            let weight = parts.get(2).map_or(Some(1.0), |weight| weight.parse().ok());
+            match (position.len(), weight) {
+                (3, Some(weight)) => effectors.push((parts[0].to_string(), glm::vec3(position[0], position[1], position[2]), weight)),
+                _ => {
+                    eprintln!("invalid effector '{}'", arg);
+                    return;
+                }
+            }
+        } else if let Some(value) = arg.strip_prefix("--damping=") {
+            match value.parse() {
+                Ok(value) => damping = value,
+                Err(_) => {
+                    eprintln!("invalid damping '{}'", arg);
+                    return;
+                }
+            }
+        } else if let Some(value) = arg.strip_prefix("--iterations=") {
+            match value.parse() {
+                Ok(value) => iterations = value,
+                Err(_) => {
+                    eprintln!("invalid iterations '{}'", arg);
+                    return;
+                }
+            }
+        } else if let Some(value) = arg.strip_prefix("--tolerance=") {
+            match value.parse() {
+                Ok(value) => tolerance = value,
+                Err(_) => {
+                    eprintln!("invalid tolerance '{}'", arg);
+                    return;
+                }
+            }
+        } else if let Some(value) = arg.strip_prefix("--max-step=") {
+            match value.parse() {
+                Ok(value) => max_step = value,
+                Err(_) => {
+                    eprintln!("invalid max step '{}'", arg);
+                    return;
+                }
+            }
+        } else if let Some(spec) = arg.strip_prefix("--rest=") {
+            let parts: Vec<&str> = spec.split(':').collect();
+            let axis = parts.get(1).and_then(|axis| ["x", "y", "z"].iter().position(|name| name == axis));
+            let value = parts.get(2).and_then(|value| value.parse::<f32>().ok());
+            let gain = parts.get(3).map_or(Some(0.1), |gain| gain.parse().ok());
+            match (axis, value, gain) {
+                (Some(axis), Some(value), Some(gain)) => rest_goals.push((parts[0].to_string(), axis, value.to_radians(), gain)),
+                _ => {
+                    eprintln!("invalid rest goal '{}'", arg);
+                    return;
+                }
+            }
+        } else if arg.ends_with(".skel") {
+            match skeleton::Skeleton::from_file(arg) {
+                Ok(loaded) => skeleton = Some(loaded),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return;
+                }
+            }
+        } else if arg.ends_with(".bvh") {
+            match bvh::Bvh::from_file(arg, bvh::BvhOptions::new()) {
+                Ok(loaded) => skeleton = Some(loaded.into_parts().0),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return;
+                }
+            }
+        }
+    }
+    let mut skeleton = match skeleton {
+        Some(skeleton) => skeleton,
+        None => {
+            eprintln!("--ik-report needs a .skel or .bvh file");
+            return;
+        }
+    };
+
+    for &method in &[jacobian_ik::JacobianMethod::Transpose, jacobian_ik::JacobianMethod::PseudoInverse, jacobian_ik::JacobianMethod::DampedLeastSquares] {
+        let mut solver = jacobian_ik::JacobianIk::new(method);
+        solver.set_damping(damping);
+        solver.set_max_iterations(iterations);
+        solver.set_tolerance(tolerance);
+        solver.set_max_step(max_step);
+        for (name, target, weight) in &effectors {
+            let joint = match skeleton.find_joint(name) {
+                Some(joint) => joint,
+                None => {
+                    eprintln!("unknown effector joint '{}'", name);
+                    return;
+                }
+            };
+            solver.add_chain(&skeleton, 0, joint);
+            let effector = solver.add_effector(joint, *target);
+            solver.set_effector_weight(effector, *weight);
+        }
+        for (name, axis, value, gain) in &rest_goals {
+            if !skeleton.find_joint(name).is_some_and(|joint| solver.add_rest_goal(joint, *axis, *value, *gain)) {
+                eprintln!("rest goal joint '{}' isn't solved for", name);
+                return;
+            }
+        }
+        skeleton.reset();
+        let start = Instant::now();
+        let result = solver.solve(&mut skeleton);
+        println!("{:?}: {} iterations, residual {}, {} in {:?}",
+            method, result.get_iterations(), result.get_residual(),
+            if result.is_converged() { "converged" } else { "not converged" }, start.elapsed());
+    }
+}
+
+// hinge|twist,<x|y|z>,<min>,<max> or cone,<x|y|z>,<angle>, in degrees
+fn ik_limit(spec: &str) -> Option<ik_chain::JointLimit> {
+    let parts: Vec<&str> = spec.split(',').collect();
+    let axis = match parts.get(1).cloned() {
+        Some("x") => glm::vec3(1.0, 0.0, 0.0),
+        Some("y") => glm::vec3(0.0, 1.0, 0.0),
+        Some("z") => glm::vec3(0.0, 0.0, 1.0),
+        _ => return None,
+    };
+    let angles: Vec<f32> = parts[2..].iter().map(|part| part.parse::<f32>().map(f32::to_radians)).collect::<Result<_, _>>().ok()?;
+    match (parts[0], angles.as_slice()) {
+        ("hinge", &[min, max]) if min <= max => Some(ik_chain::JointLimit::Hinge { axis, min, max }),
+        ("twist", &[min, max]) if min <= max => Some(ik_chain::JointLimit::Twist { axis, min, max }),
+        ("cone", &[angle]) if angle >= 0.0 => Some(ik_chain::JointLimit::Cone { axis, angle }),
+        _ => None,
+    }
+}
+
+// Ground plane with boxes and spheres on it: stack, pyramid or drop
+fn rigid_scene(kind: &str) -> Option<rigid_body::RigidWorld> {
+    use crate::rigid_body::*;
+    let mut world = RigidWorld::new();
+    world.add_plane(glm::vec3(0.0, 1.0, 0.0), 0.0);
+    let cube = Shape::Box { half_extents: glm::vec3(0.5, 0.5, 0.5) };
+    match kind {
+        "stack" => {
+            for level in 0..8 {
+                // Slightly off center so the tower has something to settle
+                world.add_body(RigidBody::new(cube, 1.0, glm::vec3(0.02 * (level % 2) as f32, 0.5 + 1.01 * level as f32, 0.0)));
+            }
+        },
+        "pyramid" => {
+            let rows = 6;
+            for row in 0..rows {
+                for column in 0..rows - row {
+                    let x = (column as f32 - (rows - row - 1) as f32 * 0.5) * 1.05;
+                    world.add_body(RigidBody::new(cube, 1.0, glm::vec3(x, 0.5 + 1.01 * row as f32, 0.0)));
+                }
+            }
+        },
+        "drop" => {
+            for index in 0..12 {
+                let position = glm::vec3((index % 3) as f32 - 1.0, 1.0 + index as f32 * 0.8, ((index / 3) % 2) as f32 * 0.8 - 0.4);
+                let mut body = if index % 3 == 2 {
+                    RigidBody::new(Shape::Sphere { radius: 0.35 }, 1.0, position)
+                } else {
+                    RigidBody::new(Shape::Box { half_extents: glm::vec3(0.5, 0.25, 0.35) }, 1.0, position)
+                };
+                body.set_orientation(glm::quat_angle_axis(index as f32 * 0.7, &glm::normalize(&glm::vec3(1.0, 0.5, 0.3))));
+                body.set_restitution(0.4);
+                world.add_body(body);
+            }
+        },
+        _ => return None,
+    }
+    Some(world)
+}
+
+// Sphere with a stretch and a bulge target, keyed to cycle between them
+fn morph_demo() -> (morph_model::MorphModel, morph_model::MorphAnimation) {
+    use crate::animation::*;
+    let (mesh_vertices, indices) = primitives::MeshData::icosphere(1.0, 3).into_parts();
+    let base: Vec<glm::Vec3> = mesh_vertices.iter().map(|v| v.get_position()).collect();
+    // Normals for the base and the targets computed the same way, so
+    // vertices a target doesn't move get no delta
+    let shape = |deform: &dyn Fn(&glm::Vec3) -> glm::Vec3| -> Vec<model::ModelVertex> {
+        let positions: Vec<glm::Vec3> = base.iter().map(deform).collect();
+        let normals = model::smooth_normals(&positions, &indices);
+        positions.iter().zip(normals.iter()).map(|(&position, &normal)| model::ModelVertex::new(position, normal)).collect()
+    };
+    let vertices = shape(&|p| *p);
+    let stretch = shape(&|p| glm::vec3(p.x * 0.7, p.y * 1.6, p.z * 0.7));
+    let bulge = shape(&|p| p * (1.0 + 0.6 * p.z.max(0.0).powi(3)));
+    let targets = vec![
+        morph_model::MorphTarget::from_mesh("stretch", &vertices, &stretch),
+        morph_model::MorphTarget::from_mesh("bulge", &vertices, &bulge),
+    ];
+
+    let key = |time: f32, value: f32| Keyframe::new(time, value, TangentRule::Smooth, TangentRule::Smooth);
+    let mut animation = morph_model::MorphAnimation::new(0.0, 2.0);
+    animation.add_channel("stretch", Channel::new(vec![key(0.0, 0.0), key(1.0, 1.0), key(2.0, 0.0)], Extrapolation::Cycle, Extrapolation::Cycle));
+    animation.add_channel("bulge", Channel::new(vec![key(0.0, 1.0), key(0.5, 0.0), key(1.5, 0.0), key(2.0, 1.0)], Extrapolation::Cycle, Extrapolation::Cycle));
+    // Targets made from the base mesh only move its own vertices
+    (morph_model::MorphModel::from_parts(vertices, indices, targets).unwrap(), animation)
+}
+
+// Steps a scene at a fixed rate without a window and prints where the bodies
+// end up, the checksum changes with any difference in the results:
+// animbox --rigid-report=<stack|pyramid|drop> [--frames=<n>]
+fn rigid_report() {
+    let mut world = None;
+    let mut frames = 600;
+    for arg in std::env::args().skip(1) {
+        if let Some(kind) = arg.strip_prefix("--rigid-report=") {
+            world = rigid_scene(kind);
+            if world.is_none() {
+                eprintln!("invalid rigid scene '{}'", arg);
+                return;
+            }
+        } else if let Some(value) = arg.strip_prefix("--frames=") {
+            match value.parse() {
+                Ok(value) => frames = value,
+                Err(_) => {
+                    eprintln!("invalid frames '{}'", arg);
+                    return;
+                }
+            }
+        }
+    }
+    let mut world = match world {
+        Some(world) => world,
+        None => return,
+    };
+
+    let start = Instant::now();
+    for _ in 0..frames {
+        world.step(world.get_time_step());
+    }
+    let elapsed = start.elapsed();
+    let mut checksum: u64 = 0;
+    for (index, body) in world.get_bodies().iter().enumerate() {
+        let position = body.get_position();
+        let orientation = body.get_orientation().coords;
+        println!("{}: position {:.5} {:.5} {:.5} orientation {:.5} {:.5} {:.5} {:.5}",
+            index, position.x, position.y, position.z, orientation.x, orientation.y, orientation.z, orientation.w);
+        for value in position.iter().chain(orientation.iter()) {
+            checksum = checksum.rotate_left(5) ^ value.to_bits() as u64;
+        }
+    }
+    println!("{} bodies, {} steps in {:?}, {} contacts, checksum {:016x}",
+        world.get_bodies().len(), frames, elapsed, world.get_contacts().len(), checksum);
+}
+
+// Tolerance from --compress=<tolerance> or --compress-report=<tolerance>,
+// None if the value doesn't parse
+fn compression_options(value: Option<&str>) -> Option<compression::CompressionOptions> {
+    let mut options = compression::CompressionOptions::new();
+    if let Some(value) = value {
+        options.tolerance = value.parse().ok().filter(|&tolerance: &f32| tolerance > 0.0)?;
+    }
+    Some(options)
+}
+
+// Compresses the clip and prints how much smaller and how far off it came out
+fn compress_clip(index: usize, clip: &animation::Animation, skeleton: &skeleton::Skeleton,
+                 options: compression::CompressionOptions) -> Result<compression::CompressedAnimation, compression::CompressionError> {
+    let compressed = compression::CompressedAnimation::new(clip, skeleton, options)?;
+    let report = compression::CompressionReport::new(clip, &compressed, skeleton);
+    println!("clip {}: {} of {} keys, {} -> {} bytes ({:.1}x, {} saved), max error {:.5} at '{}' {:.3}s",
+        index, report.kept_keys, report.raw_keys, report.raw_bytes, report.compressed_bytes, report.get_ratio(),
+        report.get_saved_bytes(), report.max_error, skeleton.get_joint(report.worst_joint).get_name(), report.worst_time);
+    Ok(compressed)
+}
+
+// Offline compression of every clip given:
+// animbox --compress-report[=<tolerance>] [--scale=<s>] [--z-up] <skel> <anim>... | <bvh>...
+fn compress_report() {
+    let mut options = None;
+    let mut bvh_options = bvh::BvhOptions::new();
+    let mut skeleton: Option<skeleton::Skeleton> = None;
+    let mut clips: Vec<(animation::Animation, Option<skeleton::Skeleton>)> = Vec::new();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    for arg in &args {
+        if arg == "--compress-report" || arg.starts_with("--compress-report=") {
+            options = compression_options(arg.strip_prefix("--compress-report="));
+            if options.is_none() {
+                eprintln!("invalid tolerance '{}'", arg);
+                return;
+            }
+        } else if let Some(scale) = arg.strip_prefix("--scale=") {
+            match scale.parse::<f32>() {
+                Ok(scale) => bvh_options.scale = scale,
+                Err(_) => {
+                    eprintln!("invalid scale '{}'", arg);
+                    return;
+                }
+            }
+        } else if arg == "--z-up" {
+            bvh_options.up_axis = bvh::UpAxis::Z;
+        }
+    }
+    let options = match options {
+        Some(options) => options,
+        None => return,
+    };
+    for arg in args.iter().filter(|arg| !arg.starts_with("--")) {
+        let loaded: Result<(), Box<dyn std::error::Error>> = if arg.ends_with(".skel") {
+            skeleton::Skeleton::from_file(arg).map(|loaded| skeleton = Some(loaded)).map_err(Into::into)
+        } else if arg.ends_with(".anim") {
+            animation::Animation::from_file(arg).map(|loaded| clips.push((loaded, None))).map_err(Into::into)
+        } else if arg.ends_with(".bvh") {
+            // Each BVH brings its own skeleton
+            bvh::Bvh::from_file(arg, bvh_options).map(|loaded| {
+                let (loaded_skeleton, loaded_animation) = loaded.into_parts();
+                clips.push((loaded_animation, Some(loaded_skeleton)));
+            }).map_err(Into::into)
+        } else {
+            eprintln!("ignoring unrecognized file '{}'", arg);
+            Ok(())
+        };
+        if let Err(err) = loaded {
+            eprintln!("{}", err);
+            return;
+        }
+    }
+
+    let (mut raw_bytes, mut compressed_bytes) = (0, 0);
+    for (index, (clip, clip_skeleton)) in clips.iter().enumerate() {
+        let skeleton = match clip_skeleton.as_ref().or(skeleton.as_ref()) {
+            Some(skeleton) => skeleton,
+            None => {
+                eprintln!("clip {} has no skeleton", index);
+                return;
+            }
+        };
+        let compressed = match compress_clip(index, clip, skeleton, options) {
+            Ok(compressed) => compressed,
+            Err(e) => {
+                eprintln!("clip {}: {}", index, e);
+                return;
+            }
+        };
+        raw_bytes += 4 * (3 + 3 * skeleton.get_num_joints()) * compressed.get_num_frames();
+        compressed_bytes += compressed.get_size();
+    }
+    println!("{} clips, {} -> {} bytes, tolerance {}", clips.len(), raw_bytes, compressed_bytes, options.tolerance);
 }
 
 fn run() {
@@ -30,9 +395,15 @@ fn run() {
     let window = glutin::WindowBuilder::new()
         .with_title("animbox")
         .with_dimensions(LogicalSize::new(width as f64, height as f64));
+    // Only the compute shader particles insist on 4.3
+    let gl_version = if std::env::args().any(|arg| arg == "--gpu-particles" || arg.starts_with("--gpu-particles=")) {
+        (4, 3)
+    } else {
+        (3, 3)
+    };
     let context = glutin::ContextBuilder::new()
         .with_gl_profile(glutin::GlProfile::Core)
-        .with_gl(glutin::GlRequest::Specific(glutin::Api::OpenGl, (3, 3)))
+        .with_gl(glutin::GlRequest::Specific(glutin::Api::OpenGl, gl_version))
         .with_vsync(true);
     let gl_window = glutin::GlWindow::new(window, context, &events_loop).unwrap();
 
@@ -56,20 +427,554 @@ fn run() {
     }
 
     let shader_program = ShaderProgram::from_file("model", ProgramType::Render);
+    let skinning_program = ShaderProgram::from_files("skinned_model.vert", "model.frag");
+    let particle_program = ShaderProgram::from_file("particle", ProgramType::Render);
+    let morph_program = ShaderProgram::from_files("morph_model.vert", "model.frag");
+    let gpu_particle_program = ShaderProgram::from_file("gpu_particle", ProgramType::Render);
     let mut camera = camera::Camera::new();
     camera.set_aspect(width / height);
     let mut cube = spinning_cube::SpinningCube::new();
     let mut cube2 = spinning_cube::SpinningCube::new();
     cube2.set_position(glm::vec3(3.0, 3.0, 0.0));
 
+    let mut skeleton: Option<skeleton::Skeleton> = None;
+    let mut skin: Option<skinned_model::SkinnedModel> = None;
+    let mut clips: Vec<Rc<animation::Animation>> = Vec::new();
+    let mut graph: Option<state_machine::StateMachine> = None;
+    let mut gltf_scene: Option<gltf_loader::GltfScene> = None;
+    // Loaded OBJs and, with --primitives, one of each generated shape in a row
+    let mut meshes: Vec<(model::Model, glm::Mat4)> = Vec::new();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut bvh_options = bvh::BvhOptions::new();
+    // --ik=<root>:<tip>[:<joint>...] adds a chain with targets on the tip and any
+    // listed joints. <joint>=hinge|twist,<x|y|z>,<min>,<max> or
+    // <joint>=cone,<x|y|z>,<angle> limits a chain joint, in degrees from its rest pose.
+    let mut ik_specs: Vec<Vec<&str>> = Vec::new();
+    let mut ik_solver = ik_chain::IkSolver::Fabrik;
+    // --two-bone=<end>[:pole=<x>,<y>,<z>][:soft=<fraction>] solves the limb above
+    // a joint analytically towards a target, bending towards the pole if given
+    let mut two_bone_specs: Vec<Vec<&str>> = Vec::new();
+    // --cloth[=corners|top|none|<particle>,<particle>...] hangs a cloth over a
+    // sphere from the particles given, --wind=<x>,<y>,<z> blows on it
+    let mut cloth: Option<cloth::Cloth> = None;
+    let mut wind = glm::vec3(0.0, 0.0, 0.0);
+    // --xpbd=rope|cloth|blob drops a position based body into the scene, repeatable
+    let mut bodies: Vec<xpbd::XpbdBody> = Vec::new();
+    // --particles[=point|box|sphere|mesh] starts a fountain from that emitter
+    // shape, B fires a burst
+    let mut particles: Option<particle_system::ParticleSystem> = None;
+    // --gpu-particles[=<count>] simulates a fountain in a compute shader, a million particles by default
+    let mut gpu_particles: Option<gpu_particles::GpuParticleSystem> = None;
+    let mut particle_compute_program: Option<ShaderProgram> = None;
+    // --rigid=stack|pyramid|drop drops boxes on the ground, space throws in another
+    let mut rigid_world: Option<rigid_body::RigidWorld> = None;
+    // --morph shows a blend shape demo, --morph=<base.obj>,<target.obj>... loads
+    // one target per OBJ, a .morph file keys their weights
+    let mut morph: Option<morph_model::MorphModel> = None;
+    let mut morph_animation: Option<morph_model::MorphAnimation> = None;
+    let mut morph_time = 0.0;
+    // --root-motion moves the character with its clip's root instead of
+    // letting the root wander off and snap back each loop
+    let mut root_motion: Option<root_motion::RootMotion> = None;
+    // --retarget=<rig.skel|rig.bvh> plays every clip on a second rig as well,
+    // mapping joints by name or through --retarget-map=<file>
+    let mut retarget_rig: Option<&str> = None;
+    let mut retarget_map: Option<&str> = None;
+    // --compress[=<tolerance>] plays the clips after a round trip through compression
+    let mut compress: Option<compression::CompressionOptions> = None;
+    // --layer=<clip>[:additive|:override][:<weight>][:<joint>...] plays a clip
+    // over the others, masked to the listed joints and everything below them.
+    // <clip>@<weight>+<clip>@<weight>... mixes several clips in one layer.
+    let mut layer_specs: Vec<Vec<&str>> = Vec::new();
+    for arg in &args {
+        if let Some(scale) = arg.strip_prefix("--scale=") {
+            match scale.parse::<f32>() {
+                Ok(scale) => bvh_options.scale = scale,
+                Err(_) => {
+                    eprintln!("invalid scale '{}'", arg);
+                    return;
+                }
+            }
+        } else if arg == "--z-up" {
+            bvh_options.up_axis = bvh::UpAxis::Z;
+        } else if let Some(spec) = arg.strip_prefix("--layer=") {
+            layer_specs.push(spec.split(':').collect());
+        } else if let Some(spec) = arg.strip_prefix("--ik=") {
+            ik_specs.push(spec.split(':').collect());
+        } else if let Some(spec) = arg.strip_prefix("--two-bone=") {
+            two_bone_specs.push(spec.split(':').collect());
+        } else if let Some(solver) = arg.strip_prefix("--ik-solver=") {
+            ik_solver = match solver {
+                "ccd" => ik_chain::IkSolver::Ccd,
+                "fabrik" => ik_chain::IkSolver::Fabrik,
+                _ => {
+                    eprintln!("invalid ik solver '{}'", arg);
+                    return;
+                }
+            };
+        } else if arg == "--morph" {
+            let (model, animation) = morph_demo();
+            morph = Some(model);
+            morph_animation = Some(animation);
+        } else if let Some(files) = arg.strip_prefix("--morph=") {
+            let files: Vec<&str> = files.split(',').collect();
+            match morph_model::MorphModel::from_obj(files[0], &files[1..]) {
+                Ok(model) => morph = Some(model),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return;
+                }
+            }
+        } else if arg == "--compress" || arg.starts_with("--compress=") {
+            compress = compression_options(arg.strip_prefix("--compress="));
+            if compress.is_none() {
+                eprintln!("invalid tolerance '{}'", arg);
+                return;
+            }
+        } else if let Some(file) = arg.strip_prefix("--retarget=") {
+            retarget_rig = Some(file);
+        } else if let Some(file) = arg.strip_prefix("--retarget-map=") {
+            retarget_map = Some(file);
+        } else if arg == "--root-motion" {
+            root_motion = Some(root_motion::RootMotion::new());
+        } else if arg == "--primitives" {
+            let makers: [&dyn Fn(&mut model::Model); 8] = [
+                &|model| model.make_uv_sphere(0.5, 24, 16),
+                &|model| model.make_icosphere(0.5, 2),
+                &|model| model.make_cylinder(0.4, 1.0, 24, 1),
+                &|model| model.make_cone(0.5, 1.0, 24),
+                &|model| model.make_capsule(0.3, 1.0, 24, 8),
+                &|model| model.make_torus(0.4, 0.15, 32, 12),
+                &|model| model.make_plane(1.0, 1.0, 4, 4),
+                &|model| model.make_arrow(1.0, 0.08, 0.2, 0.3, 16),
+            ];
+            for (index, make) in makers.iter().enumerate() {
+                let mut model = model::Model::new();
+                make(&mut model);
+                meshes.push((model, glm::translation(&glm::vec3(1.5 * index as f32 - 5.25, 0.0, 0.0))));
+            }
+        } else if arg == "--cloth" || arg.starts_with("--cloth=") {
+            let options = cloth::ClothOptions::new();
+            let mut new_cloth = cloth::Cloth::new(glm::vec3(-1.0, 2.5, 0.0), options);
+            let count = new_cloth.get_particles().get_num_particles();
+            let pins: Vec<usize> = match arg.strip_prefix("--cloth=").unwrap_or("corners") {
+                "corners" => vec![0, options.resolution_x - 1],
+                "top" => (0..options.resolution_x).collect(),
+                "none" => Vec::new(),
+                list => match list.split(',').map(|particle| particle.parse::<usize>()).collect::<Result<Vec<_>, _>>() {
+                    Ok(pins) if pins.iter().all(|&particle| particle < count) => pins,
+                    _ => {
+                        eprintln!("invalid cloth pins '{}'", arg);
+                        return;
+                    }
+                },
+            };
+            for particle in 0..count {
+                new_cloth.set_pinned(particle, pins.contains(&particle));
+            }
+            cloth = Some(new_cloth);
+        } else if let Some(kind) = arg.strip_prefix("--xpbd=") {
+            let options = xpbd::XpbdOptions::new();
+            let offset = glm::vec3(bodies.len() as f32 * 0.5, 0.0, -(bodies.len() as f32));
+            bodies.push(match kind {
+                "rope" => xpbd::XpbdBody::rope(glm::vec3(-2.0, 3.0, 0.0) + offset, glm::vec3(0.0, 3.0, 0.0) + offset, 20, 0.5, options),
+                "cloth" => xpbd::XpbdBody::cloth(glm::vec3(-1.0, 2.5, 0.0) + offset, 2.0, 2.0, 20, 20, 1.0, options),
+                "blob" => xpbd::XpbdBody::blob(glm::vec3(0.0, 3.0, 0.0) + offset, 0.5, 2, 1.0, options),
+                _ => {
+                    eprintln!("invalid xpbd body '{}'", arg);
+                    return;
+                }
+            });
+        } else if let Some(kind) = arg.strip_prefix("--rigid=") {
+            rigid_world = rigid_scene(kind);
+            if rigid_world.is_none() {
+                eprintln!("invalid rigid scene '{}'", arg);
+                return;
+            }
+        } else if arg == "--gpu-particles" || arg.starts_with("--gpu-particles=") {
+            let count = match arg.strip_prefix("--gpu-particles=").map(|count| count.parse::<usize>()) {
+                None => 1_000_000,
+                Some(Ok(count)) => count,
+                Some(Err(_)) => {
+                    eprintln!("invalid particle count '{}'", arg);
+                    return;
+                }
+            };
+            gpu_particles = Some(gpu_particles::GpuParticleSystem::new(gpu_particles::GpuParticleOptions::new(count)));
+            // Compute shaders need GL 4.3, so only build it when it's used
+            if particle_compute_program.is_none() {
+                particle_compute_program = Some(ShaderProgram::from_file("particles", ProgramType::Compute));
+            }
+        } else if arg == "--particles" || arg.starts_with("--particles=") {
+            let shape = match arg.strip_prefix("--particles=").unwrap_or("point") {
+                "point" => particle_system::EmitterShape::Point,
+                "box" => particle_system::EmitterShape::Box { half_extents: glm::vec3(0.5, 0.05, 0.5) },
+                "sphere" => particle_system::EmitterShape::Sphere { radius: 0.3 },
+                "mesh" => {
+                    let (vertices, indices) = primitives::MeshData::torus(0.6, 0.15, 32, 12).into_parts();
+                    particle_system::EmitterShape::Mesh { positions: vertices.iter().map(|v| v.get_position()).collect(), indices }
+                },
+                _ => {
+                    eprintln!("invalid emitter shape '{}'", arg);
+                    return;
+                }
+            };
+            let mut system = particle_system::ParticleSystem::new(20000);
+            let mut fountain = particle_system::Emitter::new(shape, glm::vec3(0.0, 0.0, 0.0));
+            fountain.rate = 400.0;
+            fountain.lifetime = (2.0, 3.5);
+            fountain.speed = (4.0, 6.0);
+            fountain.spread = 0.25;
+            system.add_emitter(fountain);
+            let mut sparks = particle_system::Emitter::new(particle_system::EmitterShape::Sphere { radius: 0.3 }, glm::vec3(0.0, 2.0, 0.0));
+            sparks.rate = 0.0;
+            sparks.spread = glm::pi::<f32>();
+            sparks.speed = (2.0, 4.0);
+            system.add_emitter(sparks);
+            system.add_force(particle_system::ForceField::Gravity(glm::vec3(0.0, -9.8, 0.0)));
+            system.add_force(particle_system::ForceField::Drag(0.3));
+            system.add_force(particle_system::ForceField::Vortex { center: glm::vec3(0.0, 0.0, 0.0), axis: glm::vec3(0.0, 1.0, 0.0), strength: 3.0 });
+            system.add_force(particle_system::ForceField::Noise { strength: 2.0, frequency: 1.5 });
+            system.set_ground(Some(0.0));
+            let mut size = particle_system::Curve::new(0.03);
+            size.add_key(0.2, 0.08);
+            size.add_key(1.0, 0.02);
+            system.set_size(size);
+            let mut color = particle_system::Curve::new(glm::vec4(1.0, 0.9, 0.3, 1.0));
+            color.add_key(0.5, glm::vec4(1.0, 0.3, 0.1, 0.8));
+            color.add_key(1.0, glm::vec4(0.3, 0.3, 0.3, 0.0));
+            system.set_color(color);
+            particles = Some(system);
+        } else if let Some(value) = arg.strip_prefix("--wind=") {
+            let components: Vec<f32> = value.split(',').filter_map(|component| component.parse().ok()).collect();
+            if components.len() != 3 {
+                eprintln!("invalid wind '{}'", arg);
+                return;
+            }
+            wind = glm::vec3(components[0], components[1], components[2]);
+        }
+    }
+    for arg in args.iter().filter(|arg| !arg.starts_with("--")) {
+        let loaded: Result<(), Box<dyn std::error::Error>> = if arg.ends_with(".skel") {
+            skeleton::Skeleton::from_file(arg).map(|loaded| skeleton = Some(loaded)).map_err(Into::into)
+        } else if arg.ends_with(".skin") {
+            skinned_model::SkinnedModel::from_file(arg).map(|loaded| skin = Some(loaded)).map_err(Into::into)
+        } else if arg.ends_with(".anim") {
+            animation::Animation::from_file(arg).map(|loaded| clips.push(Rc::new(loaded))).map_err(Into::into)
+        } else if arg.ends_with(".morph") {
+            morph_model::MorphAnimation::from_file(arg).map(|loaded| morph_animation = Some(loaded)).map_err(Into::into)
+        } else if arg.ends_with(".bvh") {
+            bvh::Bvh::from_file(arg, bvh_options).map(|loaded| {
+                let (loaded_skeleton, loaded_animation) = loaded.into_parts();
+                skeleton = Some(loaded_skeleton);
+                clips.push(Rc::new(loaded_animation));
+            }).map_err(Into::into)
+        } else if arg.ends_with(".graph") {
+            state_machine::StateMachine::from_file(arg, bvh_options).map(|loaded| graph = Some(loaded)).map_err(Into::into)
+        } else if arg.ends_with(".gltf") || arg.ends_with(".glb") {
+            gltf_loader::GltfScene::from_file(arg).map(|loaded| {
+                for warning in loaded.get_warnings() {
+                    eprintln!("warning: {}", warning);
+                }
+                gltf_scene = Some(loaded);
+            }).map_err(Into::into)
+        } else if arg.ends_with(".obj") {
+            obj::ObjMesh::from_file(arg).map(|loaded| {
+                for warning in loaded.get_warnings() {
+                    eprintln!("warning: {}", warning);
+                }
+                meshes.push((model::Model::from_obj(&loaded), glm::Mat4::identity()));
+            }).map_err(Into::into)
+        } else {
+            eprintln!("ignoring unrecognized file '{}'", arg);
+            Ok(())
+        };
+        if let Err(err) = loaded {
+            eprintln!("{}", err);
+            return;
+        }
+    }
+    if let Some(skin) = skin.as_mut() {
+        let num_joints = skeleton.as_ref().map_or(0, |skeleton| skeleton.get_num_joints());
+        if skin.get_num_joints() > num_joints {
+            eprintln!("skin has {} bindings but the skeleton only has {} joints", skin.get_num_joints(), num_joints);
+            return;
+        }
+        skin.make_model();
+    }
+    if let (Some(options), Some(skeleton)) = (compress, skeleton.as_ref()) {
+        for (index, clip) in clips.iter_mut().enumerate() {
+            match compress_clip(index, clip, skeleton, options) {
+                Ok(compressed) => *clip = Rc::new(compressed.decompress(skeleton)),
+                Err(e) => eprintln!("clip {} left uncompressed: {}", index, e),
+            }
+        }
+    }
+    // Second rig and the clips baked onto it, in the same order as the source clips
+    let mut retargeted: Option<(skeleton::Skeleton, Vec<animation::Animation>)> = None;
+    if let Some(file) = retarget_rig {
+        let source = match skeleton.as_ref() {
+            Some(source) => source,
+            None => {
+                eprintln!("--retarget needs a source skeleton");
+                return;
+            }
+        };
+        let loaded: Result<skeleton::Skeleton, Box<dyn std::error::Error>> = if file.ends_with(".bvh") {
+            bvh::Bvh::from_file(file, bvh_options).map(|loaded| loaded.into_parts().0).map_err(Into::into)
+        } else {
+            skeleton::Skeleton::from_file(file).map_err(Into::into)
+        };
+        let mut target = match loaded {
+            Ok(target) => target,
+            Err(err) => {
+                eprintln!("{}", err);
+                return;
+            }
+        };
+        let map = match retarget_map {
+            Some(file) => match retarget::JointMap::from_file(file) {
+                Ok(map) => map,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return;
+                }
+            },
+            None => retarget::JointMap::by_name(source, &target),
+        };
+        let retargeter = match retarget::Retargeter::new(source, &target, &map, retarget::RetargetOptions::new()) {
+            Ok(retargeter) => retargeter,
+            Err(err) => {
+                eprintln!("{}", err);
+                return;
+            }
+        };
+        println!("retargeted {} of {} joints, root travel scaled by {:.3}", retargeter.get_num_mapped(), target.get_num_joints(), retargeter.get_scale());
+        let target_clips = clips.iter().map(|clip| retargeter.retarget_clip(clip, source, &target)).collect();
+        // Side by side with the source rig
+        let spacing = retargeter.get_source_leg_length() + retargeter.get_target_leg_length();
+        target.set_transform(glm::translation(&glm::vec3(spacing.max(1.0), 0.0, 0.0)));
+        target.make_models();
+        retargeted = Some((target, target_clips));
+    }
+    if let Some(skeleton) = skeleton.as_mut() {
+        skeleton.make_models();
+    }
+    // Chains with the radius their target markers are drawn at
+    let mut ik_chains: Vec<(ik_chain::IkChain, f32)> = Vec::new();
+    if !ik_specs.is_empty() {
+        let skeleton = match skeleton.as_mut() {
+            Some(skeleton) => skeleton,
+            None => {
+                eprintln!("--ik needs a skeleton");
+                return;
+            }
+        };
+        skeleton.update();
+        for spec in &ik_specs {
+            let (limit_specs, names): (Vec<&str>, Vec<&str>) = spec.iter().partition(|part| part.contains('='));
+            let mut joints = Vec::new();
+            for name in &names {
+                match skeleton.find_joint(name) {
+                    Some(joint) => joints.push(joint),
+                    None => {
+                        eprintln!("unknown ik joint '{}'", name);
+                        return;
+                    }
+                }
+            }
+            let mut chain = match joints.get(1).and_then(|&tip| ik_chain::IkChain::new(skeleton, joints[0], tip)) {
+                Some(chain) => chain,
+                None => {
+                    eprintln!("invalid ik chain '{}'", spec.join(":"));
+                    return;
+                }
+            };
+            chain.set_solver(ik_solver);
+            for &joint in &joints[1..] {
+                if chain.add_target(joint, ik::world_position(&skeleton.get_world_mat(joint))).is_none() {
+                    eprintln!("ik joint '{}' is not below '{}'", skeleton.get_joint(joint).get_name(), spec[0]);
+                    return;
+                }
+            }
+            for limit_spec in &limit_specs {
+                let limit = limit_spec.split_once('=').and_then(|(name, limit)| Some((skeleton.find_joint(name)?, ik_limit(limit)?)));
+                if !limit.is_some_and(|(joint, limit)| chain.add_limit(joint, limit)) {
+                    eprintln!("invalid ik limit '{}'", limit_spec);
+                    return;
+                }
+            }
+            let length: f32 = chain.get_joints().windows(2)
+                .map(|pair| glm::length(&skeleton.get_joint(pair[1]).get_offset()))
+                .sum();
+            ik_chains.push((chain, 0.03 * length));
+        }
+    }
+    // Limbs with their target and the radius its marker is drawn at
+    let mut limbs: Vec<(ik::TwoBoneIk, glm::Vec3, f32)> = Vec::new();
+    if !two_bone_specs.is_empty() {
+        let skeleton = match skeleton.as_mut() {
+            Some(skeleton) => skeleton,
+            None => {
+                eprintln!("--two-bone needs a skeleton");
+                return;
+            }
+        };
+        skeleton.update();
+        for spec in &two_bone_specs {
+            let mut limb = match skeleton.find_joint(spec[0]).and_then(|end| ik::TwoBoneIk::from_end(skeleton, end)) {
+                Some(limb) => limb,
+                None => {
+                    eprintln!("invalid two bone limb '{}'", spec[0]);
+                    return;
+                }
+            };
+            for part in &spec[1..] {
+                let valid = match part.split_once('=') {
+                    Some(("pole", pole)) => {
+                        let pole: Vec<f32> = pole.split(',').filter_map(|value| value.parse().ok()).collect();
+                        if pole.len() == 3 {
+                            limb.set_pole(Some(glm::vec3(pole[0], pole[1], pole[2])));
+                        }
+                        pole.len() == 3
+                    },
+                    Some(("soft", softness)) => softness.parse().map(|softness| limb.set_softness(softness)).is_ok(),
+                    _ => false,
+                };
+                if !valid {
+                    eprintln!("invalid two bone option '{}'", part);
+                    return;
+                }
+            }
+            let [_, middle, end] = limb.get_joints();
+            let target = ik::world_position(&skeleton.get_world_mat(end));
+            let length = glm::length(&skeleton.get_joint(middle).get_offset()) + glm::length(&skeleton.get_joint(end).get_offset());
+            limbs.push((limb, target, 0.03 * length));
+        }
+    }
+    // Sphere and ground the simulations collide with
+    let obstacle_center = glm::vec3(0.0, 1.0, 0.3);
+    let obstacle_radius = 0.4;
+    let mut obstacle_model = model::Model::new();
+    let mut ground_model = model::Model::new();
+    if let Some(cloth) = cloth.as_mut() {
+        cloth.set_wind(wind);
+        cloth.set_ground(Some(0.0));
+        cloth.add_sphere(obstacle_center, obstacle_radius);
+        cloth.make_model();
+    }
+    for body in &mut bodies {
+        body.set_ground(Some(0.0));
+        body.add_sphere(obstacle_center, obstacle_radius);
+        body.make_model();
+    }
+    if let Some(particles) = particles.as_mut() {
+        particles.make_model();
+    }
+    if let Some(world) = rigid_world.as_mut() {
+        world.make_model();
+    }
+    if let Some(morph) = morph.as_mut() {
+        morph.make_model();
+    }
+    if cloth.is_some() || !bodies.is_empty() || rigid_world.is_some() {
+        ground_model.make_plane(8.0, 8.0, 1, 1);
+    }
+    if cloth.is_some() || !bodies.is_empty() {
+        // A little smaller so whatever rests on it doesn't z-fight
+        obstacle_model.make_uv_sphere(obstacle_radius * 0.95, 24, 16);
+    }
+    let mut target_model = model::Model::new();
+    target_model.make_uv_sphere(1.0, 12, 8);
+    // Chain and target, or limb and no target, being dragged with ctrl + left
+    // button, and its depth
+    let mut dragging: Option<(usize, Option<usize>, f32)> = None;
+    if let Some(scene) = gltf_scene.as_mut() {
+        scene.make_models();
+    }
+    // N cross-fades to the next loaded clip or glTF animation, L fades the layers over it in and out
+    let mut clip_index = 0;
+    let mut stack = clips.first().map(|clip| layers::LayerStack::new(blender::CrossFade::new(animation::AnimationPlayer::new(clip.clone()))));
+    let mut layer_weights: Vec<f32> = Vec::new();
+    let mut layers_on = true;
+    if !layer_specs.is_empty() {
+        let (stack, skeleton) = match (stack.as_mut(), skeleton.as_ref()) {
+            (Some(stack), Some(skeleton)) => (stack, skeleton),
+            _ => {
+                eprintln!("--layer needs a skeleton and a clip to play over");
+                return;
+            }
+        };
+        for spec in &layer_specs {
+            // Several clips joined with + are mixed, each with an optional @<weight>
+            let mut clips = Vec::new();
+            for part in spec[0].split('+') {
+                let (file, weight) = match part.split_once('@') {
+                    Some((file, weight)) => match weight.parse::<f32>() {
+                        Ok(weight) => (file, weight),
+                        Err(_) => {
+                            eprintln!("invalid clip weight '{}'", part);
+                            return;
+                        }
+                    },
+                    None => (part, 1.0),
+                };
+                let loaded: Result<animation::Animation, Box<dyn std::error::Error>> = if file.ends_with(".bvh") {
+                    bvh::Bvh::from_file(file, bvh_options).map(|loaded| loaded.into_parts().1).map_err(Into::into)
+                } else {
+                    animation::Animation::from_file(file).map_err(Into::into)
+                };
+                match loaded {
+                    Ok(clip) => clips.push((Rc::new(clip), weight)),
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        return;
+                    }
+                }
+            }
+            let clip = clips[0].0.clone();
+            let mut mode = layers::LayerMode::Override;
+            let mut weight = 1.0;
+            let mut mask: Option<layers::JointMask> = None;
+            for &part in &spec[1..] {
+                if part == "additive" {
+                    mode = layers::LayerMode::Additive { reference: layers::reference_pose(&clip, skeleton) };
+                } else if part == "override" {
+                    mode = layers::LayerMode::Override;
+                } else if let Ok(value) = part.parse::<f32>() {
+                    weight = value;
+                } else if let Some(joint) = skeleton.find_joint(part) {
+                    mask.get_or_insert_with(|| layers::JointMask::new(skeleton.get_num_joints(), 0.0)).set_subtree(skeleton, joint, 1.0);
+                } else {
+                    eprintln!("unknown layer joint '{}'", part);
+                    return;
+                }
+            }
+            let source: Box<dyn blender::PoseSource> = if clips.len() == 1 {
+                Box::new(animation::AnimationPlayer::new(clip))
+            } else {
+                let mut mix = blender::ClipBlender::new();
+                for (clip, weight) in clips {
+                    mix.add_clip(animation::AnimationPlayer::new(clip), weight);
+                }
+                Box::new(mix)
+            };
+            let mut layer = layers::AnimationLayer::new(source, mode);
+            layer.set_weight(weight);
+            layer.set_mask(mask);
+            layer_weights.push(layer.get_weight());
+            stack.add_layer(layer);
+        }
+    }
+
     let mut running = true;
     let now = Instant::now();
     let mut last_time = now.elapsed();
 
     while running {
         events_loop.poll_events(|event| {
-            match event {
-                Event::WindowEvent { event, .. } => match event {
+            if let Event::WindowEvent { event, .. } = event {
+                match event {
                     glutin::WindowEvent::CloseRequested => running = false,
                     glutin::WindowEvent::Resized(logical_size) => {
                         let dpi_factor = gl_window.get_hidpi_factor();
@@ -80,15 +985,130 @@ fn run() {
                         camera.set_aspect(width / height);
                     },
                     glutin::WindowEvent::KeyboardInput { input, .. } => {
+                        if let (Some(graph), Some(key)) = (graph.as_mut(), input.virtual_keycode) {
+                            graph.on_key(&format!("{:?}", key), input.state == glutin::ElementState::Pressed);
+                        }
                         match input.virtual_keycode {
-                            Some(glutin::VirtualKeyCode::R) => {
-                                if input.state == glutin::ElementState::Pressed {
-                                    camera.reset();
-                                    camera.set_aspect(900.0/700.0);
-                                    cube.reset();
+                            Some(glutin::VirtualKeyCode::R) if input.state == glutin::ElementState::Pressed => {
+                                camera.reset();
+                                camera.set_aspect(900.0/700.0);
+                                cube.reset();
+                                if let Some(skeleton) = skeleton.as_mut() {
+                                    skeleton.reset();
+                                }
+                                if let Some(stack) = stack.as_mut() {
+                                    stack.reset();
+                                }
+                                if let Some(graph) = graph.as_mut() {
+                                    graph.reset();
+                                }
+                                if let Some(scene) = gltf_scene.as_mut() {
+                                    scene.reset();
+                                }
+                                if let Some(cloth) = cloth.as_mut() {
+                                    cloth.reset();
+                                }
+                                for body in &mut bodies {
+                                    body.reset();
+                                }
+                                if let Some(particles) = particles.as_mut() {
+                                    particles.reset();
+                                }
+                                if let Some(gpu_particles) = gpu_particles.as_mut() {
+                                    gpu_particles.reset();
+                                }
+                                if let Some(world) = rigid_world.as_mut() {
+                                    world.reset();
+                                }
+                                morph_time = 0.0;
+                                if let (Some(root_motion), Some(skeleton)) = (root_motion.as_mut(), skeleton.as_mut()) {
+                                    root_motion.reset();
+                                    skeleton.set_transform(root_motion.get_world_mat());
+                                }
+                            },
+                            Some(glutin::VirtualKeyCode::G) if input.state == glutin::ElementState::Pressed => {
+                                if let Some(skin) = skin.as_mut() {
+                                    let mode = match skin.get_mode() {
+                                        skinned_model::SkinningMode::Cpu => skinned_model::SkinningMode::Gpu,
+                                        skinned_model::SkinningMode::Gpu => skinned_model::SkinningMode::Cpu,
+                                    };
+                                    println!("{:?} skinning", mode);
+                                    skin.set_mode(mode);
+                                }
+                                if let Some(morph) = morph.as_mut() {
+                                    let mode = match morph.get_mode() {
+                                        morph_model::MorphMode::Cpu => morph_model::MorphMode::Gpu,
+                                        morph_model::MorphMode::Gpu => morph_model::MorphMode::Cpu,
+                                    };
+                                    println!("{:?} morphing", mode);
+                                    morph.set_mode(mode);
+                                }
+                            },
+                            Some(glutin::VirtualKeyCode::Q) if input.state == glutin::ElementState::Pressed => {
+                                if let Some(skin) = skin.as_mut() {
+                                    let method = match skin.get_method() {
+                                        skinned_model::SkinningMethod::Linear => skinned_model::SkinningMethod::DualQuaternion,
+                                        skinned_model::SkinningMethod::DualQuaternion => skinned_model::SkinningMethod::Linear,
+                                    };
+                                    println!("{:?} skinning", method);
+                                    skin.set_method(method);
+                                }
+                            },
+                            Some(glutin::VirtualKeyCode::N) if input.state == glutin::ElementState::Pressed => {
+                                if let Some(stack) = stack.as_mut() {
+                                    clip_index = (clip_index + 1) % clips.len();
+                                    stack.get_base_mut().fade_to(animation::AnimationPlayer::new(clips[clip_index].clone()), 0.3);
+                                }
+                                if let Some(scene) = gltf_scene.as_mut() {
+                                    let count = scene.get_animations().len();
+                                    if count > 0 {
+                                        let next = scene.get_active_animation().map_or(0, |index| (index + 1) % count);
+                                        println!("Playing '{}'", scene.get_animations()[next].get_name());
+                                        scene.set_active_animation(Some(next));
+                                    }
+                                }
+                            },
+                            Some(glutin::VirtualKeyCode::L) if input.state == glutin::ElementState::Pressed => {
+                                if let Some(stack) = stack.as_mut() {
+                                    layers_on = !layers_on;
+                                    for (index, &weight) in layer_weights.iter().enumerate() {
+                                        stack.get_layer_mut(index).fade_to(if layers_on { weight } else { 0.0 }, 0.3);
+                                    }
+                                }
+                            },
+                            Some(glutin::VirtualKeyCode::B) if input.state == glutin::ElementState::Pressed => {
+                                if let Some(particles) = particles.as_mut() {
+                                    particles.get_emitter_mut(1).burst(500);
+                                }
+                            },
+                            Some(glutin::VirtualKeyCode::Space) if input.state == glutin::ElementState::Pressed => {
+                                if let Some(world) = rigid_world.as_mut() {
+                                    let count = world.get_bodies().len() as f32;
+                                    let mut body = rigid_body::RigidBody::new(rigid_body::Shape::Box { half_extents: glm::vec3(0.5, 0.5, 0.5) }, 1.0, glm::vec3(0.0, 8.0, 0.0));
+                                    body.set_orientation(glm::quat_angle_axis(count * 0.9, &glm::normalize(&glm::vec3(1.0, 1.0, 0.0))));
+                                    world.add_body(body);
                                 }
                             },
                             Some(glutin::VirtualKeyCode::Escape) => running = false,
+                            // Arrows and page up/down move pinned cloth and rope particles
+                            Some(key) if (cloth.is_some() || !bodies.is_empty()) && input.state == glutin::ElementState::Pressed => {
+                                let step = 0.05;
+                                let delta = match key {
+                                    glutin::VirtualKeyCode::Left => glm::vec3(-step, 0.0, 0.0),
+                                    glutin::VirtualKeyCode::Right => glm::vec3(step, 0.0, 0.0),
+                                    glutin::VirtualKeyCode::Up => glm::vec3(0.0, 0.0, -step),
+                                    glutin::VirtualKeyCode::Down => glm::vec3(0.0, 0.0, step),
+                                    glutin::VirtualKeyCode::PageUp => glm::vec3(0.0, step, 0.0),
+                                    glutin::VirtualKeyCode::PageDown => glm::vec3(0.0, -step, 0.0),
+                                    _ => glm::vec3(0.0, 0.0, 0.0),
+                                };
+                                if let Some(cloth) = cloth.as_mut() {
+                                    cloth.move_pinned(delta);
+                                }
+                                for body in &mut bodies {
+                                    body.move_pinned(delta);
+                                }
+                            },
                             _ => {}
                         }
                     },
@@ -100,7 +1120,13 @@ fn run() {
                         mouse_x = position.x as f32;
                         mouse_y = position.y as f32;
 
-                        if left_down {
+                        if let (true, Some((index, target, depth))) = (left_down, dragging) {
+                            let position = camera.unproject(mouse_x, mouse_y, depth, width, height);
+                            match target {
+                                Some(target) => ik_chains[index].0.set_target_position(target, position),
+                                None => limbs[index].1 = position,
+                            }
+                        } else if left_down {
                             let rate = 1.0;
                             let azimuth = camera.get_azimuth();
                             let incline = camera.get_incline();
@@ -114,7 +1140,24 @@ fn run() {
                             camera.set_distance(distance);
                         }
                     },
-                    glutin::WindowEvent::MouseInput { state, button, .. } => {
+                    glutin::WindowEvent::MouseInput { state, button, modifiers, .. } => {
+                        dragging = None;
+                        if button == glutin::MouseButton::Left && state == glutin::ElementState::Pressed && modifiers.ctrl {
+                            // Grab the closest target marker within a few pixels
+                            let pick_radius = 12.0;
+                            let mut closest = pick_radius * pick_radius;
+                            let targets = ik_chains.iter().enumerate().flat_map(|(chain_index, (chain, _))| {
+                                chain.get_targets().iter().enumerate().map(move |(target_index, target)| (chain_index, Some(target_index), target.get_position()))
+                            }).chain(limbs.iter().enumerate().map(|(limb_index, (_, target, _))| (limb_index, None, *target)));
+                            for (index, target, position) in targets {
+                                let screen = camera.project(&position, width, height);
+                                let distance = (screen.x - mouse_x).powi(2) + (screen.y - mouse_y).powi(2);
+                                if distance < closest && screen.z.abs() <= 1.0 {
+                                    closest = distance;
+                                    dragging = Some((index, target, screen.z));
+                                }
+                            }
+                        }
                         match button {
                             glutin::MouseButton::Left => left_down = state == glutin::ElementState::Pressed,
                             glutin::MouseButton::Right => right_down = state == glutin::ElementState::Pressed,
@@ -122,16 +1165,13 @@ fn run() {
                             _ => {}
                         }
                     },
-                    glutin::WindowEvent::MouseWheel { delta, .. } => {
-                        if let glutin::MouseScrollDelta::LineDelta(_lines, rows) = delta {
-                            let rate = 0.05;
-                            let distance = glm::clamp_scalar(camera.get_distance() * (1.0 - rows * rate), 0.01, 1000.0);
-                            camera.set_distance(distance);
-                        }
+                    glutin::WindowEvent::MouseWheel { delta: glutin::MouseScrollDelta::LineDelta(_lines, rows), .. } => {
+                        let rate = 0.05;
+                        let distance = glm::clamp_scalar(camera.get_distance() * (1.0 - rows * rate), 0.01, 1000.0);
+                        camera.set_distance(distance);
                     },
                     _ => ()
-                },
-                _ => ()
+                }
             }
         });
 
@@ -144,10 +1184,110 @@ fn run() {
         let sub_nanos = delta_time.subsec_nanos();
         let dt: f32 = secs as f32 + sub_nanos as f32 / 1000000000.0f32;
         camera.update();
-        cube.update(dt);
-        cube.draw(camera.get_view_proj_mat(), shader_program.id());
-        cube2.update(dt);
-        cube2.draw(camera.get_view_proj_mat(), shader_program.id());
+        for (mesh, model_mat) in &meshes {
+            mesh.draw(*model_mat, camera.get_view_proj_mat(), shader_program.id());
+        }
+        if let Some(cloth) = cloth.as_mut() {
+            cloth.update(dt);
+            cloth.draw(camera.get_view_proj_mat(), shader_program.id());
+        }
+        for body in &mut bodies {
+            body.update(dt);
+            body.draw(camera.get_view_proj_mat(), shader_program.id());
+        }
+        if let Some(morph) = morph.as_mut() {
+            morph_time += dt;
+            let weights = match morph_animation.as_ref() {
+                Some(animation) => animation.sample(morph_time, morph),
+                None => vec![0.0; morph.get_num_targets()],
+            };
+            let shader = match morph.get_mode() {
+                morph_model::MorphMode::Cpu => shader_program.id(),
+                morph_model::MorphMode::Gpu => morph_program.id(),
+            };
+            // Two instances of the same mesh, the second with every weight flipped
+            let flipped: Vec<f32> = weights.iter().map(|weight| 1.0 - weight).collect();
+            morph.draw(glm::translation(&glm::vec3(-1.5, 0.0, 0.0)), &weights, camera.get_view_proj_mat(), shader);
+            morph.draw(glm::translation(&glm::vec3(1.5, 0.0, 0.0)), &flipped, camera.get_view_proj_mat(), shader);
+        }
+        if let Some(world) = rigid_world.as_mut() {
+            world.update(dt);
+            world.draw(camera.get_view_proj_mat(), shader_program.id());
+        }
+        if cloth.is_some() || !bodies.is_empty() {
+            obstacle_model.draw(glm::translation(&obstacle_center), camera.get_view_proj_mat(), shader_program.id());
+        }
+        if cloth.is_some() || !bodies.is_empty() || rigid_world.is_some() {
+            ground_model.draw(glm::Mat4::identity(), camera.get_view_proj_mat(), shader_program.id());
+        }
+        if let Some(scene) = gltf_scene.as_mut() {
+            scene.update(dt);
+            scene.draw(camera.get_view_proj_mat(), shader_program.id());
+        }
+        if let Some(skeleton) = skeleton.as_mut() {
+            if let Some(graph) = graph.as_mut() {
+                graph.update(dt);
+                graph.pose(skeleton);
+            } else if let Some(stack) = stack.as_mut() {
+                let before = stack.get_base().get_current().get_time();
+                stack.update(dt);
+                let mut pose = stack.evaluate(skeleton);
+                if let Some(root_motion) = root_motion.as_mut() {
+                    let player = stack.get_base().get_current();
+                    root_motion.apply(&root_motion::clip_displacement(player.get_animation(), skeleton, before, player.get_time(), true));
+                    root_motion::strip_root(&mut pose);
+                    skeleton.set_transform(root_motion.get_world_mat());
+                }
+                pose.apply(skeleton);
+            }
+            for (chain, _) in &ik_chains {
+                let mut pose = pose::Pose::from_skeleton(skeleton);
+                chain.solve(skeleton, &mut pose);
+                pose.apply(skeleton);
+            }
+            for (limb, target, _) in &limbs {
+                let mut pose = pose::Pose::from_skeleton(skeleton);
+                limb.solve(skeleton, &mut pose, *target);
+                pose.apply(skeleton);
+            }
+            skeleton.update();
+            if let Some(skin) = skin.as_mut() {
+                skin.update(skeleton);
+                let shader = match skin.get_mode() {
+                    skinned_model::SkinningMode::Cpu => shader_program.id(),
+                    skinned_model::SkinningMode::Gpu => skinning_program.id(),
+                };
+                skin.draw(camera.get_view_proj_mat(), shader);
+            } else {
+                skeleton.draw(camera.get_view_proj_mat(), shader_program.id());
+            }
+            let targets = ik_chains.iter()
+                .flat_map(|(chain, radius)| chain.get_targets().iter().map(move |target| (target.get_position(), *radius)))
+                .chain(limbs.iter().map(|(_, target, radius)| (*target, *radius)));
+            for (position, radius) in targets {
+                let model_mat = glm::translation(&position) * glm::scaling(&glm::vec3(radius, radius, radius));
+                target_model.draw(model_mat, camera.get_view_proj_mat(), shader_program.id());
+            }
+            if let (Some((target, target_clips)), Some(stack)) = (retargeted.as_mut(), stack.as_ref()) {
+                target_clips[clip_index].sample(stack.get_base().get_current().get_time(), target).apply(target);
+                target.update();
+                target.draw(camera.get_view_proj_mat(), shader_program.id());
+            }
+        } else if gltf_scene.is_none() && meshes.is_empty() && cloth.is_none() && bodies.is_empty() && particles.is_none() && gpu_particles.is_none() && rigid_world.is_none() && morph.is_none() {
+            cube.update(dt);
+            cube.draw(camera.get_view_proj_mat(), shader_program.id());
+            cube2.update(dt);
+            cube2.draw(camera.get_view_proj_mat(), shader_program.id());
+        }
+        // Last, since they are blended over everything else without writing depth
+        if let Some(particles) = particles.as_mut() {
+            particles.update(dt);
+            particles.draw(camera.get_view_proj_mat(), particle_program.id());
+        }
+        if let (Some(gpu_particles), Some(compute_program)) = (gpu_particles.as_mut(), particle_compute_program.as_ref()) {
+            gpu_particles.update(compute_program, dt);
+            gpu_particles.draw(camera.get_view_proj_mat(), gpu_particle_program.id());
+        }
         last_time = current_time;
 
         gl_window.swap_buffers().unwrap();
@@ -1,19 +1,243 @@
 use gl::types::*;
 use crate::buffer::*;
+use crate::obj::*;
+use crate::primitives::*;
 
-#[allow(dead_code)]
-struct ModelVertex {
+pub trait Vertex {
+    fn setup_attributes();
+}
+
+// Per-instance data for Model::draw_instanced, laid out from
+// FIRST_INSTANCE_LOCATION on so it never clashes with vertex attributes
+pub trait Instance {
+    fn setup_attributes();
+}
+
+pub const FIRST_INSTANCE_LOCATION: GLuint = 4;
+
+pub fn float_attribute(location: GLuint, size: GLint, stride: usize, offset: usize) {
+    unsafe {
+        gl::EnableVertexAttribArray(location);
+        gl::VertexAttribPointer(
+            location,
+            size,
+            gl::FLOAT,
+            gl::FALSE,
+            stride as GLsizei,
+            offset as *const GLvoid
+        );
+    }
+}
+
+// A float attribute that advances once per instance instead of per vertex
+pub fn instance_attribute(location: GLuint, size: GLint, stride: usize, offset: usize) {
+    float_attribute(location, size, stride, offset);
+    unsafe {
+        gl::VertexAttribDivisor(location, 1);
+    }
+}
+
+pub fn uint_attribute(location: GLuint, size: GLint, stride: usize, offset: usize) {
+    unsafe {
+        gl::EnableVertexAttribArray(location);
+        gl::VertexAttribIPointer(
+            location,
+            size,
+            gl::UNSIGNED_INT,
+            stride as GLsizei,
+            offset as *const GLvoid
+        );
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ModelVertex {
     position: glm::Vec3,
     normal: glm::Vec3
 }
 
 impl ModelVertex {
-    fn new(position: glm::Vec3, normal: glm::Vec3) -> ModelVertex {
+    pub fn new(position: glm::Vec3, normal: glm::Vec3) -> ModelVertex {
         ModelVertex {
             position,
             normal
         }
     }
+
+    pub fn get_position(&self) -> glm::Vec3 {
+        self.position
+    }
+
+    pub fn get_normal(&self) -> glm::Vec3 {
+        self.normal
+    }
+}
+
+impl Vertex for ModelVertex {
+    fn setup_attributes() {
+        let stride = std::mem::size_of::<ModelVertex>();
+        float_attribute(0, 3, stride, 0);
+        float_attribute(1, 3, stride, 3 * std::mem::size_of::<f32>());
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TexturedVertex {
+    position: glm::Vec3,
+    normal: glm::Vec3,
+    texcoord: glm::Vec2,
+}
+
+impl TexturedVertex {
+    pub fn new(position: glm::Vec3, normal: glm::Vec3, texcoord: glm::Vec2) -> TexturedVertex {
+        TexturedVertex {
+            position,
+            normal,
+            texcoord,
+        }
+    }
+
+    pub fn get_position(&self) -> glm::Vec3 {
+        self.position
+    }
+
+    pub fn get_normal(&self) -> glm::Vec3 {
+        self.normal
+    }
+
+    pub fn get_texcoord(&self) -> glm::Vec2 {
+        self.texcoord
+    }
+}
+
+impl Vertex for TexturedVertex {
+    fn setup_attributes() {
+        let stride = std::mem::size_of::<TexturedVertex>();
+        let float_size = std::mem::size_of::<f32>();
+        float_attribute(0, 3, stride, 0);
+        float_attribute(1, 3, stride, 3 * float_size);
+        float_attribute(2, 2, stride, 6 * float_size);
+    }
+}
+
+pub const MAX_JOINT_INFLUENCES: usize = 4;
+
+// Bind pose vertex of a skinned mesh. Unused influence slots have a weight of zero.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SkinVertex {
+    position: glm::Vec3,
+    normal: glm::Vec3,
+    joints: [u32; MAX_JOINT_INFLUENCES],
+    weights: [f32; MAX_JOINT_INFLUENCES],
+}
+
+impl SkinVertex {
+    // Keeps the heaviest influences and renormalizes them so they sum to one.
+    pub fn new(position: glm::Vec3, normal: glm::Vec3, influences: &[(usize, f32)]) -> SkinVertex {
+        let mut sorted = influences.to_vec();
+        sorted.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        sorted.truncate(MAX_JOINT_INFLUENCES);
+
+        let total: f32 = sorted.iter().map(|&(_, weight)| weight).sum();
+        let mut joints = [0; MAX_JOINT_INFLUENCES];
+        let mut weights = [0.0; MAX_JOINT_INFLUENCES];
+        for (slot, &(joint, weight)) in sorted.iter().enumerate() {
+            joints[slot] = joint as u32;
+            weights[slot] = if total > 0.0 { weight / total } else { 0.0 };
+        }
+        SkinVertex {
+            position,
+            normal,
+            joints,
+            weights,
+        }
+    }
+
+    pub fn get_position(&self) -> glm::Vec3 {
+        self.position
+    }
+
+    pub fn get_normal(&self) -> glm::Vec3 {
+        self.normal
+    }
+
+    pub fn influences(&self) -> impl Iterator<Item = (usize, f32)> + '_ {
+        self.joints.iter().zip(self.weights.iter())
+            .filter(|(_, &weight)| weight > 0.0)
+            .map(|(&joint, &weight)| (joint as usize, weight))
+    }
+}
+
+impl Vertex for SkinVertex {
+    fn setup_attributes() {
+        let stride = std::mem::size_of::<SkinVertex>();
+        let float_size = std::mem::size_of::<f32>();
+        float_attribute(0, 3, stride, 0);
+        float_attribute(1, 3, stride, 3 * float_size);
+        uint_attribute(2, MAX_JOINT_INFLUENCES as GLint, stride, 6 * float_size);
+        float_attribute(3, MAX_JOINT_INFLUENCES as GLint, stride, 6 * float_size + MAX_JOINT_INFLUENCES * std::mem::size_of::<u32>());
+    }
+}
+
+// Area weighted vertex normals for an indexed triangle list
+pub fn smooth_normals(positions: &[glm::Vec3], indices: &[u32]) -> Vec<glm::Vec3> {
+    let mut normals = vec![glm::vec3(0.0, 0.0, 0.0); positions.len()];
+    for triangle in indices.chunks(3) {
+        if triangle.len() < 3 {
+            break;
+        }
+        let (a, b, c) = (triangle[0] as usize, triangle[1] as usize, triangle[2] as usize);
+        let normal = (positions[b] - positions[a]).cross(&(positions[c] - positions[a]));
+        normals[a] += normal;
+        normals[b] += normal;
+        normals[c] += normal;
+    }
+    for normal in &mut normals {
+        let length = glm::length(normal);
+        *normal = if length > 0.0 { *normal / length } else { glm::vec3(0.0, 1.0, 0.0) };
+    }
+    normals
+}
+
+pub const DEFAULT_DIFFUSE_COLOR: [f32; 3] = [0.5, 0.5, 0.5];
+
+// A range of the index buffer drawn with its own diffuse color
+#[derive(Clone, Debug)]
+pub struct Submesh {
+    name: String,
+    first: usize,
+    count: usize,
+    color: Option<glm::Vec3>,
+}
+
+impl Submesh {
+    pub fn new(name: &str, first: usize, count: usize, color: Option<glm::Vec3>) -> Submesh {
+        Submesh {
+            name: name.to_string(),
+            first,
+            count,
+            color,
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_first(&self) -> usize {
+        self.first
+    }
+
+    pub fn get_count(&self) -> usize {
+        self.count
+    }
+
+    pub fn get_color(&self) -> Option<glm::Vec3> {
+        self.color
+    }
 }
 
 pub struct Model {
@@ -21,6 +245,9 @@ pub struct Model {
     index_buffer: ElementArrayBuffer,
     vao: VertexArray,
     count: GLsizei,
+    submeshes: Vec<Submesh>,
+    instance_buffer: Option<ArrayBuffer>,
+    instance_count: GLsizei,
 }
 
 impl Model {
@@ -32,10 +259,20 @@ impl Model {
             vertex_buffer,
             index_buffer,
             vao,
-            count: 0
+            count: 0,
+            submeshes: Vec::new(),
+            instance_buffer: None,
+            instance_count: 0,
         }
     }
 
+    pub fn from_obj(mesh: &ObjMesh) -> Model {
+        let mut model = Model::new();
+        model.set_buffers(mesh.get_vertices(), mesh.get_indices());
+        model.set_submeshes(mesh.get_submeshes().to_vec());
+        model
+    }
+
     pub fn make_box(&mut self, box_min: glm::Vec3, box_max: glm::Vec3) {
         let vertices = vec![
             ModelVertex::new(glm::vec3(box_min.x, box_min.y, box_max.z), glm::vec3(0.0, 0.0, 1.0)),
@@ -81,48 +318,121 @@ impl Model {
         self.set_buffers(&vertices, &indices);
     }
 
-    fn set_buffers(&mut self, vertices: &[ModelVertex], indices: &[u32]) {
+    pub fn make_uv_sphere(&mut self, radius: f32, slices: usize, stacks: usize) {
+        self.set_mesh(&MeshData::uv_sphere(radius, slices, stacks));
+    }
+
+    pub fn make_icosphere(&mut self, radius: f32, subdivisions: usize) {
+        self.set_mesh(&MeshData::icosphere(radius, subdivisions));
+    }
+
+    pub fn make_cylinder(&mut self, radius: f32, height: f32, slices: usize, stacks: usize) {
+        self.set_mesh(&MeshData::cylinder(radius, height, slices, stacks));
+    }
+
+    pub fn make_cone(&mut self, radius: f32, height: f32, slices: usize) {
+        self.set_mesh(&MeshData::cone(radius, height, slices));
+    }
+
+    pub fn make_capsule(&mut self, radius: f32, height: f32, slices: usize, stacks: usize) {
+        self.set_mesh(&MeshData::capsule(radius, height, slices, stacks));
+    }
+
+    pub fn make_torus(&mut self, major_radius: f32, minor_radius: f32, major_segments: usize, minor_segments: usize) {
+        self.set_mesh(&MeshData::torus(major_radius, minor_radius, major_segments, minor_segments));
+    }
+
+    pub fn make_plane(&mut self, width: f32, depth: f32, subdivisions_x: usize, subdivisions_z: usize) {
+        self.set_mesh(&MeshData::plane(width, depth, subdivisions_x, subdivisions_z));
+    }
+
+    pub fn make_arrow(&mut self, length: f32, shaft_radius: f32, head_radius: f32, head_length: f32, slices: usize) {
+        self.set_mesh(&MeshData::arrow(length, shaft_radius, head_radius, head_length, slices));
+    }
+
+    pub fn set_mesh(&mut self, mesh: &MeshData) {
+        self.set_buffers(mesh.get_vertices(), mesh.get_indices());
+    }
+
+    pub fn set_buffers<V: Vertex>(&mut self, vertices: &[V], indices: &[u32]) {
+        self.upload(vertices, indices, gl::STATIC_DRAW);
+    }
+
+    // Same as set_buffers, but hints the driver that the vertices will be
+    // replaced every frame through update_vertices.
+    pub fn set_stream_buffers<V: Vertex>(&mut self, vertices: &[V], indices: &[u32]) {
+        self.upload(vertices, indices, gl::STREAM_DRAW);
+    }
+
+    // For meshes that keep their vertex count but move every frame, like
+    // cloth. Vertices are then overwritten in place with write_vertices.
+    pub fn set_dynamic_buffers<V: Vertex>(&mut self, vertices: &[V], indices: &[u32]) {
+        self.upload(vertices, indices, gl::DYNAMIC_DRAW);
+    }
+
+    pub fn update_vertices<V: Vertex>(&mut self, vertices: &[V]) {
+        self.vertex_buffer.bind();
+        self.vertex_buffer.stream_draw_data(vertices);
+        self.vertex_buffer.unbind();
+    }
+
+    // No more vertices than the last upload
+    pub fn write_vertices<V: Vertex>(&mut self, vertices: &[V]) {
+        self.vertex_buffer.bind();
+        self.vertex_buffer.sub_data(0, vertices);
+        self.vertex_buffer.unbind();
+    }
+
+    fn upload<V: Vertex>(&mut self, vertices: &[V], indices: &[u32], usage: GLenum) {
         self.count = indices.len() as GLsizei;
 
         self.vao.bind();
 
         self.vertex_buffer.bind();
-        self.vertex_buffer.static_draw_data(&vertices);
+        self.vertex_buffer.buffer_data(vertices, usage);
 
         self.index_buffer.bind();
-        self.index_buffer.static_draw_data(&indices);
-
-        unsafe {
-            let pos_location: GLuint = 0;
-            gl::EnableVertexAttribArray(pos_location);
-            gl::VertexAttribPointer(
-                pos_location,
-                3,
-                gl::FLOAT,
-                gl::FALSE,
-                (std::mem::size_of::<ModelVertex>()) as GLsizei,
-                std::ptr::null()
-            );
-
-            let norm_location: GLuint = 1;
-            gl::EnableVertexAttribArray(norm_location);
-            gl::VertexAttribPointer(
-                norm_location,
-                3,
-                gl::FLOAT,
-                gl::FALSE,
-                (std::mem::size_of::<ModelVertex>()) as GLsizei,
-                (3 * std::mem::size_of::<f32>()) as *const GLvoid
-            );
+        self.index_buffer.static_draw_data(indices);
 
-        }
+        V::setup_attributes();
 
         self.vao.unbind();
         self.vertex_buffer.unbind();
         self.index_buffer.unbind();
     }
 
-    pub fn draw(&self, model_mat: glm::Mat4, view_proj_mat: glm::Mat4, shader: GLuint) {
+    pub fn set_submeshes(&mut self, submeshes: Vec<Submesh>) {
+        self.submeshes = submeshes;
+    }
+
+    pub fn get_submeshes(&self) -> &[Submesh] {
+        &self.submeshes
+    }
+
+    // Replaces the per-instance data. The instance buffer is created and
+    // hooked into the vertex array on first use.
+    pub fn set_instances<I: Instance>(&mut self, instances: &[I]) {
+        self.instance_count = instances.len() as GLsizei;
+        match &self.instance_buffer {
+            Some(buffer) => {
+                buffer.bind();
+                buffer.stream_draw_data(instances);
+                buffer.unbind();
+            },
+            None => {
+                let buffer = ArrayBuffer::new();
+                self.vao.bind();
+                buffer.bind();
+                buffer.stream_draw_data(instances);
+                I::setup_attributes();
+                self.vao.unbind();
+                buffer.unbind();
+                self.instance_buffer = Some(buffer);
+            },
+        }
+    }
+
+    fn use_program(shader: GLuint, model_mat: glm::Mat4, view_proj_mat: glm::Mat4) {
         unsafe {
             gl::UseProgram(shader);
             gl::UniformMatrix4fv(gl::GetUniformLocation(shader, b"ModelMtx\0".as_ptr() as _), 1, gl::FALSE, model_mat.as_slice().as_ptr() as _);
@@ -130,10 +440,45 @@ impl Model {
             let mvp_mat = view_proj_mat * model_mat;
             gl::UniformMatrix4fv(gl::GetUniformLocation(shader, b"ModelViewProjMtx\0".as_ptr() as _), 1, gl::FALSE, mvp_mat.as_slice().as_ptr() as _);
         }
+    }
+
+    // Every instance from set_instances in a single draw call
+    pub fn draw_instanced(&self, model_mat: glm::Mat4, view_proj_mat: glm::Mat4, shader: GLuint) {
+        if self.instance_buffer.is_none() || self.instance_count == 0 {
+            return;
+        }
+        Self::use_program(shader, model_mat, view_proj_mat);
+        self.index_buffer.bind();
+        self.vao.bind();
+        unsafe {
+            gl::DrawElementsInstanced(gl::TRIANGLES, self.count, gl::UNSIGNED_INT, std::ptr::null(), self.instance_count);
+        }
+        self.vao.unbind();
+        self.index_buffer.unbind();
+        unsafe { gl::UseProgram(0); }
+    }
+
+    pub fn draw(&self, model_mat: glm::Mat4, view_proj_mat: glm::Mat4, shader: GLuint) {
+        let default_color = glm::make_vec3(&DEFAULT_DIFFUSE_COLOR);
+        let color_location = unsafe { gl::GetUniformLocation(shader, b"DiffuseColor\0".as_ptr() as _) };
+        Self::use_program(shader, model_mat, view_proj_mat);
         self.index_buffer.bind();
         self.vao.bind();
         unsafe {
-            gl::DrawElements(gl::TRIANGLES, self.count, gl::UNSIGNED_INT, std::ptr::null());
+            if self.submeshes.is_empty() {
+                gl::Uniform3fv(color_location, 1, default_color.as_slice().as_ptr());
+                gl::DrawElements(gl::TRIANGLES, self.count, gl::UNSIGNED_INT, std::ptr::null());
+            }
+            for submesh in &self.submeshes {
+                let color = submesh.color.unwrap_or(default_color);
+                gl::Uniform3fv(color_location, 1, color.as_slice().as_ptr());
+                gl::DrawElements(
+                    gl::TRIANGLES,
+                    submesh.count as GLsizei,
+                    gl::UNSIGNED_INT,
+                    (submesh.first * std::mem::size_of::<u32>()) as *const GLvoid
+                );
+            }
         }
         self.vao.unbind();
         self.index_buffer.unbind();
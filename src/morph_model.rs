@@ -0,0 +1,443 @@
+use std::error::Error;
+use std::fmt;
+use gl::types::*;
+use crate::animation::*;
+use crate::buffer::*;
+use crate::model::*;
+use crate::obj::*;
+use crate::tokenizer::*;
+
+// Where targets get blended. Gpu expects the morph_model.vert program at draw
+// time, Cpu works with any program taking ModelVertex.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MorphMode {
+    Cpu,
+    Gpu,
+}
+
+// A target moving a vertex the base mesh doesn't have
+#[derive(Clone, Debug)]
+pub struct MorphError {
+    target: String,
+    vertex: u32,
+    num_vertices: usize,
+}
+
+impl fmt::Display for MorphError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "morph target '{}' moves vertex {} but the base mesh has {} vertices", self.target, self.vertex, self.num_vertices)
+    }
+}
+
+impl Error for MorphError {}
+
+// Position and normal offsets from the base mesh, stored only for the
+// vertices the target moves
+#[derive(Clone, Debug)]
+pub struct MorphTarget {
+    name: String,
+    vertices: Vec<u32>,
+    position_deltas: Vec<glm::Vec3>,
+    normal_deltas: Vec<glm::Vec3>,
+}
+
+impl MorphTarget {
+    pub fn new(name: &str) -> MorphTarget {
+        MorphTarget {
+            name: name.to_string(),
+            vertices: Vec::new(),
+            position_deltas: Vec::new(),
+            normal_deltas: Vec::new(),
+        }
+    }
+
+    // From a delta for every vertex, dropping the ones that don't move
+    pub fn from_dense(name: &str, position_deltas: &[glm::Vec3], normal_deltas: &[glm::Vec3]) -> MorphTarget {
+        let mut target = MorphTarget::new(name);
+        for (vertex, position_delta) in position_deltas.iter().enumerate() {
+            let normal_delta = normal_deltas.get(vertex).copied().unwrap_or(glm::vec3(0.0, 0.0, 0.0));
+            if glm::length(position_delta) > 1e-6 || glm::length(&normal_delta) > 1e-6 {
+                target.add_delta(vertex, *position_delta, normal_delta);
+            }
+        }
+        target
+    }
+
+    // From a second mesh with the same vertices as the base, like an OBJ
+    // exported from the same topology
+    pub fn from_mesh(name: &str, base: &[ModelVertex], shape: &[ModelVertex]) -> MorphTarget {
+        let position_deltas: Vec<glm::Vec3> = base.iter().zip(shape.iter()).map(|(a, b)| b.get_position() - a.get_position()).collect();
+        let normal_deltas: Vec<glm::Vec3> = base.iter().zip(shape.iter()).map(|(a, b)| b.get_normal() - a.get_normal()).collect();
+        MorphTarget::from_dense(name, &position_deltas, &normal_deltas)
+    }
+
+    pub fn add_delta(&mut self, vertex: usize, position_delta: glm::Vec3, normal_delta: glm::Vec3) {
+        self.vertices.push(vertex as u32);
+        self.position_deltas.push(position_delta);
+        self.normal_deltas.push(normal_delta);
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_vertices(&self) -> &[u32] {
+        &self.vertices
+    }
+
+    pub fn get_position_deltas(&self) -> &[glm::Vec3] {
+        &self.position_deltas
+    }
+
+    pub fn get_normal_deltas(&self) -> &[glm::Vec3] {
+        &self.normal_deltas
+    }
+
+    pub fn get_num_deltas(&self) -> usize {
+        self.vertices.len()
+    }
+
+    fn check_vertices(&self, num_vertices: usize) -> Result<(), MorphError> {
+        match self.vertices.iter().find(|&&vertex| vertex as usize >= num_vertices) {
+            Some(&vertex) => Err(MorphError {
+                target: self.name.clone(),
+                vertex,
+                num_vertices,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+// Base vertex plus the range of its entries in the GPU delta list
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct MorphVertex {
+    position: glm::Vec3,
+    normal: glm::Vec3,
+    first_delta: u32,
+    num_deltas: u32,
+}
+
+impl Vertex for MorphVertex {
+    fn setup_attributes() {
+        let stride = std::mem::size_of::<MorphVertex>();
+        let float_size = std::mem::size_of::<f32>();
+        float_attribute(0, 3, stride, 0);
+        float_attribute(1, 3, stride, 3 * float_size);
+        uint_attribute(2, 2, stride, 6 * float_size);
+    }
+}
+
+// A base mesh and any number of targets blended on top of it. Weights come
+// with each draw, so one MorphModel can be drawn several times with
+// different expressions.
+pub struct MorphModel {
+    vertices: Vec<ModelVertex>,
+    indices: Vec<u32>,
+    targets: Vec<MorphTarget>,
+    deformed: Vec<ModelVertex>,
+    mode: MorphMode,
+    model: Option<Model>,
+    gpu_model: Option<Model>,
+    delta_texture: Option<BufferTexture>,
+    weight_texture: Option<BufferTexture>,
+}
+
+impl MorphModel {
+    // Fails if a target moves a vertex past the end of `vertices`
+    pub fn from_parts(vertices: Vec<ModelVertex>, indices: Vec<u32>, targets: Vec<MorphTarget>) -> Result<MorphModel, MorphError> {
+        for target in &targets {
+            target.check_vertices(vertices.len())?;
+        }
+        let deformed = vertices.clone();
+        Ok(MorphModel {
+            vertices,
+            indices,
+            targets,
+            deformed,
+            mode: MorphMode::Cpu,
+            model: None,
+            gpu_model: None,
+            delta_texture: None,
+            weight_texture: None,
+        })
+    }
+
+    // Base and target OBJs must have the same vertices in the same order.
+    // Targets are named after their files.
+    pub fn from_obj(base: &str, targets: &[&str]) -> Result<MorphModel, ParseError> {
+        let to_model_vertices = |mesh: &ObjMesh| -> Vec<ModelVertex> {
+            mesh.get_vertices().iter().map(|v| ModelVertex::new(v.get_position(), v.get_normal())).collect()
+        };
+        let base_mesh = ObjMesh::from_file(base)?;
+        let vertices = to_model_vertices(&base_mesh);
+        let mut morph_targets = Vec::new();
+        for &filename in targets {
+            let shape = to_model_vertices(&ObjMesh::from_file(filename)?);
+            if shape.len() != vertices.len() {
+                return Err(ParseError::new(filename, 0, &format!("{} vertices but the base mesh has {}", shape.len(), vertices.len())));
+            }
+            let name = std::path::Path::new(filename).file_stem().and_then(|stem| stem.to_str()).unwrap_or(filename);
+            morph_targets.push(MorphTarget::from_mesh(name, &vertices, &shape));
+        }
+        MorphModel::from_parts(vertices, base_mesh.get_indices().to_vec(), morph_targets)
+            .map_err(|e| ParseError::new(base, 0, &e.to_string()))
+    }
+
+    pub fn get_vertices(&self) -> &[ModelVertex] {
+        &self.vertices
+    }
+
+    pub fn get_indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    pub fn get_targets(&self) -> &[MorphTarget] {
+        &self.targets
+    }
+
+    pub fn get_num_targets(&self) -> usize {
+        self.targets.len()
+    }
+
+    pub fn find_target(&self, name: &str) -> Option<usize> {
+        self.targets.iter().position(|target| target.name == name)
+    }
+
+    // Before make_model, the GPU deltas are only built there
+    pub fn add_target(&mut self, target: MorphTarget) -> Result<usize, MorphError> {
+        target.check_vertices(self.vertices.len())?;
+        self.targets.push(target);
+        Ok(self.targets.len() - 1)
+    }
+
+    pub fn get_deformed(&self) -> &[ModelVertex] {
+        &self.deformed
+    }
+
+    pub fn get_mode(&self) -> MorphMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: MorphMode) {
+        self.mode = mode;
+    }
+
+    // Uploads both paths so the mode can be switched at any time. The GPU
+    // deltas are regrouped per vertex, two texels per entry: the position
+    // delta with the target index in w, then the normal delta.
+    pub fn make_model(&mut self) {
+        let mut model = Model::new();
+        model.set_stream_buffers(&self.deformed, &self.indices);
+        self.model = Some(model);
+
+        let mut per_vertex: Vec<Vec<(usize, usize)>> = vec![Vec::new(); self.vertices.len()];
+        for (target_index, target) in self.targets.iter().enumerate() {
+            for (entry, &vertex) in target.vertices.iter().enumerate() {
+                per_vertex[vertex as usize].push((target_index, entry));
+            }
+        }
+        let mut vertices = Vec::with_capacity(self.vertices.len());
+        let mut texels: Vec<glm::Vec4> = Vec::new();
+        for (vertex, entries) in self.vertices.iter().zip(per_vertex.iter()) {
+            vertices.push(MorphVertex {
+                position: vertex.get_position(),
+                normal: vertex.get_normal(),
+                first_delta: (texels.len() / 2) as u32,
+                num_deltas: entries.len() as u32,
+            });
+            for &(target_index, entry) in entries {
+                let target = &self.targets[target_index];
+                let position = target.position_deltas[entry];
+                let normal = target.normal_deltas[entry];
+                texels.push(glm::vec4(position.x, position.y, position.z, target_index as f32));
+                texels.push(glm::vec4(normal.x, normal.y, normal.z, 0.0));
+            }
+        }
+        // An empty buffer texture can't be attached
+        if texels.is_empty() {
+            texels.push(glm::vec4(0.0, 0.0, 0.0, 0.0));
+        }
+        let mut gpu_model = Model::new();
+        gpu_model.set_buffers(&vertices, &self.indices);
+        self.gpu_model = Some(gpu_model);
+        let delta_texture = BufferTexture::new(gl::RGBA32F);
+        delta_texture.set_data(&texels);
+        self.delta_texture = Some(delta_texture);
+        self.weight_texture = Some(BufferTexture::new(gl::R32F));
+    }
+
+    // Blending on the CPU, the reference the shader path is compared against.
+    // Missing weights count as zero.
+    pub fn deform(&mut self, weights: &[f32]) {
+        let mut positions: Vec<glm::Vec3> = self.vertices.iter().map(|v| v.get_position()).collect();
+        let mut normals: Vec<glm::Vec3> = self.vertices.iter().map(|v| v.get_normal()).collect();
+        for (target, &weight) in self.targets.iter().zip(weights.iter()) {
+            if weight == 0.0 {
+                continue;
+            }
+            for ((&vertex, position_delta), normal_delta) in target.vertices.iter().zip(target.position_deltas.iter()).zip(target.normal_deltas.iter()) {
+                positions[vertex as usize] += position_delta * weight;
+                normals[vertex as usize] += normal_delta * weight;
+            }
+        }
+        for (index, deformed) in self.deformed.iter_mut().enumerate() {
+            let length = glm::length(&normals[index]);
+            let normal = if length > 0.0 { normals[index] / length } else { self.vertices[index].get_normal() };
+            *deformed = ModelVertex::new(positions[index], normal);
+        }
+    }
+
+    pub fn draw(&mut self, model_mat: glm::Mat4, weights: &[f32], view_proj_mat: glm::Mat4, shader: GLuint) {
+        match self.mode {
+            MorphMode::Cpu => {
+                self.deform(weights);
+                if let Some(model) = self.model.as_mut() {
+                    model.update_vertices(&self.deformed);
+                    model.draw(model_mat, view_proj_mat, shader);
+                }
+            },
+            MorphMode::Gpu => {
+                if let (Some(model), Some(delta_texture), Some(weight_texture)) =
+                    (self.gpu_model.as_ref(), self.delta_texture.as_ref(), self.weight_texture.as_ref()) {
+                    let mut padded = weights.to_vec();
+                    padded.resize(self.targets.len().max(1), 0.0);
+                    weight_texture.set_data(&padded);
+                    delta_texture.bind(0);
+                    weight_texture.bind(1);
+                    unsafe {
+                        gl::ProgramUniform1i(shader, gl::GetUniformLocation(shader, b"MorphDeltas\0".as_ptr() as _), 0);
+                        gl::ProgramUniform1i(shader, gl::GetUniformLocation(shader, b"MorphWeights\0".as_ptr() as _), 1);
+                    }
+                    model.draw(model_mat, view_proj_mat, shader);
+                }
+            },
+        }
+    }
+}
+
+// Keyframe curves driving target weights by name:
+//
+// morph {
+//     range 0 2
+//     target smile {
+//         extrapolate cycle cycle
+//         keys 2 { 0 0 smooth smooth 1 1 smooth smooth }
+//     }
+// }
+#[derive(Clone, Debug)]
+pub struct MorphAnimation {
+    start_time: f32,
+    end_time: f32,
+    channels: Vec<(String, Channel)>,
+}
+
+impl MorphAnimation {
+    pub fn new(start_time: f32, end_time: f32) -> MorphAnimation {
+        MorphAnimation {
+            start_time,
+            end_time,
+            channels: Vec::new(),
+        }
+    }
+
+    pub fn from_file(filename: &str) -> Result<MorphAnimation, ParseError> {
+        let mut tokenizer = Tokenizer::from_file(filename)?;
+        Self::parse(&mut tokenizer)
+    }
+
+    pub fn parse(tokenizer: &mut Tokenizer) -> Result<MorphAnimation, ParseError> {
+        tokenizer.expect("morph")?;
+        tokenizer.expect("{")?;
+        let mut animation = MorphAnimation::new(0.0, 0.0);
+        loop {
+            let line = tokenizer.get_line();
+            let token = tokenizer.next_token()?;
+            match token.as_str() {
+                "range" => {
+                    animation.start_time = tokenizer.get_float()?;
+                    animation.end_time = tokenizer.get_float()?;
+                },
+                "target" => {
+                    let name = tokenizer.next_token()?;
+                    let channel = Channel::parse(tokenizer)?;
+                    animation.add_channel(&name, channel);
+                },
+                "}" => break,
+                _ => return Err(ParseError::new(tokenizer.get_file(), line, &format!("unknown morph attribute '{}'", token))),
+            }
+        }
+        Ok(animation)
+    }
+
+    pub fn add_channel(&mut self, target: &str, channel: Channel) {
+        self.channels.push((target.to_string(), channel));
+    }
+
+    pub fn get_channels(&self) -> &[(String, Channel)] {
+        &self.channels
+    }
+
+    pub fn get_start_time(&self) -> f32 {
+        self.start_time
+    }
+
+    pub fn get_end_time(&self) -> f32 {
+        self.end_time
+    }
+
+    // Weights in the model's target order, zero for targets without a curve.
+    // Curves for targets the model doesn't have are ignored.
+    pub fn sample(&self, time: f32, model: &MorphModel) -> Vec<f32> {
+        let mut weights = vec![0.0; model.get_num_targets()];
+        for (name, channel) in &self.channels {
+            if let Some(target) = model.find_target(name) {
+                weights[target] = channel.evaluate(time);
+            }
+        }
+        weights
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triangle() -> Vec<ModelVertex> {
+        let normal = glm::vec3(0.0, 0.0, 1.0);
+        vec![
+            ModelVertex::new(glm::vec3(0.0, 0.0, 0.0), normal),
+            ModelVertex::new(glm::vec3(1.0, 0.0, 0.0), normal),
+            ModelVertex::new(glm::vec3(0.0, 1.0, 0.0), normal),
+        ]
+    }
+
+    fn lift(name: &str, vertex: usize) -> MorphTarget {
+        let mut target = MorphTarget::new(name);
+        target.add_delta(vertex, glm::vec3(0.0, 0.0, 1.0), glm::vec3(0.0, 0.0, 0.0));
+        target
+    }
+
+    #[test]
+    fn targets_past_the_base_mesh_are_rejected() {
+        let error = MorphModel::from_parts(triangle(), vec![0, 1, 2], vec![lift("up", 1), lift("off", 3)]).err().unwrap();
+        assert_eq!(error.to_string(), "morph target 'off' moves vertex 3 but the base mesh has 3 vertices");
+
+        let mut model = MorphModel::from_parts(triangle(), vec![0, 1, 2], vec![lift("up", 1)]).unwrap();
+        assert!(model.add_target(lift("off", 7)).is_err());
+        assert_eq!(model.get_num_targets(), 1);
+        assert_eq!(model.add_target(lift("tip", 2)).unwrap(), 1);
+    }
+
+    #[test]
+    fn deform_blends_weighted_deltas() {
+        let mut model = MorphModel::from_parts(triangle(), vec![0, 1, 2], vec![lift("up", 1), lift("tip", 2)]).unwrap();
+        model.deform(&[0.5]);
+        let heights: Vec<f32> = model.get_deformed().iter().map(|v| v.get_position().z).collect();
+        assert_eq!(heights, vec![0.0, 0.5, 0.0]);
+        model.deform(&[1.0, 0.25]);
+        let heights: Vec<f32> = model.get_deformed().iter().map(|v| v.get_position().z).collect();
+        assert_eq!(heights, vec![0.0, 1.0, 0.25]);
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use crate::model::*;
+use crate::tokenizer::ParseError;
+
+// Indices into the position, texcoord and normal lists of an OBJ face corner
+type Corner = (usize, Option<usize>, Option<usize>);
+
+pub struct ObjMesh {
+    vertices: Vec<TexturedVertex>,
+    indices: Vec<u32>,
+    submeshes: Vec<Submesh>,
+    // Problems that didn't stop the mesh from loading
+    warnings: Vec<ParseError>,
+}
+
+struct Materials {
+    colors: HashMap<String, glm::Vec3>,
+}
+
+impl Materials {
+    fn from_file(filename: &str) -> Result<Materials, ParseError> {
+        let contents = fs::read_to_string(filename)
+            .map_err(|e| ParseError::new(filename, 0, &e.to_string()))?;
+        let mut colors = HashMap::new();
+        let mut current: Option<String> = None;
+        for (index, line) in contents.lines().enumerate() {
+            let error = |message: &str| ParseError::new(filename, index + 1, message);
+            let mut words = line.split('#').next().unwrap_or("").split_whitespace();
+            match words.next() {
+                Some("newmtl") => {
+                    let name = words.next().ok_or_else(|| error("newmtl is missing a name"))?;
+                    current = Some(name.to_string());
+                },
+                Some("Kd") => {
+                    let name = current.as_ref().ok_or_else(|| error("Kd before newmtl"))?;
+                    let color = parse_floats(&mut words, 3).map_err(|message| error(&message))?;
+                    colors.insert(name.clone(), glm::vec3(color[0], color[1], color[2]));
+                },
+                _ => {}
+            }
+        }
+        Ok(Materials {
+            colors,
+        })
+    }
+}
+
+fn parse_floats<'a, I: Iterator<Item = &'a str>>(words: &mut I, count: usize) -> Result<Vec<f32>, String> {
+    let mut values = Vec::with_capacity(count);
+    for _ in 0..count {
+        let word = words.next().ok_or_else(|| format!("expected {} numbers", count))?;
+        values.push(word.parse::<f32>().map_err(|_| format!("expected a number, found '{}'", word))?);
+    }
+    Ok(values)
+}
+
+// OBJ indices are 1-based, negative values count back from the end of the list so far
+fn resolve_index(word: &str, len: usize, kind: &str) -> Result<usize, String> {
+    let index = word.parse::<i64>().map_err(|_| format!("invalid {} index '{}'", kind, word))?;
+    let resolved = if index < 0 { len as i64 + index } else { index - 1 };
+    if resolved < 0 || resolved >= len as i64 {
+        return Err(format!("{} index {} out of range", kind, index));
+    }
+    Ok(resolved as usize)
+}
+
+impl ObjMesh {
+    pub fn from_file(filename: &str) -> Result<ObjMesh, ParseError> {
+        let contents = fs::read_to_string(filename)
+            .map_err(|e| ParseError::new(filename, 0, &e.to_string()))?;
+        let base = Path::new(filename).parent().unwrap_or_else(|| Path::new(""));
+
+        let mut positions: Vec<glm::Vec3> = Vec::new();
+        let mut texcoords: Vec<glm::Vec2> = Vec::new();
+        let mut normals: Vec<glm::Vec3> = Vec::new();
+        let mut corners: Vec<Corner> = Vec::new();
+        let mut lookup: HashMap<Corner, u32> = HashMap::new();
+        let mut indices: Vec<u32> = Vec::new();
+        let mut submeshes: Vec<Submesh> = Vec::new();
+        let mut materials = Materials { colors: HashMap::new() };
+        let mut group = String::from("default");
+        let mut material: Option<String> = None;
+        let mut first = 0;
+        let mut warnings = Vec::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let error = |message: &str| ParseError::new(filename, index + 1, message);
+            let mut words = line.split('#').next().unwrap_or("").split_whitespace();
+            let keyword = match words.next() {
+                Some(keyword) => keyword,
+                None => continue,
+            };
+            match keyword {
+                "v" => {
+                    let v = parse_floats(&mut words, 3).map_err(|message| error(&message))?;
+                    positions.push(glm::vec3(v[0], v[1], v[2]));
+                },
+                "vt" => {
+                    let vt = parse_floats(&mut words, 2).map_err(|message| error(&message))?;
+                    texcoords.push(glm::vec2(vt[0], vt[1]));
+                },
+                "vn" => {
+                    let vn = parse_floats(&mut words, 3).map_err(|message| error(&message))?;
+                    normals.push(glm::vec3(vn[0], vn[1], vn[2]));
+                },
+                "f" => {
+                    let mut face = Vec::new();
+                    for word in words {
+                        let mut parts = word.split('/');
+                        let v = resolve_index(parts.next().unwrap_or(""), positions.len(), "position")
+                            .map_err(|message| error(&message))?;
+                        let vt = match parts.next() {
+                            Some(part) if !part.is_empty() => Some(resolve_index(part, texcoords.len(), "texcoord")
+                                .map_err(|message| error(&message))?),
+                            _ => None,
+                        };
+                        let vn = match parts.next() {
+                            Some(part) if !part.is_empty() => Some(resolve_index(part, normals.len(), "normal")
+                                .map_err(|message| error(&message))?),
+                            _ => None,
+                        };
+                        let corner = (v, vt, vn);
+                        let vertex = *lookup.entry(corner).or_insert_with(|| {
+                            corners.push(corner);
+                            (corners.len() - 1) as u32
+                        });
+                        face.push(vertex);
+                    }
+                    if face.len() < 3 {
+                        return Err(error("face needs at least 3 vertices"));
+                    }
+                    // Fan triangulation, fine for the convex polygons modelling tools write
+                    for i in 1..face.len() - 1 {
+                        indices.extend_from_slice(&[face[0], face[i], face[i + 1]]);
+                    }
+                },
+                "g" | "o" | "usemtl" => {
+                    if indices.len() > first {
+                        let color = material.as_ref().and_then(|name| materials.colors.get(name).copied());
+                        submeshes.push(Submesh::new(&group, first, indices.len() - first, color));
+                        first = indices.len();
+                    }
+                    let name = words.collect::<Vec<_>>().join(" ");
+                    if keyword == "usemtl" {
+                        if !materials.colors.contains_key(&name) {
+                            warnings.push(error(&format!("unknown material '{}'", name)));
+                        }
+                        material = Some(name);
+                    } else if !name.is_empty() {
+                        group = name;
+                    }
+                },
+                "mtllib" => {
+                    for library in words {
+                        let path = base.join(library);
+                        let loaded = Materials::from_file(&path.to_string_lossy())?;
+                        materials.colors.extend(loaded.colors);
+                    }
+                },
+                _ => {}
+            }
+        }
+        if indices.len() > first {
+            let color = material.as_ref().and_then(|name| materials.colors.get(name).copied());
+            submeshes.push(Submesh::new(&group, first, indices.len() - first, color));
+        }
+
+        // Corners without a normal get the smooth normal of their position, so
+        // texture seams don't show up as shading seams
+        let mut generated: Option<Vec<glm::Vec3>> = None;
+        if corners.iter().any(|&(_, _, vn)| vn.is_none()) {
+            let position_indices: Vec<u32> = indices.iter().map(|&i| corners[i as usize].0 as u32).collect();
+            generated = Some(smooth_normals(&positions, &position_indices));
+        }
+        let vertices = corners.iter().map(|&(v, vt, vn)| {
+            let normal = match (vn, generated.as_ref()) {
+                (Some(vn), _) => normals[vn],
+                (None, Some(generated)) => generated[v],
+                (None, None) => glm::vec3(0.0, 1.0, 0.0),
+            };
+            let texcoord = vt.map_or(glm::vec2(0.0, 0.0), |vt| texcoords[vt]);
+            TexturedVertex::new(positions[v], normal, texcoord)
+        }).collect();
+
+        Ok(ObjMesh {
+            vertices,
+            indices,
+            submeshes,
+            warnings,
+        })
+    }
+
+    pub fn get_vertices(&self) -> &[TexturedVertex] {
+        &self.vertices
+    }
+
+    pub fn get_indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    pub fn get_submeshes(&self) -> &[Submesh] {
+        &self.submeshes
+    }
+
+    pub fn get_warnings(&self) -> &[ParseError] {
+        &self.warnings
+    }
+}
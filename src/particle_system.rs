@@ -0,0 +1,429 @@
+use std::ops::{Add, Mul};
+use gl::types::*;
+use crate::model::*;
+
+// Small xorshift generator, plenty for scattering particles
+#[derive(Clone, Debug)]
+pub struct Random {
+    state: u32,
+}
+
+impl Random {
+    pub fn new(seed: u32) -> Random {
+        Random {
+            state: seed.max(1),
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    // Uniform in [0, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1u32 << 24) as f32
+    }
+
+    pub fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.next_f32()
+    }
+
+    // Uniform on the unit sphere
+    pub fn direction(&mut self) -> glm::Vec3 {
+        let z = self.range(-1.0, 1.0);
+        let angle = self.range(0.0, 2.0 * glm::pi::<f32>());
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        glm::vec3(r * angle.cos(), r * angle.sin(), z)
+    }
+}
+
+// Piecewise linear keys over a particle's normalized age, 0 at birth and 1 at death
+#[derive(Clone, Debug)]
+pub struct Curve<T> {
+    keys: Vec<(f32, T)>,
+}
+
+impl<T> Curve<T> where T: Copy + Add<Output = T> + Mul<f32, Output = T> {
+    pub fn new(value: T) -> Curve<T> {
+        Curve {
+            keys: vec![(0.0, value)],
+        }
+    }
+
+    pub fn add_key(&mut self, time: f32, value: T) {
+        let index = self.keys.iter().position(|&(key_time, _)| key_time > time).unwrap_or(self.keys.len());
+        self.keys.insert(index, (time, value));
+    }
+
+    pub fn sample(&self, time: f32) -> T {
+        let last = self.keys[self.keys.len() - 1];
+        if time >= last.0 {
+            return last.1;
+        }
+        if time <= self.keys[0].0 {
+            return self.keys[0].1;
+        }
+        for pair in self.keys.windows(2) {
+            let ((t0, v0), (t1, v1)) = (pair[0], pair[1]);
+            if time <= t1 {
+                let t = if t1 > t0 { (time - t0) / (t1 - t0) } else { 1.0 };
+                return v0 * (1.0 - t) + v1 * t;
+            }
+        }
+        last.1
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum EmitterShape {
+    Point,
+    Box { half_extents: glm::Vec3 },
+    // Anywhere inside the ball
+    Sphere { radius: f32 },
+    // On the surface, area weighted, moving out along the face normal
+    Mesh { positions: Vec<glm::Vec3>, indices: Vec<u32> },
+}
+
+#[derive(Clone, Debug)]
+pub struct Emitter {
+    pub shape: EmitterShape,
+    pub position: glm::Vec3,
+    // Particles per second
+    pub rate: f32,
+    pub lifetime: (f32, f32),
+    pub speed: (f32, f32),
+    pub direction: glm::Vec3,
+    // Half angle in radians of the cone around `direction`, pi emits everywhere
+    pub spread: f32,
+    accumulator: f32,
+    pending_burst: usize,
+}
+
+impl Emitter {
+    pub fn new(shape: EmitterShape, position: glm::Vec3) -> Emitter {
+        Emitter {
+            shape,
+            position,
+            rate: 50.0,
+            lifetime: (1.0, 2.0),
+            speed: (1.0, 2.0),
+            direction: glm::vec3(0.0, 1.0, 0.0),
+            spread: 0.3,
+            accumulator: 0.0,
+            pending_burst: 0,
+        }
+    }
+
+    // Emits `count` extra particles on the next update
+    pub fn burst(&mut self, count: usize) {
+        self.pending_burst += count;
+    }
+
+    // Random direction at most `spread` away from `direction`
+    fn emit_direction(&self, direction: glm::Vec3, random: &mut Random) -> glm::Vec3 {
+        let axis = if glm::length(&direction) > 1e-6 { glm::normalize(&direction) } else { glm::vec3(0.0, 1.0, 0.0) };
+        let cos = self.spread.cos();
+        let z = random.range(cos.min(1.0), 1.0);
+        let angle = random.range(0.0, 2.0 * glm::pi::<f32>());
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let helper = if axis.x.abs() < 0.9 { glm::vec3(1.0, 0.0, 0.0) } else { glm::vec3(0.0, 1.0, 0.0) };
+        let u = glm::normalize(&axis.cross(&helper));
+        let v = axis.cross(&u);
+        u * (r * angle.cos()) + v * (r * angle.sin()) + axis * z
+    }
+
+    fn spawn(&self, random: &mut Random) -> Particle {
+        let (offset, direction) = match &self.shape {
+            EmitterShape::Point => (glm::vec3(0.0, 0.0, 0.0), self.direction),
+            EmitterShape::Box { half_extents } => {
+                let offset = glm::vec3(
+                    random.range(-half_extents.x, half_extents.x),
+                    random.range(-half_extents.y, half_extents.y),
+                    random.range(-half_extents.z, half_extents.z),
+                );
+                (offset, self.direction)
+            },
+            EmitterShape::Sphere { radius } => (random.direction() * (radius * random.next_f32().cbrt()), self.direction),
+            EmitterShape::Mesh { positions, indices } => Self::sample_mesh(positions, indices, random).unwrap_or((glm::vec3(0.0, 0.0, 0.0), self.direction)),
+        };
+        Particle {
+            position: self.position + offset,
+            velocity: self.emit_direction(direction, random) * random.range(self.speed.0, self.speed.1),
+            age: 0.0,
+            lifetime: random.range(self.lifetime.0, self.lifetime.1).max(1e-3),
+        }
+    }
+
+    // Picks a triangle with probability proportional to its area, then a
+    // uniform point on it
+    fn sample_mesh(positions: &[glm::Vec3], indices: &[u32], random: &mut Random) -> Option<(glm::Vec3, glm::Vec3)> {
+        let cross = |triangle: &[u32]| {
+            let (a, b, c) = (positions[triangle[0] as usize], positions[triangle[1] as usize], positions[triangle[2] as usize]);
+            (b - a).cross(&(c - a))
+        };
+        let total: f32 = indices.chunks(3).filter(|triangle| triangle.len() == 3).map(|triangle| glm::length(&cross(triangle))).sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mut pick = random.next_f32() * total;
+        for triangle in indices.chunks(3).filter(|triangle| triangle.len() == 3) {
+            let normal = cross(triangle);
+            let area = glm::length(&normal);
+            if pick > area {
+                pick -= area;
+                continue;
+            }
+            let (mut u, mut v) = (random.next_f32(), random.next_f32());
+            if u + v > 1.0 {
+                u = 1.0 - u;
+                v = 1.0 - v;
+            }
+            let a = positions[triangle[0] as usize];
+            let point = a + (positions[triangle[1] as usize] - a) * u + (positions[triangle[2] as usize] - a) * v;
+            return Some((point, normal / area));
+        }
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ForceField {
+    // An acceleration, applied regardless of mass
+    Gravity(glm::Vec3),
+    // Linear drag coefficient per second
+    Drag(f32),
+    // Swirl around an axis through `center`, weakening with distance
+    Vortex { center: glm::Vec3, axis: glm::Vec3, strength: f32 },
+    // Smooth random acceleration varying over space and time
+    Noise { strength: f32, frequency: f32 },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Particle {
+    position: glm::Vec3,
+    velocity: glm::Vec3,
+    age: f32,
+    lifetime: f32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ParticleInstance {
+    position: glm::Vec3,
+    size: f32,
+    color: glm::Vec4,
+}
+
+impl Instance for ParticleInstance {
+    fn setup_attributes() {
+        let stride = std::mem::size_of::<ParticleInstance>();
+        let float_size = std::mem::size_of::<f32>();
+        instance_attribute(FIRST_INSTANCE_LOCATION, 3, stride, 0);
+        instance_attribute(FIRST_INSTANCE_LOCATION + 1, 1, stride, 3 * float_size);
+        instance_attribute(FIRST_INSTANCE_LOCATION + 2, 4, stride, 4 * float_size);
+    }
+}
+
+fn hash(x: i32, y: i32, z: i32) -> f32 {
+    let mut h = (x as u32).wrapping_mul(73_856_093) ^ (y as u32).wrapping_mul(19_349_663) ^ (z as u32).wrapping_mul(83_492_791);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1_e995);
+    h ^= h >> 15;
+    (h & 0xffff) as f32 / 32767.5 - 1.0
+}
+
+// Trilinear value noise in [-1, 1]
+fn value_noise(point: glm::Vec3) -> f32 {
+    let base = glm::vec3(point.x.floor(), point.y.floor(), point.z.floor());
+    let fraction = point - base;
+    let smooth = fraction.map(|t| t * t * (3.0 - 2.0 * t));
+    let (x, y, z) = (base.x as i32, base.y as i32, base.z as i32);
+    let mut value = 0.0;
+    for corner in 0..8 {
+        let (dx, dy, dz) = (corner & 1, (corner >> 1) & 1, (corner >> 2) & 1);
+        let weight = (if dx == 1 { smooth.x } else { 1.0 - smooth.x })
+            * (if dy == 1 { smooth.y } else { 1.0 - smooth.y })
+            * (if dz == 1 { smooth.z } else { 1.0 - smooth.z });
+        value += weight * hash(x + dx, y + dy, z + dz);
+    }
+    value
+}
+
+pub struct ParticleSystem {
+    emitters: Vec<Emitter>,
+    forces: Vec<ForceField>,
+    particles: Vec<Particle>,
+    max_particles: usize,
+    ground: Option<f32>,
+    restitution: f32,
+    friction: f32,
+    size: Curve<f32>,
+    color: Curve<glm::Vec4>,
+    random: Random,
+    time: f32,
+    model: Option<Model>,
+}
+
+impl ParticleSystem {
+    pub fn new(max_particles: usize) -> ParticleSystem {
+        let mut color = Curve::new(glm::vec4(1.0, 1.0, 1.0, 1.0));
+        color.add_key(1.0, glm::vec4(1.0, 1.0, 1.0, 0.0));
+        ParticleSystem {
+            emitters: Vec::new(),
+            forces: Vec::new(),
+            particles: Vec::with_capacity(max_particles),
+            max_particles,
+            ground: None,
+            restitution: 0.5,
+            friction: 0.2,
+            size: Curve::new(0.05),
+            color,
+            random: Random::new(0x2545_f491),
+            time: 0.0,
+            model: None,
+        }
+    }
+
+    pub fn add_emitter(&mut self, emitter: Emitter) -> usize {
+        self.emitters.push(emitter);
+        self.emitters.len() - 1
+    }
+
+    pub fn get_emitter_mut(&mut self, emitter: usize) -> &mut Emitter {
+        &mut self.emitters[emitter]
+    }
+
+    pub fn add_force(&mut self, force: ForceField) {
+        self.forces.push(force);
+    }
+
+    pub fn get_num_particles(&self) -> usize {
+        self.particles.len()
+    }
+
+    pub fn set_ground(&mut self, ground: Option<f32>) {
+        self.ground = ground;
+    }
+
+    // Fraction of the normal speed kept on bouncing, and of the sliding speed lost
+    pub fn set_restitution(&mut self, restitution: f32, friction: f32) {
+        self.restitution = glm::clamp_scalar(restitution, 0.0, 1.0);
+        self.friction = glm::clamp_scalar(friction, 0.0, 1.0);
+    }
+
+    // Edge length of a particle over its life
+    pub fn set_size(&mut self, size: Curve<f32>) {
+        self.size = size;
+    }
+
+    // Color and alpha over life
+    pub fn set_color(&mut self, color: Curve<glm::Vec4>) {
+        self.color = color;
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        self.time += dt;
+        self.emit(dt);
+
+        for particle in &mut self.particles {
+            particle.age += dt;
+        }
+        self.particles.retain(|particle| particle.age < particle.lifetime);
+
+        for particle in &mut self.particles {
+            let mut acceleration = glm::vec3(0.0, 0.0, 0.0);
+            for force in &self.forces {
+                acceleration += match *force {
+                    ForceField::Gravity(gravity) => gravity,
+                    ForceField::Drag(drag) => -particle.velocity * drag,
+                    ForceField::Vortex { center, axis, strength } => {
+                        let axis = glm::normalize(&axis);
+                        let offset = particle.position - center;
+                        let radial = offset - axis * glm::dot(&offset, &axis);
+                        axis.cross(&radial) * (strength / (1.0 + glm::dot(&radial, &radial)))
+                    },
+                    ForceField::Noise { strength, frequency } => {
+                        let point = particle.position * frequency;
+                        glm::vec3(
+                            value_noise(point + glm::vec3(0.0, 0.0, self.time)),
+                            value_noise(point + glm::vec3(31.4, 0.0, self.time)),
+                            value_noise(point + glm::vec3(0.0, 27.1, self.time)),
+                        ) * strength
+                    },
+                };
+            }
+            particle.velocity += acceleration * dt;
+            particle.position += particle.velocity * dt;
+
+            if let Some(ground) = self.ground {
+                if particle.position.y < ground {
+                    particle.position.y = ground + (ground - particle.position.y) * self.restitution;
+                    if particle.velocity.y < 0.0 {
+                        particle.velocity.y *= -self.restitution;
+                        particle.velocity.x *= 1.0 - self.friction;
+                        particle.velocity.z *= 1.0 - self.friction;
+                    }
+                }
+            }
+        }
+    }
+
+    fn emit(&mut self, dt: f32) {
+        for emitter in &mut self.emitters {
+            emitter.accumulator += emitter.rate * dt;
+            let count = emitter.accumulator.floor() as usize + emitter.pending_burst;
+            emitter.accumulator -= emitter.accumulator.floor();
+            emitter.pending_burst = 0;
+            for _ in 0..count {
+                if self.particles.len() >= self.max_particles {
+                    break;
+                }
+                self.particles.push(emitter.spawn(&mut self.random));
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.particles.clear();
+        self.time = 0.0;
+        for emitter in &mut self.emitters {
+            emitter.accumulator = 0.0;
+            emitter.pending_burst = 0;
+        }
+    }
+
+    // A unit box per particle, scaled and colored per instance
+    pub fn make_model(&mut self) {
+        let mut model = Model::new();
+        model.make_box(glm::vec3(-0.5, -0.5, -0.5), glm::vec3(0.5, 0.5, 0.5));
+        self.model = Some(model);
+    }
+
+    pub fn draw(&mut self, view_proj_mat: glm::Mat4, shader: GLuint) {
+        let instances: Vec<ParticleInstance> = self.particles.iter().map(|particle| {
+            let life = particle.age / particle.lifetime;
+            ParticleInstance {
+                position: particle.position,
+                size: self.size.sample(life),
+                color: self.color.sample(life),
+            }
+        }).collect();
+        if let Some(model) = self.model.as_mut() {
+            model.set_instances(&instances);
+            // Alpha blended without depth writes so fading particles don't
+            // cut holes in each other
+            unsafe {
+                gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+                gl::DepthMask(gl::FALSE);
+            }
+            model.draw_instanced(glm::Mat4::identity(), view_proj_mat, shader);
+            unsafe {
+                gl::DepthMask(gl::TRUE);
+                gl::BlendFunc(gl::ONE, gl::ZERO);
+            }
+        }
+    }
+}
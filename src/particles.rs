@@ -0,0 +1,137 @@
+use gl::types::*;
+use crate::model::*;
+
+// Point masses shared by the simulations. An inverse mass of zero pins a
+// particle, forces and constraints leave it where it is.
+#[derive(Clone, Debug)]
+pub struct Particles {
+    positions: Vec<glm::Vec3>,
+    velocities: Vec<glm::Vec3>,
+    inverse_masses: Vec<f32>,
+}
+
+impl Particles {
+    pub fn new() -> Particles {
+        Particles {
+            positions: Vec::new(),
+            velocities: Vec::new(),
+            inverse_masses: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, position: glm::Vec3, mass: f32) -> usize {
+        self.positions.push(position);
+        self.velocities.push(glm::vec3(0.0, 0.0, 0.0));
+        self.inverse_masses.push(if mass > 0.0 { 1.0 / mass } else { 0.0 });
+        self.positions.len() - 1
+    }
+
+    pub fn get_num_particles(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn get_positions(&self) -> &[glm::Vec3] {
+        &self.positions
+    }
+
+    pub fn get_positions_mut(&mut self) -> &mut [glm::Vec3] {
+        &mut self.positions
+    }
+
+    pub fn get_velocities(&self) -> &[glm::Vec3] {
+        &self.velocities
+    }
+
+    pub fn get_velocities_mut(&mut self) -> &mut [glm::Vec3] {
+        &mut self.velocities
+    }
+
+    pub fn get_inverse_masses(&self) -> &[f32] {
+        &self.inverse_masses
+    }
+
+    pub fn get_inverse_mass(&self, particle: usize) -> f32 {
+        self.inverse_masses[particle]
+    }
+
+    pub fn set_inverse_mass(&mut self, particle: usize, inverse_mass: f32) {
+        self.inverse_masses[particle] = inverse_mass;
+        if inverse_mass == 0.0 {
+            self.velocities[particle] = glm::vec3(0.0, 0.0, 0.0);
+        }
+    }
+
+    pub fn is_pinned(&self, particle: usize) -> bool {
+        self.inverse_masses[particle] == 0.0
+    }
+
+    pub fn move_pinned(&mut self, delta: glm::Vec3) {
+        for (position, &inverse_mass) in self.positions.iter_mut().zip(self.inverse_masses.iter()) {
+            if inverse_mass == 0.0 {
+                *position += delta;
+            }
+        }
+    }
+
+    pub fn set_positions(&mut self, positions: &[glm::Vec3]) {
+        self.positions.copy_from_slice(positions);
+        for velocity in &mut self.velocities {
+            *velocity = glm::vec3(0.0, 0.0, 0.0);
+        }
+    }
+}
+
+// Static obstacle the simulations keep their particles out of
+#[derive(Clone, Copy, Debug)]
+pub struct CollisionSphere {
+    pub center: glm::Vec3,
+    pub radius: f32,
+}
+
+// Triangles over a set of particles drawn as a two sided dynamic model, with
+// normals recomputed from the particle positions every frame
+pub struct ParticleMesh {
+    indices: Vec<u32>,
+    model: Option<Model>,
+}
+
+impl ParticleMesh {
+    pub fn new(indices: Vec<u32>) -> ParticleMesh {
+        ParticleMesh {
+            indices,
+            model: None,
+        }
+    }
+
+    pub fn get_indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    // Both sides get their own vertices so back faces light correctly with
+    // culling on
+    fn vertices(&self, positions: &[glm::Vec3]) -> Vec<ModelVertex> {
+        let normals = smooth_normals(positions, &self.indices);
+        let front = positions.iter().zip(normals.iter()).map(|(&position, &normal)| ModelVertex::new(position, normal));
+        let back = positions.iter().zip(normals.iter()).map(|(&position, &normal)| ModelVertex::new(position, -normal));
+        front.chain(back).collect()
+    }
+
+    pub fn make_model(&mut self, positions: &[glm::Vec3]) {
+        let count = positions.len() as u32;
+        let mut indices = self.indices.clone();
+        for triangle in self.indices.chunks(3) {
+            indices.extend_from_slice(&[triangle[0] + count, triangle[2] + count, triangle[1] + count]);
+        }
+        let mut model = Model::new();
+        model.set_dynamic_buffers(&self.vertices(positions), &indices);
+        self.model = Some(model);
+    }
+
+    pub fn draw(&mut self, positions: &[glm::Vec3], view_proj_mat: glm::Mat4, shader: GLuint) {
+        let vertices = self.vertices(positions);
+        if let Some(model) = self.model.as_mut() {
+            model.write_vertices(&vertices);
+            model.draw(glm::Mat4::identity(), view_proj_mat, shader);
+        }
+    }
+}
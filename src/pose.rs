@@ -0,0 +1,171 @@
+use crate::skeleton::*;
+
+#[derive(Clone, Copy, Debug)]
+pub struct JointTransform {
+    translation: glm::Vec3,
+    rotation: glm::Quat,
+    scale: glm::Vec3,
+}
+
+impl JointTransform {
+    pub fn new(translation: glm::Vec3, rotation: glm::Quat, scale: glm::Vec3) -> JointTransform {
+        JointTransform {
+            translation,
+            rotation,
+            scale,
+        }
+    }
+
+    pub fn identity() -> JointTransform {
+        Self::new(glm::vec3(0.0, 0.0, 0.0), glm::quat(0.0, 0.0, 0.0, 1.0), glm::vec3(1.0, 1.0, 1.0))
+    }
+
+    pub fn from_joint(joint: &Joint) -> JointTransform {
+        Self::new(joint.get_offset(), joint.get_rotation(), joint.get_scale())
+    }
+
+    pub fn get_translation(&self) -> glm::Vec3 {
+        self.translation
+    }
+
+    pub fn set_translation(&mut self, translation: glm::Vec3) {
+        self.translation = translation;
+    }
+
+    pub fn get_rotation(&self) -> glm::Quat {
+        self.rotation
+    }
+
+    pub fn set_rotation(&mut self, rotation: glm::Quat) {
+        self.rotation = rotation;
+    }
+
+    pub fn get_scale(&self) -> glm::Vec3 {
+        self.scale
+    }
+
+    pub fn set_scale(&mut self, scale: glm::Vec3) {
+        self.scale = scale;
+    }
+
+    pub fn get_matrix(&self) -> glm::Mat4 {
+        glm::translation(&self.translation) * glm::quat_to_mat4(&self.rotation) * glm::scaling(&self.scale)
+    }
+
+    pub fn lerp(a: &JointTransform, b: &JointTransform, t: f32) -> JointTransform {
+        Self::blend(&[(*a, 1.0 - t), (*b, t)])
+    }
+
+    // Weighted average. Rotations are summed after flipping each one into the
+    // hemisphere of the first, then normalized (nlerp), so q and -q don't
+    // cancel out. Weights are normalized, all zero weights give the first transform.
+    pub fn blend(transforms: &[(JointTransform, f32)]) -> JointTransform {
+        let total: f32 = transforms.iter().map(|&(_, weight)| weight).sum();
+        let first = match transforms.first() {
+            Some(&(first, _)) => first,
+            None => return Self::identity(),
+        };
+        if total.abs() <= 1e-6 {
+            return first;
+        }
+        let mut translation = glm::vec3(0.0, 0.0, 0.0);
+        let mut rotation = glm::quat(0.0, 0.0, 0.0, 0.0);
+        let mut scale = glm::vec3(0.0, 0.0, 0.0);
+        for &(transform, weight) in transforms {
+            let weight = weight / total;
+            translation += transform.translation * weight;
+            scale += transform.scale * weight;
+            let sign = if glm::quat_dot(&transform.rotation, &first.rotation) < 0.0 { -1.0 } else { 1.0 };
+            rotation += transform.rotation * (weight * sign);
+        }
+        let length = glm::quat_length(&rotation);
+        let rotation = if length > 1e-6 { rotation / length } else { first.rotation };
+        Self::new(translation, rotation, scale)
+    }
+}
+
+// Local transforms for every joint of a skeleton, in skeleton order
+#[derive(Clone, Debug)]
+pub struct Pose {
+    transforms: Vec<JointTransform>,
+}
+
+impl Pose {
+    pub fn new(num_joints: usize) -> Pose {
+        Pose {
+            transforms: vec![JointTransform::identity(); num_joints],
+        }
+    }
+
+    pub fn from_transforms(transforms: Vec<JointTransform>) -> Pose {
+        Pose {
+            transforms,
+        }
+    }
+
+    pub fn from_skeleton(skeleton: &Skeleton) -> Pose {
+        Self::from_transforms(skeleton.get_joints().iter().map(JointTransform::from_joint).collect())
+    }
+
+    pub fn get_num_joints(&self) -> usize {
+        self.transforms.len()
+    }
+
+    pub fn get_transforms(&self) -> &[JointTransform] {
+        &self.transforms
+    }
+
+    pub fn get_transform(&self, joint: usize) -> &JointTransform {
+        &self.transforms[joint]
+    }
+
+    pub fn get_transform_mut(&mut self, joint: usize) -> &mut JointTransform {
+        &mut self.transforms[joint]
+    }
+
+    pub fn set_transform(&mut self, joint: usize, transform: JointTransform) {
+        self.transforms[joint] = transform;
+    }
+
+    pub fn lerp(a: &Pose, b: &Pose, t: f32) -> Pose {
+        Self::blend(&[(a, 1.0 - t), (b, t)])
+    }
+
+    // Per-joint weighted blend of any number of poses with the same joint count
+    pub fn blend(poses: &[(&Pose, f32)]) -> Pose {
+        let num_joints = poses.iter().map(|(pose, _)| pose.get_num_joints()).min().unwrap_or(0);
+        let transforms = (0..num_joints).map(|joint| {
+            let weighted: Vec<(JointTransform, f32)> = poses.iter()
+                .map(|&(pose, weight)| (pose.transforms[joint], weight))
+                .collect();
+            JointTransform::blend(&weighted)
+        }).collect();
+        Self::from_transforms(transforms)
+    }
+
+    // Local matrices followed by a parent walk, like Skeleton::update but without touching DOFs
+    pub fn world_mats(&self, skeleton: &Skeleton) -> Vec<glm::Mat4> {
+        let mut world_mats: Vec<glm::Mat4> = Vec::with_capacity(self.transforms.len());
+        for (index, transform) in self.transforms.iter().enumerate() {
+            let local = transform.get_matrix();
+            let world = match skeleton.get_joints().get(index).and_then(|joint| joint.get_parent()) {
+                Some(parent) => world_mats[parent] * local,
+                None => skeleton.get_transform() * local,
+            };
+            world_mats.push(world);
+        }
+        world_mats
+    }
+
+    // Writes the pose into the skeleton's offsets, DOFs and scales. Call
+    // Skeleton::update afterwards to refresh the world matrices.
+    pub fn apply(&self, skeleton: &mut Skeleton) {
+        let num_joints = self.transforms.len().min(skeleton.get_num_joints());
+        for (index, transform) in self.transforms.iter().take(num_joints).enumerate() {
+            let joint = skeleton.get_joint_mut(index);
+            joint.set_offset(transform.translation);
+            joint.set_rotation(&transform.rotation);
+            joint.set_scale(transform.scale);
+        }
+    }
+}
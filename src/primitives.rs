@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+use crate::model::*;
+
+// CPU side mesh produced by the generators below. Everything is built around
+// the y axis, centered on the origin unless noted otherwise.
+#[derive(Clone, Debug)]
+pub struct MeshData {
+    vertices: Vec<TexturedVertex>,
+    indices: Vec<u32>,
+}
+
+// A point of a lathe profile: radius and height, the 2D normal in that plane
+// and the v texture coordinate.
+struct ProfilePoint {
+    radius: f32,
+    y: f32,
+    normal_radius: f32,
+    normal_y: f32,
+    v: f32,
+}
+
+impl ProfilePoint {
+    fn new(radius: f32, y: f32, normal_radius: f32, normal_y: f32, v: f32) -> ProfilePoint {
+        ProfilePoint {
+            radius,
+            y,
+            normal_radius,
+            normal_y,
+            v,
+        }
+    }
+}
+
+impl MeshData {
+    pub fn new() -> MeshData {
+        MeshData {
+            vertices: Vec::new(),
+            indices: Vec::new(),
+        }
+    }
+
+    pub fn get_vertices(&self) -> &[TexturedVertex] {
+        &self.vertices
+    }
+
+    pub fn get_indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    pub fn into_parts(self) -> (Vec<TexturedVertex>, Vec<u32>) {
+        (self.vertices, self.indices)
+    }
+
+    fn add_vertex(&mut self, position: glm::Vec3, normal: glm::Vec3, texcoord: glm::Vec2) -> u32 {
+        self.vertices.push(TexturedVertex::new(position, normal, texcoord));
+        (self.vertices.len() - 1) as u32
+    }
+
+    // Appends another mesh, moved by `transform`
+    pub fn append(&mut self, other: &MeshData, transform: &glm::Mat4) {
+        let base = self.vertices.len() as u32;
+        let normal_mat = glm::transpose(&glm::inverse(transform));
+        for vertex in &other.vertices {
+            let p = vertex.get_position();
+            let n = vertex.get_normal();
+            let position = glm::vec4_to_vec3(&(transform * glm::vec4(p.x, p.y, p.z, 1.0)));
+            let normal = glm::normalize(&glm::vec4_to_vec3(&(normal_mat * glm::vec4(n.x, n.y, n.z, 0.0))));
+            self.add_vertex(position, normal, vertex.get_texcoord());
+        }
+        self.indices.extend(other.indices.iter().map(|index| index + base));
+    }
+
+    // Revolves a profile, listed from bottom to top, around the y axis
+    fn lathe(&mut self, profile: &[ProfilePoint], slices: usize) {
+        let slices = slices.max(3);
+        let base = self.vertices.len() as u32;
+        for point in profile {
+            for j in 0..=slices {
+                let u = j as f32 / slices as f32;
+                let angle = u * 2.0 * glm::pi::<f32>();
+                let (sin, cos) = angle.sin_cos();
+                self.add_vertex(
+                    glm::vec3(point.radius * sin, point.y, point.radius * cos),
+                    glm::vec3(point.normal_radius * sin, point.normal_y, point.normal_radius * cos),
+                    glm::vec2(u, point.v),
+                );
+            }
+        }
+        let ring = (slices + 1) as u32;
+        for i in 0..profile.len().saturating_sub(1) {
+            for j in 0..slices as u32 {
+                let a = base + i as u32 * ring + j;
+                let b = a + 1;
+                let c = b + ring;
+                let d = a + ring;
+                // Rings that collapse to a point (poles, cone tips) only need one triangle per quad
+                if profile[i].radius.abs() > 1e-6 {
+                    self.indices.extend_from_slice(&[a, b, c]);
+                }
+                if profile[i + 1].radius.abs() > 1e-6 {
+                    self.indices.extend_from_slice(&[a, c, d]);
+                }
+            }
+        }
+    }
+
+    fn disc(&mut self, radius: f32, y: f32, up: bool, slices: usize) {
+        let slices = slices.max(3);
+        let normal = glm::vec3(0.0, if up { 1.0 } else { -1.0 }, 0.0);
+        let center = self.add_vertex(glm::vec3(0.0, y, 0.0), normal, glm::vec2(0.5, 0.5));
+        for j in 0..=slices {
+            let angle = j as f32 / slices as f32 * 2.0 * glm::pi::<f32>();
+            let (sin, cos) = angle.sin_cos();
+            self.add_vertex(glm::vec3(radius * sin, y, radius * cos), normal, glm::vec2(0.5 + 0.5 * sin, 0.5 + 0.5 * cos));
+        }
+        for j in 0..slices as u32 {
+            let a = center + 1 + j;
+            let b = a + 1;
+            if up {
+                self.indices.extend_from_slice(&[center, a, b]);
+            } else {
+                self.indices.extend_from_slice(&[center, b, a]);
+            }
+        }
+    }
+
+    fn hemisphere_profile(profile: &mut Vec<ProfilePoint>, radius: f32, center_y: f32, upper: bool, stacks: usize,
+                          v_start: f32, v_end: f32) {
+        let stacks = stacks.max(1);
+        for k in 0..=stacks {
+            let t = k as f32 / stacks as f32;
+            let angle = if upper { t } else { t - 1.0 } * glm::half_pi::<f32>();
+            let (sin, cos) = angle.sin_cos();
+            profile.push(ProfilePoint::new(radius * cos, center_y + radius * sin, cos, sin, v_start + (v_end - v_start) * t));
+        }
+    }
+
+    pub fn uv_sphere(radius: f32, slices: usize, stacks: usize) -> MeshData {
+        let stacks = stacks.max(2);
+        let profile: Vec<ProfilePoint> = (0..=stacks).map(|k| {
+            let v = k as f32 / stacks as f32;
+            let angle = (v - 0.5) * glm::pi::<f32>();
+            let (sin, cos) = angle.sin_cos();
+            ProfilePoint::new(radius * cos, radius * sin, cos, sin, v)
+        }).collect();
+        let mut mesh = MeshData::new();
+        mesh.lathe(&profile, slices);
+        mesh
+    }
+
+    pub fn icosphere(radius: f32, subdivisions: usize) -> MeshData {
+        let t = (1.0 + 5.0f32.sqrt()) / 2.0;
+        let mut points: Vec<glm::Vec3> = [
+            (-1.0, t, 0.0), (1.0, t, 0.0), (-1.0, -t, 0.0), (1.0, -t, 0.0),
+            (0.0, -1.0, t), (0.0, 1.0, t), (0.0, -1.0, -t), (0.0, 1.0, -t),
+            (t, 0.0, -1.0), (t, 0.0, 1.0), (-t, 0.0, -1.0), (-t, 0.0, 1.0),
+        ].iter().map(|&(x, y, z)| glm::normalize(&glm::vec3(x, y, z))).collect();
+        let mut faces: Vec<[u32; 3]> = vec![
+            [0, 11, 5], [0, 5, 1], [0, 1, 7], [0, 7, 10], [0, 10, 11],
+            [1, 5, 9], [5, 11, 4], [11, 10, 2], [10, 7, 6], [7, 1, 8],
+            [3, 9, 4], [3, 4, 2], [3, 2, 6], [3, 6, 8], [3, 8, 9],
+            [4, 9, 5], [2, 4, 11], [6, 2, 10], [8, 6, 7], [9, 8, 1],
+        ];
+
+        for _ in 0..subdivisions {
+            let mut midpoints: HashMap<(u32, u32), u32> = HashMap::new();
+            let mut midpoint = |a: u32, b: u32, points: &mut Vec<glm::Vec3>| {
+                let key = (a.min(b), a.max(b));
+                *midpoints.entry(key).or_insert_with(|| {
+                    points.push(glm::normalize(&((points[a as usize] + points[b as usize]) * 0.5)));
+                    (points.len() - 1) as u32
+                })
+            };
+            let mut subdivided = Vec::with_capacity(faces.len() * 4);
+            for &[a, b, c] in &faces {
+                let ab = midpoint(a, b, &mut points);
+                let bc = midpoint(b, c, &mut points);
+                let ca = midpoint(c, a, &mut points);
+                subdivided.extend_from_slice(&[[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]);
+            }
+            faces = subdivided;
+        }
+
+        let mut mesh = MeshData::new();
+        let mut uvs = Vec::with_capacity(points.len());
+        for point in &points {
+            let u = 0.5 + point.x.atan2(point.z) / (2.0 * glm::pi::<f32>());
+            let v = 0.5 + point.y.clamp(-1.0, 1.0).asin() / glm::pi::<f32>();
+            mesh.add_vertex(point * radius, *point, glm::vec2(u, v));
+            uvs.push(glm::vec2(u, v));
+        }
+
+        // Triangles crossing the u = 0/1 seam get copies of their low u
+        // vertices shifted by one, so the texture doesn't run backwards across
+        // them. Pole vertices have no u of their own, every triangle gets a copy
+        // in the middle of its other two corners.
+        let is_pole = |index: u32| {
+            let point = points[index as usize];
+            point.x.abs() < 1e-6 && point.z.abs() < 1e-6
+        };
+        let mut seam_copies: HashMap<u32, u32> = HashMap::new();
+        for original in &faces {
+            let mut face = *original;
+            let mut us = face.map(|index| uvs[index as usize].x);
+            let non_pole_us = (0..3).filter(|&k| !is_pole(original[k])).map(|k| us[k]);
+            let (min, max) = non_pole_us.fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), u| (min.min(u), max.max(u)));
+            if max - min > 0.5 {
+                for k in 0..3 {
+                    if us[k] < 0.5 && !is_pole(original[k]) {
+                        let index = face[k];
+                        face[k] = *seam_copies.entry(index).or_insert_with(|| {
+                            let point = points[index as usize];
+                            mesh.add_vertex(point * radius, point, uvs[index as usize] + glm::vec2(1.0, 0.0))
+                        });
+                        us[k] += 1.0;
+                    }
+                }
+            }
+            for k in 0..3 {
+                if is_pole(original[k]) {
+                    let point = points[original[k] as usize];
+                    let u = 0.5 * (us[(k + 1) % 3] + us[(k + 2) % 3]);
+                    face[k] = mesh.add_vertex(point * radius, point, glm::vec2(u, uvs[original[k] as usize].y));
+                }
+            }
+            mesh.indices.extend_from_slice(&face);
+        }
+        mesh
+    }
+
+    pub fn cylinder(radius: f32, height: f32, slices: usize, stacks: usize) -> MeshData {
+        let stacks = stacks.max(1);
+        let profile: Vec<ProfilePoint> = (0..=stacks).map(|k| {
+            let v = k as f32 / stacks as f32;
+            ProfilePoint::new(radius, (v - 0.5) * height, 1.0, 0.0, v)
+        }).collect();
+        let mut mesh = MeshData::new();
+        mesh.lathe(&profile, slices);
+        mesh.disc(radius, -0.5 * height, false, slices);
+        mesh.disc(radius, 0.5 * height, true, slices);
+        mesh
+    }
+
+    pub fn cone(radius: f32, height: f32, slices: usize) -> MeshData {
+        let mut mesh = MeshData::new();
+        mesh.cone_side(radius, -0.5 * height, height, slices, 0.0, 1.0);
+        mesh.disc(radius, -0.5 * height, false, slices);
+        mesh
+    }
+
+    fn cone_side(&mut self, radius: f32, base_y: f32, height: f32, slices: usize, v_start: f32, v_end: f32) {
+        let length = (radius * radius + height * height).sqrt();
+        let (normal_radius, normal_y) = (height / length, radius / length);
+        self.lathe(&[
+            ProfilePoint::new(radius, base_y, normal_radius, normal_y, v_start),
+            ProfilePoint::new(0.0, base_y + height, normal_radius, normal_y, v_end),
+        ], slices);
+    }
+
+    // `height` is the length of the straight part between the two hemispheres
+    pub fn capsule(radius: f32, height: f32, slices: usize, stacks: usize) -> MeshData {
+        let total = height + 2.0 * radius;
+        let cap_v = if total > 0.0 { radius / total } else { 0.5 };
+        let mut profile = Vec::new();
+        Self::hemisphere_profile(&mut profile, radius, -0.5 * height, false, stacks, 0.0, cap_v);
+        Self::hemisphere_profile(&mut profile, radius, 0.5 * height, true, stacks, 1.0 - cap_v, 1.0);
+        let mut mesh = MeshData::new();
+        mesh.lathe(&profile, slices);
+        mesh
+    }
+
+    // Lies in the xz plane, revolving around the y axis
+    pub fn torus(major_radius: f32, minor_radius: f32, major_segments: usize, minor_segments: usize) -> MeshData {
+        let minor_segments = minor_segments.max(3);
+        // A torus is the lathe of a circle
+        let profile: Vec<ProfilePoint> = (0..=minor_segments).map(|k| {
+            let v = k as f32 / minor_segments as f32;
+            let (sin, cos) = (v * 2.0 * glm::pi::<f32>()).sin_cos();
+            ProfilePoint::new(major_radius + minor_radius * cos, minor_radius * sin, cos, sin, v)
+        }).collect();
+        let mut mesh = MeshData::new();
+        mesh.lathe(&profile, major_segments);
+        mesh
+    }
+
+    // Lies in the xz plane facing +y
+    pub fn plane(width: f32, depth: f32, subdivisions_x: usize, subdivisions_z: usize) -> MeshData {
+        let nx = subdivisions_x.max(1);
+        let nz = subdivisions_z.max(1);
+        let mut mesh = MeshData::new();
+        for k in 0..=nz {
+            for j in 0..=nx {
+                let u = j as f32 / nx as f32;
+                let v = k as f32 / nz as f32;
+                mesh.add_vertex(
+                    glm::vec3((u - 0.5) * width, 0.0, (0.5 - v) * depth),
+                    glm::vec3(0.0, 1.0, 0.0),
+                    glm::vec2(u, v),
+                );
+            }
+        }
+        let row = (nx + 1) as u32;
+        for k in 0..nz as u32 {
+            for j in 0..nx as u32 {
+                let a = k * row + j;
+                let b = a + 1;
+                let c = b + row;
+                let d = a + row;
+                mesh.indices.extend_from_slice(&[a, b, c, a, c, d]);
+            }
+        }
+        mesh
+    }
+
+    // Starts at the origin and points along +y, the head included in `length`
+    pub fn arrow(length: f32, shaft_radius: f32, head_radius: f32, head_length: f32, slices: usize) -> MeshData {
+        let head_length = head_length.min(length);
+        let shaft_length = length - head_length;
+        let mut mesh = MeshData::new();
+        mesh.disc(shaft_radius, 0.0, false, slices);
+        mesh.lathe(&[
+            ProfilePoint::new(shaft_radius, 0.0, 1.0, 0.0, 0.0),
+            ProfilePoint::new(shaft_radius, shaft_length, 1.0, 0.0, 0.5),
+        ], slices);
+        mesh.disc(head_radius, shaft_length, false, slices);
+        mesh.cone_side(head_radius, shaft_length, head_length, slices, 0.5, 1.0);
+        mesh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Indices in range, unit normals, and every triangle wound counter-clockwise
+    // when seen from the side its vertex normals point to
+    fn check(mesh: &MeshData, vertices: usize, indices: usize) {
+        assert_eq!((mesh.get_vertices().len(), mesh.get_indices().len()), (vertices, indices));
+        for vertex in mesh.get_vertices() {
+            assert!((glm::length(&vertex.get_normal()) - 1.0).abs() < 1e-4, "{}", vertex.get_normal());
+        }
+        for triangle in mesh.get_indices().chunks(3) {
+            let corners: Vec<&TexturedVertex> = triangle.iter().map(|&index| &mesh.get_vertices()[index as usize]).collect();
+            let [a, b, c] = [0, 1, 2].map(|k| corners[k].get_position());
+            let face_normal = (b - a).cross(&(c - a));
+            // Slivers at collapsed rings have no direction to check
+            if glm::length(&face_normal) < 1e-6 {
+                continue;
+            }
+            let vertex_normal = corners.iter().fold(glm::vec3(0.0, 0.0, 0.0), |sum, corner| sum + corner.get_normal());
+            assert!(glm::dot(&face_normal, &vertex_normal) > 0.0, "{:?} is wound backwards", triangle);
+        }
+    }
+
+    // Closed shapes around the origin have their normals pointing away from it
+    fn check_outward(mesh: &MeshData) {
+        for vertex in mesh.get_vertices() {
+            assert!(glm::dot(&vertex.get_normal(), &vertex.get_position()) > 0.0, "{} points inwards", vertex.get_position());
+        }
+    }
+
+    #[test]
+    fn uv_sphere() {
+        let mesh = MeshData::uv_sphere(1.0, 16, 8);
+        // The pole rows only need one triangle per quad
+        check(&mesh, 17 * 9, 3 * 16 * (2 * 8 - 2));
+        check_outward(&mesh);
+    }
+
+    #[test]
+    fn icosphere() {
+        for subdivisions in 0..3 {
+            let mesh = MeshData::icosphere(2.0, subdivisions);
+            let faces = 20 * 4usize.pow(subdivisions as u32);
+            let points = 10 * 4usize.pow(subdivisions as u32) + 2;
+            assert!(mesh.get_vertices().len() >= points);
+            check(&mesh, mesh.get_vertices().len(), faces * 3);
+            check_outward(&mesh);
+        }
+    }
+
+    #[test]
+    fn icosphere_uvs_dont_wrap_across_triangles() {
+        let mesh = MeshData::icosphere(1.0, 2);
+        for triangle in mesh.get_indices().chunks(3) {
+            let us: Vec<f32> = triangle.iter().map(|&index| mesh.get_vertices()[index as usize].get_texcoord().x).collect();
+            let span = us.iter().cloned().fold(f32::NEG_INFINITY, f32::max) - us.iter().cloned().fold(f32::INFINITY, f32::min);
+            assert!(span < 0.25, "{:?} spans {}", triangle, span);
+        }
+    }
+
+    #[test]
+    fn cylinder() {
+        let mesh = MeshData::cylinder(0.5, 2.0, 12, 3);
+        check(&mesh, 13 * 4 + 2 * 14, 6 * 12 * 3 + 2 * 3 * 12);
+    }
+
+    #[test]
+    fn cone() {
+        let mesh = MeshData::cone(0.5, 1.0, 12);
+        check(&mesh, 2 * 13 + 14, 3 * 12 + 3 * 12);
+        check_outward(&mesh);
+    }
+
+    #[test]
+    fn capsule() {
+        let mesh = MeshData::capsule(0.5, 1.0, 12, 4);
+        check(&mesh, 2 * 5 * 13, 12 * 12 * 4);
+        check_outward(&mesh);
+    }
+
+    #[test]
+    fn torus() {
+        let mesh = MeshData::torus(1.0, 0.25, 24, 8);
+        check(&mesh, 9 * 25, 6 * 24 * 8);
+    }
+
+    #[test]
+    fn plane() {
+        let mesh = MeshData::plane(2.0, 1.0, 4, 2);
+        check(&mesh, 5 * 3, 6 * 4 * 2);
+        assert!(mesh.get_vertices().iter().all(|vertex| vertex.get_normal() == glm::vec3(0.0, 1.0, 0.0)));
+    }
+
+    #[test]
+    fn arrow() {
+        let mesh = MeshData::arrow(1.0, 0.05, 0.1, 0.2, 8);
+        check(&mesh, 6 * 8 + 8, 15 * 8);
+    }
+}
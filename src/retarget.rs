@@ -0,0 +1,451 @@
+use crate::animation::*;
+use crate::ik::*;
+use crate::pose::*;
+use crate::skeleton::*;
+use crate::tokenizer::*;
+
+// Which source joint drives which target joint, and the target feet to keep
+// planted while the source feet are
+#[derive(Clone, Debug)]
+pub struct JointMap {
+    joints: Vec<(String, String)>,
+    feet: Vec<String>,
+}
+
+impl JointMap {
+    pub fn new() -> JointMap {
+        JointMap {
+            joints: Vec::new(),
+            feet: Vec::new(),
+        }
+    }
+
+    // Every target joint with a namesake in the source
+    pub fn by_name(source: &Skeleton, target: &Skeleton) -> JointMap {
+        let mut map = Self::new();
+        for joint in target.get_joints() {
+            if source.find_joint(joint.get_name()).is_some() {
+                map.add_joint(joint.get_name(), joint.get_name());
+            }
+        }
+        map
+    }
+
+    pub fn from_file(filename: &str) -> Result<JointMap, ParseError> {
+        let mut tokenizer = Tokenizer::from_file(filename)?;
+        Self::parse(&mut tokenizer)
+    }
+
+    // retarget {
+    //     joint <source> <target>
+    //     foot <target>
+    // }
+    pub fn parse(tokenizer: &mut Tokenizer) -> Result<JointMap, ParseError> {
+        tokenizer.expect("retarget")?;
+        tokenizer.expect("{")?;
+        let mut map = Self::new();
+        loop {
+            let line = tokenizer.get_line();
+            let token = tokenizer.next_token()?;
+            match token.as_str() {
+                "joint" => {
+                    let source = tokenizer.next_token()?;
+                    let target = tokenizer.next_token()?;
+                    map.add_joint(&source, &target);
+                },
+                "foot" => {
+                    let foot = tokenizer.next_token()?;
+                    map.add_foot(&foot);
+                },
+                "}" => break,
+                _ => return Err(ParseError::new(tokenizer.get_file(), line, &format!("unknown retarget attribute '{}'", token))),
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn get_joints(&self) -> &[(String, String)] {
+        &self.joints
+    }
+
+    // A target joint has one source, mapping it again replaces the old one
+    pub fn add_joint(&mut self, source: &str, target: &str) {
+        self.joints.retain(|(_, existing)| existing != target);
+        self.joints.push((source.to_string(), target.to_string()));
+    }
+
+    pub fn get_feet(&self) -> &[String] {
+        &self.feet
+    }
+
+    // The foot needs a source through the joint map and a leg of two bones above it
+    pub fn add_foot(&mut self, target: &str) {
+        if !self.feet.iter().any(|foot| foot == target) {
+            self.feet.push(target.to_string());
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetargetOptions {
+    // Keys per second of the retargeted clips
+    pub frame_rate: f32,
+    pub foot_contacts: bool,
+    // A source foot is planted while it is within this height of its lowest
+    // point and moving slower than this speed, both in leg lengths
+    pub contact_height: f32,
+    pub contact_speed: f32,
+    // Frames over which the foot lock eases in before and out after a contact
+    pub blend_frames: usize,
+}
+
+impl RetargetOptions {
+    pub fn new() -> RetargetOptions {
+        RetargetOptions {
+            frame_rate: 30.0,
+            foot_contacts: true,
+            contact_height: 0.05,
+            contact_speed: 0.5,
+            blend_frames: 4,
+        }
+    }
+}
+
+// World rotation of every joint, ignoring scale and the skeleton transform
+fn world_rotations(pose: &Pose, skeleton: &Skeleton) -> Vec<glm::Quat> {
+    let mut rotations: Vec<glm::Quat> = Vec::with_capacity(pose.get_num_joints());
+    for (index, transform) in pose.get_transforms().iter().enumerate() {
+        let rotation = match skeleton.get_joint(index).get_parent() {
+            Some(parent) => rotations[parent] * transform.get_rotation(),
+            None => transform.get_rotation(),
+        };
+        rotations.push(rotation);
+    }
+    rotations
+}
+
+// Joint positions with the skeleton transform taken back out
+fn rig_positions(pose: &Pose, skeleton: &Skeleton) -> Vec<glm::Vec3> {
+    let inverse = glm::inverse(&skeleton.get_transform());
+    pose.world_mats(skeleton).iter().map(|world_mat| world_position(&(inverse * world_mat))).collect()
+}
+
+// Average thigh plus shin length of the feet, or without any feet the height
+// of the root over the lowest joint, which for a rig standing in its bind pose
+// comes to about the same
+fn leg_length(skeleton: &Skeleton, feet: &[usize]) -> f32 {
+    let legs: Vec<f32> = feet.iter().filter_map(|&foot| {
+        let knee = skeleton.get_joint(foot).get_parent()?;
+        skeleton.get_joint(knee).get_parent()?;
+        Some(glm::length(&skeleton.get_joint(foot).get_offset()) + glm::length(&skeleton.get_joint(knee).get_offset()))
+    }).collect();
+    if !legs.is_empty() {
+        return legs.iter().sum::<f32>() / legs.len() as f32;
+    }
+    let positions = rig_positions(&Pose::from_skeleton(skeleton), skeleton);
+    let lowest = positions.iter().map(|position| position.y).fold(f32::INFINITY, f32::min);
+    positions.first().map_or(0.0, |root| root.y - lowest)
+}
+
+// Moves clips from one rig onto another. Rotations carry over as the change
+// from each rig's bind pose in world space, so the rigs may point their joints
+// differently at rest as long as they stand the same way, and the root travels
+// scaled by the ratio of leg lengths. The skeletons' poses when the retargeter
+// is made are taken as their bind poses.
+#[derive(Clone, Debug)]
+pub struct Retargeter {
+    // Source joint driving each target joint
+    sources: Vec<Option<usize>>,
+    // Source foot, target foot and the target leg solving it
+    feet: Vec<(usize, usize, TwoBoneIk)>,
+    source_bind_world: Vec<glm::Quat>,
+    target_bind: Pose,
+    target_bind_world: Vec<glm::Quat>,
+    source_root: glm::Vec3,
+    target_root: glm::Vec3,
+    source_leg_length: f32,
+    target_leg_length: f32,
+    options: RetargetOptions,
+}
+
+impl Retargeter {
+    pub fn new(source: &Skeleton, target: &Skeleton, map: &JointMap, options: RetargetOptions) -> Result<Retargeter, String> {
+        let mut sources = vec![None; target.get_num_joints()];
+        for (source_name, target_name) in map.get_joints() {
+            let source_joint = source.find_joint(source_name).ok_or_else(|| format!("unknown source joint '{}'", source_name))?;
+            let target_joint = target.find_joint(target_name).ok_or_else(|| format!("unknown target joint '{}'", target_name))?;
+            sources[target_joint] = Some(source_joint);
+        }
+        let mut feet = Vec::new();
+        for name in map.get_feet() {
+            let foot = target.find_joint(name).ok_or_else(|| format!("unknown target foot '{}'", name))?;
+            let source_foot = sources[foot].ok_or_else(|| format!("foot '{}' has no source joint", name))?;
+            let leg = TwoBoneIk::from_end(target, foot).ok_or_else(|| format!("foot '{}' has no leg of two bones above it", name))?;
+            feet.push((source_foot, foot, leg));
+        }
+
+        let source_bind = Pose::from_skeleton(source);
+        let target_bind = Pose::from_skeleton(target);
+        let source_feet: Vec<usize> = feet.iter().map(|(foot, _, _)| *foot).collect();
+        let target_feet: Vec<usize> = feet.iter().map(|(_, foot, _)| *foot).collect();
+        let root = |pose: &Pose| pose.get_transforms().first().map_or(glm::vec3(0.0, 0.0, 0.0), |root| root.get_translation());
+        Ok(Retargeter {
+            sources,
+            source_leg_length: leg_length(source, &source_feet),
+            target_leg_length: leg_length(target, &target_feet),
+            source_bind_world: world_rotations(&source_bind, source),
+            target_bind_world: world_rotations(&target_bind, target),
+            source_root: root(&source_bind),
+            target_root: root(&target_bind),
+            target_bind,
+            feet,
+            options,
+        })
+    }
+
+    pub fn get_options(&self) -> &RetargetOptions {
+        &self.options
+    }
+
+    pub fn get_source(&self, target_joint: usize) -> Option<usize> {
+        self.sources.get(target_joint).cloned().flatten()
+    }
+
+    pub fn get_num_mapped(&self) -> usize {
+        self.sources.iter().filter(|source| source.is_some()).count()
+    }
+
+    pub fn get_source_leg_length(&self) -> f32 {
+        self.source_leg_length
+    }
+
+    pub fn get_target_leg_length(&self) -> f32 {
+        self.target_leg_length
+    }
+
+    // Root travel is multiplied by this
+    pub fn get_scale(&self) -> f32 {
+        if self.source_leg_length > 1e-6 && self.target_leg_length > 1e-6 {
+            self.target_leg_length / self.source_leg_length
+        } else {
+            1.0
+        }
+    }
+
+    // One source pose on the target rig, without the foot contacts which need
+    // the frames around it. Unmapped target joints keep their bind rotation.
+    pub fn retarget_pose(&self, source_pose: &Pose, source: &Skeleton, target: &Skeleton) -> Pose {
+        let source_world = world_rotations(source_pose, source);
+        let mut pose = self.target_bind.clone();
+        let mut target_world: Vec<glm::Quat> = Vec::with_capacity(pose.get_num_joints());
+        for index in 0..pose.get_num_joints() {
+            let parent = match target.get_joint(index).get_parent() {
+                Some(parent) => target_world[parent],
+                None => glm::quat(0.0, 0.0, 0.0, 1.0),
+            };
+            let world = match self.sources[index] {
+                Some(source_joint) => {
+                    let delta = source_world[source_joint] * glm::quat_inverse(&self.source_bind_world[source_joint]);
+                    delta * self.target_bind_world[index]
+                },
+                None => parent * pose.get_transform(index).get_rotation(),
+            };
+            pose.get_transform_mut(index).set_rotation(glm::quat_normalize(&(glm::quat_inverse(&parent) * world)));
+            target_world.push(world);
+        }
+        if pose.get_num_joints() > 0 && source_pose.get_num_joints() > 0 {
+            let travel = source_pose.get_transform(0).get_translation() - self.source_root;
+            pose.get_transform_mut(0).set_translation(self.target_root + travel * self.get_scale());
+        }
+        pose
+    }
+
+    // Bakes the clip onto the target rig with a linear key per frame, looping
+    // the way the source clip does
+    pub fn retarget_clip(&self, animation: &Animation, source: &Skeleton, target: &Skeleton) -> Animation {
+        let start = animation.get_start_time();
+        let end = animation.get_end_time().max(start);
+        let num_frames = ((end - start) * self.options.frame_rate).ceil() as usize + 1;
+        let frame_time = if num_frames > 1 { (end - start) / (num_frames - 1) as f32 } else { 0.0 };
+        let times: Vec<f32> = (0..num_frames).map(|frame| start + frame as f32 * frame_time).collect();
+        let source_poses: Vec<Pose> = times.iter().map(|&time| animation.sample(time, source)).collect();
+        let mut poses: Vec<Pose> = source_poses.iter().map(|pose| self.retarget_pose(pose, source, target)).collect();
+        if self.options.foot_contacts {
+            self.preserve_contacts(&source_poses, &mut poses, source, target, frame_time);
+        }
+
+        let (extrapolation_in, extrapolation_out) = animation.get_channel(0)
+            .map_or((Extrapolation::Constant, Extrapolation::Constant), |channel| (channel.get_extrapolation_in(), channel.get_extrapolation_out()));
+        Animation::from_poses(start, frame_time, &poses, target, extrapolation_in, extrapolation_out)
+    }
+
+    // Wherever a source foot is planted, pins the target foot to where it was
+    // when the contact began, with the leg solved by IK and the foot keeping
+    // its own orientation
+    fn preserve_contacts(&self, source_poses: &[Pose], poses: &mut [Pose], source: &Skeleton, target: &Skeleton, frame_time: f32) {
+        let num_frames = poses.len();
+        let height = self.options.contact_height * self.source_leg_length;
+        let speed = self.options.contact_speed * self.source_leg_length;
+        let blend = self.options.blend_frames;
+        for (source_foot, target_foot, leg) in &self.feet {
+            let positions: Vec<glm::Vec3> = source_poses.iter().map(|pose| rig_positions(pose, source)[*source_foot]).collect();
+            let lowest = positions.iter().map(|position| position.y).fold(f32::INFINITY, f32::min);
+            let planted: Vec<bool> = (0..num_frames).map(|frame| {
+                let before = frame.saturating_sub(1);
+                let after = (frame + 1).min(num_frames - 1);
+                let mut travel = positions[after] - positions[before];
+                travel.y = 0.0;
+                let span = (after - before) as f32 * frame_time;
+                let moving = if span > 0.0 { glm::length(&travel) / span } else { 0.0 };
+                positions[frame].y <= lowest + height && moving <= speed
+            }).collect();
+
+            // Where the foot is held on each frame and how firmly
+            let mut locks: Vec<Option<(glm::Vec3, f32)>> = vec![None; num_frames];
+            let mut frame = 0;
+            while frame < num_frames {
+                if !planted[frame] {
+                    frame += 1;
+                    continue;
+                }
+                let first = frame;
+                while frame < num_frames && planted[frame] {
+                    frame += 1;
+                }
+                let last = frame - 1;
+                let lock = world_position(&poses[first].world_mats(target)[*target_foot]);
+                for (index, held) in locks.iter_mut().enumerate().take(last + blend + 1).skip(first.saturating_sub(blend)) {
+                    let distance = first.saturating_sub(index).max(index.saturating_sub(last));
+                    let weight = 1.0 - distance as f32 / (blend + 1) as f32;
+                    if !matches!(*held, Some((_, firmness)) if firmness >= weight) {
+                        *held = Some((lock, weight));
+                    }
+                }
+            }
+
+            let [_, knee, _] = leg.get_joints();
+            for (pose, lock) in poses.iter_mut().zip(&locks) {
+                if let Some((position, weight)) = *lock {
+                    let foot_rotation = world_rotation(pose, target, *target_foot);
+                    let mut leg = leg.clone();
+                    leg.set_weight(weight);
+                    leg.solve(target, pose, position);
+                    let knee_rotation = world_rotation(pose, target, knee);
+                    let local = glm::quat_inverse(&knee_rotation) * foot_rotation;
+                    pose.get_transform_mut(*target_foot).set_rotation(glm::quat_normalize(&local));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animation::tests::constant;
+
+    // Hips with a spine and one leg. The target is half again as big, and its
+    // spine points down its x axis at rest where the source's points down y.
+    fn rig(scale: f32, turned_spine: bool) -> Skeleton {
+        let mut skeleton = Skeleton::new();
+        let mut add = |name: &str, parent: Option<usize>, offset: glm::Vec3| {
+            let mut joint = Joint::new(name, parent);
+            joint.set_offset(offset * scale);
+            skeleton.add_joint(joint)
+        };
+        let hips = add("hips", None, glm::vec3(0.0, 2.0, 0.0));
+        let spine = add("spine", Some(hips), glm::vec3(0.0, 0.5, 0.0));
+        add("head", Some(spine), if turned_spine { glm::vec3(0.5, 0.0, 0.0) } else { glm::vec3(0.0, 0.5, 0.0) });
+        let thigh = add("thigh", Some(hips), glm::vec3(0.2, 0.0, 0.0));
+        let knee = add("knee", Some(thigh), glm::vec3(0.0, -1.0, 0.0));
+        add("foot", Some(knee), glm::vec3(0.0, -1.0, 0.0));
+        if turned_spine {
+            skeleton.get_joint_mut(spine).set_pose(glm::vec3(0.0, 0.0, 90.0f32.to_radians()));
+        }
+        skeleton.update();
+        skeleton
+    }
+
+    fn retargeter(source: &Skeleton, target: &Skeleton, options: RetargetOptions) -> Retargeter {
+        let mut map = JointMap::by_name(source, target);
+        map.add_foot("foot");
+        Retargeter::new(source, target, &map, options).unwrap()
+    }
+
+    fn assert_close(a: glm::Vec3, b: glm::Vec3) {
+        assert!(glm::length(&(a - b)) < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn root_travel_scales_with_the_legs() {
+        let (source, target) = (rig(1.0, false), rig(1.5, true));
+        let retargeter = retargeter(&source, &target, RetargetOptions::new());
+        assert_eq!(retargeter.get_num_mapped(), 6);
+        assert!((retargeter.get_source_leg_length() - 2.0).abs() < 1e-5);
+        assert!((retargeter.get_target_leg_length() - 3.0).abs() < 1e-5);
+        assert!((retargeter.get_scale() - 1.5).abs() < 1e-5);
+
+        let mut pose = Pose::from_skeleton(&source);
+        pose.get_transform_mut(0).set_translation(glm::vec3(1.0, 1.8, 0.5));
+        let retargeted = retargeter.retarget_pose(&pose, &source, &target);
+        assert_close(retargeted.get_transform(0).get_translation(), glm::vec3(1.5, 2.7, 0.75));
+    }
+
+    #[test]
+    fn rotations_carry_over_between_bind_orientations() {
+        let (source, target) = (rig(1.0, false), rig(1.5, true));
+        let retargeter = retargeter(&source, &target, RetargetOptions::new());
+        let bend = glm::quat_angle_axis(30.0f32.to_radians(), &glm::vec3(1.0, 0.0, 0.0));
+        let mut pose = Pose::from_skeleton(&source);
+        pose.get_transform_mut(1).set_rotation(bend);
+        let retargeted = retargeter.retarget_pose(&pose, &source, &target);
+
+        // The target spine turns the same way in the world even though its
+        // local axes differ, so its local rotation doesn't match the source's
+        let spine_local = retargeted.get_transform(1).get_rotation();
+        assert!(glm::length(&(spine_local.coords - bend.coords)) > 0.1);
+        let bind = target.get_joint(1).get_rotation();
+        let expected = bend * bind;
+        assert!(glm::quat_dot(&spine_local, &expected).abs() > 1.0 - 1e-5, "{:?} != {:?}", spine_local, expected);
+        let world_mats = retargeted.world_mats(&target);
+        let neck = world_position(&world_mats[2]) - world_position(&world_mats[1]);
+        assert_close(neck, glm::quat_rotate_vec3(&bend, &glm::vec3(0.0, 0.75, 0.0)));
+    }
+
+    // Bent legs sliding forward slowly enough that the foot counts as planted
+    fn shuffle() -> Animation {
+        let mut channels: Vec<Channel> = (0..21).map(|_| constant(0.0)).collect();
+        channels[0] = Channel::new(vec![
+            Keyframe::new(0.0, 0.0, TangentRule::Linear, TangentRule::Linear),
+            Keyframe::new(1.0, 0.3, TangentRule::Linear, TangentRule::Linear),
+        ], Extrapolation::Constant, Extrapolation::Constant);
+        channels[1] = constant(2.0);
+        channels[3 + 3 * 3] = constant(-30.0f32.to_radians());
+        channels[3 + 3 * 4] = constant(60.0f32.to_radians());
+        Animation::new(0.0, 1.0, channels)
+    }
+
+    fn foot_path(animation: &Animation, target: &Skeleton) -> Vec<glm::Vec3> {
+        (0..=10).map(|step| {
+            let pose = animation.sample(step as f32 / 10.0, target);
+            world_position(&pose.world_mats(target)[5])
+        }).collect()
+    }
+
+    #[test]
+    fn planted_feet_stay_put() {
+        let (source, target) = (rig(1.0, false), rig(1.5, true));
+        let clip = shuffle();
+
+        let mut options = RetargetOptions::new();
+        options.foot_contacts = false;
+        let sliding = foot_path(&retargeter(&source, &target, options).retarget_clip(&clip, &source, &target), &target);
+        assert!(glm::length(&(sliding[10] - sliding[0])) > 0.4, "{:?}", sliding);
+
+        let planted = foot_path(&retargeter(&source, &target, RetargetOptions::new()).retarget_clip(&clip, &source, &target), &target);
+        assert_close(planted[0], sliding[0]);
+        for position in &planted {
+            assert!(glm::length(&(position - planted[0])) < 1e-3, "{:?} drifted from {:?}", position, planted[0]);
+        }
+    }
+}
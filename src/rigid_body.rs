@@ -0,0 +1,897 @@
+use gl::types::*;
+use crate::model::*;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Shape {
+    Box { half_extents: glm::Vec3 },
+    Sphere { radius: f32 },
+}
+
+// A mass of zero makes the body static, nothing moves it but set_position
+#[derive(Clone, Debug)]
+pub struct RigidBody {
+    shape: Shape,
+    mass: f32,
+    inverse_mass: f32,
+    // Diagonal of the inverse inertia tensor in body space
+    inverse_inertia: glm::Vec3,
+    position: glm::Vec3,
+    orientation: glm::Quat,
+    linear_velocity: glm::Vec3,
+    angular_velocity: glm::Vec3,
+    force: glm::Vec3,
+    torque: glm::Vec3,
+    restitution: f32,
+    friction: f32,
+}
+
+impl RigidBody {
+    pub fn new(shape: Shape, mass: f32, position: glm::Vec3) -> RigidBody {
+        let inertia = match shape {
+            Shape::Box { half_extents: h } => glm::vec3(h.y * h.y + h.z * h.z, h.x * h.x + h.z * h.z, h.x * h.x + h.y * h.y) * (mass / 3.0),
+            Shape::Sphere { radius } => glm::vec3(1.0, 1.0, 1.0) * (0.4 * mass * radius * radius),
+        };
+        let invert = |value: f32| if value > 0.0 { 1.0 / value } else { 0.0 };
+        RigidBody {
+            shape,
+            mass: mass.max(0.0),
+            inverse_mass: invert(mass),
+            inverse_inertia: glm::vec3(invert(inertia.x), invert(inertia.y), invert(inertia.z)),
+            position,
+            orientation: glm::quat_identity(),
+            linear_velocity: glm::vec3(0.0, 0.0, 0.0),
+            angular_velocity: glm::vec3(0.0, 0.0, 0.0),
+            force: glm::vec3(0.0, 0.0, 0.0),
+            torque: glm::vec3(0.0, 0.0, 0.0),
+            restitution: 0.2,
+            friction: 0.6,
+        }
+    }
+
+    pub fn get_shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    pub fn get_mass(&self) -> f32 {
+        self.mass
+    }
+
+    pub fn get_inverse_mass(&self) -> f32 {
+        self.inverse_mass
+    }
+
+    pub fn is_static(&self) -> bool {
+        self.inverse_mass == 0.0
+    }
+
+    pub fn get_position(&self) -> glm::Vec3 {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: glm::Vec3) {
+        self.position = position;
+    }
+
+    pub fn get_orientation(&self) -> glm::Quat {
+        self.orientation
+    }
+
+    pub fn set_orientation(&mut self, orientation: glm::Quat) {
+        self.orientation = glm::quat_normalize(&orientation);
+    }
+
+    pub fn get_linear_velocity(&self) -> glm::Vec3 {
+        self.linear_velocity
+    }
+
+    pub fn set_linear_velocity(&mut self, velocity: glm::Vec3) {
+        self.linear_velocity = velocity;
+    }
+
+    pub fn get_angular_velocity(&self) -> glm::Vec3 {
+        self.angular_velocity
+    }
+
+    pub fn set_angular_velocity(&mut self, velocity: glm::Vec3) {
+        self.angular_velocity = velocity;
+    }
+
+    pub fn get_restitution(&self) -> f32 {
+        self.restitution
+    }
+
+    pub fn set_restitution(&mut self, restitution: f32) {
+        self.restitution = restitution;
+    }
+
+    pub fn get_friction(&self) -> f32 {
+        self.friction
+    }
+
+    pub fn set_friction(&mut self, friction: f32) {
+        self.friction = friction;
+    }
+
+    // Accumulated until the next integrate_velocity
+    pub fn apply_force(&mut self, force: glm::Vec3, point: glm::Vec3) {
+        self.force += force;
+        self.torque += (point - self.position).cross(&force);
+    }
+
+    pub fn apply_impulse(&mut self, impulse: glm::Vec3, point: glm::Vec3) {
+        self.linear_velocity += impulse * self.inverse_mass;
+        self.angular_velocity += self.get_inverse_inertia_world() * (point - self.position).cross(&impulse);
+    }
+
+    pub fn get_rotation_mat(&self) -> glm::Mat3 {
+        glm::quat_to_mat3(&self.orientation)
+    }
+
+    // R I⁻¹ Rᵀ
+    pub fn get_inverse_inertia_world(&self) -> glm::Mat3 {
+        let rotation = self.get_rotation_mat();
+        rotation * glm::Mat3::from_diagonal(&self.inverse_inertia) * rotation.transpose()
+    }
+
+    pub fn get_velocity_at(&self, point: glm::Vec3) -> glm::Vec3 {
+        self.linear_velocity + self.angular_velocity.cross(&(point - self.position))
+    }
+
+    pub fn get_world_mat(&self) -> glm::Mat4 {
+        glm::translation(&self.position) * glm::quat_to_mat4(&self.orientation)
+    }
+
+    // First half of a semi-implicit Euler step, contacts are solved on the
+    // new velocities before positions follow. The gyroscopic term is left out,
+    // integrated explicitly it adds energy to long thin bodies.
+    pub fn integrate_velocity(&mut self, dt: f32, gravity: glm::Vec3) {
+        if self.is_static() {
+            return;
+        }
+        self.linear_velocity += (gravity + self.force * self.inverse_mass) * dt;
+        self.angular_velocity += self.get_inverse_inertia_world() * self.torque * dt;
+        self.force = glm::vec3(0.0, 0.0, 0.0);
+        self.torque = glm::vec3(0.0, 0.0, 0.0);
+    }
+
+    pub fn integrate_position(&mut self, dt: f32) {
+        if self.is_static() {
+            return;
+        }
+        self.position += self.linear_velocity * dt;
+        let w = self.angular_velocity;
+        let spin = glm::quat(w.x, w.y, w.z, 0.0) * self.orientation;
+        let orientation = glm::quat(
+            self.orientation.coords.x + spin.coords.x * 0.5 * dt,
+            self.orientation.coords.y + spin.coords.y * 0.5 * dt,
+            self.orientation.coords.z + spin.coords.z * 0.5 * dt,
+            self.orientation.coords.w + spin.coords.w * 0.5 * dt,
+        );
+        self.orientation = glm::quat_normalize(&orientation);
+    }
+
+    // World space box axes, the columns of the rotation
+    fn get_axes(&self) -> [glm::Vec3; 3] {
+        let rotation = self.get_rotation_mat();
+        [
+            glm::vec3(rotation[(0, 0)], rotation[(1, 0)], rotation[(2, 0)]),
+            glm::vec3(rotation[(0, 1)], rotation[(1, 1)], rotation[(2, 1)]),
+            glm::vec3(rotation[(0, 2)], rotation[(1, 2)], rotation[(2, 2)]),
+        ]
+    }
+}
+
+// Points x with dot(normal, x) = offset, solid on the side against the normal
+#[derive(Clone, Copy, Debug)]
+pub struct Plane {
+    pub normal: glm::Vec3,
+    pub offset: f32,
+}
+
+// Contact pushing body `a` along `normal` and `b`, if it isn't a plane, the
+// other way
+#[derive(Clone, Copy, Debug)]
+pub struct Contact {
+    pub a: usize,
+    pub b: Option<usize>,
+    pub point: glm::Vec3,
+    pub normal: glm::Vec3,
+    pub depth: f32,
+    // Accumulated impulses along the normal and the two tangents, carried
+    // over to the next step when the contact persists
+    normal_impulse: f32,
+    tangent_impulse: [f32; 2],
+    local_point: glm::Vec3,
+}
+
+impl Contact {
+    fn new(a: usize, b: Option<usize>, point: glm::Vec3, normal: glm::Vec3, depth: f32) -> Contact {
+        Contact {
+            a,
+            b,
+            point,
+            normal,
+            depth,
+            normal_impulse: 0.0,
+            tangent_impulse: [0.0, 0.0],
+            local_point: point,
+        }
+    }
+
+    pub fn get_normal_impulse(&self) -> f32 {
+        self.normal_impulse
+    }
+}
+
+// Per contact values fixed for the duration of a solve
+struct ContactConstraint {
+    ra: glm::Vec3,
+    rb: glm::Vec3,
+    tangents: [glm::Vec3; 2],
+    normal_mass: f32,
+    tangent_mass: [f32; 2],
+    velocity_bias: f32,
+    friction: f32,
+}
+
+pub struct RigidWorld {
+    bodies: Vec<RigidBody>,
+    initial: Vec<RigidBody>,
+    planes: Vec<Plane>,
+    contacts: Vec<Contact>,
+    gravity: glm::Vec3,
+    iterations: usize,
+    time_step: f32,
+    accumulator: f32,
+    box_model: Option<Model>,
+    sphere_model: Option<Model>,
+}
+
+impl RigidWorld {
+    pub fn new() -> RigidWorld {
+        RigidWorld {
+            bodies: Vec::new(),
+            initial: Vec::new(),
+            planes: Vec::new(),
+            contacts: Vec::new(),
+            gravity: glm::vec3(0.0, -9.8, 0.0),
+            iterations: 10,
+            time_step: 1.0 / 120.0,
+            accumulator: 0.0,
+            box_model: None,
+            sphere_model: None,
+        }
+    }
+
+    pub fn add_body(&mut self, body: RigidBody) -> usize {
+        self.initial.push(body.clone());
+        self.bodies.push(body);
+        self.bodies.len() - 1
+    }
+
+    pub fn add_plane(&mut self, normal: glm::Vec3, offset: f32) -> usize {
+        self.planes.push(Plane {
+            normal: glm::normalize(&normal),
+            offset,
+        });
+        self.planes.len() - 1
+    }
+
+    pub fn get_bodies(&self) -> &[RigidBody] {
+        &self.bodies
+    }
+
+    pub fn get_body(&self, body: usize) -> &RigidBody {
+        &self.bodies[body]
+    }
+
+    pub fn get_body_mut(&mut self, body: usize) -> &mut RigidBody {
+        &mut self.bodies[body]
+    }
+
+    pub fn get_planes(&self) -> &[Plane] {
+        &self.planes
+    }
+
+    pub fn get_contacts(&self) -> &[Contact] {
+        &self.contacts
+    }
+
+    pub fn get_gravity(&self) -> glm::Vec3 {
+        self.gravity
+    }
+
+    pub fn set_gravity(&mut self, gravity: glm::Vec3) {
+        self.gravity = gravity;
+    }
+
+    pub fn get_iterations(&self) -> usize {
+        self.iterations
+    }
+
+    pub fn set_iterations(&mut self, iterations: usize) {
+        self.iterations = iterations;
+    }
+
+    pub fn get_time_step(&self) -> f32 {
+        self.time_step
+    }
+
+    pub fn set_time_step(&mut self, time_step: f32) {
+        self.time_step = time_step;
+    }
+
+    // Runs as many fixed steps as fit in the frame, so the simulation doesn't
+    // depend on the frame rate
+    pub fn update(&mut self, dt: f32) {
+        // Don't try to catch up after a long stall
+        self.accumulator = (self.accumulator + dt).min(0.1);
+        while self.accumulator >= self.time_step {
+            self.step(self.time_step);
+            self.accumulator -= self.time_step;
+        }
+    }
+
+    // One step of `dt`, deterministic for the same bodies and step sizes
+    pub fn step(&mut self, dt: f32) {
+        for body in &mut self.bodies {
+            body.integrate_velocity(dt, self.gravity);
+        }
+        let previous = std::mem::take(&mut self.contacts);
+        self.contacts = self.find_contacts();
+        self.warm_start(&previous);
+        self.solve(dt);
+        for body in &mut self.bodies {
+            body.integrate_position(dt);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.bodies = self.initial.clone();
+        self.contacts.clear();
+        self.accumulator = 0.0;
+    }
+
+    fn find_contacts(&self) -> Vec<Contact> {
+        let mut contacts = Vec::new();
+        for a in 0..self.bodies.len() {
+            if self.bodies[a].is_static() {
+                continue;
+            }
+            for plane in &self.planes {
+                collide_plane(a, &self.bodies[a], plane, &mut contacts);
+            }
+        }
+        for a in 0..self.bodies.len() {
+            for b in a + 1..self.bodies.len() {
+                if self.bodies[a].is_static() && self.bodies[b].is_static() {
+                    continue;
+                }
+                collide_bodies(a, &self.bodies[a], b, &self.bodies[b], &mut contacts);
+            }
+        }
+        for contact in &mut contacts {
+            let body = &self.bodies[contact.a];
+            contact.local_point = glm::quat_rotate_vec3(&glm::quat_conjugate(&body.orientation), &(contact.point - body.position));
+        }
+        contacts
+    }
+
+    // Starts each contact from the impulses it ended the last step with,
+    // matched by the same bodies and nearly the same point on `a`. Stacks
+    // settle in far fewer iterations that way.
+    fn warm_start(&mut self, previous: &[Contact]) {
+        for contact in &mut self.contacts {
+            let matching = previous.iter().find(|old| {
+                old.a == contact.a && old.b == contact.b && glm::distance(&old.local_point, &contact.local_point) < 0.02
+            });
+            if let Some(old) = matching {
+                contact.normal_impulse = old.normal_impulse;
+                contact.tangent_impulse = old.tangent_impulse;
+            }
+        }
+    }
+
+    // Sequential impulses, each contact clamps its accumulated impulse to
+    // push only and keeps friction inside the cone
+    fn solve(&mut self, dt: f32) {
+        const BAUMGARTE: f32 = 0.2;
+        const SLOP: f32 = 0.005;
+        const BOUNCE_THRESHOLD: f32 = 1.0;
+
+        let mut constraints = Vec::with_capacity(self.contacts.len());
+        for contact in &self.contacts {
+            let a = &self.bodies[contact.a];
+            let b = contact.b.map(|b| &self.bodies[b]);
+            let ra = contact.point - a.position;
+            let rb = b.map_or(glm::vec3(0.0, 0.0, 0.0), |b| contact.point - b.position);
+            let tangents = tangent_basis(&contact.normal);
+            let effective_mass = |direction: &glm::Vec3| {
+                let mut k = a.inverse_mass + glm::dot(&(a.get_inverse_inertia_world() * ra.cross(direction)).cross(&ra), direction);
+                if let Some(b) = b {
+                    k += b.inverse_mass + glm::dot(&(b.get_inverse_inertia_world() * rb.cross(direction)).cross(&rb), direction);
+                }
+                if k > 0.0 { 1.0 / k } else { 0.0 }
+            };
+            let relative = a.get_velocity_at(contact.point) - b.map_or(glm::vec3(0.0, 0.0, 0.0), |b| b.get_velocity_at(contact.point));
+            let approach = glm::dot(&relative, &contact.normal);
+            let restitution = a.restitution.max(b.map_or(a.restitution, |b| b.restitution));
+            let bounce = if approach < -BOUNCE_THRESHOLD { -restitution * approach } else { 0.0 };
+            constraints.push(ContactConstraint {
+                ra,
+                rb,
+                tangents,
+                normal_mass: effective_mass(&contact.normal),
+                tangent_mass: [effective_mass(&tangents[0]), effective_mass(&tangents[1])],
+                velocity_bias: bounce.max(BAUMGARTE / dt * (contact.depth - SLOP).max(0.0)),
+                friction: (a.friction * b.map_or(a.friction, |b| b.friction)).sqrt(),
+            });
+        }
+
+        for (index, constraint) in constraints.iter().enumerate() {
+            let contact = self.contacts[index];
+            let impulse = contact.normal * contact.normal_impulse
+                + constraint.tangents[0] * contact.tangent_impulse[0]
+                + constraint.tangents[1] * contact.tangent_impulse[1];
+            self.apply_impulse(&contact, constraint, impulse);
+        }
+
+        for _ in 0..self.iterations {
+            for (index, constraint) in constraints.iter().enumerate() {
+                let mut contact = self.contacts[index];
+
+                // Friction first, so the normal impulse has the last word on
+                // penetration
+                let limit = constraint.friction * contact.normal_impulse;
+                for tangent in 0..2 {
+                    let direction = constraint.tangents[tangent];
+                    let speed = glm::dot(&self.relative_velocity(&contact, constraint), &direction);
+                    let old = contact.tangent_impulse[tangent];
+                    contact.tangent_impulse[tangent] = glm::clamp_scalar(old - speed * constraint.tangent_mass[tangent], -limit, limit);
+                    self.apply_impulse(&contact, constraint, direction * (contact.tangent_impulse[tangent] - old));
+                }
+
+                let speed = glm::dot(&self.relative_velocity(&contact, constraint), &contact.normal);
+                let old = contact.normal_impulse;
+                contact.normal_impulse = (old + (constraint.velocity_bias - speed) * constraint.normal_mass).max(0.0);
+                self.apply_impulse(&contact, constraint, contact.normal * (contact.normal_impulse - old));
+
+                self.contacts[index] = contact;
+            }
+        }
+    }
+
+    fn relative_velocity(&self, contact: &Contact, constraint: &ContactConstraint) -> glm::Vec3 {
+        let a = &self.bodies[contact.a];
+        let mut velocity = a.linear_velocity + a.angular_velocity.cross(&constraint.ra);
+        if let Some(b) = contact.b {
+            let b = &self.bodies[b];
+            velocity -= b.linear_velocity + b.angular_velocity.cross(&constraint.rb);
+        }
+        velocity
+    }
+
+    fn apply_impulse(&mut self, contact: &Contact, constraint: &ContactConstraint, impulse: glm::Vec3) {
+        let a = &mut self.bodies[contact.a];
+        a.linear_velocity += impulse * a.inverse_mass;
+        a.angular_velocity += a.get_inverse_inertia_world() * constraint.ra.cross(&impulse);
+        if let Some(b) = contact.b {
+            let b = &mut self.bodies[b];
+            b.linear_velocity -= impulse * b.inverse_mass;
+            b.angular_velocity -= b.get_inverse_inertia_world() * constraint.rb.cross(&impulse);
+        }
+    }
+
+    // Shared unit shapes scaled per body
+    pub fn make_model(&mut self) {
+        let mut box_model = Model::new();
+        box_model.make_box(glm::vec3(-1.0, -1.0, -1.0), glm::vec3(1.0, 1.0, 1.0));
+        self.box_model = Some(box_model);
+        let mut sphere_model = Model::new();
+        sphere_model.make_uv_sphere(1.0, 24, 16);
+        self.sphere_model = Some(sphere_model);
+    }
+
+    pub fn draw(&self, view_proj_mat: glm::Mat4, shader: GLuint) {
+        for body in &self.bodies {
+            let (model, scale) = match body.shape {
+                Shape::Box { half_extents } => (self.box_model.as_ref(), half_extents),
+                Shape::Sphere { radius } => (self.sphere_model.as_ref(), glm::vec3(radius, radius, radius)),
+            };
+            if let Some(model) = model {
+                model.draw(body.get_world_mat() * glm::scaling(&scale), view_proj_mat, shader);
+            }
+        }
+    }
+}
+
+fn tangent_basis(normal: &glm::Vec3) -> [glm::Vec3; 2] {
+    let helper = if normal.x.abs() < 0.57 { glm::vec3(1.0, 0.0, 0.0) } else { glm::vec3(0.0, 1.0, 0.0) };
+    let first = glm::normalize(&normal.cross(&helper));
+    [first, normal.cross(&first)]
+}
+
+fn box_corners(body: &RigidBody, half_extents: &glm::Vec3) -> [glm::Vec3; 8] {
+    let axes = body.get_axes();
+    let mut corners = [body.position; 8];
+    for (index, corner) in corners.iter_mut().enumerate() {
+        for axis in 0..3 {
+            let sign = if index & (1 << axis) != 0 { 1.0 } else { -1.0 };
+            *corner += axes[axis] * (half_extents[axis] * sign);
+        }
+    }
+    corners
+}
+
+fn collide_plane(index: usize, body: &RigidBody, plane: &Plane, contacts: &mut Vec<Contact>) {
+    match body.shape {
+        Shape::Sphere { radius } => {
+            let distance = glm::dot(&plane.normal, &body.position) - plane.offset - radius;
+            if distance < 0.0 {
+                contacts.push(Contact::new(index, None, body.position - plane.normal * radius, plane.normal, -distance));
+            }
+        },
+        Shape::Box { half_extents } => {
+            for corner in box_corners(body, &half_extents).iter() {
+                let distance = glm::dot(&plane.normal, corner) - plane.offset;
+                if distance < 0.0 {
+                    contacts.push(Contact::new(index, None, *corner, plane.normal, -distance));
+                }
+            }
+        },
+    }
+}
+
+fn collide_bodies(a: usize, body_a: &RigidBody, b: usize, body_b: &RigidBody, contacts: &mut Vec<Contact>) {
+    match (body_a.shape, body_b.shape) {
+        (Shape::Sphere { radius: radius_a }, Shape::Sphere { radius: radius_b }) => {
+            let offset = body_b.position - body_a.position;
+            let distance = glm::length(&offset);
+            if distance < radius_a + radius_b {
+                let normal = if distance > 1e-6 { offset / distance } else { glm::vec3(0.0, 1.0, 0.0) };
+                let point = body_a.position + normal * (radius_a - 0.5 * (radius_a + radius_b - distance));
+                contacts.push(Contact::new(b, Some(a), point, normal, radius_a + radius_b - distance));
+            }
+        },
+        (Shape::Box { half_extents }, Shape::Sphere { radius }) => collide_box_sphere(a, body_a, &half_extents, b, body_b, radius, contacts),
+        (Shape::Sphere { radius }, Shape::Box { half_extents }) => collide_box_sphere(b, body_b, &half_extents, a, body_a, radius, contacts),
+        (Shape::Box { half_extents: half_a }, Shape::Box { half_extents: half_b }) => collide_boxes(a, body_a, &half_a, b, body_b, &half_b, contacts),
+    }
+}
+
+// Closest point on the box to the sphere center, or the nearest face when the
+// center is inside
+fn collide_box_sphere(box_index: usize, body_box: &RigidBody, half_extents: &glm::Vec3, sphere_index: usize, body_sphere: &RigidBody, radius: f32, contacts: &mut Vec<Contact>) {
+    let axes = body_box.get_axes();
+    let offset = body_sphere.position - body_box.position;
+    let local = glm::vec3(glm::dot(&offset, &axes[0]), glm::dot(&offset, &axes[1]), glm::dot(&offset, &axes[2]));
+    let clamped = glm::vec3(
+        glm::clamp_scalar(local.x, -half_extents.x, half_extents.x),
+        glm::clamp_scalar(local.y, -half_extents.y, half_extents.y),
+        glm::clamp_scalar(local.z, -half_extents.z, half_extents.z),
+    );
+    let to_world = |local: glm::Vec3| body_box.position + axes[0] * local.x + axes[1] * local.y + axes[2] * local.z;
+
+    let (normal, point, depth) = if clamped != local {
+        let closest = to_world(clamped);
+        let delta = body_sphere.position - closest;
+        let distance = glm::length(&delta);
+        if distance >= radius || distance <= 1e-6 {
+            return;
+        }
+        (delta / distance, closest, radius - distance)
+    } else {
+        let mut face = 0;
+        for axis in 1..3 {
+            if half_extents[axis] - local[axis].abs() < half_extents[face] - local[face].abs() {
+                face = axis;
+            }
+        }
+        let sign = if local[face] < 0.0 { -1.0 } else { 1.0 };
+        let normal = axes[face] * sign;
+        let mut surface = local;
+        surface[face] = half_extents[face] * sign;
+        (normal, to_world(surface), half_extents[face] - local[face].abs() + radius)
+    };
+    contacts.push(Contact::new(sphere_index, Some(box_index), point, normal, depth));
+}
+
+// Separating axis test over the face normals of both boxes and the crossings
+// of their edges. A face axis clips the other box's most opposed face against
+// the sides of the reference face, an edge axis gives the closest points of
+// the two edges.
+fn collide_boxes(a: usize, body_a: &RigidBody, half_a: &glm::Vec3, b: usize, body_b: &RigidBody, half_b: &glm::Vec3, contacts: &mut Vec<Contact>) {
+    let axes_a = body_a.get_axes();
+    let axes_b = body_b.get_axes();
+    let offset = body_b.position - body_a.position;
+    let projected = |axes: &[glm::Vec3; 3], half: &glm::Vec3, axis: &glm::Vec3| {
+        (0..3).map(|k| half[k] * glm::dot(&axes[k], axis).abs()).sum::<f32>()
+    };
+    let separation = |axis: &glm::Vec3| {
+        glm::dot(&offset, axis).abs() - projected(&axes_a, half_a, axis) - projected(&axes_b, half_b, axis)
+    };
+
+    // Best face axis, 0..3 on a and 3..6 on b
+    let mut face_axis = 0;
+    let mut face_separation = f32::NEG_INFINITY;
+    for axis in 0..6 {
+        let direction = if axis < 3 { axes_a[axis] } else { axes_b[axis - 3] };
+        let distance = separation(&direction);
+        if distance > 0.0 {
+            return;
+        }
+        if distance > face_separation {
+            face_separation = distance;
+            face_axis = axis;
+        }
+    }
+    let mut edge_axis = None;
+    let mut edge_separation = f32::NEG_INFINITY;
+    for (i, axis_a) in axes_a.iter().enumerate() {
+        for (j, axis_b) in axes_b.iter().enumerate() {
+            let cross = axis_a.cross(axis_b);
+            let length = glm::length(&cross);
+            if length < 1e-4 {
+                continue;
+            }
+            let direction = cross / length;
+            let distance = separation(&direction);
+            if distance > 0.0 {
+                return;
+            }
+            if distance > edge_separation {
+                edge_separation = distance;
+                edge_axis = Some((i, j, direction));
+            }
+        }
+    }
+
+    // Faces win unless an edge axis is clearly better, they give stable
+    // manifolds for resting boxes
+    if let Some((i, j, direction)) = edge_axis {
+        if edge_separation > 0.95 * face_separation + 0.01 {
+            let normal = if glm::dot(&direction, &offset) < 0.0 { -direction } else { direction };
+            let support = |body: &RigidBody, axes: &[glm::Vec3; 3], half: &glm::Vec3, skip: usize, toward: &glm::Vec3| {
+                let mut point = body.position;
+                for k in 0..3 {
+                    if k != skip {
+                        point += axes[k] * (half[k] * glm::dot(&axes[k], toward).signum());
+                    }
+                }
+                point
+            };
+            let point_a = support(body_a, &axes_a, half_a, i, &normal);
+            let point_b = support(body_b, &axes_b, half_b, j, &-normal);
+            let (closest_a, closest_b) = closest_points_on_lines(&point_a, &axes_a[i], &point_b, &axes_b[j]);
+            contacts.push(Contact::new(b, Some(a), (closest_a + closest_b) * 0.5, normal, -edge_separation));
+            return;
+        }
+    }
+
+    // Reference box is the one owning the face, the normal points from it
+    // into the incident box
+    let (reference, axes_ref, half_ref, axes_inc, half_inc, face, flip) = if face_axis < 3 {
+        (body_a, &axes_a, half_a, &axes_b, half_b, face_axis, false)
+    } else {
+        (body_b, &axes_b, half_b, &axes_a, half_a, face_axis - 3, true)
+    };
+    let incident = if flip { body_a } else { body_b };
+    let toward = incident.position - reference.position;
+    let normal = if glm::dot(&axes_ref[face], &toward) < 0.0 { -axes_ref[face] } else { axes_ref[face] };
+
+    let mut incident_face = 0;
+    for k in 1..3 {
+        if glm::dot(&axes_inc[k], &normal).abs() > glm::dot(&axes_inc[incident_face], &normal).abs() {
+            incident_face = k;
+        }
+    }
+    let incident_normal = if glm::dot(&axes_inc[incident_face], &normal) > 0.0 { -axes_inc[incident_face] } else { axes_inc[incident_face] };
+    let (u, v) = ((incident_face + 1) % 3, (incident_face + 2) % 3);
+    let center = incident.position + incident_normal * half_inc[incident_face];
+    let mut polygon = vec![
+        center + axes_inc[u] * half_inc[u] + axes_inc[v] * half_inc[v],
+        center - axes_inc[u] * half_inc[u] + axes_inc[v] * half_inc[v],
+        center - axes_inc[u] * half_inc[u] - axes_inc[v] * half_inc[v],
+        center + axes_inc[u] * half_inc[u] - axes_inc[v] * half_inc[v],
+    ];
+    for &side in &[(face + 1) % 3, (face + 2) % 3] {
+        let along = glm::dot(&axes_ref[side], &reference.position);
+        polygon = clip_polygon(&polygon, &axes_ref[side], along + half_ref[side]);
+        polygon = clip_polygon(&polygon, &-axes_ref[side], -along + half_ref[side]);
+    }
+
+    let face_offset = glm::dot(&normal, &reference.position) + half_ref[face];
+    let (pushed, other) = if flip { (a, b) } else { (b, a) };
+    for point in polygon {
+        let depth = face_offset - glm::dot(&normal, &point);
+        if depth >= 0.0 {
+            // Halfway between the incident point and the reference face
+            contacts.push(Contact::new(pushed, Some(other), point + normal * (depth * 0.5), normal, depth));
+        }
+    }
+}
+
+// Keeps the part of the polygon with dot(normal, x) <= offset
+fn clip_polygon(polygon: &[glm::Vec3], normal: &glm::Vec3, offset: f32) -> Vec<glm::Vec3> {
+    let mut clipped = Vec::with_capacity(polygon.len() + 1);
+    for index in 0..polygon.len() {
+        let start = polygon[index];
+        let end = polygon[(index + 1) % polygon.len()];
+        let start_distance = glm::dot(normal, &start) - offset;
+        let end_distance = glm::dot(normal, &end) - offset;
+        if start_distance <= 0.0 {
+            clipped.push(start);
+        }
+        if (start_distance < 0.0) != (end_distance < 0.0) && start_distance != end_distance {
+            clipped.push(glm::lerp(&start, &end, start_distance / (start_distance - end_distance)));
+        }
+    }
+    clipped
+}
+
+fn closest_points_on_lines(point_a: &glm::Vec3, direction_a: &glm::Vec3, point_b: &glm::Vec3, direction_b: &glm::Vec3) -> (glm::Vec3, glm::Vec3) {
+    let offset = point_a - point_b;
+    let along = glm::dot(direction_a, direction_b);
+    let denominator = 1.0 - along * along;
+    if denominator < 1e-6 {
+        return (*point_a, *point_b);
+    }
+    let (da, db) = (glm::dot(direction_a, &offset), glm::dot(direction_b, &offset));
+    let s = (along * db - da) / denominator;
+    let t = (db - along * da) / denominator;
+    (point_a + direction_a * s, point_b + direction_b * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ground() -> RigidWorld {
+        let mut world = RigidWorld::new();
+        world.add_plane(glm::vec3(0.0, 1.0, 0.0), 0.0);
+        world
+    }
+
+    fn run(world: &mut RigidWorld, seconds: f32) {
+        let steps = (seconds / world.get_time_step()).round() as usize;
+        for _ in 0..steps {
+            world.step(world.get_time_step());
+        }
+    }
+
+    #[test]
+    fn box_stack_comes_to_rest() {
+        let mut world = ground();
+        let cube = Shape::Box { half_extents: glm::vec3(0.5, 0.5, 0.5) };
+        for level in 0..3 {
+            world.add_body(RigidBody::new(cube, 1.0, glm::vec3(0.0, 0.5 + 1.01 * level as f32, 0.0)));
+        }
+        run(&mut world, 3.0);
+        for (level, body) in world.get_bodies().iter().enumerate() {
+            let position = body.get_position();
+            // Contacts settle within the penetration slop of touching
+            assert!((position.y - (0.5 + level as f32)).abs() < 0.02, "box {} at {:?}", level, position);
+            assert!(position.x.abs() < 0.01 && position.z.abs() < 0.01, "box {} at {:?}", level, position);
+            assert!(glm::length(&body.get_linear_velocity()) < 1e-2, "box {} moving {:?}", level, body.get_linear_velocity());
+            assert!(glm::length(&body.get_angular_velocity()) < 1e-2, "box {} spinning {:?}", level, body.get_angular_velocity());
+        }
+    }
+
+    #[test]
+    fn sphere_bounces_with_its_restitution() {
+        for &restitution in &[0.3, 0.5, 0.8] {
+            let mut world = ground();
+            let mut sphere = RigidBody::new(Shape::Sphere { radius: 0.5 }, 1.0, glm::vec3(0.0, 3.0, 0.0));
+            sphere.set_restitution(restitution);
+            let sphere = world.add_body(sphere);
+
+            let mut impact = 0.0;
+            let mut rebound = 0.0;
+            for _ in 0..240 {
+                let before = world.get_body(sphere).get_linear_velocity().y;
+                world.step(world.get_time_step());
+                let after = world.get_body(sphere).get_linear_velocity().y;
+                if before < 0.0 && after > before + 1.0 {
+                    impact = before;
+                    rebound = after;
+                    break;
+                }
+            }
+            assert!(impact < -5.0, "no impact, {}", impact);
+            let expected = -restitution * impact;
+            assert!((rebound - expected).abs() < 0.5, "restitution {}: {} -> {}", restitution, impact, rebound);
+        }
+
+        // Without restitution the only push back is the penetration correction
+        let mut world = ground();
+        let mut sphere = RigidBody::new(Shape::Sphere { radius: 0.5 }, 1.0, glm::vec3(0.0, 3.0, 0.0));
+        sphere.set_restitution(0.0);
+        let sphere = world.add_body(sphere);
+        let mut highest: f32 = 0.0;
+        let mut landed = false;
+        for _ in 0..360 {
+            world.step(world.get_time_step());
+            let body = world.get_body(sphere);
+            landed |= body.get_linear_velocity().y > 0.0;
+            if landed {
+                highest = highest.max(body.get_position().y);
+            }
+        }
+        assert!(highest < 0.6, "rose to {}", highest);
+        let body = world.get_body(sphere);
+        assert!((body.get_position().y - 0.5).abs() < 0.01, "{:?}", body.get_position());
+        assert!(body.get_linear_velocity().norm() < 1e-2, "{:?}", body.get_linear_velocity());
+    }
+
+    #[test]
+    fn steps_are_deterministic() {
+        let scene = || {
+            let mut world = ground();
+            for index in 0..6 {
+                let position = glm::vec3((index % 3) as f32 - 1.0, 1.0 + index as f32 * 0.8, 0.0);
+                let mut body = if index % 3 == 2 {
+                    RigidBody::new(Shape::Sphere { radius: 0.35 }, 1.0, position)
+                } else {
+                    RigidBody::new(Shape::Box { half_extents: glm::vec3(0.5, 0.25, 0.35) }, 1.0, position)
+                };
+                body.set_orientation(glm::quat_angle_axis(index as f32 * 0.7, &glm::normalize(&glm::vec3(1.0, 0.5, 0.3))));
+                world.add_body(body);
+            }
+            run(&mut world, 2.0);
+            world
+        };
+        let (a, b) = (scene(), scene());
+        for (a, b) in a.get_bodies().iter().zip(b.get_bodies()) {
+            let bits = |body: &RigidBody| -> Vec<u32> {
+                body.get_position().iter()
+                    .chain(body.get_orientation().coords.iter())
+                    .chain(body.get_linear_velocity().iter())
+                    .chain(body.get_angular_velocity().iter())
+                    .map(|value| value.to_bits())
+                    .collect()
+            };
+            assert_eq!(bits(a), bits(b));
+        }
+    }
+
+    fn assert_close(a: glm::Vec3, b: glm::Vec3) {
+        assert!(glm::length(&(a - b)) < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn forces_and_impulses_move_bodies() {
+        let mut world = RigidWorld::new();
+        world.set_gravity(glm::vec3(0.0, 0.0, 0.0));
+        world.set_time_step(0.01);
+        world.set_iterations(4);
+        // Inverse inertia 1 / (0.4 * 2 * 0.5²) = 5
+        let sphere = world.add_body(RigidBody::new(Shape::Sphere { radius: 0.5 }, 2.0, glm::vec3(0.0, 0.0, 0.0)));
+
+        let body = world.get_body_mut(sphere);
+        body.apply_impulse(glm::vec3(2.0, 0.0, 0.0), glm::vec3(0.0, 0.0, 0.0));
+        assert_close(body.get_linear_velocity(), glm::vec3(1.0, 0.0, 0.0));
+        assert_close(body.get_angular_velocity(), glm::vec3(0.0, 0.0, 0.0));
+        body.apply_impulse(glm::vec3(2.0, 0.0, 0.0), glm::vec3(0.0, 0.5, 0.0));
+        assert_close(body.get_linear_velocity(), glm::vec3(2.0, 0.0, 0.0));
+        assert_close(body.get_angular_velocity(), glm::vec3(0.0, 0.0, -5.0));
+
+        // Forces add up until the next step and are cleared by it
+        body.set_linear_velocity(glm::vec3(0.0, 0.0, 0.0));
+        body.set_angular_velocity(glm::vec3(0.0, 0.0, 0.0));
+        body.apply_force(glm::vec3(0.0, 3.0, 0.0), glm::vec3(0.0, 0.0, 0.0));
+        body.apply_force(glm::vec3(0.0, 1.0, 0.0), glm::vec3(0.5, 0.0, 0.0));
+        world.step(0.01);
+        let body = world.get_body(sphere);
+        assert_close(body.get_linear_velocity(), glm::vec3(0.0, 0.02, 0.0));
+        assert_close(body.get_angular_velocity(), glm::vec3(0.0, 0.0, 0.025));
+        world.step(0.01);
+        let body = world.get_body(sphere);
+        assert_close(body.get_linear_velocity(), glm::vec3(0.0, 0.02, 0.0));
+        assert_close(body.get_angular_velocity(), glm::vec3(0.0, 0.0, 0.025));
+
+        // Gravity is the world's
+        world.set_gravity(glm::vec3(0.0, -10.0, 0.0));
+        world.step(0.01);
+        assert_close(world.get_body(sphere).get_linear_velocity(), glm::vec3(0.0, -0.08, 0.0));
+    }
+}
@@ -0,0 +1,268 @@
+use crate::animation::*;
+use crate::ik::*;
+use crate::pose::*;
+use crate::skeleton::*;
+
+fn up() -> glm::Vec3 {
+    glm::vec3(0.0, 1.0, 0.0)
+}
+
+// Movement of the root over some time: a translation on the ground plane, in
+// the root's heading frame at the start, and a turn around the up axis
+#[derive(Clone, Copy, Debug)]
+pub struct RootDisplacement {
+    translation: glm::Vec3,
+    yaw: f32,
+}
+
+impl RootDisplacement {
+    pub fn new(translation: glm::Vec3, yaw: f32) -> RootDisplacement {
+        RootDisplacement {
+            translation,
+            yaw,
+        }
+    }
+
+    pub fn identity() -> RootDisplacement {
+        Self::new(glm::vec3(0.0, 0.0, 0.0), 0.0)
+    }
+
+    // From one ground position and heading to another
+    pub fn between(from: &(glm::Vec3, f32), to: &(glm::Vec3, f32)) -> RootDisplacement {
+        Self::new(glm::rotate_y_vec3(&(to.0 - from.0), -from.1), to.1 - from.1)
+    }
+
+    pub fn get_translation(&self) -> glm::Vec3 {
+        self.translation
+    }
+
+    pub fn get_yaw(&self) -> f32 {
+        self.yaw
+    }
+
+    // This displacement followed by `next`, which starts where this one ends
+    pub fn then(&self, next: &RootDisplacement) -> RootDisplacement {
+        Self::new(self.translation + glm::rotate_y_vec3(&next.translation, self.yaw), self.yaw + next.yaw)
+    }
+
+    pub fn inverse(&self) -> RootDisplacement {
+        Self::new(glm::rotate_y_vec3(&-self.translation, -self.yaw), -self.yaw)
+    }
+}
+
+// Ground position and heading of a root transform. The heading is the twist
+// of the rotation around the up axis, applied before the rest of it.
+pub fn extract_root(transform: &JointTransform) -> (glm::Vec3, f32) {
+    let translation = transform.get_translation();
+    let (_, twist) = swing_twist(&glm::quat_conjugate(&transform.get_rotation()), &up());
+    (glm::vec3(translation.x, 0.0, translation.z), -twist_angle(&twist, &up()))
+}
+
+// Moves the root back over the origin facing forward, keeping its height and
+// any lean, so the object transform can carry the travel instead
+pub fn strip_root(pose: &mut Pose) {
+    if pose.get_num_joints() == 0 {
+        return;
+    }
+    let root = pose.get_transform_mut(0);
+    let (ground, yaw) = extract_root(root);
+    let rotation = glm::quat_angle_axis(-yaw, &up()) * root.get_rotation();
+    root.set_translation(root.get_translation() - ground);
+    root.set_rotation(glm::quat_normalize(&rotation));
+}
+
+// Root displacement of a clip between two playback times. Looping clips
+// repeat over their range, each lap continuing from where the last one
+// ended instead of snapping back; others hold still outside it.
+pub fn clip_displacement(animation: &Animation, skeleton: &Skeleton, from: f32, to: f32, looping: bool) -> RootDisplacement {
+    if to < from {
+        return clip_displacement(animation, skeleton, to, from, looping).inverse();
+    }
+    let start = animation.get_start_time();
+    let end = animation.get_end_time();
+    let root_at = |time: f32| -> (glm::Vec3, f32) {
+        let pose = animation.sample(time, skeleton);
+        if pose.get_num_joints() == 0 {
+            return (glm::vec3(0.0, 0.0, 0.0), 0.0);
+        }
+        extract_root(pose.get_transform(0))
+    };
+    let length = end - start;
+    if !looping || length <= 0.0 {
+        let clamp = |time: f32| glm::clamp_scalar(time, start, end.max(start));
+        return RootDisplacement::between(&root_at(clamp(from)), &root_at(clamp(to)));
+    }
+
+    let lap = |time: f32| ((time - start) / length).floor();
+    let (from_lap, to_lap) = (lap(from), lap(to));
+    let from_local = from - from_lap * length;
+    let to_local = to - to_lap * length;
+    if from_lap == to_lap {
+        return RootDisplacement::between(&root_at(from_local), &root_at(to_local));
+    }
+    let first = root_at(start);
+    let last = root_at(end);
+    let whole = RootDisplacement::between(&first, &last);
+    let mut displacement = RootDisplacement::between(&root_at(from_local), &last);
+    for _ in 0..(to_lap - from_lap - 1.0) as usize {
+        displacement = displacement.then(&whole);
+    }
+    displacement.then(&RootDisplacement::between(&first, &root_at(to_local)))
+}
+
+// World placement carried along by root motion, a ground position and a
+// heading like the position and spin angle of a SpinningCube
+#[derive(Clone, Copy, Debug)]
+pub struct RootMotion {
+    position: glm::Vec3,
+    yaw: f32,
+}
+
+impl RootMotion {
+    pub fn new() -> RootMotion {
+        RootMotion {
+            position: glm::vec3(0.0, 0.0, 0.0),
+            yaw: 0.0,
+        }
+    }
+
+    pub fn get_position(&self) -> glm::Vec3 {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: glm::Vec3) {
+        self.position = position;
+    }
+
+    pub fn get_yaw(&self) -> f32 {
+        self.yaw
+    }
+
+    pub fn set_yaw(&mut self, yaw: f32) {
+        self.yaw = yaw;
+    }
+
+    pub fn apply(&mut self, displacement: &RootDisplacement) {
+        self.position += glm::rotate_y_vec3(&displacement.translation, self.yaw);
+        self.yaw += displacement.yaw;
+    }
+
+    pub fn get_world_mat(&self) -> glm::Mat4 {
+        glm::rotate_y(&glm::translation(&self.position), self.yaw)
+    }
+
+    pub fn reset(&mut self) {
+        self.position = glm::vec3(0.0, 0.0, 0.0);
+        self.yaw = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animation::tests::constant;
+
+    fn line(from: f32, to: f32) -> Channel {
+        Channel::new(vec![
+            Keyframe::new(0.0, from, TangentRule::Linear, TangentRule::Linear),
+            Keyframe::new(1.0, to, TangentRule::Linear, TangentRule::Linear),
+        ], Extrapolation::Constant, Extrapolation::Constant)
+    }
+
+    // One second lap walking `stride` along x while turning `turn` radians,
+    // bobbing at a height of 1 and leaning forward a little
+    fn walk(stride: f32, turn: f32) -> Animation {
+        Animation::new(0.0, 1.0, vec![
+            line(0.0, stride),
+            constant(1.0),
+            constant(0.0),
+            constant(0.2),
+            line(0.0, turn),
+            constant(0.0),
+        ])
+    }
+
+    fn skeleton() -> Skeleton {
+        let mut skeleton = Skeleton::new();
+        skeleton.add_joint(Joint::new("root", None));
+        skeleton
+    }
+
+    fn assert_close(a: &RootDisplacement, b: &RootDisplacement) {
+        assert!(glm::length(&(a.get_translation() - b.get_translation())) < 1e-3 && (a.get_yaw() - b.get_yaw()).abs() < 1e-4,
+            "{:?} != {:?}", a, b);
+    }
+
+    // Frames a seventh of a lap long, the last of each lap ending on the wrap
+    fn play(animation: &Animation, skeleton: &Skeleton, laps: usize) -> RootMotion {
+        let mut motion = RootMotion::new();
+        let steps = laps * 7;
+        for step in 0..steps {
+            let (from, to) = (step as f32 / 7.0, (step + 1) as f32 / 7.0);
+            motion.apply(&clip_displacement(animation, skeleton, from, to, true));
+        }
+        motion
+    }
+
+    #[test]
+    fn laps_add_up() {
+        let skeleton = skeleton();
+        let straight = walk(2.0, 0.0);
+        let lap = clip_displacement(&straight, &skeleton, 0.0, 1.0, true);
+        assert_close(&lap, &RootDisplacement::new(glm::vec3(2.0, 0.0, 0.0), 0.0));
+        let motion = play(&straight, &skeleton, 5);
+        assert!(glm::length(&(motion.get_position() - glm::vec3(10.0, 0.0, 0.0))) < 1e-3, "{:?}", motion.get_position());
+        assert!(motion.get_yaw().abs() < 1e-5);
+
+        // Turning laps compose, each starts off in the heading the last one left
+        let turning = walk(2.0, 0.5);
+        let lap = clip_displacement(&turning, &skeleton, 0.0, 1.0, true);
+        assert_close(&lap, &RootDisplacement::new(glm::vec3(2.0, 0.0, 0.0), 0.5));
+        let mut expected = RootMotion::new();
+        for _ in 0..5 {
+            expected.apply(&lap);
+        }
+        let motion = play(&turning, &skeleton, 5);
+        assert!(glm::length(&(motion.get_position() - expected.get_position())) < 1e-3, "{:?}", motion.get_position());
+        assert!((motion.get_yaw() - 2.5).abs() < 1e-4, "{}", motion.get_yaw());
+        assert_close(&clip_displacement(&turning, &skeleton, 0.0, 5.0, true), &RootDisplacement::new(
+            expected.get_position(), expected.get_yaw()));
+    }
+
+    #[test]
+    fn playing_backwards_undoes_playing_forwards() {
+        let skeleton = skeleton();
+        let turning = walk(2.0, 0.5);
+        for &looping in &[false, true] {
+            let forward = clip_displacement(&turning, &skeleton, 0.3, 2.7, looping);
+            let backward = clip_displacement(&turning, &skeleton, 2.7, 0.3, looping);
+            assert_close(&backward, &forward.inverse());
+            assert_close(&forward.then(&backward), &RootDisplacement::identity());
+        }
+        // Clips that don't loop hold still past their end
+        assert_close(&clip_displacement(&turning, &skeleton, 1.0, 3.0, false), &RootDisplacement::identity());
+    }
+
+    #[test]
+    fn stripped_root_goes_back_to_the_origin() {
+        let skeleton = skeleton();
+        let turning = walk(2.0, 0.5);
+        let original = turning.sample(0.6, &skeleton);
+        let (ground, yaw) = extract_root(original.get_transform(0));
+        let mut pose = original.clone();
+        strip_root(&mut pose);
+
+        let root = pose.get_transform(0);
+        assert!(glm::length(&(root.get_translation() - glm::vec3(0.0, 1.0, 0.0))) < 1e-5, "{:?}", root.get_translation());
+        let (stripped_ground, stripped_yaw) = extract_root(root);
+        assert!(glm::length(&stripped_ground) < 1e-5 && stripped_yaw.abs() < 1e-5);
+
+        // Placed back where the root was, the lean and height come out unchanged
+        let mut motion = RootMotion::new();
+        motion.set_position(ground);
+        motion.set_yaw(yaw);
+        let placed = motion.get_world_mat() * root.get_matrix();
+        let expected = original.get_transform(0).get_matrix();
+        assert!((placed - expected).iter().all(|value| value.abs() < 1e-5), "{} != {}", placed, expected);
+    }
+}
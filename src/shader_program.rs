@@ -33,8 +33,22 @@ impl ShaderProgram {
             },
         }
 
+        Self::link(&shaders)
+    }
+
+    // Render program whose stages don't share a name, like a vertex shader
+    // variant reusing another program's fragment shader
+    pub fn from_files(vertex_file: &str, fragment_file: &str) -> ShaderProgram {
+        let shaders = vec![
+            Shader::from_cstr(&Self::read_cstr(vertex_file), ShaderType::Vertex),
+            Shader::from_cstr(&Self::read_cstr(fragment_file), ShaderType::Fragment),
+        ];
+        Self::link(&shaders)
+    }
+
+    fn link(shaders: &[Shader]) -> ShaderProgram {
         let program_id = unsafe { gl::CreateProgram() };
-        for shader in &shaders {
+        for shader in shaders {
             unsafe {
                 gl::AttachShader(program_id, shader.id());
             }
@@ -42,7 +56,7 @@ impl ShaderProgram {
         unsafe {
             gl::LinkProgram(program_id);
         }
-        for shader in &shaders {
+        for shader in shaders {
             unsafe {
                 gl::DetachShader(program_id, shader.id());
             }
@@ -57,6 +71,16 @@ impl ShaderProgram {
         self.program_id
     }
 
+    // Runs a compute program over a grid of work groups. Follow with
+    // memory_barrier before reading what it wrote.
+    pub fn dispatch(&self, groups_x: GLuint, groups_y: GLuint, groups_z: GLuint) {
+        unsafe {
+            gl::UseProgram(self.program_id);
+            gl::DispatchCompute(groups_x, groups_y, groups_z);
+            gl::UseProgram(0);
+        }
+    }
+
     fn read_cstr(filename: &str) -> CString {
         let mut file = File::open(filename).unwrap();
         let mut contents: Vec<u8> = Vec::new();
@@ -65,6 +89,14 @@ impl ShaderProgram {
     }
 }
 
+// Makes writes from earlier dispatches visible to the accesses named in
+// `barriers`, like gl::SHADER_STORAGE_BARRIER_BIT
+pub fn memory_barrier(barriers: GLbitfield) {
+    unsafe {
+        gl::MemoryBarrier(barriers);
+    }
+}
+
 impl Drop for ShaderProgram {
     fn drop(&mut self) {
         unsafe {
@@ -100,8 +132,7 @@ impl Shader {
 
             // Setup shader compilation checks
             let mut success = i32::from(gl::FALSE);
-            let mut info_log = Vec::with_capacity(512);
-            info_log.set_len(512 - 1); // -1 to skip trialing null character
+            let mut info_log: Vec<u8> = vec![0; 512 - 1]; // -1 to skip trialing null character
             gl::GetShaderiv(shader_id, gl::COMPILE_STATUS, &mut success);
             if success != i32::from(gl::TRUE) {
                 gl::GetShaderInfoLog(
@@ -0,0 +1,377 @@
+use gl::types::*;
+use crate::model::*;
+use crate::tokenizer::*;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Dof {
+    value: f32,
+    min: f32,
+    max: f32,
+}
+
+impl Dof {
+    pub fn new() -> Dof {
+        Dof {
+            value: 0.0,
+            min: -100000.0,
+            max: 100000.0,
+        }
+    }
+
+    pub fn set_value(&mut self, value: f32) {
+        self.value = glm::clamp_scalar(value, self.min, self.max);
+    }
+
+    pub fn get_value(&self) -> f32 {
+        self.value
+    }
+
+    pub fn set_min_max(&mut self, min: f32, max: f32) {
+        self.min = min;
+        self.max = max;
+        self.set_value(self.value);
+    }
+
+    pub fn get_min(&self) -> f32 {
+        self.min
+    }
+
+    pub fn get_max(&self) -> f32 {
+        self.max
+    }
+}
+
+// Rotation of Euler angles applied in `order`, listed in matrix multiplication
+// order like Joint::set_rotation_order.
+pub fn euler_to_quat(angles: glm::Vec3, order: [usize; 3]) -> glm::Quat {
+    let mut rotation = glm::quat(0.0, 0.0, 0.0, 1.0);
+    for &axis in &order {
+        let mut unit = glm::vec3(0.0, 0.0, 0.0);
+        unit[axis] = 1.0;
+        rotation *= glm::quat_angle_axis(angles[axis], &unit);
+    }
+    rotation
+}
+
+// Inverse of euler_to_quat. With the middle axis at +-90 degrees the first and
+// last axes line up, so the last angle is taken as zero.
+pub fn quat_to_euler(rotation: &glm::Quat, order: [usize; 3]) -> glm::Vec3 {
+    let m = glm::quat_to_mat3(&glm::quat_normalize(rotation));
+    let [i, j, k] = order;
+    let sign = if (j + 3 - i) % 3 == 1 { 1.0 } else { -1.0 };
+    let mut angles = glm::vec3(0.0, 0.0, 0.0);
+    angles[j] = glm::clamp_scalar(sign * m[(i, k)], -1.0, 1.0).asin();
+    if m[(i, k)].abs() < 0.9999 {
+        angles[i] = (-sign * m[(j, k)]).atan2(m[(k, k)]);
+        angles[k] = (-sign * m[(i, j)]).atan2(m[(i, i)]);
+    } else {
+        angles[i] = (sign * m[(k, j)]).atan2(m[(j, j)]);
+    }
+    angles
+}
+
+#[derive(Clone, Debug)]
+pub struct Joint {
+    name: String,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    offset: glm::Vec3,
+    box_min: glm::Vec3,
+    box_max: glm::Vec3,
+    dofs: [Dof; 3],
+    rotation_order: [usize; 3],
+    scale: glm::Vec3,
+    local_mat: glm::Mat4,
+    world_mat: glm::Mat4,
+}
+
+impl Joint {
+    pub fn new(name: &str, parent: Option<usize>) -> Joint {
+        Joint {
+            name: name.to_string(),
+            parent,
+            children: Vec::new(),
+            offset: glm::vec3(0.0, 0.0, 0.0),
+            box_min: glm::vec3(-0.1, -0.1, -0.1),
+            box_max: glm::vec3(0.1, 0.1, 0.1),
+            dofs: [Dof::new(); 3],
+            rotation_order: [2, 1, 0],
+            scale: glm::vec3(1.0, 1.0, 1.0),
+            local_mat: glm::Mat4::identity(),
+            world_mat: glm::Mat4::identity(),
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    pub fn get_parent(&self) -> Option<usize> {
+        self.parent
+    }
+
+    pub fn get_children(&self) -> &[usize] {
+        &self.children
+    }
+
+    pub fn get_offset(&self) -> glm::Vec3 {
+        self.offset
+    }
+
+    pub fn set_offset(&mut self, offset: glm::Vec3) {
+        self.offset = offset;
+    }
+
+    pub fn get_box_min(&self) -> glm::Vec3 {
+        self.box_min
+    }
+
+    pub fn get_box_max(&self) -> glm::Vec3 {
+        self.box_max
+    }
+
+    pub fn set_box(&mut self, box_min: glm::Vec3, box_max: glm::Vec3) {
+        self.box_min = box_min;
+        self.box_max = box_max;
+    }
+
+    pub fn get_dof(&self, axis: usize) -> &Dof {
+        &self.dofs[axis]
+    }
+
+    pub fn get_dof_mut(&mut self, axis: usize) -> &mut Dof {
+        &mut self.dofs[axis]
+    }
+
+    pub fn get_pose(&self) -> glm::Vec3 {
+        glm::vec3(self.dofs[0].get_value(), self.dofs[1].get_value(), self.dofs[2].get_value())
+    }
+
+    pub fn set_pose(&mut self, pose: glm::Vec3) {
+        for axis in 0..3 {
+            self.dofs[axis].set_value(pose[axis]);
+        }
+    }
+
+    pub fn get_rotation(&self) -> glm::Quat {
+        euler_to_quat(self.get_pose(), self.rotation_order)
+    }
+
+    // Converted to angles in the joint's rotation order, so DOF limits still apply
+    pub fn set_rotation(&mut self, rotation: &glm::Quat) {
+        self.set_pose(quat_to_euler(rotation, self.rotation_order));
+    }
+
+    pub fn get_scale(&self) -> glm::Vec3 {
+        self.scale
+    }
+
+    pub fn set_scale(&mut self, scale: glm::Vec3) {
+        self.scale = scale;
+    }
+
+    pub fn get_local_mat(&self) -> glm::Mat4 {
+        self.local_mat
+    }
+
+    pub fn get_world_mat(&self) -> glm::Mat4 {
+        self.world_mat
+    }
+
+    pub fn get_rotation_order(&self) -> [usize; 3] {
+        self.rotation_order
+    }
+
+    // Axes in matrix multiplication order, the way BVH lists its channels. The
+    // default [2, 1, 0] gives T(offset) * Rz * Ry * Rx, so x is applied first.
+    pub fn set_rotation_order(&mut self, rotation_order: [usize; 3]) {
+        self.rotation_order = rotation_order;
+    }
+
+    fn compute_local_mat(&self) -> glm::Mat4 {
+        let mut local = glm::translation(&self.offset);
+        for &axis in &self.rotation_order {
+            let angle = self.dofs[axis].get_value();
+            local = match axis {
+                0 => glm::rotate_x(&local, angle),
+                1 => glm::rotate_y(&local, angle),
+                _ => glm::rotate_z(&local, angle),
+            };
+        }
+        glm::scale(&local, &self.scale)
+    }
+}
+
+// Joints are stored in depth-first order, so a parent always comes before its children.
+pub struct Skeleton {
+    joints: Vec<Joint>,
+    models: Vec<Model>,
+    // Places the whole skeleton in the world, the parent of the root joint
+    transform: glm::Mat4,
+    // Offset and DOF values of each joint as loaded, what reset goes back to
+    rest: Vec<(glm::Vec3, glm::Vec3)>,
+}
+
+impl Skeleton {
+    pub fn new() -> Skeleton {
+        Skeleton {
+            joints: Vec::new(),
+            models: Vec::new(),
+            transform: glm::Mat4::identity(),
+            rest: Vec::new(),
+        }
+    }
+
+    pub fn from_file(filename: &str) -> Result<Skeleton, ParseError> {
+        let mut tokenizer = Tokenizer::from_file(filename)?;
+        Self::parse(&mut tokenizer)
+    }
+
+    pub fn parse(tokenizer: &mut Tokenizer) -> Result<Skeleton, ParseError> {
+        let mut skeleton = Skeleton::new();
+        tokenizer.expect("balljoint")?;
+        skeleton.parse_joint(tokenizer, None)?;
+        if !tokenizer.is_eof() {
+            return Err(tokenizer.error("unexpected data after root joint"));
+        }
+        skeleton.set_rest();
+        skeleton.update();
+        Ok(skeleton)
+    }
+
+    fn parse_joint(&mut self, tokenizer: &mut Tokenizer, parent: Option<usize>) -> Result<(), ParseError> {
+        let name = tokenizer.next_token()?;
+        if name == "{" {
+            return Err(tokenizer.error("balljoint is missing a name"));
+        }
+        if self.find_joint(&name).is_some() {
+            return Err(tokenizer.error(&format!("duplicate joint name '{}'", name)));
+        }
+        let index = self.add_joint(Joint::new(&name, parent));
+        tokenizer.expect("{")?;
+
+        let mut pose = glm::vec3(0.0, 0.0, 0.0);
+        loop {
+            let line = tokenizer.get_line();
+            let token = tokenizer.next_token()?;
+            match token.as_str() {
+                "offset" => self.joints[index].offset = tokenizer.get_vec3()?,
+                "boxmin" => self.joints[index].box_min = tokenizer.get_vec3()?,
+                "boxmax" => self.joints[index].box_max = tokenizer.get_vec3()?,
+                "rotxlimit" | "rotylimit" | "rotzlimit" => {
+                    let axis = match token.as_str() {
+                        "rotxlimit" => 0,
+                        "rotylimit" => 1,
+                        _ => 2,
+                    };
+                    let min = tokenizer.get_float()?;
+                    let max = tokenizer.get_float()?;
+                    if min > max {
+                        return Err(ParseError::new(tokenizer.get_file(), line, &format!("{} minimum is greater than maximum", token)));
+                    }
+                    self.joints[index].dofs[axis].set_min_max(min, max);
+                },
+                "pose" => pose = tokenizer.get_vec3()?,
+                "balljoint" => self.parse_joint(tokenizer, Some(index))?,
+                "}" => break,
+                _ => return Err(ParseError::new(tokenizer.get_file(), line, &format!("unknown joint attribute '{}'", token))),
+            }
+        }
+        // Limits may come after the pose in the file, so clamp once everything is read
+        self.joints[index].set_pose(pose);
+        Ok(())
+    }
+
+    pub fn add_joint(&mut self, joint: Joint) -> usize {
+        let index = self.joints.len();
+        if let Some(parent) = joint.parent {
+            self.joints[parent].children.push(index);
+        }
+        self.joints.push(joint);
+        index
+    }
+
+    pub fn make_models(&mut self) {
+        self.models = self.joints.iter().map(|joint| {
+            let mut model = Model::new();
+            model.make_box(joint.box_min, joint.box_max);
+            model
+        }).collect();
+    }
+
+    pub fn get_joints(&self) -> &[Joint] {
+        &self.joints
+    }
+
+    pub fn get_joint(&self, index: usize) -> &Joint {
+        &self.joints[index]
+    }
+
+    pub fn get_joint_mut(&mut self, index: usize) -> &mut Joint {
+        &mut self.joints[index]
+    }
+
+    pub fn get_num_joints(&self) -> usize {
+        self.joints.len()
+    }
+
+    pub fn find_joint(&self, name: &str) -> Option<usize> {
+        self.joints.iter().position(|joint| joint.name == name)
+    }
+
+    pub fn get_world_mat(&self, index: usize) -> glm::Mat4 {
+        self.joints[index].world_mat
+    }
+
+    pub fn get_world_mats(&self) -> Vec<glm::Mat4> {
+        self.joints.iter().map(|joint| joint.world_mat).collect()
+    }
+
+    pub fn get_transform(&self) -> glm::Mat4 {
+        self.transform
+    }
+
+    // Takes effect on the next update
+    pub fn set_transform(&mut self, transform: glm::Mat4) {
+        self.transform = transform;
+    }
+
+    pub fn update(&mut self) {
+        for index in 0..self.joints.len() {
+            let local = self.joints[index].compute_local_mat();
+            let world = match self.joints[index].parent {
+                Some(parent) => self.joints[parent].world_mat * local,
+                None => self.transform * local,
+            };
+            let joint = &mut self.joints[index];
+            joint.local_mat = local;
+            joint.world_mat = world;
+        }
+    }
+
+    // Takes the current offsets and DOF values as the pose reset returns to
+    pub fn set_rest(&mut self) {
+        self.rest = self.joints.iter().map(|joint| (joint.offset, joint.get_pose())).collect();
+    }
+
+    // Joints added after the last set_rest keep their offsets and go to zero angles
+    pub fn reset(&mut self) {
+        for (index, joint) in self.joints.iter_mut().enumerate() {
+            let (offset, pose) = self.rest.get(index).cloned().unwrap_or((joint.offset, glm::vec3(0.0, 0.0, 0.0)));
+            joint.offset = offset;
+            joint.set_pose(pose);
+            joint.scale = glm::vec3(1.0, 1.0, 1.0);
+        }
+        self.update();
+    }
+
+    pub fn draw(&self, view_proj_mat: glm::Mat4, shader: GLuint) {
+        for (joint, model) in self.joints.iter().zip(self.models.iter()) {
+            model.draw(joint.world_mat, view_proj_mat, shader);
+        }
+    }
+}
@@ -0,0 +1,422 @@
+use gl::types::*;
+use crate::buffer::*;
+use crate::dual_quat::*;
+use crate::model::*;
+use crate::skeleton::*;
+use crate::tokenizer::*;
+
+// Where vertices get deformed. Gpu expects the skinned_model.vert program at
+// draw time, Cpu works with any program taking ModelVertex.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SkinningMode {
+    Cpu,
+    Gpu,
+}
+
+// How joint transforms are blended per vertex. Dual quaternions keep volume
+// around twisting joints but ignore any scale in the skinning matrices.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SkinningMethod {
+    Linear,
+    DualQuaternion,
+}
+
+// Real and dual parts as consecutive vec4s, x y z w like the shader expects
+fn dual_quat_palette(skinning_mats: &[glm::Mat4]) -> Vec<glm::Vec4> {
+    skinning_mats.iter().flat_map(|mat| {
+        let dq = DualQuat::from_mat4(mat);
+        vec![dq.get_real().coords, dq.get_dual().coords]
+    }).collect()
+}
+
+pub struct SkinnedModel {
+    vertices: Vec<SkinVertex>,
+    indices: Vec<u32>,
+    bindings: Vec<glm::Mat4>,
+    inverse_bindings: Vec<glm::Mat4>,
+    deformed: Vec<ModelVertex>,
+    mode: SkinningMode,
+    method: SkinningMethod,
+    model: Option<Model>,
+    gpu_model: Option<Model>,
+    palette: Option<ShaderStorageBuffer>,
+    dual_quat_palette: Option<ShaderStorageBuffer>,
+}
+
+impl SkinnedModel {
+    pub fn from_file(filename: &str) -> Result<SkinnedModel, ParseError> {
+        let mut tokenizer = Tokenizer::from_file(filename)?;
+        Self::parse(&mut tokenizer)
+    }
+
+    pub fn parse(tokenizer: &mut Tokenizer) -> Result<SkinnedModel, ParseError> {
+        let mut positions: Vec<glm::Vec3> = Vec::new();
+        let mut normals: Vec<glm::Vec3> = Vec::new();
+        let mut influences: Vec<Vec<(usize, f32)>> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
+        let mut bindings: Vec<glm::Mat4> = Vec::new();
+
+        while !tokenizer.is_eof() {
+            let line = tokenizer.get_line();
+            let section = tokenizer.next_token()?;
+            match section.as_str() {
+                "positions" => {
+                    let count = tokenizer.get_usize()?;
+                    tokenizer.expect("{")?;
+                    positions = (0..count).map(|_| tokenizer.get_vec3()).collect::<Result<_, _>>()?;
+                    tokenizer.expect("}")?;
+                },
+                "normals" => {
+                    let count = tokenizer.get_usize()?;
+                    tokenizer.expect("{")?;
+                    normals = (0..count).map(|_| tokenizer.get_vec3()).collect::<Result<_, _>>()?;
+                    tokenizer.expect("}")?;
+                },
+                "skinweights" => {
+                    let count = tokenizer.get_usize()?;
+                    tokenizer.expect("{")?;
+                    influences.clear();
+                    for _ in 0..count {
+                        let attachments = tokenizer.get_usize()?;
+                        let mut vertex_influences = Vec::with_capacity(attachments);
+                        for _ in 0..attachments {
+                            let joint = tokenizer.get_usize()?;
+                            let weight = tokenizer.get_float()?;
+                            vertex_influences.push((joint, weight));
+                        }
+                        influences.push(vertex_influences);
+                    }
+                    tokenizer.expect("}")?;
+                },
+                "triangles" => {
+                    let count = tokenizer.get_usize()?;
+                    tokenizer.expect("{")?;
+                    indices.clear();
+                    for _ in 0..count * 3 {
+                        indices.push(tokenizer.get_usize()? as u32);
+                    }
+                    tokenizer.expect("}")?;
+                },
+                "bindings" => {
+                    let count = tokenizer.get_usize()?;
+                    tokenizer.expect("{")?;
+                    bindings.clear();
+                    for _ in 0..count {
+                        tokenizer.expect("matrix")?;
+                        tokenizer.expect("{")?;
+                        let a = tokenizer.get_vec3()?;
+                        let b = tokenizer.get_vec3()?;
+                        let c = tokenizer.get_vec3()?;
+                        let d = tokenizer.get_vec3()?;
+                        tokenizer.expect("}")?;
+                        bindings.push(glm::Mat4::new(
+                            a.x, b.x, c.x, d.x,
+                            a.y, b.y, c.y, d.y,
+                            a.z, b.z, c.z, d.z,
+                            0.0, 0.0, 0.0, 1.0,
+                        ));
+                    }
+                    tokenizer.expect("}")?;
+                },
+                _ => {
+                    // Sections we don't use yet, like texcoords or material
+                    if tokenizer.peek() != Some("{") {
+                        tokenizer.next_token()?;
+                    }
+                    if tokenizer.peek() != Some("{") {
+                        return Err(ParseError::new(tokenizer.get_file(), line, &format!("unknown section '{}'", section)));
+                    }
+                    tokenizer.skip_block()?;
+                },
+            }
+        }
+
+        let error = |message: String| ParseError::new(tokenizer.get_file(), tokenizer.get_line(), &message);
+        if normals.len() != positions.len() {
+            return Err(error(format!("{} normals for {} positions", normals.len(), positions.len())));
+        }
+        if influences.len() != positions.len() {
+            return Err(error(format!("{} skinweights for {} positions", influences.len(), positions.len())));
+        }
+        if let Some(&index) = indices.iter().find(|&&index| index as usize >= positions.len()) {
+            return Err(error(format!("triangle references vertex {} of {}", index, positions.len())));
+        }
+        for vertex_influences in &influences {
+            if let Some(&(joint, _)) = vertex_influences.iter().find(|&&(joint, _)| joint >= bindings.len()) {
+                return Err(error(format!("skinweight references joint {} of {} bindings", joint, bindings.len())));
+            }
+        }
+
+        let vertices = positions.iter().zip(normals.iter()).zip(influences.iter())
+            .map(|((&position, &normal), vertex_influences)| SkinVertex::new(position, normal, vertex_influences))
+            .collect();
+        Ok(Self::from_parts(vertices, indices, bindings))
+    }
+
+    pub fn from_parts(vertices: Vec<SkinVertex>, indices: Vec<u32>, bindings: Vec<glm::Mat4>) -> SkinnedModel {
+        let inverse_bindings = bindings.iter().map(glm::inverse).collect();
+        let deformed = vertices.iter().map(|v| ModelVertex::new(v.get_position(), v.get_normal())).collect();
+        SkinnedModel {
+            vertices,
+            indices,
+            bindings,
+            inverse_bindings,
+            deformed,
+            mode: SkinningMode::Cpu,
+            method: SkinningMethod::Linear,
+            model: None,
+            gpu_model: None,
+            palette: None,
+            dual_quat_palette: None,
+        }
+    }
+
+    // Uploads both paths so the mode can be switched at any time
+    pub fn make_model(&mut self) {
+        let mut model = Model::new();
+        model.set_stream_buffers(&self.deformed, &self.indices);
+        self.model = Some(model);
+
+        let mut gpu_model = Model::new();
+        gpu_model.set_buffers(&self.vertices, &self.indices);
+        self.gpu_model = Some(gpu_model);
+        self.palette = Some(ShaderStorageBuffer::new());
+        self.dual_quat_palette = Some(ShaderStorageBuffer::new());
+    }
+
+    pub fn get_mode(&self) -> SkinningMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: SkinningMode) {
+        self.mode = mode;
+    }
+
+    pub fn get_method(&self) -> SkinningMethod {
+        self.method
+    }
+
+    pub fn set_method(&mut self, method: SkinningMethod) {
+        self.method = method;
+    }
+
+    pub fn get_vertices(&self) -> &[SkinVertex] {
+        &self.vertices
+    }
+
+    pub fn get_indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    pub fn get_bindings(&self) -> &[glm::Mat4] {
+        &self.bindings
+    }
+
+    pub fn get_deformed(&self) -> &[ModelVertex] {
+        &self.deformed
+    }
+
+    pub fn get_num_joints(&self) -> usize {
+        self.bindings.len()
+    }
+
+    // Joint world matrix times inverse binding, the transform applied to bind pose vertices.
+    pub fn skinning_mats(&self, skeleton: &Skeleton) -> Vec<glm::Mat4> {
+        self.inverse_bindings.iter().enumerate()
+            .map(|(joint, inverse_binding)| skeleton.get_world_mat(joint) * inverse_binding)
+            .collect()
+    }
+
+    pub fn update(&mut self, skeleton: &Skeleton) {
+        let skinning_mats = self.skinning_mats(skeleton);
+        match self.mode {
+            SkinningMode::Cpu => {
+                self.deform(&skinning_mats);
+                if let Some(model) = self.model.as_mut() {
+                    model.update_vertices(&self.deformed);
+                }
+            },
+            SkinningMode::Gpu => {
+                match (self.method, self.palette.as_ref(), self.dual_quat_palette.as_ref()) {
+                    (SkinningMethod::Linear, Some(palette), _) => {
+                        palette.bind();
+                        palette.stream_draw_data(&skinning_mats);
+                        palette.unbind();
+                    },
+                    (SkinningMethod::DualQuaternion, _, Some(palette)) => {
+                        palette.bind();
+                        palette.stream_draw_data(&dual_quat_palette(&skinning_mats));
+                        palette.unbind();
+                    },
+                    _ => {}
+                }
+            },
+        }
+    }
+
+    // Skinning on the CPU, the reference the shader path is compared against
+    pub fn deform(&mut self, skinning_mats: &[glm::Mat4]) {
+        match self.method {
+            SkinningMethod::Linear => self.deform_linear(skinning_mats),
+            SkinningMethod::DualQuaternion => self.deform_dual_quaternion(skinning_mats),
+        }
+    }
+
+    fn deform_linear(&mut self, skinning_mats: &[glm::Mat4]) {
+        for (vertex, deformed) in self.vertices.iter().zip(self.deformed.iter_mut()) {
+            let position = glm::vec4(vertex.get_position().x, vertex.get_position().y, vertex.get_position().z, 1.0);
+            let normal = glm::vec4(vertex.get_normal().x, vertex.get_normal().y, vertex.get_normal().z, 0.0);
+            let mut skinned_position = glm::vec4(0.0, 0.0, 0.0, 0.0);
+            let mut skinned_normal = glm::vec4(0.0, 0.0, 0.0, 0.0);
+            for (joint, weight) in vertex.influences() {
+                skinned_position += skinning_mats[joint] * position * weight;
+                skinned_normal += skinning_mats[joint] * normal * weight;
+            }
+            let normal = glm::vec3(skinned_normal.x, skinned_normal.y, skinned_normal.z);
+            let length = glm::length(&normal);
+            *deformed = ModelVertex::new(
+                glm::vec3(skinned_position.x, skinned_position.y, skinned_position.z),
+                if length > 0.0 { normal / length } else { vertex.get_normal() },
+            );
+        }
+    }
+
+    fn deform_dual_quaternion(&mut self, skinning_mats: &[glm::Mat4]) {
+        let dual_quats: Vec<DualQuat> = skinning_mats.iter().map(DualQuat::from_mat4).collect();
+        for (vertex, deformed) in self.vertices.iter().zip(self.deformed.iter_mut()) {
+            let influences: Vec<(DualQuat, f32)> = vertex.influences()
+                .map(|(joint, weight)| (dual_quats[joint], weight))
+                .collect();
+            let blended = DualQuat::blend(&influences);
+            *deformed = ModelVertex::new(
+                blended.transform_point(&vertex.get_position()),
+                blended.transform_vector(&vertex.get_normal()),
+            );
+        }
+    }
+
+    pub fn draw(&self, view_proj_mat: glm::Mat4, shader: GLuint) {
+        match self.mode {
+            SkinningMode::Cpu => {
+                if let Some(model) = self.model.as_ref() {
+                    model.draw(glm::Mat4::identity(), view_proj_mat, shader);
+                }
+            },
+            SkinningMode::Gpu => {
+                if let (Some(model), Some(palette), Some(dual_quat_palette)) =
+                    (self.gpu_model.as_ref(), self.palette.as_ref(), self.dual_quat_palette.as_ref()) {
+                    palette.bind_base(0);
+                    dual_quat_palette.bind_base(1);
+                    let dual_quaternion = (self.method == SkinningMethod::DualQuaternion) as GLint;
+                    unsafe {
+                        let location = gl::GetUniformLocation(shader, b"DualQuaternionSkinning\0".as_ptr() as _);
+                        gl::ProgramUniform1i(shader, location, dual_quaternion);
+                    }
+                    model.draw(glm::Mat4::identity(), view_proj_mat, shader);
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::*;
+
+    // Unit radius cylinder along y from -1 to 1. Joint 0 sits at the bottom,
+    // joint 1 at the middle, and the weights hand over between y -0.5 and 0.5.
+    fn skinned_cylinder() -> SkinnedModel {
+        let mesh = MeshData::cylinder(1.0, 2.0, 16, 8);
+        let vertices = mesh.get_vertices().iter().map(|vertex| {
+            let position = vertex.get_position();
+            let upper = glm::clamp_scalar(position.y + 0.5, 0.0, 1.0);
+            SkinVertex::new(position, vertex.get_normal(), &[(0, 1.0 - upper), (1, upper)])
+        }).collect();
+        let bindings = vec![glm::translation(&glm::vec3(0.0, -1.0, 0.0)), glm::Mat4::identity()];
+        SkinnedModel::from_parts(vertices, mesh.get_indices().to_vec(), bindings)
+    }
+
+    // Skinning matrices with the child joint twisted `angle` around the cylinder's axis
+    fn twist(model: &SkinnedModel, angle: f32) -> Vec<glm::Mat4> {
+        let world_mats = [model.get_bindings()[0], glm::rotation(angle, &glm::vec3(0.0, 1.0, 0.0))];
+        world_mats.iter().zip(model.inverse_bindings.iter()).map(|(world, inverse)| world * inverse).collect()
+    }
+
+    fn assert_close(actual: glm::Vec3, expected: glm::Vec3) {
+        assert!(glm::length(&(actual - expected)) < 1e-5, "{} vs {}", actual, expected);
+    }
+
+    // Checks the CPU path against closed-form results. The shader is only
+    // exercised by running the viewer.
+    #[test]
+    fn cpu_skinning_matches_closed_form() {
+        let normal = glm::vec3(0.0, 0.0, 1.0);
+        let vertices = vec![
+            SkinVertex::new(glm::vec3(1.0, 0.0, 0.0), normal, &[(0, 1.0)]),
+            SkinVertex::new(glm::vec3(0.0, 1.0, 0.0), normal, &[(1, 1.0)]),
+            SkinVertex::new(glm::vec3(1.0, 0.0, 0.0), normal, &[(0, 0.5), (1, 0.5)]),
+        ];
+        let mut model = SkinnedModel::from_parts(vertices, vec![0, 1, 2], vec![glm::Mat4::identity(); 2]);
+        let point = |mat: &glm::Mat4, p: glm::Vec3| glm::vec4_to_vec3(&(mat * glm::vec4(p.x, p.y, p.z, 1.0)));
+        let y = glm::vec3(0.0, 1.0, 0.0);
+        for &method in &[SkinningMethod::Linear, SkinningMethod::DualQuaternion] {
+            model.set_method(method);
+
+            // Vertices with a single joint follow it rigidly
+            let rigid = [
+                glm::translation(&glm::vec3(1.0, 2.0, 3.0)) * glm::rotation(0.7, &glm::vec3(1.0, 0.0, 0.0)),
+                glm::translation(&glm::vec3(-1.0, 0.0, 0.0)) * glm::rotation(1.1, &glm::normalize(&glm::vec3(1.0, 1.0, 0.0))),
+            ];
+            model.deform(&rigid);
+            assert_close(model.get_deformed()[0].get_position(), point(&rigid[0], glm::vec3(1.0, 0.0, 0.0)));
+            assert_close(model.get_deformed()[1].get_position(), point(&rigid[1], glm::vec3(0.0, 1.0, 0.0)));
+
+            // Translations blend linearly with either method
+            model.deform(&[glm::translation(&glm::vec3(2.0, 0.0, 0.0)), glm::translation(&glm::vec3(0.0, 4.0, 0.0))]);
+            assert_close(model.get_deformed()[2].get_position(), glm::vec3(2.0, 2.0, 0.0));
+
+            // Half way between no turn and a quarter turn about y
+            model.deform(&[glm::Mat4::identity(), glm::rotation(glm::half_pi(), &y)]);
+            let half = std::f32::consts::FRAC_1_SQRT_2;
+            let expected = match method {
+                // The average of the two rotated points, pulled towards the axis
+                SkinningMethod::Linear => glm::vec3(0.5, 0.0, -0.5),
+                // A rigid eighth turn
+                SkinningMethod::DualQuaternion => glm::vec3(half, 0.0, -half),
+            };
+            assert_close(model.get_deformed()[2].get_position(), expected);
+        }
+    }
+
+    // Smallest distance from the axis among the vertices near the twisting joint
+    fn middle_radius(model: &SkinnedModel) -> f32 {
+        model.get_deformed().iter()
+            .filter(|vertex| vertex.get_position().y.abs() < 0.3)
+            .map(|vertex| glm::length(&glm::vec2(vertex.get_position().x, vertex.get_position().z)))
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    #[test]
+    fn dual_quaternions_keep_twisted_volume() {
+        let mut model = skinned_cylinder();
+        for &method in &[SkinningMethod::Linear, SkinningMethod::DualQuaternion] {
+            model.set_method(method);
+            model.deform(&twist(&model, 0.0));
+            for (vertex, deformed) in model.get_vertices().iter().zip(model.get_deformed()) {
+                assert!(glm::length(&(deformed.get_position() - vertex.get_position())) < 1e-5, "{:?} moved at rest", method);
+            }
+        }
+
+        let angle = 150.0f32.to_radians();
+        model.set_method(SkinningMethod::Linear);
+        model.deform(&twist(&model, angle));
+        let linear = middle_radius(&model);
+        model.set_method(SkinningMethod::DualQuaternion);
+        model.deform(&twist(&model, angle));
+        let dual_quaternion = middle_radius(&model);
+        // Linear blending averages the two rotations and collapses towards the axis
+        assert!(linear < 0.5, "linear radius {}", linear);
+        assert!(dual_quaternion > 0.99, "dual quaternion radius {}", dual_quaternion);
+    }
+}
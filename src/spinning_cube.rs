@@ -1,12 +1,14 @@
 use crate::model::*;
+use crate::rigid_body::*;
 use gl::types::*;
 
+// A free rigid body spun about `axis`, with no gravity and nothing to hit it
+// keeps turning at the same rate
 pub struct SpinningCube {
     cube: Model,
-    position: glm::Vec3,
+    body: RigidBody,
     axis: glm::Vec3,
     spin_delta: f32,
-    angle: f32,
     world_mat: glm::Mat4,
 }
 
@@ -14,25 +16,27 @@ impl SpinningCube {
     pub fn new() -> SpinningCube {
         let mut cube = Model::new();
         cube.make_box(glm::vec3(-1.0, -1.0, -1.0), glm::vec3(1.0, 1.0, 1.0));
+        let axis = glm::vec3(0.0, 1.0, 0.0);
+        let spin_delta = 1.0;
+        let mut body = RigidBody::new(Shape::Box { half_extents: glm::vec3(1.0, 1.0, 1.0) }, 1.0, glm::vec3(0.0, 0.0, 0.0));
+        body.set_angular_velocity(axis * spin_delta);
         SpinningCube {
             cube,
-            position: glm::vec3(0.0, 0.0, 0.0),
-            axis: glm::vec3(0.0, 1.0, 0.0),
-            spin_delta: 1.0,
-            angle: 0.0,
+            body,
+            axis,
+            spin_delta,
             world_mat: glm::Mat4::identity()
         }
     }
 
     pub fn set_position(&mut self, pos: glm::Vec3) {
-        self.position = pos
+        self.body.set_position(pos)
     }
 
     pub fn update(&mut self, dt: f32) {
-        self.angle += self.spin_delta * dt;
-        self.world_mat = glm::Mat4::identity();
-        self.world_mat = glm::translate(&self.world_mat, &self.position);
-        self.world_mat = glm::rotate(&self.world_mat, self.angle, &self.axis);
+        self.body.integrate_velocity(dt, glm::vec3(0.0, 0.0, 0.0));
+        self.body.integrate_position(dt);
+        self.world_mat = self.body.get_world_mat();
     }
 
     pub fn draw(&self, view_proj_mat: glm::Mat4, shader: GLuint) {
@@ -40,7 +44,9 @@ impl SpinningCube {
     }
 
     pub fn reset(&mut self) {
-        self.angle = 0.0;
+        self.body.set_orientation(glm::quat_identity());
+        self.body.set_linear_velocity(glm::vec3(0.0, 0.0, 0.0));
+        self.body.set_angular_velocity(self.axis * self.spin_delta);
         self.world_mat = glm::Mat4::identity();
     }
 }
@@ -0,0 +1,770 @@
+use std::path::Path;
+use std::rc::Rc;
+use crate::animation::*;
+use crate::blend_space::*;
+use crate::blender::*;
+use crate::bvh::*;
+use crate::pose::*;
+use crate::skeleton::*;
+use crate::tokenizer::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Parameter {
+    Float(f32),
+    Bool(bool),
+    // Set until a transition that checks it fires
+    Trigger(bool),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Comparison {
+    fn test(&self, a: f32, b: f32) -> bool {
+        match *self {
+            Comparison::Less => a < b,
+            Comparison::LessEqual => a <= b,
+            Comparison::Greater => a > b,
+            Comparison::GreaterEqual => a >= b,
+            Comparison::Equal => (a - b).abs() <= f32::EPSILON,
+            Comparison::NotEqual => (a - b).abs() > f32::EPSILON,
+        }
+    }
+}
+
+// Conditions refer to parameters by index
+#[derive(Clone, Copy, Debug)]
+pub enum Condition {
+    Float(usize, Comparison, f32),
+    Bool(usize, bool),
+    Trigger(usize),
+}
+
+// What a state plays. Blend spaces read their input from float parameters,
+// the second one only for 2D spaces.
+#[derive(Clone)]
+pub enum Motion {
+    Clip(Rc<Animation>),
+    BlendSpace(Rc<BlendSpace>, usize, Option<usize>),
+}
+
+pub struct State {
+    name: String,
+    motion: Motion,
+    speed: f32,
+}
+
+impl State {
+    pub fn new(name: &str, motion: Motion) -> State {
+        State {
+            name: name.to_string(),
+            motion,
+            speed: 1.0,
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_motion(&self) -> &Motion {
+        &self.motion
+    }
+
+    pub fn get_speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed;
+    }
+}
+
+// A transition from None is taken from any state
+#[derive(Clone, Debug)]
+pub struct Transition {
+    from: Option<usize>,
+    to: usize,
+    duration: f32,
+    exit_time: Option<f32>,
+    conditions: Vec<Condition>,
+}
+
+impl Transition {
+    pub fn new(from: Option<usize>, to: usize, duration: f32) -> Transition {
+        Transition {
+            from,
+            to,
+            duration,
+            exit_time: None,
+            conditions: Vec::new(),
+        }
+    }
+
+    pub fn get_from(&self) -> Option<usize> {
+        self.from
+    }
+
+    pub fn get_to(&self) -> usize {
+        self.to
+    }
+
+    pub fn get_duration(&self) -> f32 {
+        self.duration
+    }
+
+    pub fn get_exit_time(&self) -> Option<f32> {
+        self.exit_time
+    }
+
+    // Normalized time of the source state, 1 is the end of its first loop
+    pub fn set_exit_time(&mut self, exit_time: Option<f32>) {
+        self.exit_time = exit_time;
+    }
+
+    pub fn get_conditions(&self) -> &[Condition] {
+        &self.conditions
+    }
+
+    pub fn add_condition(&mut self, condition: Condition) {
+        self.conditions.push(condition);
+    }
+}
+
+// Holding the key sets the parameter to `value`, releasing it restores the
+// default. Triggers fire on press.
+#[derive(Clone, Debug)]
+pub struct KeyBinding {
+    key: String,
+    parameter: usize,
+    value: Parameter,
+}
+
+enum StateSource {
+    Clip(AnimationPlayer),
+    BlendSpace(BlendSpacePlayer),
+}
+
+// A running state inside the machine's cross-fade, with the float parameters
+// its blend space reads
+pub struct StatePlayer {
+    source: StateSource,
+    inputs: Option<(usize, Option<usize>)>,
+}
+
+impl StatePlayer {
+    fn new(state: &State) -> StatePlayer {
+        let source = match &state.motion {
+            Motion::Clip(animation) => {
+                let mut player = AnimationPlayer::new(animation.clone());
+                player.set_speed(state.speed);
+                StateSource::Clip(player)
+            },
+            Motion::BlendSpace(space, _, _) => {
+                let mut player = BlendSpacePlayer::new(space.clone());
+                player.set_speed(state.speed);
+                StateSource::BlendSpace(player)
+            },
+        };
+        let inputs = match state.motion {
+            Motion::BlendSpace(_, x, y) => Some((x, y)),
+            Motion::Clip(_) => None,
+        };
+        StatePlayer {
+            source,
+            inputs,
+        }
+    }
+
+    // 1 is the end of the first loop
+    pub fn get_normalized_time(&self) -> f32 {
+        match &self.source {
+            StateSource::Clip(player) => {
+                let animation = player.get_animation();
+                let duration = animation.get_end_time() - animation.get_start_time();
+                if duration > 0.0 { (player.get_time() - animation.get_start_time()) / duration } else { 1.0 }
+            },
+            StateSource::BlendSpace(player) => player.get_phase(),
+        }
+    }
+
+    fn read_inputs(&mut self, parameters: &[(String, Parameter)]) {
+        if let (StateSource::BlendSpace(player), Some((x, y))) = (&mut self.source, self.inputs) {
+            let y = y.map_or(0.0, |y| float_value(parameters, y));
+            player.set_input(glm::vec2(float_value(parameters, x), y));
+        }
+    }
+}
+
+impl PoseSource for StatePlayer {
+    fn update(&mut self, dt: f32) {
+        match &mut self.source {
+            StateSource::Clip(player) => player.update(dt),
+            StateSource::BlendSpace(player) => player.update(dt),
+        }
+    }
+
+    fn evaluate(&self, skeleton: &Skeleton) -> Pose {
+        match &self.source {
+            StateSource::Clip(player) => player.sample(skeleton),
+            StateSource::BlendSpace(player) => player.evaluate(skeleton),
+        }
+    }
+
+    fn reset(&mut self) {
+        match &mut self.source {
+            StateSource::Clip(player) => player.reset(),
+            StateSource::BlendSpace(player) => player.reset(),
+        }
+    }
+}
+
+pub struct StateMachine {
+    parameters: Vec<(String, Parameter)>,
+    defaults: Vec<Parameter>,
+    states: Vec<State>,
+    transitions: Vec<Transition>,
+    bindings: Vec<KeyBinding>,
+    start: usize,
+    current: usize,
+    player: Option<CrossFade<StatePlayer>>,
+}
+
+impl StateMachine {
+    pub fn new() -> StateMachine {
+        StateMachine {
+            parameters: Vec::new(),
+            defaults: Vec::new(),
+            states: Vec::new(),
+            transitions: Vec::new(),
+            bindings: Vec::new(),
+            start: 0,
+            current: 0,
+            player: None,
+        }
+    }
+
+    // Clip paths are relative to the graph file. BVH clips are read with
+    // `bvh_options` and only their motion is used.
+    pub fn from_file(filename: &str, bvh_options: BvhOptions) -> Result<StateMachine, ParseError> {
+        let mut tokenizer = Tokenizer::from_file(filename)?;
+        let base = Path::new(filename).parent().unwrap_or_else(|| Path::new(""));
+        Self::parse(&mut tokenizer, |path| {
+            let path = base.join(path).to_string_lossy().into_owned();
+            if path.ends_with(".bvh") {
+                Bvh::from_file(&path, bvh_options).map(|bvh| bvh.into_parts().1)
+            } else {
+                Animation::from_file(&path)
+            }
+        })
+    }
+
+    pub fn parse<F>(tokenizer: &mut Tokenizer, mut load_clip: F) -> Result<StateMachine, ParseError>
+        where F: FnMut(&str) -> Result<Animation, ParseError> {
+        let mut machine = StateMachine::new();
+        let mut clips: Vec<(String, Rc<Animation>)> = Vec::new();
+        let mut spaces: Vec<(String, Motion)> = Vec::new();
+        let mut start: Option<(String, usize)> = None;
+        let file = tokenizer.get_file().to_string();
+        tokenizer.expect("statemachine")?;
+        tokenizer.expect("{")?;
+        loop {
+            let line = tokenizer.get_line();
+            let token = tokenizer.next_token()?;
+            let error = |message: String| ParseError::new(&file, line, &message);
+            match token.as_str() {
+                "parameter" => {
+                    let name = tokenizer.next_token()?;
+                    if machine.find_parameter(&name).is_some() {
+                        return Err(error(format!("duplicate parameter '{}'", name)));
+                    }
+                    let parameter = match tokenizer.next_token()?.as_str() {
+                        "float" => Parameter::Float(tokenizer.get_float()?),
+                        "bool" => Parameter::Bool(Self::parse_bool(tokenizer)?),
+                        "trigger" => Parameter::Trigger(false),
+                        other => return Err(error(format!("unknown parameter type '{}'", other))),
+                    };
+                    machine.add_parameter(&name, parameter);
+                },
+                "clip" => {
+                    let name = tokenizer.next_token()?;
+                    let path = tokenizer.next_token()?;
+                    let animation = load_clip(&path)?;
+                    clips.push((name, Rc::new(animation)));
+                },
+                "blendspace" => {
+                    let name = tokenizer.next_token()?;
+                    let mut space = match tokenizer.next_token()?.as_str() {
+                        "1d" => BlendSpace::new_1d(),
+                        "2d" => BlendSpace::new_2d(),
+                        other => return Err(error(format!("unknown blend space dimension '{}'", other))),
+                    };
+                    let mut inputs = Vec::new();
+                    for _ in 0..space.get_dimensions() {
+                        let parameter = tokenizer.next_token()?;
+                        match machine.find_parameter(&parameter) {
+                            Some(index) if matches!(machine.parameters[index].1, Parameter::Float(_)) => inputs.push(index),
+                            _ => return Err(error(format!("blend space input '{}' is not a float parameter", parameter))),
+                        }
+                    }
+                    tokenizer.expect("{")?;
+                    loop {
+                        let line = tokenizer.get_line();
+                        let token = tokenizer.next_token()?;
+                        match token.as_str() {
+                            "sample" => {
+                                let clip = tokenizer.next_token()?;
+                                let animation = clips.iter().find(|(name, _)| *name == clip)
+                                    .ok_or_else(|| ParseError::new(&file, line, &format!("unknown clip '{}'", clip)))?;
+                                let x = tokenizer.get_float()?;
+                                let y = if space.get_dimensions() == 2 { tokenizer.get_float()? } else { 0.0 };
+                                space.add_sample(glm::vec2(x, y), animation.1.clone());
+                            },
+                            "}" => break,
+                            _ => return Err(ParseError::new(&file, line, &format!("unknown blend space attribute '{}'", token))),
+                        }
+                    }
+                    if space.get_samples().is_empty() {
+                        return Err(error(format!("blend space '{}' has no samples", name)));
+                    }
+                    spaces.push((name, Motion::BlendSpace(Rc::new(space), inputs[0], inputs.get(1).copied())));
+                },
+                "state" => {
+                    let name = tokenizer.next_token()?;
+                    if machine.find_state(&name).is_some() {
+                        return Err(error(format!("duplicate state '{}'", name)));
+                    }
+                    tokenizer.expect("{")?;
+                    let mut motion = None;
+                    let mut speed = 1.0;
+                    loop {
+                        let line = tokenizer.get_line();
+                        let token = tokenizer.next_token()?;
+                        match token.as_str() {
+                            "clip" => {
+                                let clip = tokenizer.next_token()?;
+                                let animation = clips.iter().find(|(name, _)| *name == clip)
+                                    .ok_or_else(|| ParseError::new(&file, line, &format!("unknown clip '{}'", clip)))?;
+                                motion = Some(Motion::Clip(animation.1.clone()));
+                            },
+                            "blendspace" => {
+                                let space = tokenizer.next_token()?;
+                                let found = spaces.iter().find(|(name, _)| *name == space)
+                                    .ok_or_else(|| ParseError::new(&file, line, &format!("unknown blend space '{}'", space)))?;
+                                motion = Some(found.1.clone());
+                            },
+                            "speed" => speed = tokenizer.get_float()?,
+                            "}" => break,
+                            _ => return Err(ParseError::new(&file, line, &format!("unknown state attribute '{}'", token))),
+                        }
+                    }
+                    let motion = motion.ok_or_else(|| error(format!("state '{}' has nothing to play", name)))?;
+                    let mut state = State::new(&name, motion);
+                    state.set_speed(speed);
+                    machine.add_state(state);
+                },
+                "transition" => {
+                    let from = tokenizer.next_token()?;
+                    let to = tokenizer.next_token()?;
+                    let from = match from.as_str() {
+                        "any" => None,
+                        _ => Some(machine.find_state(&from).ok_or_else(|| error(format!("unknown state '{}'", from)))?),
+                    };
+                    let to = machine.find_state(&to).ok_or_else(|| error(format!("unknown state '{}'", to)))?;
+                    let mut transition = Transition::new(from, to, 0.0);
+                    tokenizer.expect("{")?;
+                    loop {
+                        let line = tokenizer.get_line();
+                        let token = tokenizer.next_token()?;
+                        let error = |message: String| ParseError::new(&file, line, &message);
+                        match token.as_str() {
+                            "duration" => transition.duration = tokenizer.get_float()?.max(0.0),
+                            "exittime" => transition.exit_time = Some(tokenizer.get_float()?),
+                            "when" => {
+                                let name = tokenizer.next_token()?;
+                                let index = machine.find_parameter(&name).ok_or_else(|| error(format!("unknown parameter '{}'", name)))?;
+                                let condition = match machine.parameters[index].1 {
+                                    Parameter::Float(_) => {
+                                        let comparison = match tokenizer.next_token()?.as_str() {
+                                            "<" => Comparison::Less,
+                                            "<=" => Comparison::LessEqual,
+                                            ">" => Comparison::Greater,
+                                            ">=" => Comparison::GreaterEqual,
+                                            "==" => Comparison::Equal,
+                                            "!=" => Comparison::NotEqual,
+                                            other => return Err(error(format!("unknown comparison '{}'", other))),
+                                        };
+                                        Condition::Float(index, comparison, tokenizer.get_float()?)
+                                    },
+                                    Parameter::Bool(_) => {
+                                        let value = match tokenizer.peek() {
+                                            Some("true") | Some("false") => Self::parse_bool(tokenizer)?,
+                                            _ => true,
+                                        };
+                                        Condition::Bool(index, value)
+                                    },
+                                    Parameter::Trigger(_) => Condition::Trigger(index),
+                                };
+                                transition.add_condition(condition);
+                            },
+                            "}" => break,
+                            _ => return Err(error(format!("unknown transition attribute '{}'", token))),
+                        }
+                    }
+                    if transition.conditions.is_empty() && transition.exit_time.is_none() {
+                        return Err(error("transition needs a condition or an exit time".to_string()));
+                    }
+                    machine.add_transition(transition);
+                },
+                "key" => {
+                    let key = tokenizer.next_token()?;
+                    let name = tokenizer.next_token()?;
+                    let index = machine.find_parameter(&name).ok_or_else(|| error(format!("unknown parameter '{}'", name)))?;
+                    let value = match machine.parameters[index].1 {
+                        Parameter::Float(_) => Parameter::Float(tokenizer.get_float()?),
+                        Parameter::Bool(_) => match tokenizer.peek() {
+                            Some("true") | Some("false") => Parameter::Bool(Self::parse_bool(tokenizer)?),
+                            _ => Parameter::Bool(true),
+                        },
+                        Parameter::Trigger(_) => Parameter::Trigger(true),
+                    };
+                    machine.bind_key(&key, index, value);
+                },
+                "start" => start = Some((tokenizer.next_token()?, line)),
+                "}" => break,
+                _ => return Err(error(format!("unknown statemachine attribute '{}'", token))),
+            }
+        }
+        if machine.states.is_empty() {
+            return Err(tokenizer.error("statemachine has no states"));
+        }
+        if let Some((name, line)) = start {
+            let index = machine.find_state(&name)
+                .ok_or_else(|| ParseError::new(tokenizer.get_file(), line, &format!("unknown state '{}'", name)))?;
+            machine.set_start_state(index);
+        }
+        Ok(machine)
+    }
+
+    fn parse_bool(tokenizer: &mut Tokenizer) -> Result<bool, ParseError> {
+        let line = tokenizer.get_line();
+        match tokenizer.next_token()?.as_str() {
+            "true" | "1" => Ok(true),
+            "false" | "0" => Ok(false),
+            other => Err(ParseError::new(tokenizer.get_file(), line, &format!("expected true or false, found '{}'", other))),
+        }
+    }
+
+    pub fn add_parameter(&mut self, name: &str, value: Parameter) -> usize {
+        self.parameters.push((name.to_string(), value));
+        self.defaults.push(value);
+        self.parameters.len() - 1
+    }
+
+    pub fn add_state(&mut self, state: State) -> usize {
+        self.states.push(state);
+        self.states.len() - 1
+    }
+
+    pub fn add_transition(&mut self, transition: Transition) {
+        self.transitions.push(transition);
+    }
+
+    pub fn bind_key(&mut self, key: &str, parameter: usize, value: Parameter) {
+        self.bindings.push(KeyBinding {
+            key: key.to_string(),
+            parameter,
+            value,
+        });
+    }
+
+    pub fn find_parameter(&self, name: &str) -> Option<usize> {
+        self.parameters.iter().position(|(parameter, _)| parameter == name)
+    }
+
+    pub fn find_state(&self, name: &str) -> Option<usize> {
+        self.states.iter().position(|state| state.name == name)
+    }
+
+    pub fn get_parameter(&self, name: &str) -> Option<Parameter> {
+        self.find_parameter(name).map(|index| self.parameters[index].1)
+    }
+
+    // Only changes parameters of the same type, returns whether it did
+    pub fn set_parameter(&mut self, name: &str, value: Parameter) -> bool {
+        match self.find_parameter(name) {
+            Some(index) => self.set_parameter_index(index, value),
+            None => false,
+        }
+    }
+
+    fn set_parameter_index(&mut self, index: usize, value: Parameter) -> bool {
+        let parameter = &mut self.parameters[index].1;
+        if std::mem::discriminant(parameter) != std::mem::discriminant(&value) {
+            return false;
+        }
+        *parameter = value;
+        true
+    }
+
+    pub fn set_float(&mut self, name: &str, value: f32) -> bool {
+        self.set_parameter(name, Parameter::Float(value))
+    }
+
+    pub fn set_bool(&mut self, name: &str, value: bool) -> bool {
+        self.set_parameter(name, Parameter::Bool(value))
+    }
+
+    pub fn set_trigger(&mut self, name: &str) -> bool {
+        self.set_parameter(name, Parameter::Trigger(true))
+    }
+
+    // Key names are glutin's VirtualKeyCode names, like W, Space or LShift
+    pub fn on_key(&mut self, key: &str, pressed: bool) {
+        for index in 0..self.bindings.len() {
+            if self.bindings[index].key != key {
+                continue;
+            }
+            let parameter = self.bindings[index].parameter;
+            let value = match (self.bindings[index].value, pressed) {
+                (Parameter::Trigger(_), false) => continue,
+                (value, true) => value,
+                (_, false) => self.defaults[parameter],
+            };
+            self.set_parameter_index(parameter, value);
+        }
+    }
+
+    pub fn get_states(&self) -> &[State] {
+        &self.states
+    }
+
+    pub fn get_transitions(&self) -> &[Transition] {
+        &self.transitions
+    }
+
+    pub fn get_current_state(&self) -> &State {
+        &self.states[self.current]
+    }
+
+    pub fn set_start_state(&mut self, state: usize) {
+        self.start = state;
+        self.current = state;
+        self.player = None;
+    }
+
+    pub fn get_state_player(&self) -> Option<&StatePlayer> {
+        self.player.as_ref().map(|player| player.get_current())
+    }
+
+    pub fn is_transitioning(&self) -> bool {
+        self.player.as_ref().is_some_and(|player| player.is_fading())
+    }
+
+    fn conditions_met(&self, transition: &Transition, normalized_time: f32) -> bool {
+        if let Some(exit_time) = transition.exit_time {
+            if normalized_time < exit_time {
+                return false;
+            }
+        }
+        transition.conditions.iter().all(|condition| match (*condition, self.parameters[condition_parameter(condition)].1) {
+            (Condition::Float(_, comparison, value), Parameter::Float(current)) => comparison.test(current, value),
+            (Condition::Bool(_, value), Parameter::Bool(current)) => current == value,
+            (Condition::Trigger(_), Parameter::Trigger(current)) => current,
+            _ => false,
+        })
+    }
+
+    // Advances the current state, then takes the first transition whose
+    // conditions hold. Transitions out of the state come before any-state ones.
+    pub fn update(&mut self, dt: f32) {
+        if self.states.is_empty() {
+            return;
+        }
+        let states = &self.states;
+        let current = self.current;
+        let parameters = &self.parameters;
+        let player = self.player.get_or_insert_with(|| CrossFade::new(StatePlayer::new(&states[current])));
+        // States fading out keep following their inputs too
+        player.for_each_mut(|state| state.read_inputs(parameters));
+        player.update(dt);
+        let normalized_time = player.get_current().get_normalized_time();
+
+        let from_current = self.transitions.iter().filter(|transition| transition.from == Some(self.current));
+        let from_any = self.transitions.iter().filter(|transition| transition.from.is_none() && transition.to != self.current);
+        let taken = from_current.chain(from_any)
+            .find(|transition| self.conditions_met(transition, normalized_time))
+            .cloned();
+        if let Some(transition) = taken {
+            for condition in &transition.conditions {
+                if let Condition::Trigger(index) = *condition {
+                    self.parameters[index].1 = Parameter::Trigger(false);
+                }
+            }
+            self.current = transition.to;
+            let next = StatePlayer::new(&self.states[transition.to]);
+            if let Some(player) = self.player.as_mut() {
+                player.fade_to(next, transition.duration);
+            }
+        }
+    }
+
+    pub fn evaluate(&self, skeleton: &Skeleton) -> Pose {
+        match self.player.as_ref() {
+            Some(player) => player.evaluate(skeleton),
+            None => match self.states.get(self.current) {
+                Some(state) => StatePlayer::new(state).evaluate(skeleton),
+                None => Pose::from_skeleton(skeleton),
+            },
+        }
+    }
+
+    pub fn pose(&self, skeleton: &mut Skeleton) {
+        self.evaluate(skeleton).apply(skeleton);
+    }
+
+    pub fn reset(&mut self) {
+        for (parameter, default) in self.parameters.iter_mut().zip(self.defaults.iter()) {
+            parameter.1 = *default;
+        }
+        self.current = self.start;
+        self.player = None;
+    }
+}
+
+fn float_value(parameters: &[(String, Parameter)], index: usize) -> f32 {
+    match parameters[index].1 {
+        Parameter::Float(value) => value,
+        _ => 0.0,
+    }
+}
+
+fn condition_parameter(condition: &Condition) -> usize {
+    match *condition {
+        Condition::Float(index, _, _) => index,
+        Condition::Bool(index, _) => index,
+        Condition::Trigger(index) => index,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animation::tests::constant;
+
+    // Holds the root at `x` for `duration` seconds
+    fn hold(x: f32, duration: f32) -> Rc<Animation> {
+        let mut channels = vec![constant(x)];
+        channels.extend((0..5).map(|_| constant(0.0)));
+        Rc::new(Animation::new(0.0, duration, channels))
+    }
+
+    fn skeleton() -> Skeleton {
+        let mut skeleton = Skeleton::new();
+        skeleton.add_joint(Joint::new("root", None));
+        skeleton
+    }
+
+    fn root_x(machine: &StateMachine) -> f32 {
+        machine.evaluate(&skeleton()).get_transform(0).get_translation().x
+    }
+
+    fn when(from: usize, to: usize, condition: Condition) -> Transition {
+        let mut transition = Transition::new(Some(from), to, 0.0);
+        transition.add_condition(condition);
+        transition
+    }
+
+    #[test]
+    fn float_conditions_switch_states() {
+        let mut machine = StateMachine::new();
+        let speed = machine.add_parameter("speed", Parameter::Float(0.0));
+        let idle = machine.add_state(State::new("idle", Motion::Clip(hold(0.0, 1.0))));
+        let walk = machine.add_state(State::new("walk", Motion::Clip(hold(1.0, 1.0))));
+        machine.add_transition(when(idle, walk, Condition::Float(speed, Comparison::Greater, 0.5)));
+        machine.add_transition(when(walk, idle, Condition::Float(speed, Comparison::LessEqual, 0.5)));
+
+        machine.update(0.1);
+        assert_eq!(machine.get_current_state().get_name(), "idle");
+        assert!(machine.set_float("speed", 1.0));
+        assert!(!machine.set_bool("speed", true), "parameters keep their type");
+        assert_eq!(machine.get_parameter("speed"), Some(Parameter::Float(1.0)));
+        machine.update(0.1);
+        assert_eq!(machine.get_current_state().get_name(), "walk");
+        assert_eq!(root_x(&machine), 1.0);
+        machine.set_float("speed", 0.2);
+        machine.update(0.1);
+        assert_eq!(machine.get_current_state().get_name(), "idle");
+    }
+
+    #[test]
+    fn exit_time_waits_for_the_clip() {
+        let mut machine = StateMachine::new();
+        let land = machine.add_state(State::new("land", Motion::Clip(hold(0.0, 1.0))));
+        let idle = machine.add_state(State::new("idle", Motion::Clip(hold(1.0, 1.0))));
+        let mut transition = Transition::new(Some(land), idle, 0.0);
+        transition.set_exit_time(Some(1.0));
+        machine.add_transition(transition);
+
+        machine.update(0.5);
+        assert_eq!(machine.get_current_state().get_name(), "land");
+        machine.update(0.6);
+        assert_eq!(machine.get_current_state().get_name(), "idle");
+    }
+
+    #[test]
+    fn triggers_fire_once() {
+        let mut machine = StateMachine::new();
+        let jump_trigger = machine.add_parameter("jump", Parameter::Trigger(false));
+        let idle = machine.add_state(State::new("idle", Motion::Clip(hold(0.0, 1.0))));
+        let jump = machine.add_state(State::new("jump", Motion::Clip(hold(1.0, 1.0))));
+        let mut from_any = Transition::new(None, jump, 0.0);
+        from_any.add_condition(Condition::Trigger(jump_trigger));
+        machine.add_transition(from_any);
+        let mut back = Transition::new(Some(jump), idle, 0.0);
+        back.set_exit_time(Some(1.0));
+        machine.add_transition(back);
+
+        assert!(machine.set_trigger("jump"));
+        machine.update(0.1);
+        assert_eq!(machine.get_current_state().get_name(), "jump");
+        assert_eq!(machine.get_parameter("jump"), Some(Parameter::Trigger(false)));
+        machine.update(1.1);
+        assert_eq!(machine.get_current_state().get_name(), "idle");
+        for _ in 0..3 {
+            machine.update(0.5);
+            assert_eq!(machine.get_current_state().get_name(), "idle");
+        }
+    }
+
+    #[test]
+    fn fading_blend_space_keeps_reading_its_input() {
+        let mut space = BlendSpace::new_1d();
+        space.add_sample(glm::vec2(0.0, 0.0), hold(0.0, 1.0));
+        space.add_sample(glm::vec2(1.0, 0.0), hold(10.0, 1.0));
+        let mut machine = StateMachine::new();
+        let speed = machine.add_parameter("speed", Parameter::Float(0.0));
+        let stop = machine.add_parameter("stop", Parameter::Bool(false));
+        let moving = machine.add_state(State::new("move", Motion::BlendSpace(Rc::new(space), speed, None)));
+        let stopped = machine.add_state(State::new("stop", Motion::Clip(hold(0.0, 1.0))));
+        let mut transition = when(moving, stopped, Condition::Bool(stop, true));
+        transition.duration = 1.0;
+        machine.add_transition(transition);
+
+        machine.set_bool("stop", true);
+        machine.update(0.0);
+        assert!(machine.is_transitioning());
+        // The state on its way out now runs at full speed, at x = 10, and is still half the blend
+        machine.set_float("speed", 1.0);
+        machine.update(0.5);
+        assert!(machine.is_transitioning());
+        assert!((root_x(&machine) - 5.0).abs() < 1e-4, "{}", root_x(&machine));
+    }
+}
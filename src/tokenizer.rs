@@ -0,0 +1,179 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+
+#[derive(Debug)]
+pub struct ParseError {
+    file: String,
+    line: usize,
+    message: String,
+}
+
+impl ParseError {
+    pub fn new(file: &str, line: usize, message: &str) -> ParseError {
+        ParseError {
+            file: file.to_string(),
+            line,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn get_file(&self) -> &str {
+        &self.file
+    }
+
+    pub fn get_line(&self) -> usize {
+        self.line
+    }
+
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+struct Token {
+    text: String,
+    line: usize,
+}
+
+// Splits a text file into whitespace separated tokens. Braces are always
+// tokens of their own and `#` starts a comment running to the end of the line.
+pub struct Tokenizer {
+    file: String,
+    tokens: Vec<Token>,
+    cursor: usize,
+}
+
+impl Tokenizer {
+    pub fn from_file(filename: &str) -> Result<Tokenizer, ParseError> {
+        let contents = fs::read_to_string(filename)
+            .map_err(|e| ParseError::new(filename, 0, &e.to_string()))?;
+        Ok(Tokenizer::from_contents(filename, &contents))
+    }
+
+    pub fn from_contents(filename: &str, contents: &str) -> Tokenizer {
+        let mut tokens = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => line,
+            };
+            let mut text = String::new();
+            for c in line.chars() {
+                if c.is_whitespace() || c == '{' || c == '}' {
+                    if !text.is_empty() {
+                        tokens.push(Token { text: text.clone(), line: index + 1 });
+                        text.clear();
+                    }
+                    if !c.is_whitespace() {
+                        tokens.push(Token { text: c.to_string(), line: index + 1 });
+                    }
+                } else {
+                    text.push(c);
+                }
+            }
+            if !text.is_empty() {
+                tokens.push(Token { text, line: index + 1 });
+            }
+        }
+        Tokenizer {
+            file: filename.to_string(),
+            tokens,
+            cursor: 0,
+        }
+    }
+
+    pub fn get_file(&self) -> &str {
+        &self.file
+    }
+
+    // Line of the token that will be read next, or of the last token at end of file.
+    pub fn get_line(&self) -> usize {
+        match self.tokens.get(self.cursor) {
+            Some(token) => token.line,
+            None => self.tokens.last().map_or(0, |t| t.line),
+        }
+    }
+
+    pub fn error(&self, message: &str) -> ParseError {
+        ParseError::new(&self.file, self.get_line(), message)
+    }
+
+    pub fn is_eof(&self) -> bool {
+        self.cursor >= self.tokens.len()
+    }
+
+    pub fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.cursor).map(|t| t.text.as_str())
+    }
+
+    pub fn next_token(&mut self) -> Result<String, ParseError> {
+        match self.tokens.get(self.cursor) {
+            Some(token) => {
+                self.cursor += 1;
+                Ok(token.text.clone())
+            },
+            None => Err(self.error("unexpected end of file")),
+        }
+    }
+
+    pub fn expect(&mut self, expected: &str) -> Result<(), ParseError> {
+        let line = self.get_line();
+        let token = self.next_token()?;
+        if token == expected {
+            Ok(())
+        } else {
+            Err(ParseError::new(&self.file, line, &format!("expected '{}', found '{}'", expected, token)))
+        }
+    }
+
+    pub fn get_float(&mut self) -> Result<f32, ParseError> {
+        let line = self.get_line();
+        let token = self.next_token()?;
+        token.parse::<f32>()
+            .map_err(|_| ParseError::new(&self.file, line, &format!("expected a number, found '{}'", token)))
+    }
+
+    pub fn get_int(&mut self) -> Result<i32, ParseError> {
+        let line = self.get_line();
+        let token = self.next_token()?;
+        token.parse::<i32>()
+            .map_err(|_| ParseError::new(&self.file, line, &format!("expected an integer, found '{}'", token)))
+    }
+
+    pub fn get_usize(&mut self) -> Result<usize, ParseError> {
+        let line = self.get_line();
+        let token = self.next_token()?;
+        token.parse::<usize>()
+            .map_err(|_| ParseError::new(&self.file, line, &format!("expected a non-negative integer, found '{}'", token)))
+    }
+
+    pub fn get_vec3(&mut self) -> Result<glm::Vec3, ParseError> {
+        let x = self.get_float()?;
+        let y = self.get_float()?;
+        let z = self.get_float()?;
+        Ok(glm::vec3(x, y, z))
+    }
+
+    // Skips a `{ ... }` block whose opening brace is the next token, including nested blocks.
+    pub fn skip_block(&mut self) -> Result<(), ParseError> {
+        self.expect("{")?;
+        let mut depth = 1;
+        while depth > 0 {
+            match self.next_token()?.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
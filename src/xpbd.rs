@@ -0,0 +1,381 @@
+use gl::types::*;
+use crate::model::*;
+use crate::particles::*;
+use crate::primitives::*;
+
+// Compliances are inverse stiffnesses in SI units: m/N for distance and
+// bending, m^3/N for volume. Zero is perfectly stiff.
+#[derive(Clone, Copy, Debug)]
+pub struct XpbdOptions {
+    pub substeps: usize,
+    // Constraint passes per substep, one is usually enough with small steps
+    pub iterations: usize,
+    pub gravity: glm::Vec3,
+    // Fraction of velocity lost per second
+    pub damping: f32,
+    pub friction: f32,
+    pub distance_compliance: f32,
+    pub bending_compliance: f32,
+    pub volume_compliance: f32,
+    // Target volume as a multiple of the rest volume, above one inflates
+    pub pressure: f32,
+}
+
+impl XpbdOptions {
+    pub fn new() -> XpbdOptions {
+        XpbdOptions {
+            substeps: 20,
+            iterations: 1,
+            gravity: glm::vec3(0.0, -9.8, 0.0),
+            damping: 0.1,
+            friction: 0.3,
+            distance_compliance: 0.0,
+            bending_compliance: 0.01,
+            volume_compliance: 0.0,
+            pressure: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Constraint {
+    Distance { a: usize, b: usize, rest_length: f32 },
+    // Keeps the far corners of two triangles sharing an edge, or particles
+    // two apart along a rope, at their rest distance
+    Bending { a: usize, b: usize, rest_length: f32 },
+    // Volume enclosed by the whole surface mesh
+    Volume { rest_volume: f32 },
+}
+
+// Extended position based dynamics (Macklin et al. 2016) with the small
+// steps scheme: many substeps, few iterations, Lagrange multipliers reset
+// every substep.
+pub struct XpbdBody {
+    options: XpbdOptions,
+    particles: Particles,
+    previous: Vec<glm::Vec3>,
+    constraints: Vec<Constraint>,
+    lambdas: Vec<f32>,
+    // Surface triangles, None for ropes
+    mesh: Option<ParticleMesh>,
+    radius: f32,
+    ground: Option<f32>,
+    spheres: Vec<CollisionSphere>,
+    initial: Vec<glm::Vec3>,
+    bead_model: Option<Model>,
+}
+
+impl XpbdBody {
+    fn new(particles: Particles, constraints: Vec<Constraint>, mesh: Option<ParticleMesh>, radius: f32, options: XpbdOptions) -> XpbdBody {
+        XpbdBody {
+            options,
+            previous: particles.get_positions().to_vec(),
+            initial: particles.get_positions().to_vec(),
+            lambdas: vec![0.0; constraints.len()],
+            particles,
+            constraints,
+            mesh,
+            radius,
+            ground: None,
+            spheres: Vec::new(),
+            bead_model: None,
+        }
+    }
+
+    // Chain of `segments` links hanging from `start`, which is pinned
+    pub fn rope(start: glm::Vec3, end: glm::Vec3, segments: usize, mass: f32, options: XpbdOptions) -> XpbdBody {
+        let segments = segments.max(1);
+        let mut particles = Particles::new();
+        for index in 0..=segments {
+            particles.add(glm::lerp(&start, &end, index as f32 / segments as f32), mass / (segments + 1) as f32);
+        }
+        particles.set_inverse_mass(0, 0.0);
+        let positions = particles.get_positions().to_vec();
+        let mut constraints = Vec::new();
+        for index in 0..segments {
+            constraints.push(Constraint::Distance { a: index, b: index + 1, rest_length: glm::length(&(positions[index + 1] - positions[index])) });
+        }
+        for index in 0..segments.saturating_sub(1) {
+            constraints.push(Constraint::Bending { a: index, b: index + 2, rest_length: glm::length(&(positions[index + 2] - positions[index])) });
+        }
+        let radius = 0.4 * glm::length(&(end - start)) / segments as f32;
+        Self::new(particles, constraints, None, radius, options)
+    }
+
+    // Sheet in the xy plane hanging from its top corners, `position` is the top left
+    pub fn cloth(position: glm::Vec3, width: f32, height: f32, resolution_x: usize, resolution_y: usize, mass: f32, options: XpbdOptions) -> XpbdBody {
+        let columns = resolution_x.max(2);
+        let rows = resolution_y.max(2);
+        let index = |x: usize, y: usize| (y * columns + x) as u32;
+        let mut particles = Particles::new();
+        for y in 0..rows {
+            for x in 0..columns {
+                let offset = glm::vec3(width * x as f32 / (columns - 1) as f32, -height * y as f32 / (rows - 1) as f32, 0.0);
+                particles.add(position + offset, mass / (columns * rows) as f32);
+            }
+        }
+        particles.set_inverse_mass(index(0, 0) as usize, 0.0);
+        particles.set_inverse_mass(index(columns - 1, 0) as usize, 0.0);
+        let mut indices = Vec::new();
+        for y in 0..rows - 1 {
+            for x in 0..columns - 1 {
+                indices.extend_from_slice(&[index(x, y), index(x, y + 1), index(x + 1, y + 1), index(x, y), index(x + 1, y + 1), index(x + 1, y)]);
+            }
+        }
+        let constraints = Self::surface_constraints(particles.get_positions(), &indices);
+        Self::new(particles, constraints, Some(ParticleMesh::new(indices)), 0.0, options)
+    }
+
+    // Closed icosphere held in shape by its edges and its volume
+    pub fn blob(center: glm::Vec3, radius: f32, subdivisions: usize, mass: f32, options: XpbdOptions) -> XpbdBody {
+        let sphere = MeshData::icosphere(radius, subdivisions);
+        let mut particles = Particles::new();
+        for vertex in sphere.get_vertices() {
+            particles.add(center + vertex.get_position(), mass / sphere.get_vertices().len() as f32);
+        }
+        let indices = sphere.get_indices().to_vec();
+        let mut constraints = Self::surface_constraints(particles.get_positions(), &indices);
+        constraints.push(Constraint::Volume { rest_volume: Self::volume(particles.get_positions(), &indices) });
+        Self::new(particles, constraints, Some(ParticleMesh::new(indices)), 0.0, options)
+    }
+
+    // A distance constraint per edge and a bending constraint per pair of
+    // triangles sharing an edge
+    fn surface_constraints(positions: &[glm::Vec3], indices: &[u32]) -> Vec<Constraint> {
+        let mut edges: std::collections::HashMap<(usize, usize), usize> = std::collections::HashMap::new();
+        let mut constraints = Vec::new();
+        for triangle in indices.chunks(3) {
+            for corner in 0..3 {
+                let a = triangle[corner] as usize;
+                let b = triangle[(corner + 1) % 3] as usize;
+                let opposite = triangle[(corner + 2) % 3] as usize;
+                let distance = |a: usize, b: usize| glm::length(&(positions[b] - positions[a]));
+                match edges.get(&(a.min(b), a.max(b))) {
+                    Some(&other) => constraints.push(Constraint::Bending { a: opposite, b: other, rest_length: distance(opposite, other) }),
+                    None => {
+                        edges.insert((a.min(b), a.max(b)), opposite);
+                        constraints.push(Constraint::Distance { a, b, rest_length: distance(a, b) });
+                    },
+                }
+            }
+        }
+        constraints
+    }
+
+    fn volume(positions: &[glm::Vec3], indices: &[u32]) -> f32 {
+        indices.chunks(3).map(|triangle| {
+            let (a, b, c) = (positions[triangle[0] as usize], positions[triangle[1] as usize], positions[triangle[2] as usize]);
+            a.cross(&b).dot(&c) / 6.0
+        }).sum()
+    }
+
+    pub fn get_options(&self) -> &XpbdOptions {
+        &self.options
+    }
+
+    pub fn get_options_mut(&mut self) -> &mut XpbdOptions {
+        &mut self.options
+    }
+
+    pub fn get_particles(&self) -> &Particles {
+        &self.particles
+    }
+
+    pub fn get_particles_mut(&mut self) -> &mut Particles {
+        &mut self.particles
+    }
+
+    pub fn get_constraints(&self) -> &[Constraint] {
+        &self.constraints
+    }
+
+    pub fn get_volume(&self) -> f32 {
+        match &self.mesh {
+            Some(mesh) => Self::volume(self.particles.get_positions(), mesh.get_indices()),
+            None => 0.0,
+        }
+    }
+
+    pub fn set_ground(&mut self, ground: Option<f32>) {
+        self.ground = ground;
+    }
+
+    pub fn add_sphere(&mut self, center: glm::Vec3, radius: f32) -> usize {
+        self.spheres.push(CollisionSphere {
+            center,
+            radius,
+        });
+        self.spheres.len() - 1
+    }
+
+    pub fn get_spheres(&self) -> &[CollisionSphere] {
+        &self.spheres
+    }
+
+    pub fn move_pinned(&mut self, delta: glm::Vec3) {
+        self.particles.move_pinned(delta);
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        let dt = dt.min(1.0 / 30.0);
+        if dt <= 0.0 {
+            return;
+        }
+        let substeps = self.options.substeps.max(1);
+        let h = dt / substeps as f32;
+        for _ in 0..substeps {
+            self.predict(h);
+            for lambda in &mut self.lambdas {
+                *lambda = 0.0;
+            }
+            for _ in 0..self.options.iterations.max(1) {
+                self.solve_constraints(h);
+                self.solve_collisions();
+            }
+            self.update_velocities(h);
+        }
+    }
+
+    fn predict(&mut self, h: f32) {
+        let damping = (1.0 - self.options.damping * h).max(0.0);
+        self.previous.copy_from_slice(self.particles.get_positions());
+        for particle in 0..self.particles.get_num_particles() {
+            if self.particles.is_pinned(particle) {
+                continue;
+            }
+            let velocity = (self.particles.get_velocities()[particle] + self.options.gravity * h) * damping;
+            self.particles.get_velocities_mut()[particle] = velocity;
+            self.particles.get_positions_mut()[particle] += velocity * h;
+        }
+    }
+
+    // Applies one XPBD update for a constraint with value `c` and gradients
+    // `gradients` at `particles`
+    fn project(&mut self, constraint: usize, c: f32, particles: &[usize], gradients: &[glm::Vec3], compliance: f32, h: f32) {
+        let inverse_masses = self.particles.get_inverse_masses();
+        let denominator: f32 = particles.iter().zip(gradients)
+            .map(|(&particle, gradient)| inverse_masses[particle] * glm::dot(gradient, gradient))
+            .sum();
+        let alpha = compliance / (h * h);
+        if denominator + alpha <= 1e-12 {
+            return;
+        }
+        let delta_lambda = (-c - alpha * self.lambdas[constraint]) / (denominator + alpha);
+        self.lambdas[constraint] += delta_lambda;
+        for (&particle, gradient) in particles.iter().zip(gradients) {
+            let inverse_mass = self.particles.get_inverse_mass(particle);
+            self.particles.get_positions_mut()[particle] += gradient * (delta_lambda * inverse_mass);
+        }
+    }
+
+    fn solve_constraints(&mut self, h: f32) {
+        for index in 0..self.constraints.len() {
+            match self.constraints[index] {
+                Constraint::Distance { a, b, rest_length } => self.solve_distance(index, a, b, rest_length, self.options.distance_compliance, h),
+                Constraint::Bending { a, b, rest_length } => self.solve_distance(index, a, b, rest_length, self.options.bending_compliance, h),
+                Constraint::Volume { rest_volume } => self.solve_volume(index, rest_volume, h),
+            }
+        }
+    }
+
+    fn solve_distance(&mut self, constraint: usize, a: usize, b: usize, rest_length: f32, compliance: f32, h: f32) {
+        let positions = self.particles.get_positions();
+        let delta = positions[a] - positions[b];
+        let length = glm::length(&delta);
+        if length <= 1e-6 {
+            return;
+        }
+        let direction = delta / length;
+        self.project(constraint, length - rest_length, &[a, b], &[direction, -direction], compliance, h);
+    }
+
+    fn solve_volume(&mut self, constraint: usize, rest_volume: f32, h: f32) {
+        let mesh = match &self.mesh {
+            Some(mesh) => mesh,
+            None => return,
+        };
+        let positions = self.particles.get_positions();
+        let mut gradients = vec![glm::vec3(0.0, 0.0, 0.0); positions.len()];
+        for triangle in mesh.get_indices().chunks(3) {
+            let (a, b, c) = (triangle[0] as usize, triangle[1] as usize, triangle[2] as usize);
+            gradients[a] += positions[b].cross(&positions[c]) / 6.0;
+            gradients[b] += positions[c].cross(&positions[a]) / 6.0;
+            gradients[c] += positions[a].cross(&positions[b]) / 6.0;
+        }
+        let c = Self::volume(positions, mesh.get_indices()) - self.options.pressure * rest_volume;
+        let particles: Vec<usize> = (0..positions.len()).collect();
+        self.project(constraint, c, &particles, &gradients, self.options.volume_compliance, h);
+    }
+
+    // Inequality constraints against the ground and spheres, stiff and
+    // rebuilt every substep. Friction takes back part of the sliding motion.
+    fn solve_collisions(&mut self) {
+        let friction = glm::clamp_scalar(self.options.friction, 0.0, 1.0);
+        for particle in 0..self.particles.get_num_particles() {
+            if self.particles.is_pinned(particle) {
+                continue;
+            }
+            let mut position = self.particles.get_positions()[particle];
+            let mut contacts = Vec::new();
+            if let Some(ground) = self.ground {
+                if position.y < ground + self.radius {
+                    position.y = ground + self.radius;
+                    contacts.push(glm::vec3(0.0, 1.0, 0.0));
+                }
+            }
+            for sphere in &self.spheres {
+                let offset = position - sphere.center;
+                let distance = glm::length(&offset);
+                let reach = sphere.radius + self.radius;
+                if distance < reach && distance > 1e-6 {
+                    let normal = offset / distance;
+                    position = sphere.center + normal * reach;
+                    contacts.push(normal);
+                }
+            }
+            for normal in contacts {
+                let moved = position - self.previous[particle];
+                let sliding = moved - normal * glm::dot(&moved, &normal);
+                position -= sliding * friction;
+            }
+            self.particles.get_positions_mut()[particle] = position;
+        }
+    }
+
+    fn update_velocities(&mut self, h: f32) {
+        for particle in 0..self.particles.get_num_particles() {
+            if self.particles.is_pinned(particle) {
+                continue;
+            }
+            let velocity = (self.particles.get_positions()[particle] - self.previous[particle]) / h;
+            self.particles.get_velocities_mut()[particle] = velocity;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.particles.set_positions(&self.initial);
+    }
+
+    pub fn make_model(&mut self) {
+        match self.mesh.as_mut() {
+            Some(mesh) => mesh.make_model(self.particles.get_positions()),
+            None => {
+                let mut model = Model::new();
+                model.make_uv_sphere(self.radius, 8, 6);
+                self.bead_model = Some(model);
+            },
+        }
+    }
+
+    // Surfaces draw as a mesh, ropes as a bead per particle
+    pub fn draw(&mut self, view_proj_mat: glm::Mat4, shader: GLuint) {
+        if let Some(mesh) = self.mesh.as_mut() {
+            mesh.draw(self.particles.get_positions(), view_proj_mat, shader);
+        }
+        if let Some(model) = &self.bead_model {
+            for position in self.particles.get_positions() {
+                model.draw(glm::translation(position), view_proj_mat, shader);
+            }
+        }
+    }
+}